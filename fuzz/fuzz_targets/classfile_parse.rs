@@ -0,0 +1,55 @@
+#![no_main]
+
+use jvmti2_sys::classfile::{AttributeInfo, ClassFile, Constant};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let class = match ClassFile::parse(data) {
+        Ok(class) => class,
+        Err(err) => {
+            assert!(err.offset <= data.len());
+            let _ = err.to_string();
+            return;
+        }
+    };
+
+    // Indices checked by the parser must resolve.
+    let pool = &class.constant_pool;
+    let _ = class.name().to_str();
+    assert_eq!(class.super_name().is_some(), class.super_class != 0);
+    assert_eq!(class.interface_names().count(), class.interfaces.len());
+    for (index, constant) in pool.iter() {
+        match constant {
+            Constant::Utf8(s) => {
+                let _ = s.to_str();
+            }
+            Constant::Fieldref { .. }
+            | Constant::Methodref { .. }
+            | Constant::InterfaceMethodref { .. } => {
+                assert!(pool.member_ref(index).is_some());
+            }
+            _ => {}
+        }
+    }
+    for method in &class.methods {
+        if let Some(code) = method.code() {
+            for handler in &code.exception_table {
+                assert!(handler.start_pc < handler.end_pc);
+                assert!((handler.end_pc as usize) <= code.code.len());
+            }
+            for line in code.line_numbers() {
+                assert!((line.start_pc as usize) < code.code.len());
+            }
+            for variable in code.local_variables() {
+                assert!(pool.utf8(variable.name_index).is_some());
+            }
+        }
+    }
+    for attribute in &class.attributes {
+        if let AttributeInfo::Record(components) = &attribute.info {
+            for component in components {
+                assert!(pool.utf8(component.descriptor_index).is_some());
+            }
+        }
+    }
+});
@@ -0,0 +1,29 @@
+#![no_main]
+
+use std::collections::HashMap;
+
+use jvmti2_sys::classfile::opcodes::{DUP, ICONST_0, POP};
+use jvmti2_sys::classfile::{Bytecode, ClassFile, ClassWriter, Probes};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(class) = ClassFile::parse(data) else {
+        return;
+    };
+    let mut writer = ClassWriter::new(&class);
+    let mut probe = Bytecode::new();
+    probe.op(ICONST_0).op(POP);
+    let mut exception = Bytecode::new();
+    exception.op(DUP).op(POP);
+    let probes = Probes {
+        entry: Some(probe.clone()),
+        before_return: Some(probe),
+        before_throw: Some(exception.clone()),
+        on_exception: Some(exception),
+    };
+    for index in 0..class.methods.len() {
+        let _ = writer.instrument_method(&class, index, &probes, &HashMap::new());
+    }
+    // Whatever was instrumented must still parse.
+    writer.write().unwrap();
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use jvmti2_sys::classfile::Mutf8;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(s) = Mutf8::from_bytes(data) {
+        let _ = s.to_str();
+    }
+    if let Ok(s) = core::str::from_utf8(data) {
+        let encoded = Mutf8::encode(s);
+        let decoded = Mutf8::from_bytes(&encoded).expect("encoded string is well-formed");
+        assert_eq!(decoded.to_str(), s);
+    }
+});
@@ -0,0 +1,389 @@
+//! Sampled allocation profiling.
+//!
+//! Since JDK 11 the VM can report a sample of allocations through the
+//! `SampledObjectAlloc` event, picking on average one allocation every
+//! `SetHeapSamplingInterval` bytes. [`AllocationProfiler`] records the stack
+//! trace of every sample, aggregates samples by allocated class and stack,
+//! and scales them up by the inverse of their sampling probability to
+//! estimate the total number and size of allocations.
+//!
+//! Forward `SampledObjectAlloc` events to
+//! [`AllocationProfiler::on_sampled_object_alloc`]. With
+//! [`AllocationProfilerOptions::track_live`], sampled objects are tagged and
+//! `ObjectFree` events forwarded to [`AllocationProfiler::on_object_free`]
+//! keep track of the samples that are still alive.
+//!
+//! Requires the `can_generate_sampled_object_alloc_events` capability, and
+//! `can_tag_objects` and `can_generate_object_free_events` to track live
+//! objects.
+
+use core::ptr::null_mut;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use jni_sys::{jclass, jint, jlong, jobject, JNIEnv};
+
+use crate::collapsed::CollapsedStacks;
+use crate::method_cache::MethodCache;
+use crate::stack::{stack_trace, Frame};
+use crate::tags::{TagAllocator, TagError, TagMap};
+use crate::util::{check, class_name, take_string};
+use crate::{jthread, jvmtiEnv, jvmtiError, jvmtiEvent, jvmtiEventMode};
+
+/// Options for [`AllocationProfiler`].
+#[derive(Clone, Copy, Debug)]
+pub struct AllocationProfilerOptions {
+    /// The average number of bytes allocated between two samples. Zero
+    /// samples every allocation.
+    pub interval: jint,
+    /// The maximum number of frames recorded per sample.
+    pub max_depth: usize,
+    /// Tag sampled objects to track which of them are still alive. Objects
+    /// that already carry a tag are not tracked.
+    pub track_live: bool,
+}
+
+impl Default for AllocationProfilerOptions {
+    fn default() -> Self {
+        AllocationProfilerOptions {
+            interval: 512 * 1024,
+            max_depth: 64,
+            track_live: false,
+        }
+    }
+}
+
+/// The class and stack trace of sampled allocations.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AllocationSite {
+    /// The signature of the allocated class, e.g. `[B`.
+    pub class_signature: String,
+    /// The stack trace of the allocation, innermost frame first.
+    pub stack: Vec<Frame>,
+}
+
+/// Sample counts and estimated totals of an [`AllocationSite`].
+///
+/// Estimates weight every sample of `size` bytes by `1 / p` where
+/// `p = 1 - exp(-size / interval)` is the probability of the allocation being
+/// sampled.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllocationStats {
+    pub samples: u64,
+    /// The sum of the sizes of the sampled objects.
+    pub sampled_bytes: u64,
+    /// The estimated number of allocations.
+    pub allocations: f64,
+    /// The estimated number of allocated bytes.
+    pub bytes: f64,
+    /// The number of samples not collected yet, if live objects are tracked.
+    pub live_samples: u64,
+    /// The estimated number of allocations not collected yet.
+    pub live_allocations: f64,
+    /// The estimated number of bytes not collected yet.
+    pub live_bytes: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct LiveSample {
+    site: usize,
+    /// The [`Sites::generation`] the sample was counted in.
+    generation: u64,
+    allocations: f64,
+    bytes: f64,
+}
+
+#[derive(Debug, Default)]
+struct Sites {
+    index: HashMap<Arc<AllocationSite>, usize>,
+    stats: Vec<(Arc<AllocationSite>, AllocationStats)>,
+    /// Incremented by every reset, so that live samples counted before the
+    /// reset are not subtracted from the sites that replaced theirs.
+    generation: u64,
+}
+
+/// Aggregates `SampledObjectAlloc` events by class and stack trace.
+///
+/// To track live objects, the profiler tags samples from
+/// [`FIRST_TAG`](Self::FIRST_TAG) on and loses them when the tags are
+/// overwritten or cleared, e.g. by [`HeapGraph::build`](crate::heap_graph::HeapGraph::build).
+/// It needs a dedicated JVMTI environment unless everything else tagging
+/// objects in its environment uses a disjoint range of tags.
+#[derive(Debug)]
+pub struct AllocationProfiler {
+    options: AllocationProfilerOptions,
+    sites: Mutex<Sites>,
+    live: TagMap<LiveSample>,
+}
+
+impl AllocationProfiler {
+    /// The first tag given to sampled objects when live objects are tracked.
+    pub const FIRST_TAG: jlong = 1 << 40;
+
+    pub fn new(options: AllocationProfilerOptions) -> Self {
+        AllocationProfiler {
+            options,
+            sites: Mutex::new(Sites::default()),
+            live: TagMap::with_allocator(TagAllocator::starting_at(Self::FIRST_TAG)),
+        }
+    }
+
+    pub fn options(&self) -> &AllocationProfilerOptions {
+        &self.options
+    }
+
+    /// Sets the sampling interval and enables the `SampledObjectAlloc` event,
+    /// and the `ObjectFree` event if live objects are tracked.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn start(&self, env: *mut jvmtiEnv) -> Result<(), jvmtiError> {
+        check(jvmti!(
+            env,
+            v11,
+            SetHeapSamplingInterval,
+            self.options.interval
+        ))?;
+        self.set_events(env, jvmtiEventMode::JVMTI_ENABLE)
+    }
+
+    /// Disables the events enabled by [`start`](Self::start). Results are
+    /// kept; live objects are no longer tracked.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn stop(&self, env: *mut jvmtiEnv) -> Result<(), jvmtiError> {
+        self.set_events(env, jvmtiEventMode::JVMTI_DISABLE)
+    }
+
+    unsafe fn set_events(
+        &self,
+        env: *mut jvmtiEnv,
+        mode: jvmtiEventMode,
+    ) -> Result<(), jvmtiError> {
+        check(jvmti!(
+            env,
+            v1,
+            SetEventNotificationMode,
+            mode,
+            jvmtiEvent::JVMTI_EVENT_SAMPLED_OBJECT_ALLOC,
+            null_mut::<jni_sys::_jobject>()
+        ))?;
+        if self.options.track_live {
+            check(jvmti!(
+                env,
+                v1,
+                SetEventNotificationMode,
+                mode,
+                jvmtiEvent::JVMTI_EVENT_OBJECT_FREE,
+                null_mut::<jni_sys::_jobject>()
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Records a sampled allocation. Forward `SampledObjectAlloc` events
+    /// here.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with the arguments of a `SampledObjectAlloc` event,
+    /// from the event callback.
+    pub unsafe fn on_sampled_object_alloc(
+        &self,
+        env: *mut jvmtiEnv,
+        _jni: *mut JNIEnv,
+        thread: jthread,
+        object: jobject,
+        klass: jclass,
+        size: jlong,
+    ) -> Result<(), jvmtiError> {
+        let stack = stack_trace(env, thread, self.options.max_depth)?;
+        let mut signature = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetClassSignature,
+            klass,
+            &mut signature,
+            null_mut()
+        ))?;
+        let site = AllocationSite {
+            class_signature: take_string(env, signature).unwrap_or_default(),
+            stack,
+        };
+
+        let interval = self.options.interval as f64;
+        let probability = if interval > 0.0 {
+            1.0 - (-(size as f64) / interval).exp()
+        } else {
+            1.0
+        };
+        let allocations = 1.0 / probability;
+        let bytes = size as f64 / probability;
+
+        let (index, generation) = {
+            let mut sites = self.sites.lock().unwrap();
+            let index = match sites.index.get(&site) {
+                Some(&index) => index,
+                None => {
+                    let site = Arc::new(site);
+                    let index = sites.stats.len();
+                    sites.index.insert(site.clone(), index);
+                    sites.stats.push((site, AllocationStats::default()));
+                    index
+                }
+            };
+            let stats = &mut sites.stats[index].1;
+            stats.samples += 1;
+            stats.sampled_bytes += size as u64;
+            stats.allocations += allocations;
+            stats.bytes += bytes;
+            if self.options.track_live {
+                stats.live_samples += 1;
+                stats.live_allocations += allocations;
+                stats.live_bytes += bytes;
+            }
+            (index, sites.generation)
+        };
+
+        if self.options.track_live {
+            let sample = LiveSample {
+                site: index,
+                generation,
+                allocations,
+                bytes,
+            };
+            match self.live.insert(env, object, sample) {
+                Ok(_) => {}
+                // Objects tagged by someone else are counted but not tracked.
+                Err(TagError::ForeignTag(_)) => self.remove_live(sample),
+                Err(TagError::Jvmti(err)) => {
+                    self.remove_live(sample);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the sampled object tagged `tag` as collected. Forward
+    /// `ObjectFree` events here; returns whether the tag belonged to a
+    /// sampled object.
+    pub fn on_object_free(&self, tag: jlong) -> bool {
+        match self.live.on_object_free(tag) {
+            Some(sample) => {
+                self.remove_live(sample);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_live(&self, sample: LiveSample) {
+        let mut sites = self.sites.lock().unwrap();
+        if sites.generation != sample.generation {
+            return;
+        }
+        if let Some((_, stats)) = sites.stats.get_mut(sample.site) {
+            stats.live_samples = stats.live_samples.saturating_sub(1);
+            stats.live_allocations = (stats.live_allocations - sample.allocations).max(0.0);
+            stats.live_bytes = (stats.live_bytes - sample.bytes).max(0.0);
+        }
+    }
+
+    /// Returns the statistics of every allocation site, sorted by estimated
+    /// bytes in descending order.
+    pub fn snapshot(&self) -> Vec<(AllocationSite, AllocationStats)> {
+        let sites = self.sites.lock().unwrap();
+        let mut snapshot: Vec<_> = sites
+            .stats
+            .iter()
+            .map(|(site, stats)| (AllocationSite::clone(site), *stats))
+            .collect();
+        snapshot.sort_by(|a, b| b.1.bytes.total_cmp(&a.1.bytes));
+        snapshot
+    }
+
+    /// Returns the statistics summed over all allocation sites.
+    pub fn total(&self) -> AllocationStats {
+        let sites = self.sites.lock().unwrap();
+        let mut total = AllocationStats::default();
+        for (_, stats) in &sites.stats {
+            total.samples += stats.samples;
+            total.sampled_bytes += stats.sampled_bytes;
+            total.allocations += stats.allocations;
+            total.bytes += stats.bytes;
+            total.live_samples += stats.live_samples;
+            total.live_allocations += stats.live_allocations;
+            total.live_bytes += stats.live_bytes;
+        }
+        total
+    }
+
+    /// Returns the allocation stacks, weighted by `weight` applied to the
+    /// statistics of each site. Sites with the same stack are merged.
+    pub fn collapsed(&self, weight: impl Fn(&AllocationStats) -> u64) -> CollapsedStacks {
+        let sites = self.sites.lock().unwrap();
+        let mut stacks = CollapsedStacks::new();
+        for (site, stats) in &sites.stats {
+            stacks.add(&site.stack, weight(stats));
+        }
+        stacks
+    }
+
+    /// Discards all samples.
+    pub fn reset(&self) {
+        let mut sites = self.sites.lock().unwrap();
+        sites.index.clear();
+        sites.stats.clear();
+        sites.generation += 1;
+        self.live.clear();
+    }
+
+    /// Writes the `top` allocation sites by estimated bytes as text, with
+    /// their stack traces resolved through `cache`.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn write_report(
+        &self,
+        env: *mut jvmtiEnv,
+        cache: &MethodCache,
+        mut out: impl Write,
+        top: usize,
+    ) -> io::Result<()> {
+        let total = self.total();
+        writeln!(
+            out,
+            "{} samples, {:.0} allocations, {:.0} bytes estimated (interval {} bytes)",
+            total.samples, total.allocations, total.bytes, self.options.interval
+        )?;
+        for (site, stats) in self.snapshot().into_iter().take(top) {
+            writeln!(out)?;
+            write!(
+                out,
+                "{:.0} bytes ({:.1}%), {:.0} allocations of {}",
+                stats.bytes,
+                100.0 * stats.bytes / total.bytes,
+                stats.allocations,
+                class_name(&site.class_signature)
+            )?;
+            if self.options.track_live {
+                write!(out, ", {:.0} bytes live", stats.live_bytes)?;
+            }
+            writeln!(out)?;
+            for frame in &site.stack {
+                match cache.get(env, frame.method) {
+                    Ok(info) => writeln!(out, "    at {}", info.describe(frame.location))?,
+                    Err(_) => writeln!(out, "    at <unknown method>")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,512 @@
+//! Attributes of classes, fields, methods and code.
+
+use super::constant_pool::{ConstantPool, Expect, Mutf8};
+use super::reader::Reader;
+use super::{ParseError, ParseErrorKind};
+
+/// An attribute with its raw contents and, for the attributes this module
+/// knows, their parsed form.
+#[derive(Clone, Debug)]
+pub struct Attribute<'a> {
+    pub name_index: u16,
+    pub name: Mutf8<'a>,
+    /// The offset of `data` in the class file.
+    pub offset: usize,
+    /// The contents of the attribute, after its length.
+    pub data: &'a [u8],
+    pub info: AttributeInfo<'a>,
+}
+
+/// The parsed contents of an attribute. References to the constant pool
+/// are kept as indices.
+///
+/// Attributes are only parsed where the JVM specification defines them;
+/// elsewhere, as well as for other names, they are [`Other`](Self::Other).
+#[derive(Clone, Debug)]
+pub enum AttributeInfo<'a> {
+    /// The `Integer`, `Float`, `Long`, `Double` or `String` initializing a
+    /// static field.
+    ConstantValue {
+        index: u16,
+    },
+    Code(Code<'a>),
+    /// The `Class` entries of the checked exceptions a method declares.
+    Exceptions(Vec<u16>),
+    /// The generic signature of a class, field, method or record component.
+    Signature {
+        index: u16,
+    },
+    SourceFile {
+        index: u16,
+    },
+    LineNumberTable(Vec<LineNumber>),
+    LocalVariableTable(Vec<LocalVariable>),
+    BootstrapMethods(Vec<BootstrapMethod>),
+    NestHost {
+        host_class_index: u16,
+    },
+    NestMembers(Vec<u16>),
+    Record(Vec<RecordComponent<'a>>),
+    PermittedSubclasses(Vec<u16>),
+    Other,
+}
+
+impl AttributeInfo<'_> {
+    /// Returns whether an attribute may appear more than once in the same
+    /// structure.
+    fn repeatable(&self) -> bool {
+        matches!(
+            self,
+            AttributeInfo::LineNumberTable(_)
+                | AttributeInfo::LocalVariableTable(_)
+                | AttributeInfo::Other
+        )
+    }
+}
+
+/// The `Code` attribute of a method.
+#[derive(Clone, Debug)]
+pub struct Code<'a> {
+    pub max_stack: u16,
+    pub max_locals: u16,
+    pub code: &'a [u8],
+    /// The offset of `code` in the class file.
+    pub code_offset: usize,
+    pub exception_table: Vec<ExceptionHandler>,
+    pub attributes: Vec<Attribute<'a>>,
+}
+
+impl<'a> Code<'a> {
+    /// Returns the entries of all `LineNumberTable` attributes.
+    pub fn line_numbers(&self) -> impl Iterator<Item = &LineNumber> + '_ {
+        self.attributes
+            .iter()
+            .filter_map(|attribute| match &attribute.info {
+                AttributeInfo::LineNumberTable(lines) => Some(lines),
+                _ => None,
+            })
+            .flatten()
+    }
+
+    /// Returns the source line of the instruction at `pc`, if known.
+    pub fn line_at(&self, pc: u16) -> Option<u16> {
+        self.line_numbers()
+            .filter(|line| line.start_pc <= pc)
+            .max_by_key(|line| line.start_pc)
+            .map(|line| line.line_number)
+    }
+
+    /// Returns the entries of all `LocalVariableTable` attributes.
+    pub fn local_variables(&self) -> impl Iterator<Item = &LocalVariable> + '_ {
+        self.attributes
+            .iter()
+            .filter_map(|attribute| match &attribute.info {
+                AttributeInfo::LocalVariableTable(variables) => Some(variables),
+                _ => None,
+            })
+            .flatten()
+    }
+}
+
+/// An entry of the exception table of a [`Code`] attribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExceptionHandler {
+    pub start_pc: u16,
+    /// The end of the protected range, exclusive.
+    pub end_pc: u16,
+    pub handler_pc: u16,
+    /// The `Class` entry of the caught exception, 0 for any.
+    pub catch_type: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineNumber {
+    pub start_pc: u16,
+    pub line_number: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalVariable {
+    pub start_pc: u16,
+    pub length: u16,
+    pub name_index: u16,
+    pub descriptor_index: u16,
+    /// The local variable slot.
+    pub index: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BootstrapMethod {
+    /// The `MethodHandle` entry of the bootstrap method.
+    pub method_ref: u16,
+    /// The loadable entries passed as static arguments.
+    pub arguments: Vec<u16>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordComponent<'a> {
+    pub name_index: u16,
+    pub descriptor_index: u16,
+    pub attributes: Vec<Attribute<'a>>,
+}
+
+/// The structure attributes belong to, which decides which ones are parsed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Location {
+    Class,
+    Field,
+    Method,
+    /// The attributes of a `Code` attribute, with the length of its code.
+    Code(u32),
+    RecordComponent,
+}
+
+/// Parses a `u2` count followed by that many attributes.
+pub(super) fn parse_attributes<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    location: Location,
+) -> Result<Vec<Attribute<'a>>, ParseError> {
+    let count = r.u2()?;
+    let mut attributes: Vec<Attribute<'a>> = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let start = r.offset();
+        let attribute = parse_attribute(r, pool, location)?;
+        if !attribute.info.repeatable()
+            && attributes
+                .iter()
+                .any(|other| other.name == attribute.name && !other.info.repeatable())
+        {
+            return Err(r.error_at(start, ParseErrorKind::DuplicateAttribute));
+        }
+        attributes.push(attribute);
+    }
+    Ok(attributes)
+}
+
+fn parse_attribute<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    location: Location,
+) -> Result<Attribute<'a>, ParseError> {
+    let name_index = r.u2()?;
+    let name = utf8(r, pool, name_index)?;
+    let length = r.u4()? as usize;
+    let offset = r.offset();
+    let mut data = r.sub(length)?;
+    let bytes = data.clone().bytes(length)?;
+
+    let name_str = name.to_str();
+    let (context, parse): (&'static str, Option<ParseFn>) = match (&*name_str, location) {
+        ("ConstantValue", Location::Field) => ("ConstantValue attribute", Some(constant_value)),
+        ("Code", Location::Method) => ("Code attribute", Some(code)),
+        ("Exceptions", Location::Method) => ("Exceptions attribute", Some(exceptions)),
+        (
+            "Signature",
+            Location::Class | Location::Field | Location::Method | Location::RecordComponent,
+        ) => ("Signature attribute", Some(signature)),
+        ("SourceFile", Location::Class) => ("SourceFile attribute", Some(source_file)),
+        ("LineNumberTable", Location::Code(_)) => {
+            ("LineNumberTable attribute", Some(line_number_table))
+        }
+        ("LocalVariableTable", Location::Code(_)) => {
+            ("LocalVariableTable attribute", Some(local_variable_table))
+        }
+        ("BootstrapMethods", Location::Class) => {
+            ("BootstrapMethods attribute", Some(bootstrap_methods))
+        }
+        ("NestHost", Location::Class) => ("NestHost attribute", Some(nest_host)),
+        ("NestMembers", Location::Class) => ("NestMembers attribute", Some(nest_members)),
+        ("Record", Location::Class) => ("Record attribute", Some(record)),
+        ("PermittedSubclasses", Location::Class) => {
+            ("PermittedSubclasses attribute", Some(permitted_subclasses))
+        }
+        _ => ("attribute", None),
+    };
+    let info = match parse {
+        Some(parse) => {
+            data.enter(context);
+            let info = parse(&mut data, pool, location)?;
+            data.finish()?;
+            info
+        }
+        None => AttributeInfo::Other,
+    };
+    Ok(Attribute {
+        name_index,
+        name,
+        offset,
+        data: bytes,
+        info,
+    })
+}
+
+type ParseFn = for<'a> fn(
+    &mut Reader<'a>,
+    &ConstantPool<'a>,
+    Location,
+) -> Result<AttributeInfo<'a>, ParseError>;
+
+/// Reads an index and checks that it refers to an entry of the `expected`
+/// kind.
+pub(super) fn index(
+    r: &mut Reader<'_>,
+    pool: &ConstantPool<'_>,
+    expected: Expect,
+) -> Result<u16, ParseError> {
+    let offset = r.offset();
+    let index = r.u2()?;
+    pool.check(index, expected)
+        .map_err(|kind| r.error_at(offset, kind))?;
+    Ok(index)
+}
+
+/// Like [`index`], but allows 0.
+pub(super) fn optional_index(
+    r: &mut Reader<'_>,
+    pool: &ConstantPool<'_>,
+    expected: Expect,
+) -> Result<u16, ParseError> {
+    let offset = r.offset();
+    match r.u2()? {
+        0 => Ok(0),
+        index => pool
+            .check(index, expected)
+            .map(|_| index)
+            .map_err(|kind| r.error_at(offset, kind)),
+    }
+}
+
+/// Checks that an index already read refers to a `Utf8` entry.
+fn utf8<'a>(r: &Reader<'a>, pool: &ConstantPool<'a>, index: u16) -> Result<Mutf8<'a>, ParseError> {
+    pool.utf8(index).ok_or_else(|| {
+        r.error_at(
+            r.offset() - 2,
+            ParseErrorKind::BadConstantIndex {
+                index,
+                expected: "Utf8",
+            },
+        )
+    })
+}
+
+/// Reads a `u2` count followed by that many indices of the `expected` kind.
+fn index_list(
+    r: &mut Reader<'_>,
+    pool: &ConstantPool<'_>,
+    expected: Expect,
+) -> Result<Vec<u16>, ParseError> {
+    let count = r.u2()?;
+    (0..count).map(|_| index(r, pool, expected)).collect()
+}
+
+/// Reads a `u2` code offset and checks that it is below `limit`.
+fn pc(r: &mut Reader<'_>, limit: u32) -> Result<u16, ParseError> {
+    let offset = r.offset();
+    let pc = r.u2()?;
+    if pc as u32 >= limit {
+        return Err(r.error_at(offset, ParseErrorKind::BadCodeOffset(pc as u32)));
+    }
+    Ok(pc)
+}
+
+fn constant_value<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    _: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    Ok(AttributeInfo::ConstantValue {
+        index: index(r, pool, Expect::ConstantValue)?,
+    })
+}
+
+fn code<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    _: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    let max_stack = r.u2()?;
+    let max_locals = r.u2()?;
+    let length_offset = r.offset();
+    let length = r.u4()?;
+    if length == 0 || length > u16::MAX as u32 {
+        return Err(r.error_at(length_offset, ParseErrorKind::BadCodeLength(length)));
+    }
+    let code_offset = r.offset();
+    let code = r.bytes(length as usize)?;
+
+    let count = r.u2()?;
+    let mut exception_table = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let offset = r.offset();
+        let start_pc = pc(r, length)?;
+        let end_pc = r.u2()?;
+        if end_pc <= start_pc || end_pc as u32 > length {
+            return Err(r.error_at(offset + 2, ParseErrorKind::BadCodeOffset(end_pc as u32)));
+        }
+        let handler_pc = pc(r, length)?;
+        let catch_type = optional_index(r, pool, Expect::Class)?;
+        exception_table.push(ExceptionHandler {
+            start_pc,
+            end_pc,
+            handler_pc,
+            catch_type,
+        });
+    }
+    let attributes = parse_attributes(r, pool, Location::Code(length))?;
+    Ok(AttributeInfo::Code(Code {
+        max_stack,
+        max_locals,
+        code,
+        code_offset,
+        exception_table,
+        attributes,
+    }))
+}
+
+fn exceptions<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    _: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    Ok(AttributeInfo::Exceptions(index_list(
+        r,
+        pool,
+        Expect::Class,
+    )?))
+}
+
+fn signature<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    _: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    Ok(AttributeInfo::Signature {
+        index: index(r, pool, Expect::Utf8)?,
+    })
+}
+
+fn source_file<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    _: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    Ok(AttributeInfo::SourceFile {
+        index: index(r, pool, Expect::Utf8)?,
+    })
+}
+
+fn line_number_table<'a>(
+    r: &mut Reader<'a>,
+    _: &ConstantPool<'a>,
+    location: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    let Location::Code(length) = location else {
+        unreachable!()
+    };
+    let count = r.u2()?;
+    let mut lines = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        lines.push(LineNumber {
+            start_pc: pc(r, length)?,
+            line_number: r.u2()?,
+        });
+    }
+    Ok(AttributeInfo::LineNumberTable(lines))
+}
+
+fn local_variable_table<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    location: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    let Location::Code(length) = location else {
+        unreachable!()
+    };
+    let count = r.u2()?;
+    let mut variables = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let offset = r.offset();
+        let start_pc = pc(r, length)?;
+        let var_length = r.u2()?;
+        let end = start_pc as u32 + var_length as u32;
+        if end > length {
+            return Err(r.error_at(offset + 2, ParseErrorKind::BadCodeOffset(end)));
+        }
+        variables.push(LocalVariable {
+            start_pc,
+            length: var_length,
+            name_index: index(r, pool, Expect::Utf8)?,
+            descriptor_index: index(r, pool, Expect::Utf8)?,
+            index: r.u2()?,
+        });
+    }
+    Ok(AttributeInfo::LocalVariableTable(variables))
+}
+
+fn bootstrap_methods<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    _: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    let count = r.u2()?;
+    let mut methods = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        methods.push(BootstrapMethod {
+            method_ref: index(r, pool, Expect::MethodHandle)?,
+            arguments: index_list(r, pool, Expect::Loadable)?,
+        });
+    }
+    Ok(AttributeInfo::BootstrapMethods(methods))
+}
+
+fn nest_host<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    _: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    Ok(AttributeInfo::NestHost {
+        host_class_index: index(r, pool, Expect::Class)?,
+    })
+}
+
+fn nest_members<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    _: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    Ok(AttributeInfo::NestMembers(index_list(
+        r,
+        pool,
+        Expect::Class,
+    )?))
+}
+
+fn record<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    _: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    let count = r.u2()?;
+    let mut components = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        components.push(RecordComponent {
+            name_index: index(r, pool, Expect::Utf8)?,
+            descriptor_index: index(r, pool, Expect::Utf8)?,
+            attributes: parse_attributes(r, pool, Location::RecordComponent)?,
+        });
+    }
+    Ok(AttributeInfo::Record(components))
+}
+
+fn permitted_subclasses<'a>(
+    r: &mut Reader<'a>,
+    pool: &ConstantPool<'a>,
+    _: Location,
+) -> Result<AttributeInfo<'a>, ParseError> {
+    Ok(AttributeInfo::PermittedSubclasses(index_list(
+        r,
+        pool,
+        Expect::Class,
+    )?))
+}
@@ -0,0 +1,865 @@
+//! The constant pool and modified UTF-8 strings.
+
+use core::fmt;
+use core::ptr::null_mut;
+use std::borrow::Cow;
+
+use jni_sys::jclass;
+
+use super::reader::Reader;
+use super::{read_header, ParseError, ParseErrorKind};
+use crate::util::{check, take_array};
+use crate::{jvmtiEnv, jvmtiError};
+
+/// A string in the JVM's modified UTF-8, borrowed from the class file.
+///
+/// Modified UTF-8 encodes `U+0000` as two bytes and supplementary
+/// characters as surrogate pairs of three bytes each. Most strings are
+/// plain ASCII, which [`to_str`](Self::to_str) returns without copying.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Mutf8<'a>(pub(super) &'a [u8]);
+
+impl<'a> Mutf8<'a> {
+    /// Wraps `bytes` if they are well-formed modified UTF-8.
+    pub fn from_bytes(bytes: &'a [u8]) -> Option<Self> {
+        validate(bytes).ok().map(|_| Mutf8(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// Returns the length in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Decodes the string. Unpaired surrogates become `U+FFFD`.
+    pub fn to_str(&self) -> Cow<'a, str> {
+        // Where modified UTF-8 differs from UTF-8, the bytes are not valid
+        // UTF-8, so anything `from_utf8` accepts decodes the same either way.
+        if let Ok(s) = core::str::from_utf8(self.0) {
+            return Cow::Borrowed(s);
+        }
+        let mut units = Vec::with_capacity(self.0.len());
+        let mut bytes = self.0.iter().map(|&b| b as u16);
+        while let Some(b) = bytes.next() {
+            let unit = match b {
+                0x00..=0x7f => b,
+                0xc0..=0xdf => (b & 0x1f) << 6 | (bytes.next().unwrap_or(0) & 0x3f),
+                _ => {
+                    let b2 = bytes.next().unwrap_or(0) & 0x3f;
+                    let b3 = bytes.next().unwrap_or(0) & 0x3f;
+                    (b & 0x0f) << 12 | b2 << 6 | b3
+                }
+            };
+            units.push(unit);
+        }
+        Cow::Owned(String::from_utf16_lossy(&units))
+    }
+
+    /// Encodes `s` as modified UTF-8.
+    pub fn encode(s: &str) -> Vec<u8> {
+        let mut out = Vec::with_capacity(s.len());
+        for unit in s.encode_utf16() {
+            match unit {
+                0x0001..=0x007f => out.push(unit as u8),
+                0x0000 | 0x0080..=0x07ff => {
+                    out.push(0xc0 | (unit >> 6) as u8);
+                    out.push(0x80 | (unit & 0x3f) as u8);
+                }
+                _ => {
+                    out.push(0xe0 | (unit >> 12) as u8);
+                    out.push(0x80 | (unit >> 6 & 0x3f) as u8);
+                    out.push(0x80 | (unit & 0x3f) as u8);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Returns the offset of the first malformed byte, if any.
+fn validate(bytes: &[u8]) -> Result<(), usize> {
+    let mut i = 0;
+    while i < bytes.len() {
+        let len = match bytes[i] {
+            0x01..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            _ => return Err(i),
+        };
+        for j in i + 1..i + len {
+            match bytes.get(j) {
+                Some(b) if b & 0xc0 == 0x80 => {}
+                _ => return Err(j),
+            }
+        }
+        i += len;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Mutf8<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_str())
+    }
+}
+
+impl fmt::Debug for Mutf8<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.to_str(), f)
+    }
+}
+
+impl PartialEq<str> for Mutf8<'_> {
+    fn eq(&self, other: &str) -> bool {
+        self.to_str() == other
+    }
+}
+
+impl PartialEq<&str> for Mutf8<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        self.to_str() == *other
+    }
+}
+
+/// The kind of method handle of a `CONSTANT_MethodHandle`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum ReferenceKind {
+    GetField = 1,
+    GetStatic = 2,
+    PutField = 3,
+    PutStatic = 4,
+    InvokeVirtual = 5,
+    InvokeStatic = 6,
+    InvokeSpecial = 7,
+    NewInvokeSpecial = 8,
+    InvokeInterface = 9,
+}
+
+impl ReferenceKind {
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        Some(match kind {
+            1 => ReferenceKind::GetField,
+            2 => ReferenceKind::GetStatic,
+            3 => ReferenceKind::PutField,
+            4 => ReferenceKind::PutStatic,
+            5 => ReferenceKind::InvokeVirtual,
+            6 => ReferenceKind::InvokeStatic,
+            7 => ReferenceKind::InvokeSpecial,
+            8 => ReferenceKind::NewInvokeSpecial,
+            9 => ReferenceKind::InvokeInterface,
+            _ => return None,
+        })
+    }
+}
+
+/// A constant pool entry. References to other entries are kept as indices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Constant<'a> {
+    /// Index 0 and the slot following a `Long` or `Double`.
+    Unusable,
+    Utf8(Mutf8<'a>),
+    Integer(i32),
+    Float(f32),
+    Long(i64),
+    Double(f64),
+    Class {
+        name_index: u16,
+    },
+    String {
+        string_index: u16,
+    },
+    Fieldref {
+        class_index: u16,
+        name_and_type_index: u16,
+    },
+    Methodref {
+        class_index: u16,
+        name_and_type_index: u16,
+    },
+    InterfaceMethodref {
+        class_index: u16,
+        name_and_type_index: u16,
+    },
+    NameAndType {
+        name_index: u16,
+        descriptor_index: u16,
+    },
+    MethodHandle {
+        reference_kind: ReferenceKind,
+        reference_index: u16,
+    },
+    MethodType {
+        descriptor_index: u16,
+    },
+    Dynamic {
+        bootstrap_method_attr_index: u16,
+        name_and_type_index: u16,
+    },
+    InvokeDynamic {
+        bootstrap_method_attr_index: u16,
+        name_and_type_index: u16,
+    },
+    Module {
+        name_index: u16,
+    },
+    Package {
+        name_index: u16,
+    },
+}
+
+impl Constant<'_> {
+    /// Returns the `CONSTANT_*` tag of the entry, 0 for unusable slots.
+    pub fn tag(&self) -> u8 {
+        match self {
+            Constant::Unusable => 0,
+            Constant::Utf8(_) => 1,
+            Constant::Integer(_) => 3,
+            Constant::Float(_) => 4,
+            Constant::Long(_) => 5,
+            Constant::Double(_) => 6,
+            Constant::Class { .. } => 7,
+            Constant::String { .. } => 8,
+            Constant::Fieldref { .. } => 9,
+            Constant::Methodref { .. } => 10,
+            Constant::InterfaceMethodref { .. } => 11,
+            Constant::NameAndType { .. } => 12,
+            Constant::MethodHandle { .. } => 15,
+            Constant::MethodType { .. } => 16,
+            Constant::Dynamic { .. } => 17,
+            Constant::InvokeDynamic { .. } => 18,
+            Constant::Module { .. } => 19,
+            Constant::Package { .. } => 20,
+        }
+    }
+
+    /// Returns whether the entry takes two slots.
+    pub fn is_wide(&self) -> bool {
+        matches!(self, Constant::Long(_) | Constant::Double(_))
+    }
+
+    /// Returns whether `ldc` and bootstrap arguments may refer to the entry.
+    pub fn is_loadable(&self) -> bool {
+        matches!(
+            self,
+            Constant::Integer(_)
+                | Constant::Float(_)
+                | Constant::Long(_)
+                | Constant::Double(_)
+                | Constant::Class { .. }
+                | Constant::String { .. }
+                | Constant::MethodHandle { .. }
+                | Constant::MethodType { .. }
+                | Constant::Dynamic { .. }
+        )
+    }
+}
+
+/// The kind of entry a reference must point to.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Expect {
+    Utf8,
+    Class,
+    NameAndType,
+    Fieldref,
+    Methodref,
+    InterfaceMethodref,
+    /// A `Methodref` or an `InterfaceMethodref`.
+    AnyMethodref,
+    MethodHandle,
+    /// An entry `ldc` or a bootstrap argument may refer to.
+    Loadable,
+    /// An entry a `ConstantValue` attribute may refer to.
+    ConstantValue,
+}
+
+impl Expect {
+    fn matches(self, constant: &Constant<'_>) -> bool {
+        match self {
+            Expect::Utf8 => matches!(constant, Constant::Utf8(_)),
+            Expect::Class => matches!(constant, Constant::Class { .. }),
+            Expect::NameAndType => matches!(constant, Constant::NameAndType { .. }),
+            Expect::Fieldref => matches!(constant, Constant::Fieldref { .. }),
+            Expect::Methodref => matches!(constant, Constant::Methodref { .. }),
+            Expect::InterfaceMethodref => matches!(constant, Constant::InterfaceMethodref { .. }),
+            Expect::AnyMethodref => matches!(
+                constant,
+                Constant::Methodref { .. } | Constant::InterfaceMethodref { .. }
+            ),
+            Expect::MethodHandle => matches!(constant, Constant::MethodHandle { .. }),
+            Expect::Loadable => constant.is_loadable(),
+            Expect::ConstantValue => matches!(
+                constant,
+                Constant::Integer(_)
+                    | Constant::Float(_)
+                    | Constant::Long(_)
+                    | Constant::Double(_)
+                    | Constant::String { .. }
+            ),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Expect::Utf8 => "Utf8",
+            Expect::Class => "Class",
+            Expect::NameAndType => "NameAndType",
+            Expect::Fieldref => "Fieldref",
+            Expect::Methodref => "Methodref",
+            Expect::InterfaceMethodref => "InterfaceMethodref",
+            Expect::AnyMethodref => "Methodref or InterfaceMethodref",
+            Expect::MethodHandle => "MethodHandle",
+            Expect::Loadable => "loadable constant",
+            Expect::ConstantValue => "constant value",
+        }
+    }
+}
+
+/// A resolved `Fieldref`, `Methodref` or `InterfaceMethodref`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemberRef<'a> {
+    /// The internal name of the class, e.g. `java/lang/String`.
+    pub class: Mutf8<'a>,
+    pub name: Mutf8<'a>,
+    pub descriptor: Mutf8<'a>,
+}
+
+/// A resolved `Dynamic` or `InvokeDynamic`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DynamicRef<'a> {
+    /// The index in the `BootstrapMethods` attribute.
+    pub bootstrap_method: u16,
+    pub name: Mutf8<'a>,
+    pub descriptor: Mutf8<'a>,
+}
+
+/// The constant pool of a class file.
+///
+/// Every reference between entries has been checked when parsing, so the
+/// lookups only fail for indices that do not come from the same class file.
+#[derive(Clone, Debug, Default)]
+pub struct ConstantPool<'a> {
+    entries: Vec<Constant<'a>>,
+    /// The offset of each entry in the class file.
+    offsets: Vec<usize>,
+}
+
+impl<'a> ConstantPool<'a> {
+    pub(super) fn parse(r: &mut Reader<'a>) -> Result<Self, ParseError> {
+        let saved = r.enter("constant pool");
+        let count = r.u2()?;
+        let pool = Self::parse_entries(r, count)?;
+        r.leave(saved);
+        Ok(pool)
+    }
+
+    /// Parses the entries of a constant pool, without `constant_pool_count`,
+    /// as `GetConstantPool` returns them. Offsets in errors are relative to
+    /// `bytes`.
+    pub fn from_entries(count: u16, bytes: &'a [u8]) -> Result<Self, ParseError> {
+        let mut r = Reader::new(bytes);
+        r.enter("constant pool");
+        let pool = Self::parse_entries(&mut r, count)?;
+        r.finish()?;
+        Ok(pool)
+    }
+
+    /// Parses only the constant pool of a class file, ignoring what comes
+    /// after it.
+    pub fn from_class_file(data: &'a [u8]) -> Result<Self, ParseError> {
+        let mut r = Reader::new(data);
+        read_header(&mut r)?;
+        Self::parse(&mut r)
+    }
+
+    fn parse_entries(r: &mut Reader<'a>, count: u16) -> Result<Self, ParseError> {
+        if count == 0 {
+            return Err(r.error(ParseErrorKind::BadConstantPoolCount));
+        }
+        let mut entries = Vec::with_capacity(count as usize);
+        let mut offsets = Vec::with_capacity(count as usize);
+        entries.push(Constant::Unusable);
+        offsets.push(r.offset());
+        while entries.len() < count as usize {
+            let offset = r.offset();
+            let constant = parse_constant(r)?;
+            entries.push(constant);
+            offsets.push(offset);
+            if constant.is_wide() {
+                if entries.len() == count as usize {
+                    return Err(r.error_at(offset, ParseErrorKind::WideConstantAtEnd));
+                }
+                entries.push(Constant::Unusable);
+                offsets.push(offset);
+            }
+        }
+
+        let pool = ConstantPool { entries, offsets };
+        for (constant, &offset) in pool.entries.iter().zip(&pool.offsets) {
+            pool.check_references(r, offset, constant)?;
+        }
+        Ok(pool)
+    }
+
+    fn check_references(
+        &self,
+        r: &Reader<'a>,
+        offset: usize,
+        constant: &Constant<'a>,
+    ) -> Result<(), ParseError> {
+        let at =
+            |result: Result<(), ParseErrorKind>| result.map_err(|kind| r.error_at(offset, kind));
+        match *constant {
+            Constant::Class { name_index }
+            | Constant::Module { name_index }
+            | Constant::Package { name_index } => at(self.check(name_index, Expect::Utf8)),
+            Constant::String { string_index } => at(self.check(string_index, Expect::Utf8)),
+            Constant::MethodType { descriptor_index } => {
+                at(self.check(descriptor_index, Expect::Utf8))
+            }
+            Constant::NameAndType {
+                name_index,
+                descriptor_index,
+            } => {
+                at(self.check(name_index, Expect::Utf8))?;
+                at(self.check(descriptor_index, Expect::Utf8))
+            }
+            Constant::Fieldref {
+                class_index,
+                name_and_type_index,
+            }
+            | Constant::Methodref {
+                class_index,
+                name_and_type_index,
+            }
+            | Constant::InterfaceMethodref {
+                class_index,
+                name_and_type_index,
+            } => {
+                at(self.check(class_index, Expect::Class))?;
+                at(self.check(name_and_type_index, Expect::NameAndType))
+            }
+            Constant::Dynamic {
+                name_and_type_index,
+                ..
+            }
+            | Constant::InvokeDynamic {
+                name_and_type_index,
+                ..
+            } => at(self.check(name_and_type_index, Expect::NameAndType)),
+            Constant::MethodHandle {
+                reference_kind,
+                reference_index,
+            } => {
+                let expected = match reference_kind {
+                    ReferenceKind::GetField
+                    | ReferenceKind::GetStatic
+                    | ReferenceKind::PutField
+                    | ReferenceKind::PutStatic => Expect::Fieldref,
+                    ReferenceKind::InvokeVirtual | ReferenceKind::NewInvokeSpecial => {
+                        Expect::Methodref
+                    }
+                    ReferenceKind::InvokeStatic | ReferenceKind::InvokeSpecial => {
+                        Expect::AnyMethodref
+                    }
+                    ReferenceKind::InvokeInterface => Expect::InterfaceMethodref,
+                };
+                at(self.check(reference_index, expected))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that `index` refers to an entry of the `expected` kind.
+    pub(super) fn check(&self, index: u16, expected: Expect) -> Result<(), ParseErrorKind> {
+        match self.get(index) {
+            Some(constant) if expected.matches(constant) => Ok(()),
+            _ => Err(ParseErrorKind::BadConstantIndex {
+                index,
+                expected: expected.name(),
+            }),
+        }
+    }
+
+    /// Returns the offset of the entry at `index` in the class file.
+    pub(super) fn offset(&self, index: u16) -> usize {
+        self.offsets[index as usize]
+    }
+
+    /// Returns the number of slots, i.e. `constant_pool_count`, including
+    /// the unusable slot 0.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.len() <= 1
+    }
+
+    /// Returns the entry at `index`, or `None` for invalid indices and
+    /// unusable slots.
+    pub fn get(&self, index: u16) -> Option<&Constant<'a>> {
+        match self.entries.get(index as usize) {
+            Some(Constant::Unusable) | None => None,
+            Some(constant) => Some(constant),
+        }
+    }
+
+    /// Returns the usable entries with their indices.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &Constant<'a>)> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, constant)| !matches!(constant, Constant::Unusable))
+            .map(|(index, constant)| (index as u16, constant))
+    }
+
+    pub fn utf8(&self, index: u16) -> Option<Mutf8<'a>> {
+        match self.get(index) {
+            Some(Constant::Utf8(s)) => Some(*s),
+            _ => None,
+        }
+    }
+
+    /// Returns the internal name of the `Class` entry at `index`, e.g.
+    /// `java/lang/String`, or a descriptor for array classes.
+    pub fn class_name(&self, index: u16) -> Option<Mutf8<'a>> {
+        match self.get(index) {
+            Some(Constant::Class { name_index }) => self.utf8(*name_index),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of the `String` entry at `index`.
+    pub fn string(&self, index: u16) -> Option<Mutf8<'a>> {
+        match self.get(index) {
+            Some(Constant::String { string_index }) => self.utf8(*string_index),
+            _ => None,
+        }
+    }
+
+    /// Returns the name and descriptor of the `NameAndType` entry at `index`.
+    pub fn name_and_type(&self, index: u16) -> Option<(Mutf8<'a>, Mutf8<'a>)> {
+        match self.get(index) {
+            Some(Constant::NameAndType {
+                name_index,
+                descriptor_index,
+            }) => Some((self.utf8(*name_index)?, self.utf8(*descriptor_index)?)),
+            _ => None,
+        }
+    }
+
+    /// Resolves the `Fieldref`, `Methodref` or `InterfaceMethodref` at
+    /// `index`.
+    pub fn member_ref(&self, index: u16) -> Option<MemberRef<'a>> {
+        match self.get(index) {
+            Some(
+                Constant::Fieldref {
+                    class_index,
+                    name_and_type_index,
+                }
+                | Constant::Methodref {
+                    class_index,
+                    name_and_type_index,
+                }
+                | Constant::InterfaceMethodref {
+                    class_index,
+                    name_and_type_index,
+                },
+            ) => {
+                let (name, descriptor) = self.name_and_type(*name_and_type_index)?;
+                Some(MemberRef {
+                    class: self.class_name(*class_index)?,
+                    name,
+                    descriptor,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Resolves the `MethodHandle` at `index` to its kind and the field or
+    /// method it refers to.
+    pub fn method_handle(&self, index: u16) -> Option<(ReferenceKind, MemberRef<'a>)> {
+        match self.get(index) {
+            Some(Constant::MethodHandle {
+                reference_kind,
+                reference_index,
+            }) => Some((*reference_kind, self.member_ref(*reference_index)?)),
+            _ => None,
+        }
+    }
+
+    /// Returns the descriptor of the `MethodType` entry at `index`.
+    pub fn method_type(&self, index: u16) -> Option<Mutf8<'a>> {
+        match self.get(index) {
+            Some(Constant::MethodType { descriptor_index }) => self.utf8(*descriptor_index),
+            _ => None,
+        }
+    }
+
+    /// Resolves the `Dynamic` or `InvokeDynamic` entry at `index`.
+    pub fn dynamic(&self, index: u16) -> Option<DynamicRef<'a>> {
+        match self.get(index) {
+            Some(
+                Constant::Dynamic {
+                    bootstrap_method_attr_index,
+                    name_and_type_index,
+                }
+                | Constant::InvokeDynamic {
+                    bootstrap_method_attr_index,
+                    name_and_type_index,
+                },
+            ) => {
+                let (name, descriptor) = self.name_and_type(*name_and_type_index)?;
+                Some(DynamicRef {
+                    bootstrap_method: *bootstrap_method_attr_index,
+                    name,
+                    descriptor,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the `Module` entry at `index`.
+    pub fn module_name(&self, index: u16) -> Option<Mutf8<'a>> {
+        match self.get(index) {
+            Some(Constant::Module { name_index }) => self.utf8(*name_index),
+            _ => None,
+        }
+    }
+
+    /// Returns the internal name of the `Package` entry at `index`, e.g.
+    /// `java/lang`.
+    pub fn package_name(&self, index: u16) -> Option<Mutf8<'a>> {
+        match self.get(index) {
+            Some(Constant::Package { name_index }) => self.utf8(*name_index),
+            _ => None,
+        }
+    }
+}
+
+/// The constant pool of a loaded class, as `GetConstantPool` returns it.
+///
+/// The VM rebuilds the pool from its internal form, so it may differ from
+/// the one in the class file the class was loaded from, but its indices are
+/// those used by the bytecodes `GetBytecodes` returns.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoadedConstantPool {
+    pub minor_version: u16,
+    pub major_version: u16,
+    /// `constant_pool_count`, the number of slots including slot 0.
+    pub count: u16,
+    /// The entries, without `constant_pool_count`.
+    pub bytes: Vec<u8>,
+}
+
+impl LoadedConstantPool {
+    /// Requires the `can_get_constant_pool` capability.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `klass` a valid reference.
+    pub unsafe fn new(env: *mut jvmtiEnv, klass: jclass) -> Result<Self, jvmtiError> {
+        let (mut minor_version, mut major_version) = (0, 0);
+        check(jvmti!(
+            env,
+            v1_1,
+            GetClassVersionNumbers,
+            klass,
+            &mut minor_version,
+            &mut major_version
+        ))?;
+        let (mut count, mut byte_count) = (0, 0);
+        let mut bytes = null_mut();
+        check(jvmti!(
+            env,
+            v1_1,
+            GetConstantPool,
+            klass,
+            &mut count,
+            &mut byte_count,
+            &mut bytes
+        ))?;
+        Ok(LoadedConstantPool {
+            minor_version: minor_version as u16,
+            major_version: major_version as u16,
+            count: count as u16,
+            bytes: take_array(env, bytes, byte_count),
+        })
+    }
+
+    /// Parses the entries. Offsets in errors are relative to
+    /// [`bytes`](Self::bytes).
+    pub fn parse(&self) -> Result<ConstantPool<'_>, ParseError> {
+        ConstantPool::from_entries(self.count, &self.bytes)
+    }
+}
+
+fn parse_constant<'a>(r: &mut Reader<'a>) -> Result<Constant<'a>, ParseError> {
+    let tag = r.u1()?;
+    Ok(match tag {
+        1 => {
+            let len = r.u2()? as usize;
+            let offset = r.offset();
+            let bytes = r.bytes(len)?;
+            if let Err(at) = validate(bytes) {
+                return Err(r.error_at(offset + at, ParseErrorKind::BadUtf8));
+            }
+            Constant::Utf8(Mutf8(bytes))
+        }
+        3 => Constant::Integer(r.u4()? as i32),
+        4 => Constant::Float(f32::from_bits(r.u4()?)),
+        5 => Constant::Long(r.u8()? as i64),
+        6 => Constant::Double(f64::from_bits(r.u8()?)),
+        7 => Constant::Class {
+            name_index: r.u2()?,
+        },
+        8 => Constant::String {
+            string_index: r.u2()?,
+        },
+        9 => Constant::Fieldref {
+            class_index: r.u2()?,
+            name_and_type_index: r.u2()?,
+        },
+        10 => Constant::Methodref {
+            class_index: r.u2()?,
+            name_and_type_index: r.u2()?,
+        },
+        11 => Constant::InterfaceMethodref {
+            class_index: r.u2()?,
+            name_and_type_index: r.u2()?,
+        },
+        12 => Constant::NameAndType {
+            name_index: r.u2()?,
+            descriptor_index: r.u2()?,
+        },
+        15 => {
+            let kind = r.u1()?;
+            let reference_kind = ReferenceKind::from_u8(kind).ok_or_else(|| {
+                r.error_at(r.offset() - 1, ParseErrorKind::BadReferenceKind(kind))
+            })?;
+            Constant::MethodHandle {
+                reference_kind,
+                reference_index: r.u2()?,
+            }
+        }
+        16 => Constant::MethodType {
+            descriptor_index: r.u2()?,
+        },
+        17 => Constant::Dynamic {
+            bootstrap_method_attr_index: r.u2()?,
+            name_and_type_index: r.u2()?,
+        },
+        18 => Constant::InvokeDynamic {
+            bootstrap_method_attr_index: r.u2()?,
+            name_and_type_index: r.u2()?,
+        },
+        19 => Constant::Module {
+            name_index: r.u2()?,
+        },
+        20 => Constant::Package {
+            name_index: r.u2()?,
+        },
+        tag => {
+            return Err(r.error_at(r.offset() - 1, ParseErrorKind::BadConstantTag(tag)));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &[u8] = include_bytes!("testdata/Fixture.class");
+
+    /// Returns the fixture's constant pool as `GetConstantPool` would.
+    fn loaded_fixture_pool() -> LoadedConstantPool {
+        let mut r = Reader::new(FIXTURE);
+        let (minor_version, major_version) = read_header(&mut r).unwrap();
+        let start = r.offset() + 2;
+        let count = ConstantPool::parse(&mut r).unwrap().len() as u16;
+        LoadedConstantPool {
+            minor_version,
+            major_version,
+            count,
+            bytes: FIXTURE[start..r.offset()].to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_entries_without_count() {
+        let loaded = loaded_fixture_pool();
+        let pool = loaded.parse().unwrap();
+        let expected = ConstantPool::from_class_file(FIXTURE).unwrap();
+        assert_eq!(pool.len(), loaded.count as usize);
+        assert_eq!(pool.entries, expected.entries);
+        // Offsets are relative to the entries.
+        assert_eq!(pool.offset(1), 0);
+
+        let calls = pool
+            .iter()
+            .find_map(|(index, constant)| match constant {
+                Constant::Fieldref { .. } => pool
+                    .member_ref(index)
+                    .filter(|member| member.name == "calls"),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(calls.class, "Fixture");
+        assert_eq!(calls.descriptor, "I");
+        assert!(pool
+            .iter()
+            .any(|(index, _)| pool.class_name(index) == Some(Mutf8(b"java/util/ArrayList"))));
+    }
+
+    #[test]
+    fn wide_constants_take_two_slots() {
+        let loaded = loaded_fixture_pool();
+        let pool = loaded.parse().unwrap();
+        let mut wide = 0;
+        for (index, constant) in pool.iter() {
+            if !constant.is_wide() {
+                continue;
+            }
+            wide += 1;
+            assert!(pool.get(index + 1).is_none());
+            assert!(pool.iter().all(|(other, _)| other != index + 1));
+            if (index as usize + 2) < pool.len() {
+                assert!(pool.get(index + 2).is_some());
+                assert!(pool.offset(index + 2) > pool.offset(index));
+            }
+        }
+        // `BIG` and `RATIO`.
+        assert_eq!(wide, 2);
+        assert!(pool
+            .iter()
+            .any(|(_, constant)| *constant == Constant::Long(1 << 40)));
+        assert!(pool
+            .iter()
+            .any(|(_, constant)| *constant == Constant::Double(0.5)));
+
+        // A `Long` followed by an `Integer` in the slot after it.
+        let bytes = [5, 0, 0, 0, 0, 0, 0, 0, 7, 3, 0, 0, 0, 9];
+        let pool = ConstantPool::from_entries(4, &bytes).unwrap();
+        assert_eq!(pool.get(1), Some(&Constant::Long(7)));
+        assert_eq!(pool.get(2), None);
+        assert_eq!(pool.get(3), Some(&Constant::Integer(9)));
+        assert_eq!(
+            ConstantPool::from_entries(2, &bytes[..9]).unwrap_err().kind,
+            ParseErrorKind::WideConstantAtEnd
+        );
+        assert_eq!(
+            ConstantPool::from_entries(3, &bytes).unwrap_err().kind,
+            ParseErrorKind::TrailingBytes(5)
+        );
+    }
+}
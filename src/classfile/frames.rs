@@ -0,0 +1,948 @@
+//! `StackMapTable` computation.
+//!
+//! Infers the verification types of the locals and the operand stack at
+//! each instruction, as the type checking verifier does, and encodes the
+//! frames it needs: at branch targets, exception handlers and after
+//! unconditional jumps. Unreachable code is replaced by `nop`s ending in
+//! `athrow`, as it would need frames that cannot be inferred.
+
+use std::collections::HashMap;
+
+use super::attributes::ExceptionHandler;
+use super::constant_pool::Constant;
+use super::instrument::InstrumentError;
+use super::opcodes::*;
+use super::writer::{ConstantPoolBuilder, ConstantPoolFull};
+
+/// Knowledge of the class hierarchy, needed to merge reference types where
+/// control flow joins, e.g. a `String` on one branch and an `Integer` on
+/// the other.
+///
+/// Where a superclass is not known, values merge to `java/lang/Object`,
+/// which only fails verification if the merged value is then used as a
+/// more specific type.
+pub trait ClassHierarchy {
+    /// Returns the internal name of the superclass of `class`, or `None` for
+    /// `java/lang/Object`, interfaces and unknown classes.
+    fn super_class(&self, class: &str) -> Option<String>;
+}
+
+impl ClassHierarchy for HashMap<String, String> {
+    fn super_class(&self, class: &str) -> Option<String> {
+        self.get(class).cloned()
+    }
+}
+
+impl<F: Fn(&str) -> Option<String>> ClassHierarchy for F {
+    fn super_class(&self, class: &str) -> Option<String> {
+        self(class)
+    }
+}
+
+const OBJECT: &str = "java/lang/Object";
+
+/// A verification type. `Long` and `Double` take two slots, the second of
+/// which is `Top`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(super) enum VType {
+    Top,
+    Integer,
+    Float,
+    Long,
+    Double,
+    Null,
+    UninitializedThis,
+    /// A class by internal name, or an array by descriptor.
+    Object(String),
+    /// An object created by the `new` at this offset, before its
+    /// constructor has been called.
+    Uninitialized(u16),
+}
+
+impl VType {
+    fn is_wide(&self) -> bool {
+        matches!(self, VType::Long | VType::Double)
+    }
+
+    fn object(name: &str) -> Self {
+        VType::Object(name.to_owned())
+    }
+
+    /// Returns the type of a value of the field descriptor `descriptor`.
+    fn from_descriptor(descriptor: &str) -> Self {
+        match descriptor.as_bytes().first() {
+            Some(b'Z' | b'B' | b'C' | b'S' | b'I') => VType::Integer,
+            Some(b'F') => VType::Float,
+            Some(b'J') => VType::Long,
+            Some(b'D') => VType::Double,
+            Some(b'L') => VType::object(descriptor[1..].trim_end_matches(';')),
+            _ => VType::object(descriptor),
+        }
+    }
+}
+
+/// The types of the locals and the operand stack, one entry per slot.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(super) struct Frame {
+    pub(super) locals: Vec<VType>,
+    pub(super) stack: Vec<VType>,
+}
+
+/// Splits a method descriptor into its parameter and return descriptors.
+pub(super) fn parse_method_descriptor(descriptor: &str) -> Option<(Vec<&str>, &str)> {
+    let rest = descriptor.strip_prefix('(')?;
+    let (params, ret) = rest.split_once(')')?;
+    let mut list = Vec::new();
+    let mut i = 0;
+    let bytes = params.as_bytes();
+    while i < bytes.len() {
+        let start = i;
+        while bytes[i] == b'[' {
+            i += 1;
+            if i == bytes.len() {
+                return None;
+            }
+        }
+        if bytes[i] == b'L' {
+            i += params[i..].find(';')?;
+        }
+        i += 1;
+        list.push(&params[start..i]);
+    }
+    Some((list, ret))
+}
+
+/// The method being analyzed.
+pub(super) struct Method<'m> {
+    pub(super) pool: &'m ConstantPoolBuilder<'m>,
+    pub(super) hierarchy: &'m dyn ClassHierarchy,
+    pub(super) this_class: &'m str,
+    pub(super) name: &'m str,
+    pub(super) descriptor: &'m str,
+    pub(super) is_static: bool,
+}
+
+impl Method<'_> {
+    /// Returns the frame at method entry.
+    pub(super) fn initial_frame(&self) -> Result<Frame, InstrumentError> {
+        let (params, _) =
+            parse_method_descriptor(self.descriptor).ok_or(InstrumentError::BadDescriptor)?;
+        let mut frame = Frame::default();
+        if !self.is_static {
+            frame.locals.push(match self.name {
+                "<init>" if self.this_class != OBJECT => VType::UninitializedThis,
+                _ => VType::object(self.this_class),
+            });
+        }
+        for param in params {
+            push_slots(&mut frame.locals, VType::from_descriptor(param));
+        }
+        Ok(frame)
+    }
+
+    /// Returns the closest common superclass of two classes.
+    fn common_superclass(&self, a: &str, b: &str) -> String {
+        let chain = |mut class: String| {
+            let mut chain = vec![class.clone()];
+            while chain.len() < 256 {
+                match self.super_class(&class) {
+                    Some(parent) => {
+                        chain.push(parent.clone());
+                        class = parent;
+                    }
+                    None => break,
+                }
+            }
+            chain
+        };
+        let of_a = chain(a.to_owned());
+        chain(b.to_owned())
+            .into_iter()
+            .find(|class| of_a.contains(class))
+            .unwrap_or_else(|| OBJECT.to_owned())
+    }
+
+    fn super_class(&self, class: &str) -> Option<String> {
+        if class == OBJECT {
+            return None;
+        }
+        self.hierarchy.super_class(class)
+    }
+
+    /// Returns the least type both `a` and `b` are assignable to.
+    fn merge_types(&self, a: &VType, b: &VType) -> VType {
+        match (a, b) {
+            _ if a == b => a.clone(),
+            (VType::Null, VType::Object(_)) => b.clone(),
+            (VType::Object(_), VType::Null) => a.clone(),
+            (VType::Object(a), VType::Object(b)) => VType::Object(self.merge_objects(a, b)),
+            _ => VType::Top,
+        }
+    }
+
+    fn merge_objects(&self, a: &str, b: &str) -> String {
+        match (a.strip_prefix('['), b.strip_prefix('[')) {
+            (Some(a), Some(b)) => {
+                let reference = |c: &str| c.starts_with('[') || c.starts_with('L');
+                if !reference(a) || !reference(b) {
+                    return OBJECT.to_owned();
+                }
+                let name = |c: &str| match c.strip_prefix('L') {
+                    Some(name) => name.trim_end_matches(';').to_owned(),
+                    None => c.to_owned(),
+                };
+                let element = self.merge_objects(&name(a), &name(b));
+                match element.starts_with('[') {
+                    true => format!("[{element}"),
+                    false => format!("[L{element};"),
+                }
+            }
+            (None, None) => self.common_superclass(a, b),
+            _ => OBJECT.to_owned(),
+        }
+    }
+
+    /// Merges `from` into `into`, returning whether `into` changed.
+    fn merge_frames(
+        &self,
+        into: &mut Frame,
+        from: &Frame,
+        pc: usize,
+    ) -> Result<bool, InstrumentError> {
+        if into.stack.len() != from.stack.len() {
+            return Err(InstrumentError::StackHeight { pc });
+        }
+        let mut changed = false;
+        for (into, from) in into.stack.iter_mut().zip(&from.stack) {
+            let merged = self.merge_types(into, from);
+            if merged != *into {
+                *into = merged;
+                changed = true;
+            }
+        }
+        if from.locals.len() < into.locals.len() {
+            into.locals.truncate(from.locals.len());
+            changed = true;
+        }
+        for (into, from) in into.locals.iter_mut().zip(&from.locals) {
+            let merged = self.merge_types(into, from);
+            if merged != *into {
+                *into = merged;
+                changed = true;
+            }
+        }
+        // Keep the halves of wide values consistent.
+        for i in 0..into.locals.len() {
+            if into.locals[i].is_wide() && into.locals.get(i + 1) != Some(&VType::Top) {
+                into.locals[i] = VType::Top;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+}
+
+fn push_slots(slots: &mut Vec<VType>, value: VType) {
+    let wide = value.is_wide();
+    slots.push(value);
+    if wide {
+        slots.push(VType::Top);
+    }
+}
+
+/// The result of [`analyze`].
+#[derive(Debug)]
+pub(super) struct Analysis {
+    pub(super) max_stack: u16,
+    pub(super) max_locals: u16,
+    /// The frames the `StackMapTable` needs, by offset.
+    pub(super) frames: Vec<(usize, Frame)>,
+    /// For constructors, the offset after the call to the superclass or
+    /// another constructor of the class.
+    pub(super) this_initialized: Option<usize>,
+}
+
+/// Analyzes `code`, replacing unreachable code and removing it from the
+/// exception handler ranges.
+pub(super) fn analyze(
+    method: &Method<'_>,
+    code: &mut [u8],
+    handlers: &mut Vec<ExceptionHandler>,
+) -> Result<Analysis, InstrumentError> {
+    let mut insns = Vec::new();
+    let mut starts = vec![false; code.len()];
+    for insn in instructions(code) {
+        let insn = insn.map_err(|pc| InstrumentError::BadInstruction { pc })?;
+        if matches!(insn.opcode(), JSR | JSR_W | RET) || insn.opcode() == WIDE && insn.u1(1) == RET
+        {
+            return Err(InstrumentError::Subroutine { pc: insn.pc });
+        }
+        starts[insn.pc] = true;
+        insns.push(insn);
+    }
+    let valid_target = |pc: usize| pc < code.len() && starts[pc];
+    for handler in handlers.iter() {
+        if !valid_target(handler.start_pc as usize) || !valid_target(handler.handler_pc as usize) {
+            return Err(InstrumentError::BadInstruction {
+                pc: handler.handler_pc as usize,
+            });
+        }
+    }
+
+    let initial = method.initial_frame()?;
+    let mut max_stack = 0;
+    let mut max_locals = initial.locals.len();
+    let mut this_initialized = None;
+    let mut states: Vec<Option<Frame>> = vec![None; code.len()];
+    let mut frame_points = vec![false; code.len()];
+    for handler in handlers.iter() {
+        frame_points[handler.handler_pc as usize] = true;
+    }
+    states[0] = Some(initial);
+    let mut worklist = vec![0];
+
+    while let Some(pc) = worklist.pop() {
+        let index = insns.partition_point(|insn| insn.pc < pc);
+        let insn = insns[index];
+        let before = states[pc].clone().unwrap();
+        let mut frame = before.clone();
+        let mut exec = Exec {
+            method,
+            code,
+            frame: &mut frame,
+            max_stack: &mut max_stack,
+            pc,
+        };
+        let initializes = exec.execute(&insn)?;
+        if initializes && this_initialized.is_none() {
+            this_initialized = Some(pc + insn.bytes.len());
+        }
+        max_locals = max_locals.max(frame.locals.len());
+
+        let mut successors = insn.branch_targets();
+        for &target in &successors {
+            if !valid_target(target) {
+                return Err(InstrumentError::BadInstruction { pc });
+            }
+            frame_points[target] = true;
+        }
+        let next = pc + insn.bytes.len();
+        if is_unconditional(insn.opcode()) {
+            if next < code.len() {
+                frame_points[next] = true;
+            }
+        } else if next < code.len() {
+            successors.push(next);
+        } else {
+            return Err(InstrumentError::FallsOffEnd);
+        }
+
+        for handler in handlers.iter() {
+            if (handler.start_pc as usize..handler.end_pc as usize).contains(&pc) {
+                let exception = match handler.catch_type {
+                    0 => VType::object("java/lang/Throwable"),
+                    index => VType::object(&class_name(method.pool, index, pc)?),
+                };
+                let target = handler.handler_pc as usize;
+                for locals in [&before.locals, &frame.locals] {
+                    let state = Frame {
+                        locals: locals.clone(),
+                        stack: vec![exception.clone()],
+                    };
+                    max_stack = max_stack.max(1);
+                    if merge_into(method, &mut states[target], &state, target)? {
+                        worklist.push(target);
+                    }
+                }
+            }
+        }
+        for target in successors {
+            if merge_into(method, &mut states[target], &frame, target)? {
+                worklist.push(target);
+            }
+        }
+    }
+
+    // Replace unreachable code by `nop ... athrow`, which only needs a
+    // frame with a `Throwable` on the stack.
+    let pcs: Vec<usize> = insns.iter().map(|insn| insn.pc).collect();
+    let mut frames = Vec::new();
+    let mut dead = Vec::new();
+    let mut i = 0;
+    while i < pcs.len() {
+        let pc = pcs[i];
+        if states[pc].is_some() {
+            if frame_points[pc] {
+                frames.push((pc, states[pc].clone().unwrap()));
+            }
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while end < pcs.len() && states[pcs[end]].is_none() {
+            end += 1;
+        }
+        let end_pc = pcs.get(end).copied().unwrap_or(code.len());
+        code[pc..end_pc - 1].fill(NOP);
+        code[end_pc - 1] = ATHROW;
+        frames.push((
+            pc,
+            Frame {
+                locals: Vec::new(),
+                stack: vec![VType::object("java/lang/Throwable")],
+            },
+        ));
+        max_stack = max_stack.max(1);
+        dead.push((pc, end_pc));
+        i = end;
+    }
+    for (start, end) in dead {
+        let mut split = Vec::with_capacity(handlers.len());
+        for handler in handlers.drain(..) {
+            let (h_start, h_end) = (handler.start_pc as usize, handler.end_pc as usize);
+            if end <= h_start || h_end <= start {
+                split.push(handler);
+                continue;
+            }
+            if h_start < start {
+                split.push(ExceptionHandler {
+                    end_pc: start as u16,
+                    ..handler
+                });
+            }
+            if end < h_end {
+                split.push(ExceptionHandler {
+                    start_pc: end as u16,
+                    ..handler
+                });
+            }
+        }
+        *handlers = split;
+    }
+
+    Ok(Analysis {
+        max_stack: max_stack.min(u16::MAX as usize) as u16,
+        max_locals: max_locals.min(u16::MAX as usize) as u16,
+        frames,
+        this_initialized,
+    })
+}
+
+fn merge_into(
+    method: &Method<'_>,
+    state: &mut Option<Frame>,
+    from: &Frame,
+    pc: usize,
+) -> Result<bool, InstrumentError> {
+    match state {
+        Some(state) => method.merge_frames(state, from, pc),
+        None => {
+            *state = Some(from.clone());
+            Ok(true)
+        }
+    }
+}
+
+fn class_name(
+    pool: &ConstantPoolBuilder<'_>,
+    index: u16,
+    pc: usize,
+) -> Result<String, InstrumentError> {
+    pool.class_name(index)
+        .map(|name| name.to_str().into_owned())
+        .ok_or(InstrumentError::BadConstant { pc, index })
+}
+
+/// Executes one instruction on a frame.
+struct Exec<'e, 'm> {
+    method: &'e Method<'m>,
+    code: &'e [u8],
+    frame: &'e mut Frame,
+    max_stack: &'e mut usize,
+    pc: usize,
+}
+
+impl Exec<'_, '_> {
+    fn push(&mut self, value: VType) {
+        push_slots(&mut self.frame.stack, value);
+        *self.max_stack = (*self.max_stack).max(self.frame.stack.len());
+    }
+
+    /// Pops `slots` slots, returning the lowest one.
+    fn pop(&mut self, slots: usize) -> Result<VType, InstrumentError> {
+        let len = self.frame.stack.len();
+        if len < slots {
+            return Err(InstrumentError::StackUnderflow { pc: self.pc });
+        }
+        Ok(self
+            .frame
+            .stack
+            .drain(len - slots..)
+            .next()
+            .unwrap_or(VType::Top))
+    }
+
+    /// Pops a value of the field descriptor `descriptor`.
+    fn pop_value(&mut self, descriptor: &str) -> Result<(), InstrumentError> {
+        let slots = 1 + VType::from_descriptor(descriptor).is_wide() as usize;
+        self.pop(slots).map(drop)
+    }
+
+    fn load(&mut self, slot: usize, value: VType) -> Result<(), InstrumentError> {
+        let local = self
+            .frame
+            .locals
+            .get(slot)
+            .cloned()
+            .ok_or(InstrumentError::BadLocal { pc: self.pc, slot })?;
+        // `aload` keeps the exact type of the local.
+        self.push(if value == VType::Top { local } else { value });
+        Ok(())
+    }
+
+    fn store(&mut self, slot: usize, wide: bool) -> Result<(), InstrumentError> {
+        let value = self.pop(1 + wide as usize)?;
+        let locals = &mut self.frame.locals;
+        let end = slot + 1 + wide as usize;
+        if locals.len() < end {
+            locals.resize(end, VType::Top);
+        }
+        if slot > 0 && locals[slot - 1].is_wide() {
+            locals[slot - 1] = VType::Top;
+        }
+        locals[slot] = value;
+        if wide {
+            locals[slot + 1] = VType::Top;
+        }
+        Ok(())
+    }
+
+    fn constant(&self, index: u16) -> Result<Constant<'_>, InstrumentError> {
+        self.method
+            .pool
+            .get(index)
+            .ok_or(InstrumentError::BadConstant { pc: self.pc, index })
+    }
+
+    fn class(&self, index: u16) -> Result<String, InstrumentError> {
+        class_name(self.method.pool, index, self.pc)
+    }
+
+    /// Executes `insn`. Returns whether it is the constructor call that
+    /// initializes `this`.
+    fn execute(&mut self, insn: &Instruction<'_>) -> Result<bool, InstrumentError> {
+        // The types of the typed instruction families, in opcode order.
+        const TYPES: [VType; 5] = [
+            VType::Integer,
+            VType::Long,
+            VType::Float,
+            VType::Double,
+            VType::Top,
+        ];
+        let op = insn.opcode();
+        match op {
+            NOP | GOTO | GOTO_W | IINC => {}
+            ACONST_NULL => self.push(VType::Null),
+            ICONST_M1..=ICONST_5 | BIPUSH | SIPUSH => self.push(VType::Integer),
+            LCONST_0 | LCONST_1 => self.push(VType::Long),
+            FCONST_0..=FCONST_2 => self.push(VType::Float),
+            DCONST_0 | DCONST_1 => self.push(VType::Double),
+            LDC | LDC_W | LDC2_W => {
+                let index = match op {
+                    LDC => insn.u1(1) as u16,
+                    _ => insn.u2(1),
+                };
+                let value = match self.constant(index)? {
+                    Constant::Integer(_) => VType::Integer,
+                    Constant::Float(_) => VType::Float,
+                    Constant::Long(_) => VType::Long,
+                    Constant::Double(_) => VType::Double,
+                    Constant::String { .. } => VType::object("java/lang/String"),
+                    Constant::Class { .. } => VType::object("java/lang/Class"),
+                    Constant::MethodType { .. } => VType::object("java/lang/invoke/MethodType"),
+                    Constant::MethodHandle { .. } => VType::object("java/lang/invoke/MethodHandle"),
+                    Constant::Dynamic {
+                        name_and_type_index,
+                        ..
+                    } => {
+                        let (_, descriptor) =
+                            self.method
+                                .pool
+                                .name_and_type(name_and_type_index)
+                                .ok_or(InstrumentError::BadConstant { pc: self.pc, index })?;
+                        VType::from_descriptor(&descriptor.to_str())
+                    }
+                    _ => return Err(InstrumentError::BadConstant { pc: self.pc, index }),
+                };
+                self.push(value);
+            }
+            ILOAD..=ALOAD => {
+                self.load(insn.u1(1) as usize, TYPES[(op - ILOAD) as usize].clone())?
+            }
+            ILOAD_0..=ALOAD_3 => {
+                let n = (op - ILOAD_0) as usize;
+                self.load(n % 4, TYPES[n / 4].clone())?;
+            }
+            IALOAD..=SALOAD => {
+                self.pop(1)?;
+                let array = self.pop(1)?;
+                let value = match op {
+                    AALOAD => match array {
+                        VType::Object(name) if name.starts_with('[') => {
+                            VType::from_descriptor(&name[1..])
+                        }
+                        VType::Null => VType::Null,
+                        _ => VType::object(OBJECT),
+                    },
+                    LALOAD => VType::Long,
+                    FALOAD => VType::Float,
+                    DALOAD => VType::Double,
+                    _ => VType::Integer,
+                };
+                self.push(value);
+            }
+            ISTORE..=ASTORE => {
+                let wide = matches!(op, LSTORE | DSTORE);
+                self.store(insn.u1(1) as usize, wide)?;
+            }
+            ISTORE_0..=ASTORE_3 => {
+                let n = (op - ISTORE_0) as usize;
+                self.store(n % 4, TYPES[n / 4].is_wide())?;
+            }
+            IASTORE..=SASTORE => {
+                let wide = matches!(op, LASTORE | DASTORE);
+                self.pop(1 + wide as usize)?;
+                self.pop(2)?;
+            }
+            POP => drop(self.pop(1)?),
+            POP2 => drop(self.pop(2)?),
+            DUP | DUP_X1 | DUP_X2 | DUP2 | DUP2_X1 | DUP2_X2 | SWAP => {
+                let (count, depth) = match op {
+                    DUP => (1, 0),
+                    DUP_X1 => (1, 1),
+                    DUP_X2 => (1, 2),
+                    DUP2 => (2, 0),
+                    DUP2_X1 => (2, 1),
+                    DUP2_X2 => (2, 2),
+                    _ => (1, 1),
+                };
+                let len = self.frame.stack.len();
+                if len < count + depth {
+                    return Err(InstrumentError::StackUnderflow { pc: self.pc });
+                }
+                let top: Vec<VType> = self.frame.stack[len - count..].to_vec();
+                if op == SWAP {
+                    self.frame.stack.swap(len - 1, len - 2);
+                } else {
+                    let at = len - count - depth;
+                    self.frame.stack.splice(at..at, top);
+                    *self.max_stack = (*self.max_stack).max(self.frame.stack.len());
+                }
+            }
+            IADD..=DREM => {
+                let ty = TYPES[((op - IADD) % 4) as usize].clone();
+                let slots = 1 + ty.is_wide() as usize;
+                self.pop(slots)?;
+                self.pop(slots)?;
+                self.push(ty);
+            }
+            INEG..=DNEG => {
+                let ty = TYPES[(op - INEG) as usize].clone();
+                self.pop(1 + ty.is_wide() as usize)?;
+                self.push(ty);
+            }
+            ISHL..=LUSHR => {
+                let ty = TYPES[((op - ISHL) % 2) as usize].clone();
+                self.pop(1)?;
+                self.pop(1 + ty.is_wide() as usize)?;
+                self.push(ty);
+            }
+            IAND..=LXOR => {
+                let ty = TYPES[((op - IAND) % 2) as usize].clone();
+                let slots = 1 + ty.is_wide() as usize;
+                self.pop(slots)?;
+                self.pop(slots)?;
+                self.push(ty);
+            }
+            I2L..=I2S => {
+                let (from, to) = match op {
+                    I2L => (0, 1),
+                    I2F => (0, 2),
+                    I2D => (0, 3),
+                    L2I => (1, 0),
+                    L2F => (1, 2),
+                    L2D => (1, 3),
+                    F2I => (2, 0),
+                    F2L => (2, 1),
+                    F2D => (2, 3),
+                    D2I => (3, 0),
+                    D2L => (3, 1),
+                    D2F => (3, 2),
+                    _ => (0, 0),
+                };
+                self.pop(1 + TYPES[from].is_wide() as usize)?;
+                self.push(TYPES[to].clone());
+            }
+            LCMP | DCMPL | DCMPG => {
+                self.pop(4)?;
+                self.push(VType::Integer);
+            }
+            FCMPL | FCMPG => {
+                self.pop(2)?;
+                self.push(VType::Integer);
+            }
+            IFEQ..=IFLE | IFNULL | IFNONNULL | TABLESWITCH | LOOKUPSWITCH => drop(self.pop(1)?),
+            IF_ICMPEQ..=IF_ACMPNE => drop(self.pop(2)?),
+            IRETURN | FRETURN | ARETURN | ATHROW | MONITORENTER | MONITOREXIT => drop(self.pop(1)?),
+            LRETURN | DRETURN => drop(self.pop(2)?),
+            RETURN => {}
+            GETSTATIC..=PUTFIELD => {
+                let index = insn.u2(1);
+                let field = self
+                    .method
+                    .pool
+                    .member_ref(index)
+                    .ok_or(InstrumentError::BadConstant { pc: self.pc, index })?;
+                let descriptor = field.descriptor.to_str();
+                if matches!(op, PUTSTATIC | PUTFIELD) {
+                    self.pop_value(&descriptor)?;
+                }
+                if matches!(op, GETFIELD | PUTFIELD) {
+                    self.pop(1)?;
+                }
+                if matches!(op, GETSTATIC | GETFIELD) {
+                    self.push(VType::from_descriptor(&descriptor));
+                }
+            }
+            INVOKEVIRTUAL..=INVOKEDYNAMIC => return self.invoke(insn),
+            NEW => self.push(VType::Uninitialized(self.pc as u16)),
+            NEWARRAY => {
+                self.pop(1)?;
+                let array = match insn.u1(1) {
+                    4 => "[Z",
+                    5 => "[C",
+                    6 => "[F",
+                    7 => "[D",
+                    8 => "[B",
+                    9 => "[S",
+                    10 => "[I",
+                    11 => "[J",
+                    _ => return Err(InstrumentError::BadInstruction { pc: self.pc }),
+                };
+                self.push(VType::object(array));
+            }
+            ANEWARRAY => {
+                self.pop(1)?;
+                let element = self.class(insn.u2(1))?;
+                self.push(VType::Object(match element.starts_with('[') {
+                    true => format!("[{element}"),
+                    false => format!("[L{element};"),
+                }));
+            }
+            ARRAYLENGTH | INSTANCEOF => {
+                self.pop(1)?;
+                self.push(VType::Integer);
+            }
+            CHECKCAST => {
+                self.pop(1)?;
+                let class = self.class(insn.u2(1))?;
+                self.push(VType::Object(class));
+            }
+            MULTIANEWARRAY => {
+                self.pop(insn.u1(3) as usize)?;
+                let class = self.class(insn.u2(1))?;
+                self.push(VType::Object(class));
+            }
+            WIDE => {
+                let slot = insn.u2(2) as usize;
+                match insn.u1(1) {
+                    IINC => {}
+                    op @ ILOAD..=ALOAD => self.load(slot, TYPES[(op - ILOAD) as usize].clone())?,
+                    op => self.store(slot, matches!(op, LSTORE | DSTORE))?,
+                }
+            }
+            _ => return Err(InstrumentError::BadInstruction { pc: self.pc }),
+        }
+        Ok(false)
+    }
+
+    fn invoke(&mut self, insn: &Instruction<'_>) -> Result<bool, InstrumentError> {
+        let op = insn.opcode();
+        let index = insn.u2(1);
+        let bad_constant = InstrumentError::BadConstant { pc: self.pc, index };
+        let (name, descriptor) = match op {
+            INVOKEDYNAMIC => match self.constant(index)? {
+                Constant::InvokeDynamic {
+                    name_and_type_index,
+                    ..
+                } => {
+                    let (name, descriptor) = self
+                        .method
+                        .pool
+                        .name_and_type(name_and_type_index)
+                        .ok_or(bad_constant)?;
+                    (name.to_str().into_owned(), descriptor.to_str().into_owned())
+                }
+                _ => return Err(bad_constant),
+            },
+            _ => {
+                let method = self.method.pool.member_ref(index).ok_or(bad_constant)?;
+                (
+                    method.name.to_str().into_owned(),
+                    method.descriptor.to_str().into_owned(),
+                )
+            }
+        };
+        let (params, ret) =
+            parse_method_descriptor(&descriptor).ok_or(InstrumentError::BadDescriptor)?;
+        for param in params.iter().rev() {
+            self.pop_value(param)?;
+        }
+        let mut initializes_this = false;
+        if !matches!(op, INVOKESTATIC | INVOKEDYNAMIC) {
+            let receiver = self.pop(1)?;
+            if op == INVOKESPECIAL && name == "<init>" {
+                let initialized = match receiver {
+                    VType::UninitializedThis => {
+                        initializes_this = true;
+                        VType::object(self.method.this_class)
+                    }
+                    VType::Uninitialized(new) => {
+                        let new = new as usize;
+                        if self.code.get(new) != Some(&NEW) {
+                            return Err(InstrumentError::BadInstruction { pc: self.pc });
+                        }
+                        let class = u16::from_be_bytes([self.code[new + 1], self.code[new + 2]]);
+                        VType::Object(self.class(class)?)
+                    }
+                    _ => return Err(InstrumentError::BadInstruction { pc: self.pc }),
+                };
+                for slot in self
+                    .frame
+                    .locals
+                    .iter_mut()
+                    .chain(self.frame.stack.iter_mut())
+                {
+                    if *slot == receiver {
+                        *slot = initialized.clone();
+                    }
+                }
+            }
+        }
+        if ret != "V" {
+            self.push(VType::from_descriptor(ret));
+        }
+        Ok(initializes_this)
+    }
+}
+
+/// Encodes a `StackMapTable` attribute, without its name and length.
+pub(super) fn encode_stack_map_table(
+    initial: &Frame,
+    frames: &[(usize, Frame)],
+    pool: &mut ConstantPoolBuilder<'_>,
+) -> Result<Vec<u8>, ConstantPoolFull> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(frames.len() as u16).to_be_bytes());
+    let mut previous_locals = compress(&initial.locals, true);
+    let mut previous_pc: Option<usize> = None;
+    for (pc, frame) in frames {
+        let delta = match previous_pc {
+            Some(previous) => pc - previous - 1,
+            None => *pc,
+        } as u16;
+        previous_pc = Some(*pc);
+        let locals = compress(&frame.locals, true);
+        let stack = compress(&frame.stack, false);
+
+        let common = previous_locals
+            .iter()
+            .zip(&locals)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if locals == previous_locals && stack.is_empty() {
+            if delta < 64 {
+                out.push(delta as u8);
+            } else {
+                out.push(251);
+                out.extend_from_slice(&delta.to_be_bytes());
+            }
+        } else if locals == previous_locals && stack.len() == 1 {
+            if delta < 64 {
+                out.push(64 + delta as u8);
+            } else {
+                out.push(247);
+                out.extend_from_slice(&delta.to_be_bytes());
+            }
+            write_type(&mut out, &stack[0], pool)?;
+        } else if stack.is_empty()
+            && common == locals.len()
+            && (1..=3).contains(&(previous_locals.len() - locals.len()))
+        {
+            out.push((251 - (previous_locals.len() - locals.len())) as u8);
+            out.extend_from_slice(&delta.to_be_bytes());
+        } else if stack.is_empty()
+            && common == previous_locals.len()
+            && (1..=3).contains(&(locals.len() - previous_locals.len()))
+        {
+            out.push((251 + locals.len() - previous_locals.len()) as u8);
+            out.extend_from_slice(&delta.to_be_bytes());
+            for local in &locals[common..] {
+                write_type(&mut out, local, pool)?;
+            }
+        } else {
+            out.push(255);
+            out.extend_from_slice(&delta.to_be_bytes());
+            for list in [&locals, &stack] {
+                out.extend_from_slice(&(list.len() as u16).to_be_bytes());
+                for ty in list.iter() {
+                    write_type(&mut out, ty, pool)?;
+                }
+            }
+        }
+        previous_locals = locals;
+    }
+    Ok(out)
+}
+
+/// Turns slots into the entries of a frame, where wide values take one
+/// entry. Trailing `Top`s of locals are dropped.
+fn compress(slots: &[VType], locals: bool) -> Vec<VType> {
+    let mut list = Vec::with_capacity(slots.len());
+    let mut i = 0;
+    while i < slots.len() {
+        list.push(slots[i].clone());
+        i += 1 + slots[i].is_wide() as usize;
+    }
+    if locals {
+        while list.last() == Some(&VType::Top) {
+            list.pop();
+        }
+    }
+    list
+}
+
+fn write_type(
+    out: &mut Vec<u8>,
+    ty: &VType,
+    pool: &mut ConstantPoolBuilder<'_>,
+) -> Result<(), ConstantPoolFull> {
+    match ty {
+        VType::Top => out.push(0),
+        VType::Integer => out.push(1),
+        VType::Float => out.push(2),
+        VType::Double => out.push(3),
+        VType::Long => out.push(4),
+        VType::Null => out.push(5),
+        VType::UninitializedThis => out.push(6),
+        VType::Object(name) => {
+            out.push(7);
+            out.extend_from_slice(&pool.add_class(name)?.to_be_bytes());
+        }
+        VType::Uninitialized(offset) => {
+            out.push(8);
+            out.extend_from_slice(&offset.to_be_bytes());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,654 @@
+//! Bytecode instrumentation.
+//!
+//! [`ClassWriter::instrument_method`] inserts [`Probes`], short sequences of
+//! straight-line bytecode, at the entry and exits of a method. The code is
+//! then laid out again: branch offsets are adjusted, widening `goto` to
+//! `goto_w` and conditional branches to an inverted branch over a `goto_w`
+//! where they no longer fit, switch padding is recomputed and the exception,
+//! line number and local variable tables are moved along. Finally
+//! `max_stack`, `max_locals` and the `StackMapTable` are recomputed from
+//! the new code.
+
+use core::fmt;
+use std::borrow::Cow;
+
+use super::frames::{self, Analysis, ClassHierarchy, Method};
+use super::opcodes::*;
+use super::writer::{write_attributes, ConstantPoolBuilder, ConstantPoolFull, RawAttribute};
+use super::{AccessFlags, ClassFile, ClassWriter, ExceptionHandler};
+
+/// Straight-line bytecode to insert into a method: no branches, returns or
+/// `athrow`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bytecode(Vec<u8>);
+
+impl Bytecode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Appends an instruction without operands.
+    pub fn op(&mut self, opcode: u8) -> &mut Self {
+        self.0.push(opcode);
+        self
+    }
+
+    /// Appends an instruction with a one byte operand, e.g. `bipush`.
+    pub fn op_u1(&mut self, opcode: u8, operand: u8) -> &mut Self {
+        self.0.extend_from_slice(&[opcode, operand]);
+        self
+    }
+
+    /// Appends an instruction with a two byte operand, e.g. `getstatic` or
+    /// `invokestatic` with the index of a constant.
+    pub fn op_u2(&mut self, opcode: u8, operand: u16) -> &mut Self {
+        self.0.push(opcode);
+        self.0.extend_from_slice(&operand.to_be_bytes());
+        self
+    }
+
+    /// Appends `invokeinterface`, where `count` is the number of argument
+    /// slots including the receiver.
+    pub fn invokeinterface(&mut self, index: u16, count: u8) -> &mut Self {
+        self.op_u2(INVOKEINTERFACE, index);
+        self.0.extend_from_slice(&[count, 0]);
+        self
+    }
+
+    /// Appends `ldc` or `ldc_w` for the `Integer`, `Float`, `String`,
+    /// `Class`, `MethodType`, `MethodHandle` or `Dynamic` constant at
+    /// `index`.
+    pub fn ldc(&mut self, index: u16) -> &mut Self {
+        match u8::try_from(index) {
+            Ok(index) => self.op_u1(LDC, index),
+            Err(_) => self.op_u2(LDC_W, index),
+        }
+    }
+
+    /// Appends the shortest instruction pushing `value`, adding an
+    /// `Integer` constant to `pool` if needed.
+    pub fn push_int(
+        &mut self,
+        pool: &mut ConstantPoolBuilder<'_>,
+        value: i32,
+    ) -> Result<&mut Self, ConstantPoolFull> {
+        Ok(match value {
+            -1..=5 => self.op((ICONST_0 as i32 + value) as u8),
+            -128..=127 => self.op_u1(BIPUSH, value as u8),
+            -32768..=32767 => self.op_u2(SIPUSH, value as u16),
+            _ => self.ldc(pool.add_integer(value)?),
+        })
+    }
+
+    /// Appends a load or store of a local, where `opcode` is one of `iload`
+    /// to `aload` or `istore` to `astore`, using the short form or `wide`
+    /// as needed.
+    pub fn local(&mut self, opcode: u8, slot: u16) -> &mut Self {
+        let (short, base) = match opcode {
+            ILOAD..=ALOAD => (ILOAD_0, ILOAD),
+            _ => (ISTORE_0, ISTORE),
+        };
+        match slot {
+            0..=3 => self.op(short + (opcode - base) * 4 + slot as u8),
+            4..=255 => self.op_u1(opcode, slot as u8),
+            _ => {
+                self.op(WIDE);
+                self.op_u2(opcode, slot)
+            }
+        }
+    }
+}
+
+impl From<Vec<u8>> for Bytecode {
+    fn from(bytes: Vec<u8>) -> Self {
+        Bytecode(bytes)
+    }
+}
+
+/// The code to insert into a method. Probes may use the operand stack
+/// freely but must leave it as they found it, and must not use the locals
+/// of the method unless they know its layout.
+#[derive(Clone, Debug, Default)]
+pub struct Probes {
+    /// Runs when the method is entered. In constructors, this is before the
+    /// superclass constructor is called, when `this` may not be used.
+    pub entry: Option<Bytecode>,
+    /// Runs before each return instruction, with the return value, if any,
+    /// on the top of the stack.
+    pub before_return: Option<Bytecode>,
+    /// Runs before each `athrow`, with the exception on the top of the
+    /// stack.
+    pub before_throw: Option<Bytecode>,
+    /// Runs when an exception propagates out of the method, with the
+    /// exception on the top of the stack, before it is rethrown. In
+    /// constructors, this only covers the code after the superclass
+    /// constructor is called.
+    pub on_exception: Option<Bytecode>,
+}
+
+/// Why a method could not be instrumented. Offsets are in the instrumented
+/// code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InstrumentError {
+    /// A probe contains a malformed instruction, a branch, a return or an
+    /// `athrow`.
+    BadProbe,
+    /// A malformed or unverifiable instruction, or a branch or exception
+    /// handler pointing outside of the code.
+    BadInstruction {
+        pc: usize,
+    },
+    /// `jsr` and `ret`, which class files since version 51 may not use,
+    /// are not supported.
+    Subroutine {
+        pc: usize,
+    },
+    BadConstant {
+        pc: usize,
+        index: u16,
+    },
+    BadDescriptor,
+    /// A load from a local that was never stored.
+    BadLocal {
+        pc: usize,
+        slot: usize,
+    },
+    StackUnderflow {
+        pc: usize,
+    },
+    /// The stack has different heights where control flow joins.
+    StackHeight {
+        pc: usize,
+    },
+    /// Execution can continue past the end of the code.
+    FallsOffEnd,
+    /// The code would be longer than 65535 bytes.
+    CodeTooLarge(usize),
+    /// The constants needed by the new code do not fit into the constant
+    /// pool.
+    ConstantPoolFull,
+}
+
+impl fmt::Display for InstrumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstrumentError::BadProbe => write!(f, "probe is not straight-line code"),
+            InstrumentError::BadInstruction { pc } => write!(f, "bad instruction at {pc}"),
+            InstrumentError::Subroutine { pc } => write!(f, "unsupported subroutine at {pc}"),
+            InstrumentError::BadConstant { pc, index } => {
+                write!(f, "bad constant pool index {index} at {pc}")
+            }
+            InstrumentError::BadDescriptor => write!(f, "malformed method descriptor"),
+            InstrumentError::BadLocal { pc, slot } => {
+                write!(f, "local {slot} is not set at {pc}")
+            }
+            InstrumentError::StackUnderflow { pc } => write!(f, "stack underflow at {pc}"),
+            InstrumentError::StackHeight { pc } => {
+                write!(f, "inconsistent stack height at {pc}")
+            }
+            InstrumentError::FallsOffEnd => write!(f, "execution falls off the end of the code"),
+            InstrumentError::CodeTooLarge(length) => {
+                write!(f, "code length {length} exceeds 65535 bytes")
+            }
+            InstrumentError::ConstantPoolFull => write!(f, "{ConstantPoolFull}"),
+        }
+    }
+}
+
+impl std::error::Error for InstrumentError {}
+
+impl From<ConstantPoolFull> for InstrumentError {
+    fn from(_: ConstantPoolFull) -> Self {
+        InstrumentError::ConstantPoolFull
+    }
+}
+
+/// A piece of the new code.
+enum Item<'c> {
+    Probe(&'c [u8]),
+    Original(Instruction<'c>),
+}
+
+/// Returns the opcode branching when `opcode` does not.
+fn invert(opcode: u8) -> u8 {
+    match opcode {
+        IFNULL => IFNONNULL,
+        IFNONNULL => IFNULL,
+        _ => ((opcode - IFEQ) ^ 1) + IFEQ,
+    }
+}
+
+fn is_short_branch(opcode: u8) -> bool {
+    matches!(opcode, IFEQ..=GOTO | IFNULL | IFNONNULL)
+}
+
+fn check_probe(probe: &Bytecode) -> Result<&[u8], InstrumentError> {
+    for insn in instructions(probe.as_bytes()) {
+        let insn = insn.map_err(|_| InstrumentError::BadProbe)?;
+        let op = insn.opcode();
+        if is_unconditional(op)
+            || !insn.branch_targets().is_empty()
+            || op == RET
+            || op == WIDE && insn.u1(1) == RET
+        {
+            return Err(InstrumentError::BadProbe);
+        }
+    }
+    Ok(probe.as_bytes())
+}
+
+/// The new code, before branches are resolved.
+struct Layout<'c> {
+    items: Vec<Item<'c>>,
+    /// The item each original offset now starts at, `usize::MAX` inside
+    /// instructions. The entry past the end is the end of the original
+    /// code.
+    labels: Vec<usize>,
+    /// Which short branches are widened.
+    wide: Vec<bool>,
+    /// The offset of each item, and the length of the code at the end.
+    offsets: Vec<usize>,
+}
+
+impl Layout<'_> {
+    fn label(&self, pc: usize) -> Option<usize> {
+        match self.labels.get(pc) {
+            Some(&item) if item != usize::MAX => Some(self.offsets[item]),
+            _ => None,
+        }
+    }
+
+    fn size(&self, index: usize, offset: usize) -> usize {
+        match &self.items[index] {
+            Item::Probe(bytes) => bytes.len(),
+            Item::Original(insn) => match insn.opcode() {
+                TABLESWITCH | LOOKUPSWITCH => {
+                    insn.bytes.len() - switch_padding(insn.pc) + switch_padding(offset)
+                }
+                GOTO if self.wide[index] => 5,
+                _ if self.wide[index] => 8,
+                _ => insn.bytes.len(),
+            },
+        }
+    }
+
+    /// Computes the offsets, widening branches until they all fit.
+    fn resolve(&mut self) {
+        loop {
+            self.offsets.clear();
+            let mut offset = 0;
+            for index in 0..self.items.len() {
+                self.offsets.push(offset);
+                offset += self.size(index, offset);
+            }
+            self.offsets.push(offset);
+
+            let mut changed = false;
+            for index in 0..self.items.len() {
+                let Item::Original(insn) = &self.items[index] else {
+                    continue;
+                };
+                if self.wide[index] || !is_short_branch(insn.opcode()) {
+                    continue;
+                }
+                let target = self.label(insn.branch_targets()[0]).unwrap();
+                if i16::try_from(target as i64 - self.offsets[index] as i64).is_err() {
+                    self.wide[index] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    fn emit(&self) -> Vec<u8> {
+        let mut code = Vec::with_capacity(*self.offsets.last().unwrap());
+        for (index, item) in self.items.iter().enumerate() {
+            let pc = self.offsets[index];
+            let insn = match item {
+                Item::Probe(bytes) => {
+                    code.extend_from_slice(bytes);
+                    continue;
+                }
+                Item::Original(insn) => insn,
+            };
+            let targets = insn.branch_targets();
+            let relative =
+                |target: usize, from: usize| self.label(target).unwrap() as i64 - from as i64;
+            match insn.opcode() {
+                GOTO if self.wide[index] => {
+                    code.push(GOTO_W);
+                    code.extend_from_slice(&(relative(targets[0], pc) as i32).to_be_bytes());
+                }
+                op if self.wide[index] => {
+                    code.push(invert(op));
+                    code.extend_from_slice(&8i16.to_be_bytes());
+                    code.push(GOTO_W);
+                    code.extend_from_slice(&(relative(targets[0], pc + 3) as i32).to_be_bytes());
+                }
+                op if is_short_branch(op) => {
+                    code.push(op);
+                    code.extend_from_slice(&(relative(targets[0], pc) as i16).to_be_bytes());
+                }
+                GOTO_W => {
+                    code.push(GOTO_W);
+                    code.extend_from_slice(&(relative(targets[0], pc) as i32).to_be_bytes());
+                }
+                op @ (TABLESWITCH | LOOKUPSWITCH) => {
+                    code.push(op);
+                    code.resize(code.len() + switch_padding(pc), 0);
+                    let operands = 1 + switch_padding(insn.pc);
+                    code.extend_from_slice(&(relative(targets[0], pc) as i32).to_be_bytes());
+                    if op == TABLESWITCH {
+                        code.extend_from_slice(&insn.bytes[operands + 4..operands + 12]);
+                        for &target in &targets[1..] {
+                            code.extend_from_slice(&(relative(target, pc) as i32).to_be_bytes());
+                        }
+                    } else {
+                        code.extend_from_slice(&insn.bytes[operands + 4..operands + 8]);
+                        for (i, &target) in targets[1..].iter().enumerate() {
+                            let key = operands + 8 + i * 8;
+                            code.extend_from_slice(&insn.bytes[key..key + 4]);
+                            code.extend_from_slice(&(relative(target, pc) as i32).to_be_bytes());
+                        }
+                    }
+                }
+                _ => code.extend_from_slice(insn.bytes),
+            }
+        }
+        code
+    }
+}
+
+/// Adds `entry`, which does not count as part of the original code, then
+/// the original instructions with the probes for them.
+fn lay_out<'c>(
+    code: &'c [u8],
+    entry: Option<&'c [u8]>,
+    before_return: Option<&'c [u8]>,
+    before_throw: Option<&'c [u8]>,
+) -> Result<Layout<'c>, InstrumentError> {
+    let mut layout = Layout {
+        items: Vec::new(),
+        labels: vec![usize::MAX; code.len() + 1],
+        wide: Vec::new(),
+        offsets: Vec::new(),
+    };
+    if let Some(entry) = entry {
+        layout.items.push(Item::Probe(entry));
+    }
+    for insn in instructions(code) {
+        let insn = insn.map_err(|pc| InstrumentError::BadInstruction { pc })?;
+        if matches!(insn.opcode(), JSR | JSR_W | RET) || insn.opcode() == WIDE && insn.u1(1) == RET
+        {
+            return Err(InstrumentError::Subroutine { pc: insn.pc });
+        }
+        layout.labels[insn.pc] = layout.items.len();
+        let probe = match insn.opcode() {
+            IRETURN..=RETURN => before_return,
+            ATHROW => before_throw,
+            _ => None,
+        };
+        if let Some(probe) = probe {
+            layout.items.push(Item::Probe(probe));
+        }
+        layout.items.push(Item::Original(insn));
+    }
+    layout.labels[code.len()] = layout.items.len();
+    for item in &layout.items {
+        if let Item::Original(insn) = item {
+            for target in insn.branch_targets() {
+                if !matches!(layout.labels.get(target), Some(&l) if l != usize::MAX) {
+                    return Err(InstrumentError::BadInstruction { pc: insn.pc });
+                }
+            }
+        }
+    }
+    Ok(layout)
+}
+
+/// Moves the offsets of a `LineNumberTable`, `LocalVariableTable` or
+/// `LocalVariableTypeTable` attribute. Entries that do not start at an
+/// instruction are dropped.
+fn relocate_table(data: &[u8], local_variables: bool, layout: &Layout<'_>) -> Vec<u8> {
+    let entry_len = if local_variables { 10 } else { 4 };
+    let mut entries = Vec::new();
+    for entry in data.get(2..).unwrap_or_default().chunks_exact(entry_len) {
+        let start_pc = u16::from_be_bytes([entry[0], entry[1]]) as usize;
+        // Parameters stay in scope during the entry probe.
+        let start = match start_pc {
+            0 if local_variables => Some(0),
+            _ => layout.label(start_pc),
+        };
+        let Some(start) = start else {
+            continue;
+        };
+        let mut entry = entry.to_vec();
+        entry[0..2].copy_from_slice(&(start as u16).to_be_bytes());
+        if local_variables {
+            let length = u16::from_be_bytes([entry[2], entry[3]]) as usize;
+            let Some(end) = layout.label(start_pc + length) else {
+                continue;
+            };
+            entry[2..4].copy_from_slice(&((end - start) as u16).to_be_bytes());
+        }
+        entries.push(entry);
+    }
+    let mut out = (entries.len() as u16).to_be_bytes().to_vec();
+    for entry in entries {
+        out.extend_from_slice(&entry);
+    }
+    out
+}
+
+/// The class's own place in the hierarchy, and the caller's knowledge of
+/// the rest.
+struct Hierarchy<'h> {
+    this_class: &'h str,
+    super_class: Option<&'h str>,
+    rest: &'h dyn ClassHierarchy,
+}
+
+impl ClassHierarchy for Hierarchy<'_> {
+    fn super_class(&self, class: &str) -> Option<String> {
+        match class == self.this_class {
+            true => self.super_class.map(str::to_owned),
+            false => self.rest.super_class(class),
+        }
+    }
+}
+
+impl<'a> ClassWriter<'a> {
+    /// Inserts `probes` into the method at `index` in `class`, which this
+    /// writer was created from, replacing its `Code` attribute. Returns
+    /// `false` if the method has no code.
+    ///
+    /// Probes may refer to constants added to
+    /// [`constant_pool`](Self::constant_pool) beforehand. `hierarchy` is
+    /// used to compute the stack map frames; see [`ClassHierarchy`].
+    ///
+    /// Of the attributes of the code, `LineNumberTable`,
+    /// `LocalVariableTable` and `LocalVariableTypeTable` are kept and
+    /// `StackMapTable` is recomputed for class files of version 50 and
+    /// later. The others, such as type annotations, are dropped. Methods
+    /// using `jsr` and `ret` are not supported.
+    pub fn instrument_method(
+        &mut self,
+        class: &ClassFile<'a>,
+        index: usize,
+        probes: &Probes,
+        hierarchy: &dyn ClassHierarchy,
+    ) -> Result<bool, InstrumentError> {
+        let member = &class.methods[index];
+        let Some(code) = member.code() else {
+            return Ok(false);
+        };
+        let this_class = class.name().to_str();
+        let super_class = class.super_name().map(|name| name.to_str());
+        let hierarchy = Hierarchy {
+            this_class: &this_class,
+            super_class: super_class.as_deref(),
+            rest: hierarchy,
+        };
+        let name = member.name.to_str();
+        let descriptor = member.descriptor.to_str();
+        let entry = probes.entry.as_ref().map(check_probe).transpose()?;
+        let before_return = probes.before_return.as_ref().map(check_probe).transpose()?;
+        let before_throw = probes.before_throw.as_ref().map(check_probe).transpose()?;
+        let on_exception = probes.on_exception.as_ref().map(check_probe).transpose()?;
+
+        let mut layout = lay_out(code.code, entry, before_return, before_throw)?;
+        let original_end = layout.items.len();
+        let mut handlers = code.exception_table.clone();
+
+        // The catch-all handler for `on_exception` covers the original
+        // code, after `this` is initialized in constructors.
+        let mut covered_from = None;
+        if let Some(on_exception) = on_exception {
+            covered_from = Some(0);
+            if name == "<init>" {
+                let method = Method {
+                    pool: &self.constant_pool,
+                    hierarchy: &hierarchy,
+                    this_class: &this_class,
+                    name: &name,
+                    descriptor: &descriptor,
+                    is_static: false,
+                };
+                let mut code = code.code.to_vec();
+                let mut handlers = handlers.clone();
+                let analysis = frames::analyze(&method, &mut code, &mut handlers)?;
+                covered_from = match this_class == "java/lang/Object" {
+                    true => Some(0),
+                    false => analysis.this_initialized.filter(|&pc| pc < code.len()),
+                };
+            }
+            if covered_from.is_some() {
+                layout.items.push(Item::Probe(on_exception));
+                layout.items.push(Item::Probe(&[ATHROW]));
+            }
+        }
+        layout.wide = vec![false; layout.items.len()];
+        layout.resolve();
+        let mut new_code = layout.emit();
+        if new_code.len() > u16::MAX as usize {
+            return Err(InstrumentError::CodeTooLarge(new_code.len()));
+        }
+
+        for handler in &mut handlers {
+            let start = layout.label(handler.start_pc as usize);
+            let end = layout.label(handler.end_pc as usize);
+            let target = layout.label(handler.handler_pc as usize);
+            match (start, end, target) {
+                (Some(start), Some(end), Some(target)) => {
+                    handler.start_pc = start as u16;
+                    handler.end_pc = end as u16;
+                    handler.handler_pc = target as u16;
+                }
+                _ => {
+                    return Err(InstrumentError::BadInstruction {
+                        pc: handler.handler_pc as usize,
+                    })
+                }
+            }
+        }
+        if let Some(from) = covered_from {
+            handlers.push(ExceptionHandler {
+                start_pc: layout.label(from).unwrap() as u16,
+                end_pc: layout.offsets[original_end] as u16,
+                handler_pc: layout.offsets[original_end] as u16,
+                catch_type: 0,
+            });
+        }
+
+        let method = Method {
+            pool: &self.constant_pool,
+            hierarchy: &hierarchy,
+            this_class: &this_class,
+            name: &name,
+            descriptor: &descriptor,
+            is_static: member.access_flags.contains(AccessFlags::STATIC),
+        };
+        let initial = method.initial_frame()?;
+        let Analysis {
+            max_stack,
+            max_locals,
+            frames,
+            ..
+        } = frames::analyze(&method, &mut new_code, &mut handlers)?;
+
+        let mut attributes = Vec::new();
+        for attribute in &code.attributes {
+            let name = attribute.name.to_str();
+            let data = match &*name {
+                "LineNumberTable" => relocate_table(attribute.data, false, &layout),
+                "LocalVariableTable" | "LocalVariableTypeTable" => {
+                    relocate_table(attribute.data, true, &layout)
+                }
+                _ => continue,
+            };
+            attributes.push(RawAttribute {
+                name_index: attribute.name_index,
+                data: Cow::Owned(data),
+            });
+        }
+        if class.major_version >= 50 && !frames.is_empty() {
+            let data = frames::encode_stack_map_table(&initial, &frames, &mut self.constant_pool)?;
+            attributes.push(RawAttribute {
+                name_index: self.constant_pool.add_utf8("StackMapTable")?,
+                data: Cow::Owned(data),
+            });
+        }
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&max_stack.to_be_bytes());
+        data.extend_from_slice(&max_locals.to_be_bytes());
+        data.extend_from_slice(&(new_code.len() as u32).to_be_bytes());
+        data.extend_from_slice(&new_code);
+        data.extend_from_slice(&(handlers.len() as u16).to_be_bytes());
+        for handler in &handlers {
+            for value in [
+                handler.start_pc,
+                handler.end_pc,
+                handler.handler_pc,
+                handler.catch_type,
+            ] {
+                data.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        write_attributes(&mut data, &attributes);
+
+        let code_name = member
+            .attributes
+            .iter()
+            .find(|attribute| attribute.name == "Code")
+            .map(|attribute| attribute.name_index);
+        let writer = &mut self.methods[index];
+        match writer
+            .attributes
+            .iter_mut()
+            .find(|attribute| Some(attribute.name_index) == code_name)
+        {
+            Some(attribute) => attribute.data = Cow::Owned(data),
+            None => writer.attributes.push(RawAttribute {
+                name_index: code_name.unwrap(),
+                data: Cow::Owned(data),
+            }),
+        }
+        Ok(true)
+    }
+}
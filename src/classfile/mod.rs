@@ -0,0 +1,549 @@
+//! A zero-copy class file parser.
+//!
+//! [`ClassFile::parse`] reads the bytes a `ClassFileLoadHook` receives as
+//! `class_data`, or any other class file, into a [`ClassFile`] borrowing
+//! from them: strings and code are slices of the input, and references to
+//! the constant pool are kept as indices, checked to point to entries of
+//! the right kind.
+//!
+//! The attributes the JVM specification defines for the structure they
+//! appear in are parsed into [`AttributeInfo`]: `Code`, `LineNumberTable`,
+//! `LocalVariableTable`, `BootstrapMethods`, `NestHost`, `NestMembers`,
+//! `Record`, `PermittedSubclasses` and a few small ones. The others are
+//! kept as raw bytes.
+//!
+//! Malformed input yields a [`ParseError`] with the offset of the offending
+//! bytes and the structure being parsed.
+//!
+//! The constant pool of a loaded class, which `GetConstantPool` returns
+//! without the rest of the class file, is read with [`LoadedConstantPool`].
+//!
+//! [`ClassWriter`] writes a parsed class file back, with constants added to
+//! its pool and methods instrumented with [`Probes`], and
+//! [`set_new_class_data`] hands the result back to the `ClassFileLoadHook`.
+
+use core::ffi::c_uchar;
+use core::fmt;
+
+use bitflags::bitflags;
+use jni_sys::jint;
+
+mod attributes;
+mod constant_pool;
+mod frames;
+mod instrument;
+pub mod opcodes;
+mod reader;
+mod writer;
+
+pub use attributes::{
+    Attribute, AttributeInfo, BootstrapMethod, Code, ExceptionHandler, LineNumber, LocalVariable,
+    RecordComponent,
+};
+pub use constant_pool::{
+    Constant, ConstantPool, DynamicRef, LoadedConstantPool, MemberRef, Mutf8, ReferenceKind,
+};
+pub use frames::ClassHierarchy;
+pub use instrument::{Bytecode, InstrumentError, Probes};
+pub use writer::{
+    set_new_class_data, ClassWriter, ConstantPoolBuilder, ConstantPoolFull, MemberWriter,
+    RawAttribute,
+};
+
+use attributes::{index, optional_index, parse_attributes, Location};
+use constant_pool::Expect;
+use reader::Reader;
+
+pub const MAGIC: u32 = 0xCAFE_BABE;
+
+bitflags! {
+    /// The access flags of a class, field or method. Some bits have a
+    /// different meaning depending on where they appear.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct AccessFlags: u16 {
+        const PUBLIC = 0x0001;
+        const PRIVATE = 0x0002;
+        const PROTECTED = 0x0004;
+        const STATIC = 0x0008;
+        const FINAL = 0x0010;
+        /// `ACC_SUPER` for classes.
+        const SYNCHRONIZED = 0x0020;
+        /// `ACC_BRIDGE` for methods.
+        const VOLATILE = 0x0040;
+        /// `ACC_VARARGS` for methods.
+        const TRANSIENT = 0x0080;
+        const NATIVE = 0x0100;
+        const INTERFACE = 0x0200;
+        const ABSTRACT = 0x0400;
+        const STRICT = 0x0800;
+        const SYNTHETIC = 0x1000;
+        const ANNOTATION = 0x2000;
+        const ENUM = 0x4000;
+        /// `ACC_MANDATED` for parameters.
+        const MODULE = 0x8000;
+    }
+}
+
+impl AccessFlags {
+    pub const SUPER: Self = Self::SYNCHRONIZED;
+    pub const BRIDGE: Self = Self::VOLATILE;
+    pub const VARARGS: Self = Self::TRANSIENT;
+}
+
+/// A parse failure.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// The offset in the class file of the offending bytes.
+    pub offset: usize,
+    /// The structure being parsed, e.g. `constant pool` or `Code attribute`.
+    pub context: &'static str,
+    pub kind: ParseErrorKind,
+}
+
+/// What is wrong with a class file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The input ends in the middle of a structure, or an attribute is
+    /// shorter than its contents.
+    UnexpectedEnd {
+        needed: usize,
+        available: usize,
+    },
+    /// An attribute is longer than its contents, or the class file does not
+    /// end after its attributes.
+    TrailingBytes(usize),
+    BadMagic(u32),
+    UnsupportedVersion {
+        major: u16,
+        minor: u16,
+    },
+    BadConstantPoolCount,
+    BadConstantTag(u8),
+    /// A `Long` or `Double` takes the last slot of the constant pool.
+    WideConstantAtEnd,
+    BadUtf8,
+    BadReferenceKind(u8),
+    /// A reference to the constant pool is out of range or points to the
+    /// wrong kind of entry.
+    BadConstantIndex {
+        index: u16,
+        expected: &'static str,
+    },
+    /// A `Dynamic` or `InvokeDynamic` entry refers to a missing bootstrap
+    /// method.
+    BadBootstrapIndex(u16),
+    DuplicateAttribute,
+    BadCodeLength(u32),
+    /// A code offset is outside of the code.
+    BadCodeOffset(u32),
+    /// An abstract or native method has code, or another method has none.
+    BadCode {
+        abstract_or_native: bool,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "malformed {} at offset {:#x}: ",
+            self.context, self.offset
+        )?;
+        match &self.kind {
+            ParseErrorKind::UnexpectedEnd { needed, available } => {
+                write!(f, "needed {needed} bytes, {available} left")
+            }
+            ParseErrorKind::TrailingBytes(count) => write!(f, "{count} unexpected trailing bytes"),
+            ParseErrorKind::BadMagic(magic) => write!(f, "bad magic {magic:#010x}"),
+            ParseErrorKind::UnsupportedVersion { major, minor } => {
+                write!(f, "unsupported version {major}.{minor}")
+            }
+            ParseErrorKind::BadConstantPoolCount => write!(f, "constant pool count is 0"),
+            ParseErrorKind::BadConstantTag(tag) => write!(f, "unknown constant tag {tag}"),
+            ParseErrorKind::WideConstantAtEnd => {
+                write!(f, "Long or Double in the last constant pool slot")
+            }
+            ParseErrorKind::BadUtf8 => write!(f, "malformed modified UTF-8"),
+            ParseErrorKind::BadReferenceKind(kind) => {
+                write!(f, "unknown method handle reference kind {kind}")
+            }
+            ParseErrorKind::BadConstantIndex { index, expected } => {
+                write!(f, "constant pool index {index} is not a {expected} entry")
+            }
+            ParseErrorKind::BadBootstrapIndex(index) => {
+                write!(f, "bootstrap method {index} does not exist")
+            }
+            ParseErrorKind::DuplicateAttribute => write!(f, "attribute appears more than once"),
+            ParseErrorKind::BadCodeLength(length) => write!(f, "bad code length {length}"),
+            ParseErrorKind::BadCodeOffset(offset) => {
+                write!(f, "code offset {offset} out of range")
+            }
+            ParseErrorKind::BadCode {
+                abstract_or_native: true,
+            } => write!(f, "abstract or native method has code"),
+            ParseErrorKind::BadCode {
+                abstract_or_native: false,
+            } => write!(f, "method has no code"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Reads the magic number and version of a class file, returning the minor
+/// and major version.
+fn read_header(r: &mut Reader<'_>) -> Result<(u16, u16), ParseError> {
+    let magic = r.u4()?;
+    if magic != MAGIC {
+        return Err(r.error_at(0, ParseErrorKind::BadMagic(magic)));
+    }
+    let minor = r.u2()?;
+    let major = r.u2()?;
+    if major < 45 {
+        return Err(r.error_at(4, ParseErrorKind::UnsupportedVersion { major, minor }));
+    }
+    Ok((minor, major))
+}
+
+/// A field or method.
+#[derive(Clone, Debug)]
+pub struct Member<'a> {
+    pub access_flags: AccessFlags,
+    pub name_index: u16,
+    pub descriptor_index: u16,
+    pub name: Mutf8<'a>,
+    pub descriptor: Mutf8<'a>,
+    pub attributes: Vec<Attribute<'a>>,
+}
+
+impl<'a> Member<'a> {
+    fn parse(
+        r: &mut Reader<'a>,
+        pool: &ConstantPool<'a>,
+        location: Location,
+    ) -> Result<Self, ParseError> {
+        let access_flags = AccessFlags::from_bits_retain(r.u2()?);
+        let name_index = index(r, pool, Expect::Utf8)?;
+        let descriptor_index = index(r, pool, Expect::Utf8)?;
+        Ok(Member {
+            access_flags,
+            name_index,
+            descriptor_index,
+            name: pool.utf8(name_index).unwrap(),
+            descriptor: pool.utf8(descriptor_index).unwrap(),
+            attributes: parse_attributes(r, pool, location)?,
+        })
+    }
+
+    /// Returns the `Code` attribute of a method.
+    pub fn code(&self) -> Option<&Code<'a>> {
+        self.attributes
+            .iter()
+            .find_map(|attribute| match &attribute.info {
+                AttributeInfo::Code(code) => Some(code),
+                _ => None,
+            })
+    }
+}
+
+/// A parsed class file, borrowing from its bytes.
+#[derive(Clone, Debug)]
+pub struct ClassFile<'a> {
+    pub minor_version: u16,
+    pub major_version: u16,
+    pub constant_pool: ConstantPool<'a>,
+    pub access_flags: AccessFlags,
+    pub this_class: u16,
+    /// The `Class` entry of the superclass, 0 for `java.lang.Object` and
+    /// modules.
+    pub super_class: u16,
+    pub interfaces: Vec<u16>,
+    pub fields: Vec<Member<'a>>,
+    pub methods: Vec<Member<'a>>,
+    pub attributes: Vec<Attribute<'a>>,
+}
+
+impl<'a> ClassFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        let mut r = Reader::new(data);
+        let (minor_version, major_version) = read_header(&mut r)?;
+        let constant_pool = ConstantPool::parse(&mut r)?;
+        let pool = &constant_pool;
+
+        let access_flags = AccessFlags::from_bits_retain(r.u2()?);
+        let this_class = index(&mut r, pool, Expect::Class)?;
+        let super_class = optional_index(&mut r, pool, Expect::Class)?;
+        let count = r.u2()?;
+        let interfaces = (0..count)
+            .map(|_| index(&mut r, pool, Expect::Class))
+            .collect::<Result<_, _>>()?;
+
+        r.enter("field");
+        let count = r.u2()?;
+        let fields = (0..count)
+            .map(|_| Member::parse(&mut r, pool, Location::Field))
+            .collect::<Result<_, _>>()?;
+
+        r.enter("method");
+        let count = r.u2()?;
+        let mut methods = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let offset = r.offset();
+            let method = Member::parse(&mut r, pool, Location::Method)?;
+            let abstract_or_native = method
+                .access_flags
+                .intersects(AccessFlags::ABSTRACT | AccessFlags::NATIVE);
+            if method.code().is_some() == abstract_or_native {
+                return Err(r.error_at(offset, ParseErrorKind::BadCode { abstract_or_native }));
+            }
+            methods.push(method);
+        }
+
+        r.enter("class file");
+        let attributes = parse_attributes(&mut r, pool, Location::Class)?;
+        r.finish()?;
+
+        let class = ClassFile {
+            minor_version,
+            major_version,
+            constant_pool,
+            access_flags,
+            this_class,
+            super_class,
+            interfaces,
+            fields,
+            methods,
+            attributes,
+        };
+        class.check_bootstrap_indices(&mut r)?;
+        Ok(class)
+    }
+
+    /// Parses the `class_data` of a `ClassFileLoadHook` event.
+    ///
+    /// # Safety
+    ///
+    /// `class_data` must point to `class_data_len` readable bytes that
+    /// outlive the result.
+    pub unsafe fn from_raw(
+        class_data: *const c_uchar,
+        class_data_len: jint,
+    ) -> Result<Self, ParseError> {
+        if class_data.is_null() {
+            return Self::parse(&[]);
+        }
+        Self::parse(core::slice::from_raw_parts(
+            class_data,
+            class_data_len.max(0) as usize,
+        ))
+    }
+
+    /// Checks the `Dynamic` and `InvokeDynamic` entries against the
+    /// `BootstrapMethods` attribute, which comes after the constant pool.
+    fn check_bootstrap_indices(&self, r: &mut Reader<'a>) -> Result<(), ParseError> {
+        r.enter("constant pool");
+        let count = self.bootstrap_methods().len();
+        for (index, constant) in self.constant_pool.iter() {
+            if let Constant::Dynamic {
+                bootstrap_method_attr_index,
+                ..
+            }
+            | Constant::InvokeDynamic {
+                bootstrap_method_attr_index,
+                ..
+            } = *constant
+            {
+                if bootstrap_method_attr_index as usize >= count {
+                    return Err(r.error_at(
+                        self.constant_pool.offset(index),
+                        ParseErrorKind::BadBootstrapIndex(bootstrap_method_attr_index),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the internal name of the class, e.g. `java/lang/String`.
+    pub fn name(&self) -> Mutf8<'a> {
+        self.constant_pool.class_name(self.this_class).unwrap()
+    }
+
+    /// Returns the internal name of the superclass.
+    pub fn super_name(&self) -> Option<Mutf8<'a>> {
+        self.constant_pool.class_name(self.super_class)
+    }
+
+    /// Returns the internal names of the direct superinterfaces.
+    pub fn interface_names(&self) -> impl Iterator<Item = Mutf8<'a>> + '_ {
+        self.interfaces
+            .iter()
+            .filter_map(|&index| self.constant_pool.class_name(index))
+    }
+
+    /// Returns the first attribute called `name`.
+    pub fn attribute(&self, name: &str) -> Option<&Attribute<'a>> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.name == name)
+    }
+
+    /// Returns the name of the source file, from the `SourceFile` attribute.
+    pub fn source_file(&self) -> Option<Mutf8<'a>> {
+        self.attributes
+            .iter()
+            .find_map(|attribute| match attribute.info {
+                AttributeInfo::SourceFile { index } => self.constant_pool.utf8(index),
+                _ => None,
+            })
+    }
+
+    /// Returns the entries of the `BootstrapMethods` attribute.
+    pub fn bootstrap_methods(&self) -> &[BootstrapMethod] {
+        self.attributes
+            .iter()
+            .find_map(|attribute| match &attribute.info {
+                AttributeInfo::BootstrapMethods(methods) => Some(&methods[..]),
+                _ => None,
+            })
+            .unwrap_or(&[])
+    }
+
+    pub fn field(&self, name: &str, descriptor: &str) -> Option<&Member<'a>> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name && field.descriptor == descriptor)
+    }
+
+    pub fn method(&self, name: &str, descriptor: &str) -> Option<&Member<'a>> {
+        self.methods
+            .iter()
+            .find(|method| method.name == name && method.descriptor == descriptor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::opcodes::{GETSTATIC, IADD, ICONST_1, PUTSTATIC};
+    use super::*;
+
+    /// `testdata/Fixture.java` compiled by javac 17 for Java 11.
+    const FIXTURE: &[u8] = include_bytes!("testdata/Fixture.class");
+
+    fn signatures(class: &ClassFile<'_>) -> Vec<String> {
+        class
+            .methods
+            .iter()
+            .map(|method| format!("{}{}", method.name.to_str(), method.descriptor.to_str()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_fixture() {
+        let class = ClassFile::parse(FIXTURE).unwrap();
+        assert_eq!(class.name().to_str(), "Fixture");
+        assert_eq!(
+            class.super_name().map(|name| name.to_str().into_owned()),
+            Some("java/lang/Object".to_owned())
+        );
+        assert_eq!(
+            signatures(&class),
+            [
+                "<init>(Ljava/lang/String;)V",
+                "classify(I)I",
+                "dense(I)I",
+                "widen(I)Ljava/lang/String;",
+                "sum([J)J",
+                "describe(Ljava/lang/Object;)Ljava/lang/String;",
+                "apply(I)I",
+                "rename(ILjava/lang/String;)V",
+                "lambda$apply$0(I)I",
+            ]
+        );
+        assert!(class.methods.iter().all(|method| method.code().is_some()));
+    }
+
+    #[test]
+    fn writes_unmodified_fixture_unchanged() {
+        let class = ClassFile::parse(FIXTURE).unwrap();
+        assert_eq!(ClassWriter::new(&class).write().unwrap(), FIXTURE);
+    }
+
+    #[test]
+    fn instruments_fixture() {
+        let class = ClassFile::parse(FIXTURE).unwrap();
+        let mut writer = ClassWriter::new(&class);
+        let calls = writer
+            .constant_pool
+            .add_fieldref("Fixture", "calls", "I")
+            .unwrap();
+        let mut count = Bytecode::new();
+        count
+            .op_u2(GETSTATIC, calls)
+            .op(ICONST_1)
+            .op(IADD)
+            .op_u2(PUTSTATIC, calls);
+        let probes = Probes {
+            entry: Some(count.clone()),
+            before_return: Some(count.clone()),
+            before_throw: Some(count.clone()),
+            on_exception: Some(count),
+        };
+        let hierarchy: HashMap<String, String> = [
+            ("java/lang/Exception", "java/lang/Throwable"),
+            ("java/lang/RuntimeException", "java/lang/Exception"),
+            (
+                "java/lang/NullPointerException",
+                "java/lang/RuntimeException",
+            ),
+            (
+                "java/lang/IllegalArgumentException",
+                "java/lang/RuntimeException",
+            ),
+        ]
+        .into_iter()
+        .map(|(class, super_class)| (class.to_owned(), super_class.to_owned()))
+        .collect();
+        for index in 0..class.methods.len() {
+            assert!(writer
+                .instrument_method(&class, index, &probes, &hierarchy)
+                .unwrap());
+        }
+
+        let bytes = writer.write().unwrap();
+        let instrumented = ClassFile::parse(&bytes).unwrap();
+        assert_eq!(signatures(&instrumented), signatures(&class));
+        for (method, original) in instrumented.methods.iter().zip(&class.methods) {
+            let code = method.code().unwrap();
+            let original = original.code().unwrap();
+            assert!(code.code.len() > original.code.len());
+            assert!(code.max_stack >= original.max_stack);
+            assert!(code.exception_table.len() > original.exception_table.len());
+        }
+    }
+
+    #[test]
+    fn constant_pool_fills_up_to_u16_max_slots() {
+        let mut pool = ConstantPoolBuilder::new();
+        for value in 1..65533 {
+            pool.add_integer(value).unwrap();
+        }
+        assert_eq!(pool.len(), 65533);
+        // The last `Long` takes the last two slots.
+        assert_eq!(pool.clone().add_long(0), Ok(65533));
+        assert_eq!(pool.add_integer(0), Ok(65533));
+        // A `Long` needs two slots, but only one is left.
+        assert_eq!(pool.add_long(0), Err(ConstantPoolFull));
+        assert_eq!(pool.add_integer(-1), Ok(65534));
+        assert_eq!(pool.len(), 65535);
+        assert_eq!(pool.add_integer(-2), Err(ConstantPoolFull));
+        // Existing entries are still found.
+        assert_eq!(pool.add_integer(1), Ok(1));
+
+        let mut out = Vec::new();
+        pool.write(&mut out);
+        assert_eq!(out[..2], [0xff, 0xff]);
+    }
+}
@@ -0,0 +1,588 @@
+//! JVM opcodes and instruction decoding.
+
+pub const NOP: u8 = 0x00;
+pub const ACONST_NULL: u8 = 0x01;
+pub const ICONST_M1: u8 = 0x02;
+pub const ICONST_0: u8 = 0x03;
+pub const ICONST_1: u8 = 0x04;
+pub const ICONST_2: u8 = 0x05;
+pub const ICONST_3: u8 = 0x06;
+pub const ICONST_4: u8 = 0x07;
+pub const ICONST_5: u8 = 0x08;
+pub const LCONST_0: u8 = 0x09;
+pub const LCONST_1: u8 = 0x0a;
+pub const FCONST_0: u8 = 0x0b;
+pub const FCONST_1: u8 = 0x0c;
+pub const FCONST_2: u8 = 0x0d;
+pub const DCONST_0: u8 = 0x0e;
+pub const DCONST_1: u8 = 0x0f;
+pub const BIPUSH: u8 = 0x10;
+pub const SIPUSH: u8 = 0x11;
+pub const LDC: u8 = 0x12;
+pub const LDC_W: u8 = 0x13;
+pub const LDC2_W: u8 = 0x14;
+pub const ILOAD: u8 = 0x15;
+pub const LLOAD: u8 = 0x16;
+pub const FLOAD: u8 = 0x17;
+pub const DLOAD: u8 = 0x18;
+pub const ALOAD: u8 = 0x19;
+pub const ILOAD_0: u8 = 0x1a;
+pub const ILOAD_1: u8 = 0x1b;
+pub const ILOAD_2: u8 = 0x1c;
+pub const ILOAD_3: u8 = 0x1d;
+pub const LLOAD_0: u8 = 0x1e;
+pub const LLOAD_1: u8 = 0x1f;
+pub const LLOAD_2: u8 = 0x20;
+pub const LLOAD_3: u8 = 0x21;
+pub const FLOAD_0: u8 = 0x22;
+pub const FLOAD_1: u8 = 0x23;
+pub const FLOAD_2: u8 = 0x24;
+pub const FLOAD_3: u8 = 0x25;
+pub const DLOAD_0: u8 = 0x26;
+pub const DLOAD_1: u8 = 0x27;
+pub const DLOAD_2: u8 = 0x28;
+pub const DLOAD_3: u8 = 0x29;
+pub const ALOAD_0: u8 = 0x2a;
+pub const ALOAD_1: u8 = 0x2b;
+pub const ALOAD_2: u8 = 0x2c;
+pub const ALOAD_3: u8 = 0x2d;
+pub const IALOAD: u8 = 0x2e;
+pub const LALOAD: u8 = 0x2f;
+pub const FALOAD: u8 = 0x30;
+pub const DALOAD: u8 = 0x31;
+pub const AALOAD: u8 = 0x32;
+pub const BALOAD: u8 = 0x33;
+pub const CALOAD: u8 = 0x34;
+pub const SALOAD: u8 = 0x35;
+pub const ISTORE: u8 = 0x36;
+pub const LSTORE: u8 = 0x37;
+pub const FSTORE: u8 = 0x38;
+pub const DSTORE: u8 = 0x39;
+pub const ASTORE: u8 = 0x3a;
+pub const ISTORE_0: u8 = 0x3b;
+pub const ISTORE_1: u8 = 0x3c;
+pub const ISTORE_2: u8 = 0x3d;
+pub const ISTORE_3: u8 = 0x3e;
+pub const LSTORE_0: u8 = 0x3f;
+pub const LSTORE_1: u8 = 0x40;
+pub const LSTORE_2: u8 = 0x41;
+pub const LSTORE_3: u8 = 0x42;
+pub const FSTORE_0: u8 = 0x43;
+pub const FSTORE_1: u8 = 0x44;
+pub const FSTORE_2: u8 = 0x45;
+pub const FSTORE_3: u8 = 0x46;
+pub const DSTORE_0: u8 = 0x47;
+pub const DSTORE_1: u8 = 0x48;
+pub const DSTORE_2: u8 = 0x49;
+pub const DSTORE_3: u8 = 0x4a;
+pub const ASTORE_0: u8 = 0x4b;
+pub const ASTORE_1: u8 = 0x4c;
+pub const ASTORE_2: u8 = 0x4d;
+pub const ASTORE_3: u8 = 0x4e;
+pub const IASTORE: u8 = 0x4f;
+pub const LASTORE: u8 = 0x50;
+pub const FASTORE: u8 = 0x51;
+pub const DASTORE: u8 = 0x52;
+pub const AASTORE: u8 = 0x53;
+pub const BASTORE: u8 = 0x54;
+pub const CASTORE: u8 = 0x55;
+pub const SASTORE: u8 = 0x56;
+pub const POP: u8 = 0x57;
+pub const POP2: u8 = 0x58;
+pub const DUP: u8 = 0x59;
+pub const DUP_X1: u8 = 0x5a;
+pub const DUP_X2: u8 = 0x5b;
+pub const DUP2: u8 = 0x5c;
+pub const DUP2_X1: u8 = 0x5d;
+pub const DUP2_X2: u8 = 0x5e;
+pub const SWAP: u8 = 0x5f;
+pub const IADD: u8 = 0x60;
+pub const LADD: u8 = 0x61;
+pub const FADD: u8 = 0x62;
+pub const DADD: u8 = 0x63;
+pub const ISUB: u8 = 0x64;
+pub const LSUB: u8 = 0x65;
+pub const FSUB: u8 = 0x66;
+pub const DSUB: u8 = 0x67;
+pub const IMUL: u8 = 0x68;
+pub const LMUL: u8 = 0x69;
+pub const FMUL: u8 = 0x6a;
+pub const DMUL: u8 = 0x6b;
+pub const IDIV: u8 = 0x6c;
+pub const LDIV: u8 = 0x6d;
+pub const FDIV: u8 = 0x6e;
+pub const DDIV: u8 = 0x6f;
+pub const IREM: u8 = 0x70;
+pub const LREM: u8 = 0x71;
+pub const FREM: u8 = 0x72;
+pub const DREM: u8 = 0x73;
+pub const INEG: u8 = 0x74;
+pub const LNEG: u8 = 0x75;
+pub const FNEG: u8 = 0x76;
+pub const DNEG: u8 = 0x77;
+pub const ISHL: u8 = 0x78;
+pub const LSHL: u8 = 0x79;
+pub const ISHR: u8 = 0x7a;
+pub const LSHR: u8 = 0x7b;
+pub const IUSHR: u8 = 0x7c;
+pub const LUSHR: u8 = 0x7d;
+pub const IAND: u8 = 0x7e;
+pub const LAND: u8 = 0x7f;
+pub const IOR: u8 = 0x80;
+pub const LOR: u8 = 0x81;
+pub const IXOR: u8 = 0x82;
+pub const LXOR: u8 = 0x83;
+pub const IINC: u8 = 0x84;
+pub const I2L: u8 = 0x85;
+pub const I2F: u8 = 0x86;
+pub const I2D: u8 = 0x87;
+pub const L2I: u8 = 0x88;
+pub const L2F: u8 = 0x89;
+pub const L2D: u8 = 0x8a;
+pub const F2I: u8 = 0x8b;
+pub const F2L: u8 = 0x8c;
+pub const F2D: u8 = 0x8d;
+pub const D2I: u8 = 0x8e;
+pub const D2L: u8 = 0x8f;
+pub const D2F: u8 = 0x90;
+pub const I2B: u8 = 0x91;
+pub const I2C: u8 = 0x92;
+pub const I2S: u8 = 0x93;
+pub const LCMP: u8 = 0x94;
+pub const FCMPL: u8 = 0x95;
+pub const FCMPG: u8 = 0x96;
+pub const DCMPL: u8 = 0x97;
+pub const DCMPG: u8 = 0x98;
+pub const IFEQ: u8 = 0x99;
+pub const IFNE: u8 = 0x9a;
+pub const IFLT: u8 = 0x9b;
+pub const IFGE: u8 = 0x9c;
+pub const IFGT: u8 = 0x9d;
+pub const IFLE: u8 = 0x9e;
+pub const IF_ICMPEQ: u8 = 0x9f;
+pub const IF_ICMPNE: u8 = 0xa0;
+pub const IF_ICMPLT: u8 = 0xa1;
+pub const IF_ICMPGE: u8 = 0xa2;
+pub const IF_ICMPGT: u8 = 0xa3;
+pub const IF_ICMPLE: u8 = 0xa4;
+pub const IF_ACMPEQ: u8 = 0xa5;
+pub const IF_ACMPNE: u8 = 0xa6;
+pub const GOTO: u8 = 0xa7;
+pub const JSR: u8 = 0xa8;
+pub const RET: u8 = 0xa9;
+pub const TABLESWITCH: u8 = 0xaa;
+pub const LOOKUPSWITCH: u8 = 0xab;
+pub const IRETURN: u8 = 0xac;
+pub const LRETURN: u8 = 0xad;
+pub const FRETURN: u8 = 0xae;
+pub const DRETURN: u8 = 0xaf;
+pub const ARETURN: u8 = 0xb0;
+pub const RETURN: u8 = 0xb1;
+pub const GETSTATIC: u8 = 0xb2;
+pub const PUTSTATIC: u8 = 0xb3;
+pub const GETFIELD: u8 = 0xb4;
+pub const PUTFIELD: u8 = 0xb5;
+pub const INVOKEVIRTUAL: u8 = 0xb6;
+pub const INVOKESPECIAL: u8 = 0xb7;
+pub const INVOKESTATIC: u8 = 0xb8;
+pub const INVOKEINTERFACE: u8 = 0xb9;
+pub const INVOKEDYNAMIC: u8 = 0xba;
+pub const NEW: u8 = 0xbb;
+pub const NEWARRAY: u8 = 0xbc;
+pub const ANEWARRAY: u8 = 0xbd;
+pub const ARRAYLENGTH: u8 = 0xbe;
+pub const ATHROW: u8 = 0xbf;
+pub const CHECKCAST: u8 = 0xc0;
+pub const INSTANCEOF: u8 = 0xc1;
+pub const MONITORENTER: u8 = 0xc2;
+pub const MONITOREXIT: u8 = 0xc3;
+pub const WIDE: u8 = 0xc4;
+pub const MULTIANEWARRAY: u8 = 0xc5;
+pub const IFNULL: u8 = 0xc6;
+pub const IFNONNULL: u8 = 0xc7;
+pub const GOTO_W: u8 = 0xc8;
+pub const JSR_W: u8 = 0xc9;
+
+static NAMES: [&str; 202] = [
+    "nop",
+    "aconst_null",
+    "iconst_m1",
+    "iconst_0",
+    "iconst_1",
+    "iconst_2",
+    "iconst_3",
+    "iconst_4",
+    "iconst_5",
+    "lconst_0",
+    "lconst_1",
+    "fconst_0",
+    "fconst_1",
+    "fconst_2",
+    "dconst_0",
+    "dconst_1",
+    "bipush",
+    "sipush",
+    "ldc",
+    "ldc_w",
+    "ldc2_w",
+    "iload",
+    "lload",
+    "fload",
+    "dload",
+    "aload",
+    "iload_0",
+    "iload_1",
+    "iload_2",
+    "iload_3",
+    "lload_0",
+    "lload_1",
+    "lload_2",
+    "lload_3",
+    "fload_0",
+    "fload_1",
+    "fload_2",
+    "fload_3",
+    "dload_0",
+    "dload_1",
+    "dload_2",
+    "dload_3",
+    "aload_0",
+    "aload_1",
+    "aload_2",
+    "aload_3",
+    "iaload",
+    "laload",
+    "faload",
+    "daload",
+    "aaload",
+    "baload",
+    "caload",
+    "saload",
+    "istore",
+    "lstore",
+    "fstore",
+    "dstore",
+    "astore",
+    "istore_0",
+    "istore_1",
+    "istore_2",
+    "istore_3",
+    "lstore_0",
+    "lstore_1",
+    "lstore_2",
+    "lstore_3",
+    "fstore_0",
+    "fstore_1",
+    "fstore_2",
+    "fstore_3",
+    "dstore_0",
+    "dstore_1",
+    "dstore_2",
+    "dstore_3",
+    "astore_0",
+    "astore_1",
+    "astore_2",
+    "astore_3",
+    "iastore",
+    "lastore",
+    "fastore",
+    "dastore",
+    "aastore",
+    "bastore",
+    "castore",
+    "sastore",
+    "pop",
+    "pop2",
+    "dup",
+    "dup_x1",
+    "dup_x2",
+    "dup2",
+    "dup2_x1",
+    "dup2_x2",
+    "swap",
+    "iadd",
+    "ladd",
+    "fadd",
+    "dadd",
+    "isub",
+    "lsub",
+    "fsub",
+    "dsub",
+    "imul",
+    "lmul",
+    "fmul",
+    "dmul",
+    "idiv",
+    "ldiv",
+    "fdiv",
+    "ddiv",
+    "irem",
+    "lrem",
+    "frem",
+    "drem",
+    "ineg",
+    "lneg",
+    "fneg",
+    "dneg",
+    "ishl",
+    "lshl",
+    "ishr",
+    "lshr",
+    "iushr",
+    "lushr",
+    "iand",
+    "land",
+    "ior",
+    "lor",
+    "ixor",
+    "lxor",
+    "iinc",
+    "i2l",
+    "i2f",
+    "i2d",
+    "l2i",
+    "l2f",
+    "l2d",
+    "f2i",
+    "f2l",
+    "f2d",
+    "d2i",
+    "d2l",
+    "d2f",
+    "i2b",
+    "i2c",
+    "i2s",
+    "lcmp",
+    "fcmpl",
+    "fcmpg",
+    "dcmpl",
+    "dcmpg",
+    "ifeq",
+    "ifne",
+    "iflt",
+    "ifge",
+    "ifgt",
+    "ifle",
+    "if_icmpeq",
+    "if_icmpne",
+    "if_icmplt",
+    "if_icmpge",
+    "if_icmpgt",
+    "if_icmple",
+    "if_acmpeq",
+    "if_acmpne",
+    "goto",
+    "jsr",
+    "ret",
+    "tableswitch",
+    "lookupswitch",
+    "ireturn",
+    "lreturn",
+    "freturn",
+    "dreturn",
+    "areturn",
+    "return",
+    "getstatic",
+    "putstatic",
+    "getfield",
+    "putfield",
+    "invokevirtual",
+    "invokespecial",
+    "invokestatic",
+    "invokeinterface",
+    "invokedynamic",
+    "new",
+    "newarray",
+    "anewarray",
+    "arraylength",
+    "athrow",
+    "checkcast",
+    "instanceof",
+    "monitorenter",
+    "monitorexit",
+    "wide",
+    "multianewarray",
+    "ifnull",
+    "ifnonnull",
+    "goto_w",
+    "jsr_w",
+];
+
+/// Returns the mnemonic of `opcode`, e.g. `invokevirtual`, or `None` for
+/// opcodes that may not appear in class files.
+pub fn name(opcode: u8) -> Option<&'static str> {
+    NAMES.get(opcode as usize).copied()
+}
+
+/// Returns the number of padding bytes after a `tableswitch` or
+/// `lookupswitch` at `pc`, which align its operands to four bytes.
+pub fn switch_padding(pc: usize) -> usize {
+    (4 - (pc + 1) % 4) % 4
+}
+
+/// Returns the length of the instruction at `pc`, including its operands,
+/// or `None` if it is not a valid instruction or runs past the code.
+pub fn instruction_length(code: &[u8], pc: usize) -> Option<usize> {
+    let opcode = *code.get(pc)?;
+    let length = match opcode {
+        BIPUSH | LDC | ILOAD..=ALOAD | ISTORE..=ASTORE | RET | NEWARRAY => 2,
+        SIPUSH
+        | LDC_W
+        | LDC2_W
+        | IINC
+        | IFEQ..=JSR
+        | GETSTATIC..=INVOKESTATIC
+        | NEW
+        | ANEWARRAY
+        | CHECKCAST
+        | INSTANCEOF
+        | IFNULL
+        | IFNONNULL => 3,
+        MULTIANEWARRAY => 4,
+        INVOKEINTERFACE | INVOKEDYNAMIC | GOTO_W | JSR_W => 5,
+        WIDE => match *code.get(pc + 1)? {
+            IINC => 6,
+            ILOAD..=ALOAD | ISTORE..=ASTORE | RET => 4,
+            _ => return None,
+        },
+        TABLESWITCH => {
+            let operands = pc + 1 + switch_padding(pc);
+            let low = read_i32(code, operands + 4)?;
+            let high = read_i32(code, operands + 8)?;
+            if low > high {
+                return None;
+            }
+            let count = (high as i64 - low as i64 + 1) as usize;
+            operands - pc + 12 + count * 4
+        }
+        LOOKUPSWITCH => {
+            let operands = pc + 1 + switch_padding(pc);
+            let count = read_i32(code, operands + 4)?;
+            if count < 0 {
+                return None;
+            }
+            operands - pc + 8 + count as usize * 8
+        }
+        NOP..=DCONST_1
+        | ILOAD_0..=SALOAD
+        | ISTORE_0..=LXOR
+        | I2L..=DCMPG
+        | IRETURN..=RETURN
+        | ARRAYLENGTH
+        | ATHROW
+        | MONITORENTER
+        | MONITOREXIT => 1,
+        _ => return None,
+    };
+    match pc.checked_add(length) {
+        Some(end) if end <= code.len() => Some(length),
+        _ => None,
+    }
+}
+
+pub(crate) fn read_i32(code: &[u8], at: usize) -> Option<i32> {
+    let bytes = code.get(at..at.checked_add(4)?)?;
+    Some(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// An instruction and its operands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction<'a> {
+    pub pc: usize,
+    /// The instruction, starting with its opcode. For `wide` instructions,
+    /// this is `wide`.
+    pub bytes: &'a [u8],
+}
+
+impl Instruction<'_> {
+    pub fn opcode(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    /// Returns the `u1` operand at `offset` from the opcode.
+    pub fn u1(&self, offset: usize) -> u8 {
+        self.bytes[offset]
+    }
+
+    /// Returns the `u2` operand at `offset` from the opcode.
+    pub fn u2(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.bytes[offset], self.bytes[offset + 1]])
+    }
+
+    /// Returns the `s4` operand at `offset` from the opcode.
+    pub fn s4(&self, offset: usize) -> i32 {
+        read_i32(self.bytes, offset).unwrap()
+    }
+
+    /// Returns the targets of a branch or switch, default first, as
+    /// absolute code offsets.
+    pub fn branch_targets(&self) -> Vec<usize> {
+        let target = |offset: i32| (self.pc as i64 + offset as i64) as usize;
+        match self.opcode() {
+            IFEQ..=JSR | IFNULL | IFNONNULL => vec![target(self.u2(1) as i16 as i32)],
+            GOTO_W | JSR_W => vec![target(self.s4(1))],
+            TABLESWITCH | LOOKUPSWITCH => {
+                let operands = 1 + switch_padding(self.pc);
+                let mut targets = vec![target(self.s4(operands))];
+                if self.opcode() == TABLESWITCH {
+                    let count = self.s4(operands + 8) as i64 - self.s4(operands + 4) as i64 + 1;
+                    for i in 0..count as usize {
+                        targets.push(target(self.s4(operands + 12 + i * 4)));
+                    }
+                } else {
+                    let count = self.s4(operands + 4) as usize;
+                    for i in 0..count {
+                        targets.push(target(self.s4(operands + 12 + i * 8)));
+                    }
+                }
+                targets
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Returns whether execution never continues with the next instruction.
+pub fn is_unconditional(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        GOTO | GOTO_W | RET | TABLESWITCH | LOOKUPSWITCH | IRETURN..=RETURN | ATHROW
+    )
+}
+
+/// Decodes the instructions of a method.
+pub fn instructions(code: &[u8]) -> Instructions<'_> {
+    Instructions { code, pc: 0 }
+}
+
+/// The iterator returned by [`instructions`]. Yields `Err(pc)` and stops at
+/// the first malformed instruction.
+#[derive(Clone, Debug)]
+pub struct Instructions<'a> {
+    code: &'a [u8],
+    pc: usize,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pc >= self.code.len() {
+            return None;
+        }
+        let pc = self.pc;
+        match instruction_length(self.code, pc) {
+            Some(length) => {
+                self.pc += length;
+                Some(Ok(Instruction {
+                    pc,
+                    bytes: &self.code[pc..pc + length],
+                }))
+            }
+            None => {
+                self.pc = self.code.len();
+                Some(Err(pc))
+            }
+        }
+    }
+}
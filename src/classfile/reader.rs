@@ -0,0 +1,113 @@
+//! A bounds-checked big-endian cursor over class file bytes.
+
+use super::{ParseError, ParseErrorKind};
+
+#[derive(Clone, Debug)]
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Offset of `data` in the class file, for error reporting.
+    base: usize,
+    /// The structure being parsed, for error reporting.
+    context: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Reader {
+            data,
+            pos: 0,
+            base: 0,
+            context: "class file",
+        }
+    }
+
+    /// Returns the offset of the next byte in the class file.
+    pub(crate) fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    /// Sets the structure being parsed and returns the previous one.
+    pub(crate) fn enter(&mut self, context: &'static str) -> &'static str {
+        core::mem::replace(&mut self.context, context)
+    }
+
+    pub(crate) fn leave(&mut self, context: &'static str) {
+        self.context = context;
+    }
+
+    /// Returns an error at the current offset.
+    pub(crate) fn error(&self, kind: ParseErrorKind) -> ParseError {
+        self.error_at(self.offset(), kind)
+    }
+
+    pub(crate) fn error_at(&self, offset: usize, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            offset,
+            context: self.context,
+            kind,
+        }
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        if self.remaining() < len {
+            return Err(self.error(ParseErrorKind::UnexpectedEnd {
+                needed: len,
+                available: self.remaining(),
+            }));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Splits off a reader over the next `len` bytes.
+    pub(crate) fn sub(&mut self, len: usize) -> Result<Reader<'a>, ParseError> {
+        let base = self.offset();
+        let data = self.bytes(len)?;
+        Ok(Reader {
+            data,
+            pos: 0,
+            base,
+            context: self.context,
+        })
+    }
+
+    pub(crate) fn u1(&mut self) -> Result<u8, ParseError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u2(&mut self) -> Result<u16, ParseError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn u4(&mut self) -> Result<u32, ParseError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u64, ParseError> {
+        let high = self.u4()? as u64;
+        let low = self.u4()? as u64;
+        Ok(high << 32 | low)
+    }
+
+    /// Reads a `u2` count followed by that many `u2` values.
+    pub(crate) fn u2_list(&mut self) -> Result<Vec<u16>, ParseError> {
+        let count = self.u2()?;
+        (0..count).map(|_| self.u2()).collect()
+    }
+
+    /// Fails unless all bytes have been consumed.
+    pub(crate) fn finish(&self) -> Result<(), ParseError> {
+        match self.remaining() {
+            0 => Ok(()),
+            remaining => Err(self.error(ParseErrorKind::TrailingBytes(remaining))),
+        }
+    }
+}
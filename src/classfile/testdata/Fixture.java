@@ -0,0 +1,83 @@
+import java.util.ArrayList;
+import java.util.List;
+import java.util.function.IntUnaryOperator;
+
+/** Compiled with `javac --release 11 -g Fixture.java` for the class file tests. */
+public class Fixture {
+    static int calls;
+    static final long BIG = 1L << 40;
+    static final double RATIO = 0.5;
+
+    private final List<String> names = new ArrayList<>();
+
+    public Fixture(String first) {
+        super();
+        if (first != null) {
+            names.add(first);
+        }
+    }
+
+    static int classify(int value) {
+        switch (value) {
+            case 1:
+                return 10;
+            case 2:
+                return 20;
+            case 100:
+                return 1000;
+            default:
+                return value < 0 ? -1 : 0;
+        }
+    }
+
+    static int dense(int value) {
+        switch (value) {
+            case 0: return 5;
+            case 1: return 6;
+            case 2: return 7;
+            case 3: return 8;
+            default: return 9;
+        }
+    }
+
+    static String widen(int value) {
+        value += 1000;
+        return value > 0 ? "tab\tline\n\u0001\"quoted\"" : null;
+    }
+
+    long sum(long[] values) {
+        long total = BIG;
+        for (long value : values) {
+            total += value;
+        }
+        return total;
+    }
+
+    String describe(Object value) {
+        try {
+            if (value instanceof String) {
+                return "string " + value;
+            }
+            return String.valueOf(value.hashCode() * RATIO);
+        } catch (NullPointerException e) {
+            throw new IllegalArgumentException("null", e);
+        } finally {
+            calls++;
+        }
+    }
+
+    int apply(int value) {
+        IntUnaryOperator twice = x -> x * 2;
+        return new Inner().add(twice.applyAsInt(value));
+    }
+
+    synchronized void rename(int index, String name) {
+        names.set(index, name);
+    }
+
+    class Inner {
+        int add(int value) {
+            return value + names.size();
+        }
+    }
+}
@@ -0,0 +1,510 @@
+//! Class file serialization.
+//!
+//! [`ClassWriter`] holds a class file in a form that can be modified and
+//! written back: the constant pool as a [`ConstantPoolBuilder`], which keeps
+//! the indices of the original entries and appends new ones, and members
+//! and attributes as raw bytes borrowed from the original class file until
+//! they are replaced.
+
+use core::ffi::c_uint;
+use core::fmt;
+use core::ptr::null_mut;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use jni_sys::jint;
+
+use super::constant_pool::{Constant, ConstantPool, MemberRef, Mutf8, ReferenceKind};
+use super::{AccessFlags, ClassFile, Member, ParseError, MAGIC};
+use crate::util::check;
+use crate::{jvmtiEnv, jvmtiError};
+
+#[derive(Clone, Debug)]
+enum Entry<'a> {
+    Utf8(Cow<'a, [u8]>),
+    /// Any other entry, including [`Constant::Unusable`].
+    Constant(Constant<'a>),
+}
+
+/// Returned by the `add_*` methods of [`ConstantPoolBuilder`] when a new
+/// entry does not fit into the 65535 slots of a constant pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConstantPoolFull;
+
+impl fmt::Display for ConstantPoolFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("constant pool is full")
+    }
+}
+
+impl std::error::Error for ConstantPoolFull {}
+
+/// A constant pool that entries can be added to.
+///
+/// The `add_*` methods return the index of an equal entry if there is one,
+/// and otherwise append a new entry, failing with [`ConstantPoolFull`] if
+/// `constant_pool_count` would exceed `u16::MAX`.
+#[derive(Clone, Debug)]
+pub struct ConstantPoolBuilder<'a> {
+    entries: Vec<Entry<'a>>,
+    /// The index of each entry by its serialized form.
+    indices: HashMap<Vec<u8>, u16>,
+}
+
+impl Default for ConstantPoolBuilder<'_> {
+    fn default() -> Self {
+        ConstantPoolBuilder {
+            entries: vec![Entry::Constant(Constant::Unusable)],
+            indices: HashMap::new(),
+        }
+    }
+}
+
+impl<'a> ConstantPoolBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from the entries of `pool`, keeping their indices.
+    pub fn from_pool(pool: &ConstantPool<'a>) -> Self {
+        let mut builder = ConstantPoolBuilder {
+            entries: Vec::with_capacity(pool.len()),
+            indices: HashMap::new(),
+        };
+        for index in 0..pool.len() {
+            let entry = match pool.get(index as u16) {
+                Some(Constant::Utf8(s)) => Entry::Utf8(Cow::Borrowed(s.as_bytes())),
+                Some(constant) => Entry::Constant(*constant),
+                None => Entry::Constant(Constant::Unusable),
+            };
+            if !matches!(entry, Entry::Constant(Constant::Unusable)) {
+                builder
+                    .indices
+                    .entry(encode_entry(&entry))
+                    .or_insert(index as u16);
+            }
+            builder.entries.push(entry);
+        }
+        builder
+    }
+
+    /// Returns the number of slots, i.e. `constant_pool_count`.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.len() <= 1
+    }
+
+    fn add(&mut self, entry: Entry<'a>) -> Result<u16, ConstantPoolFull> {
+        let key = encode_entry(&entry);
+        if let Some(&index) = self.indices.get(&key) {
+            return Ok(index);
+        }
+        let wide = matches!(entry, Entry::Constant(c) if c.is_wide());
+        let index = self.entries.len();
+        // The last slot of an entry must stay below `constant_pool_count`,
+        // which is itself a `u2`.
+        if index + (wide as usize) >= u16::MAX as usize {
+            return Err(ConstantPoolFull);
+        }
+        self.entries.push(entry);
+        if wide {
+            self.entries.push(Entry::Constant(Constant::Unusable));
+        }
+        self.indices.insert(key, index as u16);
+        Ok(index as u16)
+    }
+
+    pub fn add_utf8(&mut self, s: &str) -> Result<u16, ConstantPoolFull> {
+        self.add(Entry::Utf8(Cow::Owned(Mutf8::encode(s))))
+    }
+
+    /// Adds a `Class` entry for an internal name such as `java/lang/String`
+    /// or an array descriptor.
+    pub fn add_class(&mut self, name: &str) -> Result<u16, ConstantPoolFull> {
+        let name_index = self.add_utf8(name)?;
+        self.add(Entry::Constant(Constant::Class { name_index }))
+    }
+
+    pub fn add_string(&mut self, value: &str) -> Result<u16, ConstantPoolFull> {
+        let string_index = self.add_utf8(value)?;
+        self.add(Entry::Constant(Constant::String { string_index }))
+    }
+
+    pub fn add_integer(&mut self, value: i32) -> Result<u16, ConstantPoolFull> {
+        self.add(Entry::Constant(Constant::Integer(value)))
+    }
+
+    pub fn add_float(&mut self, value: f32) -> Result<u16, ConstantPoolFull> {
+        self.add(Entry::Constant(Constant::Float(value)))
+    }
+
+    pub fn add_long(&mut self, value: i64) -> Result<u16, ConstantPoolFull> {
+        self.add(Entry::Constant(Constant::Long(value)))
+    }
+
+    pub fn add_double(&mut self, value: f64) -> Result<u16, ConstantPoolFull> {
+        self.add(Entry::Constant(Constant::Double(value)))
+    }
+
+    pub fn add_name_and_type(
+        &mut self,
+        name: &str,
+        descriptor: &str,
+    ) -> Result<u16, ConstantPoolFull> {
+        let name_index = self.add_utf8(name)?;
+        let descriptor_index = self.add_utf8(descriptor)?;
+        self.add(Entry::Constant(Constant::NameAndType {
+            name_index,
+            descriptor_index,
+        }))
+    }
+
+    pub fn add_fieldref(
+        &mut self,
+        class: &str,
+        name: &str,
+        descriptor: &str,
+    ) -> Result<u16, ConstantPoolFull> {
+        let class_index = self.add_class(class)?;
+        let name_and_type_index = self.add_name_and_type(name, descriptor)?;
+        self.add(Entry::Constant(Constant::Fieldref {
+            class_index,
+            name_and_type_index,
+        }))
+    }
+
+    pub fn add_methodref(
+        &mut self,
+        class: &str,
+        name: &str,
+        descriptor: &str,
+    ) -> Result<u16, ConstantPoolFull> {
+        let class_index = self.add_class(class)?;
+        let name_and_type_index = self.add_name_and_type(name, descriptor)?;
+        self.add(Entry::Constant(Constant::Methodref {
+            class_index,
+            name_and_type_index,
+        }))
+    }
+
+    pub fn add_interface_methodref(
+        &mut self,
+        class: &str,
+        name: &str,
+        descriptor: &str,
+    ) -> Result<u16, ConstantPoolFull> {
+        let class_index = self.add_class(class)?;
+        let name_and_type_index = self.add_name_and_type(name, descriptor)?;
+        self.add(Entry::Constant(Constant::InterfaceMethodref {
+            class_index,
+            name_and_type_index,
+        }))
+    }
+
+    pub fn add_method_type(&mut self, descriptor: &str) -> Result<u16, ConstantPoolFull> {
+        let descriptor_index = self.add_utf8(descriptor)?;
+        self.add(Entry::Constant(Constant::MethodType { descriptor_index }))
+    }
+
+    /// Adds a `MethodHandle` entry referring to the `Fieldref`, `Methodref`
+    /// or `InterfaceMethodref` at `reference_index`.
+    pub fn add_method_handle(
+        &mut self,
+        reference_kind: ReferenceKind,
+        reference_index: u16,
+    ) -> Result<u16, ConstantPoolFull> {
+        self.add(Entry::Constant(Constant::MethodHandle {
+            reference_kind,
+            reference_index,
+        }))
+    }
+
+    /// Returns the entry at `index`, with `Utf8` entries borrowing from the
+    /// builder.
+    pub fn get(&self, index: u16) -> Option<Constant<'_>> {
+        match self.entries.get(index as usize)? {
+            Entry::Utf8(bytes) => Some(Constant::Utf8(Mutf8(bytes))),
+            Entry::Constant(Constant::Unusable) => None,
+            Entry::Constant(constant) => Some(*constant),
+        }
+    }
+
+    pub fn utf8(&self, index: u16) -> Option<Mutf8<'_>> {
+        match self.entries.get(index as usize)? {
+            Entry::Utf8(bytes) => Some(Mutf8(bytes)),
+            _ => None,
+        }
+    }
+
+    pub fn class_name(&self, index: u16) -> Option<Mutf8<'_>> {
+        match self.get(index)? {
+            Constant::Class { name_index } => self.utf8(name_index),
+            _ => None,
+        }
+    }
+
+    pub fn name_and_type(&self, index: u16) -> Option<(Mutf8<'_>, Mutf8<'_>)> {
+        match self.get(index)? {
+            Constant::NameAndType {
+                name_index,
+                descriptor_index,
+            } => Some((self.utf8(name_index)?, self.utf8(descriptor_index)?)),
+            _ => None,
+        }
+    }
+
+    pub fn member_ref(&self, index: u16) -> Option<MemberRef<'_>> {
+        match self.get(index)? {
+            Constant::Fieldref {
+                class_index,
+                name_and_type_index,
+            }
+            | Constant::Methodref {
+                class_index,
+                name_and_type_index,
+            }
+            | Constant::InterfaceMethodref {
+                class_index,
+                name_and_type_index,
+            } => {
+                let (name, descriptor) = self.name_and_type(name_and_type_index)?;
+                Some(MemberRef {
+                    class: self.class_name(class_index)?,
+                    name,
+                    descriptor,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes `constant_pool_count` and the entries.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.entries.len() as u16).to_be_bytes());
+        for entry in &self.entries {
+            if !matches!(entry, Entry::Constant(Constant::Unusable)) {
+                out.extend_from_slice(&encode_entry(entry));
+            }
+        }
+    }
+}
+
+fn encode_entry(entry: &Entry<'_>) -> Vec<u8> {
+    let mut out = Vec::new();
+    let constant = match entry {
+        Entry::Utf8(bytes) => {
+            out.push(1);
+            out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+            out.extend_from_slice(bytes);
+            return out;
+        }
+        Entry::Constant(constant) => constant,
+    };
+    out.push(constant.tag());
+    let mut u2 = |value: u16| out.extend_from_slice(&value.to_be_bytes());
+    match *constant {
+        Constant::Unusable | Constant::Utf8(_) => unreachable!(),
+        Constant::Integer(value) => out.extend_from_slice(&value.to_be_bytes()),
+        Constant::Float(value) => out.extend_from_slice(&value.to_bits().to_be_bytes()),
+        Constant::Long(value) => out.extend_from_slice(&value.to_be_bytes()),
+        Constant::Double(value) => out.extend_from_slice(&value.to_bits().to_be_bytes()),
+        Constant::Class { name_index: index }
+        | Constant::String {
+            string_index: index,
+        }
+        | Constant::MethodType {
+            descriptor_index: index,
+        }
+        | Constant::Module { name_index: index }
+        | Constant::Package { name_index: index } => u2(index),
+        Constant::Fieldref {
+            class_index: a,
+            name_and_type_index: b,
+        }
+        | Constant::Methodref {
+            class_index: a,
+            name_and_type_index: b,
+        }
+        | Constant::InterfaceMethodref {
+            class_index: a,
+            name_and_type_index: b,
+        }
+        | Constant::NameAndType {
+            name_index: a,
+            descriptor_index: b,
+        }
+        | Constant::Dynamic {
+            bootstrap_method_attr_index: a,
+            name_and_type_index: b,
+        }
+        | Constant::InvokeDynamic {
+            bootstrap_method_attr_index: a,
+            name_and_type_index: b,
+        } => {
+            u2(a);
+            u2(b);
+        }
+        Constant::MethodHandle {
+            reference_kind,
+            reference_index,
+        } => {
+            out.push(reference_kind as u8);
+            out.extend_from_slice(&reference_index.to_be_bytes());
+        }
+    }
+    out
+}
+
+/// An attribute to write, by the index of its name.
+#[derive(Clone, Debug)]
+pub struct RawAttribute<'a> {
+    pub name_index: u16,
+    pub data: Cow<'a, [u8]>,
+}
+
+impl RawAttribute<'_> {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.name_index.to_be_bytes());
+        out.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.data);
+    }
+}
+
+/// A field or method to write.
+#[derive(Clone, Debug)]
+pub struct MemberWriter<'a> {
+    pub access_flags: AccessFlags,
+    pub name_index: u16,
+    pub descriptor_index: u16,
+    pub attributes: Vec<RawAttribute<'a>>,
+}
+
+impl<'a> MemberWriter<'a> {
+    fn new(member: &Member<'a>) -> Self {
+        MemberWriter {
+            access_flags: member.access_flags,
+            name_index: member.name_index,
+            descriptor_index: member.descriptor_index,
+            attributes: raw_attributes(&member.attributes),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.access_flags.bits().to_be_bytes());
+        out.extend_from_slice(&self.name_index.to_be_bytes());
+        out.extend_from_slice(&self.descriptor_index.to_be_bytes());
+        write_attributes(out, &self.attributes);
+    }
+}
+
+fn raw_attributes<'a>(attributes: &[super::Attribute<'a>]) -> Vec<RawAttribute<'a>> {
+    attributes
+        .iter()
+        .map(|attribute| RawAttribute {
+            name_index: attribute.name_index,
+            data: Cow::Borrowed(attribute.data),
+        })
+        .collect()
+}
+
+pub(super) fn write_attributes(out: &mut Vec<u8>, attributes: &[RawAttribute<'_>]) {
+    out.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+    for attribute in attributes {
+        attribute.write(out);
+    }
+}
+
+/// A class file to modify and write.
+#[derive(Clone, Debug)]
+pub struct ClassWriter<'a> {
+    pub minor_version: u16,
+    pub major_version: u16,
+    pub constant_pool: ConstantPoolBuilder<'a>,
+    pub access_flags: AccessFlags,
+    pub this_class: u16,
+    pub super_class: u16,
+    pub interfaces: Vec<u16>,
+    pub fields: Vec<MemberWriter<'a>>,
+    /// The methods, in the order of the original class file.
+    pub methods: Vec<MemberWriter<'a>>,
+    pub attributes: Vec<RawAttribute<'a>>,
+}
+
+impl<'a> ClassWriter<'a> {
+    /// Starts from the contents of `class`, which are written back as they
+    /// are until modified.
+    pub fn new(class: &ClassFile<'a>) -> Self {
+        ClassWriter {
+            minor_version: class.minor_version,
+            major_version: class.major_version,
+            constant_pool: ConstantPoolBuilder::from_pool(&class.constant_pool),
+            access_flags: class.access_flags,
+            this_class: class.this_class,
+            super_class: class.super_class,
+            interfaces: class.interfaces.clone(),
+            fields: class.fields.iter().map(MemberWriter::new).collect(),
+            methods: class.methods.iter().map(MemberWriter::new).collect(),
+            attributes: raw_attributes(&class.attributes),
+        }
+    }
+
+    /// Serializes the class file without checking it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&MAGIC.to_be_bytes());
+        out.extend_from_slice(&self.minor_version.to_be_bytes());
+        out.extend_from_slice(&self.major_version.to_be_bytes());
+        self.constant_pool.write(&mut out);
+        out.extend_from_slice(&self.access_flags.bits().to_be_bytes());
+        out.extend_from_slice(&self.this_class.to_be_bytes());
+        out.extend_from_slice(&self.super_class.to_be_bytes());
+        out.extend_from_slice(&(self.interfaces.len() as u16).to_be_bytes());
+        for interface in &self.interfaces {
+            out.extend_from_slice(&interface.to_be_bytes());
+        }
+        for members in [&self.fields, &self.methods] {
+            out.extend_from_slice(&(members.len() as u16).to_be_bytes());
+            for member in members {
+                member.write(&mut out);
+            }
+        }
+        write_attributes(&mut out, &self.attributes);
+        out
+    }
+
+    /// Serializes the class file and checks that [`ClassFile::parse`]
+    /// accepts the result.
+    pub fn write(&self) -> Result<Vec<u8>, ParseError> {
+        let bytes = self.to_bytes();
+        ClassFile::parse(&bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// Copies `bytes` into memory from `Allocate` and stores it in the
+/// `new_class_data_len` and `new_class_data` arguments of a
+/// `ClassFileLoadHook` event. [`jvmtiEventClassFileLoadHook`] declares
+/// `new_class_data` as `*mut *mut c_uint`, but it points to bytes.
+///
+/// [`jvmtiEventClassFileLoadHook`]: crate::jvmtiEventClassFileLoadHook
+///
+/// # Safety
+///
+/// `env` must be a valid JVMTI environment and the pointers those passed to
+/// the event.
+pub unsafe fn set_new_class_data(
+    env: *mut jvmtiEnv,
+    bytes: &[u8],
+    new_class_data_len: *mut jint,
+    new_class_data: *mut *mut c_uint,
+) -> Result<(), jvmtiError> {
+    let mut data = null_mut();
+    check(jvmti!(env, v1, Allocate, bytes.len() as i64, &mut data))?;
+    core::ptr::copy_nonoverlapping(bytes.as_ptr(), data, bytes.len());
+    *new_class_data_len = bytes.len() as jint;
+    *new_class_data = data as *mut c_uint;
+    Ok(())
+}
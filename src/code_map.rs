@@ -0,0 +1,344 @@
+//! Resolution of code addresses to Java methods.
+//!
+//! [`CodeMap`] keeps an index of the code regions reported by the
+//! `CompiledMethodLoad`, `CompiledMethodUnload` and `DynamicCodeGenerated`
+//! events, so that program counters collected elsewhere, e.g. by a signal
+//! handler or from perf data, can be mapped back to the compiled method,
+//! the methods inlined at that address and their bytecode indices, or to
+//! the name of a VM stub.
+//!
+//! The map can be read from any thread while the events update it. Reading
+//! takes a lock and allocates, so it is not async-signal-safe: record raw
+//! addresses in signal handlers and resolve them afterwards.
+//!
+//! Requires the `can_generate_compiled_method_load_events` capability.
+
+use core::ffi::{c_char, c_void};
+use core::ptr::null_mut;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::sync::{Arc, RwLock};
+
+use jni_sys::{jint, jmethodID};
+
+use crate::jvmticmlr::{inline_records, record_at, InlinedFrames};
+use crate::stack::Frame;
+use crate::util::check;
+use crate::{jlocation, jvmtiAddrLocationMap, jvmtiEnv, jvmtiError, jvmtiEvent, jvmtiEventMode};
+
+/// What a [`CodeRegion`] contains.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CodeKind {
+    /// The code of a compiled method.
+    Compiled { method: jmethodID },
+    /// Code generated by the VM, such as the interpreter or a stub.
+    Stub { name: String },
+}
+
+/// A region of executable code.
+#[derive(Clone, Debug)]
+pub struct CodeRegion {
+    pub start: usize,
+    pub size: usize,
+    pub kind: CodeKind,
+    /// The `jvmtiAddrLocationMap` of the method, as `(address, location)`
+    /// sorted by address.
+    locations: Vec<(usize, jlocation)>,
+    /// The inline records of the method, sorted by address.
+    inlined: Vec<InlinedFrames>,
+}
+
+// Regions only hold method IDs, which are not bound to a thread.
+unsafe impl Send for CodeRegion {}
+unsafe impl Sync for CodeRegion {}
+
+impl CodeRegion {
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        (self.start..self.end()).contains(&address)
+    }
+
+    /// Returns the frames executing at `address`, innermost first, with the
+    /// bytecode index as location, or `-1` where it is unknown. Empty for
+    /// stubs.
+    ///
+    /// Inline records describe the code ending at their address, so the
+    /// first record at or after `address` applies, as in HotSpot's own
+    /// lookup. Without inline records, the location comes from the last
+    /// entry of the address location map at or before `address`.
+    pub fn frames_at(&self, address: usize) -> Vec<Frame> {
+        let CodeKind::Compiled { method } = self.kind else {
+            return Vec::new();
+        };
+        if let Some(record) = record_at(&self.inlined, address) {
+            if !record.frames.is_empty() {
+                return record.frames.clone();
+            }
+        }
+        let index = self
+            .locations
+            .partition_point(|&(start, _)| start <= address);
+        let location = match index {
+            0 => -1,
+            index => self.locations[index - 1].1,
+        };
+        vec![Frame { method, location }]
+    }
+}
+
+/// The result of [`CodeMap::resolve`].
+#[derive(Clone, Debug)]
+pub struct ResolvedAddress {
+    pub region: Arc<CodeRegion>,
+    /// The frames executing at the address, innermost first. Empty for
+    /// stubs.
+    pub frames: Vec<Frame>,
+}
+
+/// An index of the code regions of the VM by address.
+#[derive(Debug, Default)]
+pub struct CodeMap {
+    regions: RwLock<BTreeMap<usize, Arc<CodeRegion>>>,
+}
+
+impl CodeMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enables the `CompiledMethodLoad`, `CompiledMethodUnload` and
+    /// `DynamicCodeGenerated` events and has the VM send them for the code
+    /// that already exists.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment whose callbacks forward the
+    /// events to this map.
+    pub unsafe fn start(&self, env: *mut jvmtiEnv) -> Result<(), jvmtiError> {
+        for event in [
+            jvmtiEvent::JVMTI_EVENT_COMPILED_METHOD_LOAD,
+            jvmtiEvent::JVMTI_EVENT_COMPILED_METHOD_UNLOAD,
+            jvmtiEvent::JVMTI_EVENT_DYNAMIC_CODE_GENERATED,
+        ] {
+            check(jvmti!(
+                env,
+                v1,
+                SetEventNotificationMode,
+                jvmtiEventMode::JVMTI_ENABLE,
+                event,
+                null_mut::<jni_sys::_jobject>()
+            ))?;
+        }
+        check(jvmti!(
+            env,
+            v1,
+            GenerateEvents,
+            jvmtiEvent::JVMTI_EVENT_DYNAMIC_CODE_GENERATED
+        ))?;
+        check(jvmti!(
+            env,
+            v1,
+            GenerateEvents,
+            jvmtiEvent::JVMTI_EVENT_COMPILED_METHOD_LOAD
+        ))
+    }
+
+    /// Adds a compiled method. Forward `CompiledMethodLoad` events here.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with the arguments of a `CompiledMethodLoad` event,
+    /// from the event callback.
+    pub unsafe fn on_compiled_method_load(
+        &self,
+        method: jmethodID,
+        code_size: jint,
+        code_addr: *const c_void,
+        map_length: jint,
+        map: *const jvmtiAddrLocationMap,
+        compile_info: *const c_void,
+    ) {
+        let mut locations = Vec::new();
+        if !map.is_null() && map_length > 0 {
+            let map = core::slice::from_raw_parts(map, map_length as usize);
+            locations.extend(
+                map.iter()
+                    .map(|entry| (entry.start_address as usize, entry.location)),
+            );
+            locations.sort_by_key(|&(address, _)| address);
+        }
+        let inlined = inline_records(compile_info);
+        self.insert(CodeRegion {
+            start: code_addr as usize,
+            size: code_size.max(0) as usize,
+            kind: CodeKind::Compiled { method },
+            locations,
+            inlined,
+        });
+    }
+
+    /// Removes a compiled method. Forward `CompiledMethodUnload` events
+    /// here.
+    pub fn on_compiled_method_unload(&self, method: jmethodID, code_addr: *const c_void) {
+        let mut regions = self.regions.write().unwrap();
+        let address = code_addr as usize;
+        if let Some(region) = regions.get(&address) {
+            if region.kind == (CodeKind::Compiled { method }) {
+                regions.remove(&address);
+            }
+        }
+    }
+
+    /// Adds generated code such as the interpreter or stubs. Forward
+    /// `DynamicCodeGenerated` events here.
+    ///
+    /// # Safety
+    ///
+    /// `name` must be a valid C string.
+    pub unsafe fn on_dynamic_code_generated(
+        &self,
+        name: *const c_char,
+        address: *const c_void,
+        length: jint,
+    ) {
+        self.insert(CodeRegion {
+            start: address as usize,
+            size: length.max(0) as usize,
+            kind: CodeKind::Stub {
+                name: CStr::from_ptr(name).to_string_lossy().into_owned(),
+            },
+            locations: Vec::new(),
+            inlined: Vec::new(),
+        });
+    }
+
+    fn insert(&self, region: CodeRegion) {
+        let mut regions = self.regions.write().unwrap();
+        regions.insert(region.start, Arc::new(region));
+    }
+
+    /// Returns the region containing `address`.
+    pub fn region(&self, address: usize) -> Option<Arc<CodeRegion>> {
+        let regions = self.regions.read().unwrap();
+        let (_, region) = regions.range(..=address).next_back()?;
+        Some(region.clone()).filter(|region| region.contains(address))
+    }
+
+    /// Returns the region containing `address` and the frames executing
+    /// there.
+    pub fn resolve(&self, address: usize) -> Option<ResolvedAddress> {
+        let region = self.region(address)?;
+        let frames = region.frames_at(address);
+        Some(ResolvedAddress { region, frames })
+    }
+
+    /// Returns all regions, sorted by address.
+    pub fn regions(&self) -> Vec<Arc<CodeRegion>> {
+        self.regions.read().unwrap().values().cloned().collect()
+    }
+
+    /// Returns the number of regions.
+    pub fn len(&self) -> usize {
+        self.regions.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.read().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn method(id: usize) -> jmethodID {
+        id as jmethodID
+    }
+
+    fn frames(location: jlocation) -> Vec<Frame> {
+        vec![Frame {
+            method: method(1),
+            location,
+        }]
+    }
+
+    fn region(locations: Vec<(usize, jlocation)>, inlined: Vec<InlinedFrames>) -> CodeRegion {
+        CodeRegion {
+            start: 0x100,
+            size: 0x100,
+            kind: CodeKind::Compiled { method: method(1) },
+            locations,
+            inlined,
+        }
+    }
+
+    #[test]
+    fn first_inline_record_at_or_after_the_address_applies() {
+        let inner = |location| {
+            vec![
+                Frame {
+                    method: method(2),
+                    location,
+                },
+                Frame {
+                    method: method(1),
+                    location: 7,
+                },
+            ]
+        };
+        let region = region(
+            vec![(0x100, 0), (0x180, 9)],
+            vec![
+                InlinedFrames {
+                    address: 0x120,
+                    frames: inner(3),
+                },
+                InlinedFrames {
+                    address: 0x140,
+                    frames: Vec::new(),
+                },
+                InlinedFrames {
+                    address: 0x160,
+                    frames: inner(5),
+                },
+            ],
+        );
+        assert_eq!(region.frames_at(0x100), inner(3));
+        assert_eq!(region.frames_at(0x120), inner(3));
+        assert_eq!(region.frames_at(0x121), frames(0));
+        assert_eq!(region.frames_at(0x141), inner(5));
+        assert_eq!(region.frames_at(0x160), inner(5));
+        // Past the last record, and for records without frames, the address
+        // location map applies.
+        assert_eq!(region.frames_at(0x161), frames(0));
+        assert_eq!(region.frames_at(0x180), frames(9));
+        assert_eq!(region.frames_at(0x1ff), frames(9));
+    }
+
+    #[test]
+    fn addresses_before_the_first_location_are_unknown() {
+        let mapped = region(vec![(0x110, 2), (0x130, 4)], Vec::new());
+        assert_eq!(mapped.frames_at(0x100), frames(-1));
+        assert_eq!(mapped.frames_at(0x10f), frames(-1));
+        assert_eq!(mapped.frames_at(0x110), frames(2));
+        assert_eq!(mapped.frames_at(0x12f), frames(2));
+        assert_eq!(mapped.frames_at(0x130), frames(4));
+
+        let empty = region(Vec::new(), Vec::new());
+        assert_eq!(empty.frames_at(0x100), frames(-1));
+    }
+
+    #[test]
+    fn stubs_have_no_frames() {
+        let region = CodeRegion {
+            kind: CodeKind::Stub {
+                name: "Interpreter".to_owned(),
+            },
+            ..region(vec![(0x100, 0)], Vec::new())
+        };
+        assert!(region.frames_at(0x100).is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+//! Collapsed stack output for flame graphs.
+//!
+//! [`CollapsedStacks`] sums weights by stack trace and writes them in the
+//! folded format read by `flamegraph.pl` and inferno: one line per stack,
+//! frames from the outermost to the innermost separated by `;`, followed by
+//! a space and the weight.
+//!
+//! Frames are written as `java/lang/Thread.run`, the form `flamegraph.pl
+//! --color=java` recognizes. With [`CollapsedOptions::annotate`], frames
+//! get the suffixes used by async-profiler: `_[j]` for compiled frames,
+//! `_[i]` for inlined frames and `_[n]` for native methods.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use jni_sys::jint;
+
+use crate::jvmtiEnv;
+use crate::method_cache::{MethodCache, MethodInfo};
+use crate::stack::Frame;
+
+const ACC_NATIVE: jint = 0x0100;
+
+/// How a frame was executing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    Interpreted,
+    Compiled,
+    /// Compiled into the code of the next frame towards the bottom of the
+    /// stack.
+    Inlined,
+    Native,
+}
+
+impl FrameKind {
+    /// Returns the suffix appended to annotated frames.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            FrameKind::Interpreted => "",
+            FrameKind::Compiled => "_[j]",
+            FrameKind::Inlined => "_[i]",
+            FrameKind::Native => "_[n]",
+        }
+    }
+}
+
+/// Options for [`CollapsedStacks::write`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CollapsedOptions {
+    /// Append the line number of each frame, as `Class.method:12`.
+    pub line_numbers: bool,
+    /// Append the [`FrameKind`] suffix of each frame.
+    pub annotate: bool,
+}
+
+/// Weights summed by stack trace.
+#[derive(Clone, Debug, Default)]
+pub struct CollapsedStacks {
+    stacks: HashMap<Vec<Frame>, u64>,
+}
+
+impl CollapsedStacks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `weight` to `stack`, innermost frame first.
+    pub fn add(&mut self, stack: &[Frame], weight: u64) {
+        match self.stacks.get_mut(stack) {
+            Some(total) => *total += weight,
+            None => {
+                self.stacks.insert(stack.to_vec(), weight);
+            }
+        }
+    }
+
+    /// Returns the stacks and their weights, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[Frame], u64)> + '_ {
+        self.stacks
+            .iter()
+            .map(|(stack, &weight)| (stack.as_slice(), weight))
+    }
+
+    /// Returns the number of distinct stacks.
+    pub fn len(&self) -> usize {
+        self.stacks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stacks.is_empty()
+    }
+
+    /// Returns the sum of all weights.
+    pub fn total(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /// Writes the stacks in folded format, resolving methods through
+    /// `cache`. Native methods are annotated as such; all other frames count
+    /// as interpreted.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn write(
+        &self,
+        env: *mut jvmtiEnv,
+        cache: &MethodCache,
+        out: impl Write,
+        options: CollapsedOptions,
+    ) -> io::Result<()> {
+        self.write_with(env, cache, out, options, |_, _| FrameKind::Interpreted)
+    }
+
+    /// Like [`write`](Self::write), with `classify` telling the kind of the
+    /// frame at some index of a stack, innermost frame first. Native methods
+    /// are recognized without it.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn write_with(
+        &self,
+        env: *mut jvmtiEnv,
+        cache: &MethodCache,
+        mut out: impl Write,
+        options: CollapsedOptions,
+        mut classify: impl FnMut(&[Frame], usize) -> FrameKind,
+    ) -> io::Result<()> {
+        let mut lines = Vec::with_capacity(self.stacks.len());
+        for (stack, &weight) in &self.stacks {
+            let mut line = String::new();
+            for (index, frame) in stack.iter().enumerate().rev() {
+                if index + 1 < stack.len() {
+                    line.push(';');
+                }
+                let kind = match cache.get(env, frame.method) {
+                    Ok(info) => {
+                        line.push_str(&frame_name(&info));
+                        if options.line_numbers {
+                            if let Some(number) = info.line_table.line_for(frame.location) {
+                                let _ = write!(line, ":{number}");
+                            }
+                        }
+                        if info.modifiers & ACC_NATIVE != 0 {
+                            FrameKind::Native
+                        } else {
+                            classify(stack, index)
+                        }
+                    }
+                    Err(_) => {
+                        line.push_str("<unknown method>");
+                        classify(stack, index)
+                    }
+                };
+                if options.annotate {
+                    line.push_str(kind.suffix());
+                }
+            }
+            lines.push((line, weight));
+        }
+        // Identical lines can come from distinct locations when line numbers
+        // are left out.
+        lines.sort_unstable();
+        let mut lines = lines.into_iter().peekable();
+        while let Some((line, mut weight)) = lines.next() {
+            while let Some((_, next)) = lines.next_if(|(next, _)| *next == line) {
+                weight += next;
+            }
+            writeln!(out, "{line} {weight}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Extend<(Vec<Frame>, u64)> for CollapsedStacks {
+    fn extend<I: IntoIterator<Item = (Vec<Frame>, u64)>>(&mut self, iter: I) {
+        for (stack, weight) in iter {
+            *self.stacks.entry(stack).or_default() += weight;
+        }
+    }
+}
+
+impl FromIterator<(Vec<Frame>, u64)> for CollapsedStacks {
+    fn from_iter<I: IntoIterator<Item = (Vec<Frame>, u64)>>(iter: I) -> Self {
+        let mut stacks = CollapsedStacks::new();
+        stacks.extend(iter);
+        stacks
+    }
+}
+
+/// Renders a method as `java/lang/Thread.run`.
+fn frame_name(info: &MethodInfo) -> String {
+    let class = info.class_signature.as_str();
+    let class = class
+        .strip_prefix('L')
+        .and_then(|class| class.strip_suffix(';'))
+        .unwrap_or(class);
+    format!("{class}.{}", info.name)
+}
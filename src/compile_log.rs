@@ -0,0 +1,506 @@
+//! A log of JIT compilations.
+//!
+//! [`CompilationLog`] records the `CompiledMethodLoad` and
+//! `CompiledMethodUnload` events, much like a structured
+//! `-XX:+PrintCompilation`: when each method was compiled or unloaded, where
+//! its code lives, how large it is and which methods were inlined into it.
+//! The log can be summarized and exported as JSON Lines, one event per line.
+//!
+//! Methods are resolved when their code is loaded, as their class may be
+//! unloaded before the log is exported.
+//!
+//! Requires the `can_generate_compiled_method_load_events` capability.
+
+use core::ffi::c_void;
+use core::fmt;
+use core::ptr::null_mut;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use jni_sys::{jint, jlong, jmethodID};
+
+use crate::jvmticmlr::{inline_records, InlinedFrames};
+use crate::method_cache::MethodCache;
+use crate::util::check;
+use crate::{jvmtiAddrLocationMap, jvmtiEnv, jvmtiError, jvmtiEvent, jvmtiEventMode};
+
+/// A method inlined into a compiled method.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InlinedMethod {
+    /// The qualified method name, e.g. `java.lang.String.hashCode`.
+    pub name: String,
+    /// The method descriptor, e.g. `()I`.
+    pub signature: String,
+    /// The bytecode index of the call in the caller.
+    pub caller_bci: jint,
+    /// The methods inlined into this one.
+    pub inlined: Vec<InlinedMethod>,
+}
+
+impl InlinedMethod {
+    /// Returns the number of methods in this subtree, including this one.
+    pub fn count(&self) -> usize {
+        1 + self.inlined.iter().map(InlinedMethod::count).sum::<usize>()
+    }
+
+    /// Returns the depth of this subtree, 1 for a method without inlinees.
+    pub fn depth(&self) -> usize {
+        1 + self
+            .inlined
+            .iter()
+            .map(InlinedMethod::depth)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Whether a [`CompilationEvent`] is a load or an unload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompilationEventKind {
+    Load {
+        code_size: usize,
+        /// The methods inlined into the compiled method.
+        inlined: Vec<InlinedMethod>,
+    },
+    Unload,
+}
+
+/// A recorded compilation event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompilationEvent {
+    /// The time of the event as returned by `GetTime`, in nanoseconds.
+    pub time: jlong,
+    /// The qualified method name, e.g. `java.lang.String.hashCode`.
+    pub name: String,
+    /// The method descriptor, e.g. `()I`.
+    pub signature: String,
+    pub code_addr: usize,
+    pub kind: CompilationEventKind,
+}
+
+impl CompilationEvent {
+    /// Writes the event as a single line of JSON.
+    pub fn write_json(&self, mut out: impl Write) -> io::Result<()> {
+        write!(out, "{{\"time_ns\":{},\"event\":", self.time)?;
+        match &self.kind {
+            CompilationEventKind::Load { .. } => write!(out, "\"load\"")?,
+            CompilationEventKind::Unload => write!(out, "\"unload\"")?,
+        }
+        write!(
+            out,
+            ",\"method\":{},\"signature\":{},\"code_addr\":\"{:#x}\"",
+            JsonStr(&self.name),
+            JsonStr(&self.signature),
+            self.code_addr
+        )?;
+        if let CompilationEventKind::Load { code_size, inlined } = &self.kind {
+            write!(out, ",\"code_size\":{code_size},\"inlined\":")?;
+            write_inlined(&mut out, inlined)?;
+        }
+        writeln!(out, "}}")
+    }
+}
+
+fn write_inlined(out: &mut impl Write, inlined: &[InlinedMethod]) -> io::Result<()> {
+    write!(out, "[")?;
+    for (index, method) in inlined.iter().enumerate() {
+        if index > 0 {
+            write!(out, ",")?;
+        }
+        write!(
+            out,
+            "{{\"method\":{},\"signature\":{},\"bci\":{}",
+            JsonStr(&method.name),
+            JsonStr(&method.signature),
+            method.caller_bci
+        )?;
+        if !method.inlined.is_empty() {
+            write!(out, ",\"inlined\":")?;
+            write_inlined(out, &method.inlined)?;
+        }
+        write!(out, "}}")?;
+    }
+    write!(out, "]")
+}
+
+/// Formats a string as a JSON string literal.
+struct JsonStr<'a>(&'a str);
+
+impl fmt::Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{c}")?,
+            }
+        }
+        f.write_str("\"")
+    }
+}
+
+/// Statistics over a [`CompilationLog`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompilationSummary {
+    pub loads: usize,
+    pub unloads: usize,
+    /// The number of distinct methods compiled.
+    pub methods: usize,
+    /// The size of all code ever loaded.
+    pub total_code_bytes: usize,
+    /// The size of the code still loaded.
+    pub live_code_bytes: usize,
+    /// The number of inlined method bodies over all compilations.
+    pub inlined_methods: usize,
+    pub max_inline_depth: usize,
+    /// The methods compiled the most often, with their compilation count.
+    pub most_compiled: Vec<(String, usize)>,
+}
+
+impl fmt::Display for CompilationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} compilations of {} methods, {} unloads",
+            self.loads, self.methods, self.unloads
+        )?;
+        writeln!(
+            f,
+            "{} bytes of code loaded, {} bytes live",
+            self.total_code_bytes, self.live_code_bytes
+        )?;
+        writeln!(
+            f,
+            "{} inlined methods, maximum inlining depth {}",
+            self.inlined_methods, self.max_inline_depth
+        )?;
+        for (name, count) in &self.most_compiled {
+            writeln!(f, "{count:>6}  {name}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    events: Vec<CompilationEvent>,
+    /// Index of the load event of the code still loaded, by address.
+    live: HashMap<usize, usize>,
+}
+
+/// Records compilation events.
+#[derive(Debug, Default)]
+pub struct CompilationLog {
+    state: Mutex<State>,
+    cache: MethodCache,
+}
+
+impl CompilationLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enables the `CompiledMethodLoad` and `CompiledMethodUnload` events.
+    /// With `existing`, also has the VM send `CompiledMethodLoad` events for
+    /// the methods compiled so far.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment whose callbacks forward the
+    /// events to this log.
+    pub unsafe fn start(&self, env: *mut jvmtiEnv, existing: bool) -> Result<(), jvmtiError> {
+        for event in [
+            jvmtiEvent::JVMTI_EVENT_COMPILED_METHOD_LOAD,
+            jvmtiEvent::JVMTI_EVENT_COMPILED_METHOD_UNLOAD,
+        ] {
+            check(jvmti!(
+                env,
+                v1,
+                SetEventNotificationMode,
+                jvmtiEventMode::JVMTI_ENABLE,
+                event,
+                null_mut::<jni_sys::_jobject>()
+            ))?;
+        }
+        if existing {
+            check(jvmti!(
+                env,
+                v1,
+                GenerateEvents,
+                jvmtiEvent::JVMTI_EVENT_COMPILED_METHOD_LOAD
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Records a compilation. Forward `CompiledMethodLoad` events here.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with the arguments of a `CompiledMethodLoad` event,
+    /// from the event callback.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn on_compiled_method_load(
+        &self,
+        env: *mut jvmtiEnv,
+        method: jmethodID,
+        code_size: jint,
+        code_addr: *const c_void,
+        _map_length: jint,
+        _map: *const jvmtiAddrLocationMap,
+        compile_info: *const c_void,
+    ) {
+        let time = now(env);
+        let (name, signature) = self.method_name(env, method);
+        let inlined = inline_tree(&inline_records(compile_info), |method| {
+            self.method_name(env, method)
+        });
+
+        let mut state = self.state.lock().unwrap();
+        let index = state.events.len();
+        state.live.insert(code_addr as usize, index);
+        state.events.push(CompilationEvent {
+            time,
+            name,
+            signature,
+            code_addr: code_addr as usize,
+            kind: CompilationEventKind::Load {
+                code_size: code_size.max(0) as usize,
+                inlined,
+            },
+        });
+    }
+
+    /// Records an unload. Forward `CompiledMethodUnload` events here.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with the arguments of a `CompiledMethodUnload` event,
+    /// from the event callback.
+    pub unsafe fn on_compiled_method_unload(
+        &self,
+        env: *mut jvmtiEnv,
+        method: jmethodID,
+        code_addr: *const c_void,
+    ) {
+        let time = now(env);
+        // Resolved before locking, as it may call into the VM; the name from
+        // the load event is preferred as the class may be gone by now.
+        let resolved = self.method_name(env, method);
+        let mut state = self.state.lock().unwrap();
+        let (name, signature) = match state.live.remove(&(code_addr as usize)) {
+            Some(index) => {
+                let load = &state.events[index];
+                (load.name.clone(), load.signature.clone())
+            }
+            None => resolved,
+        };
+        state.events.push(CompilationEvent {
+            time,
+            name,
+            signature,
+            code_addr: code_addr as usize,
+            kind: CompilationEventKind::Unload,
+        });
+    }
+
+    unsafe fn method_name(&self, env: *mut jvmtiEnv, method: jmethodID) -> (String, String) {
+        match self.cache.get(env, method) {
+            Ok(info) => (info.qualified_name(), info.signature.clone()),
+            Err(_) => ("<unknown method>".to_owned(), String::new()),
+        }
+    }
+
+    /// Returns a copy of the events recorded so far, in order.
+    pub fn events(&self) -> Vec<CompilationEvent> {
+        self.state.lock().unwrap().events.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().events.is_empty()
+    }
+
+    /// Discards the recorded events.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.events.clear();
+        state.live.clear();
+    }
+
+    /// Summarizes the log, listing the `top` most compiled methods.
+    pub fn summary(&self, top: usize) -> CompilationSummary {
+        let state = self.state.lock().unwrap();
+        let mut summary = CompilationSummary::default();
+        let mut counts: HashMap<(&str, &str), usize> = HashMap::new();
+        for event in &state.events {
+            match &event.kind {
+                CompilationEventKind::Load { code_size, inlined } => {
+                    summary.loads += 1;
+                    summary.total_code_bytes += code_size;
+                    summary.inlined_methods +=
+                        inlined.iter().map(InlinedMethod::count).sum::<usize>();
+                    let depth = inlined.iter().map(InlinedMethod::depth).max().unwrap_or(0);
+                    summary.max_inline_depth = summary.max_inline_depth.max(depth);
+                    *counts.entry((&event.name, &event.signature)).or_default() += 1;
+                }
+                CompilationEventKind::Unload => summary.unloads += 1,
+            }
+        }
+        for &index in state.live.values() {
+            if let CompilationEventKind::Load { code_size, .. } = state.events[index].kind {
+                summary.live_code_bytes += code_size;
+            }
+        }
+        summary.methods = counts.len();
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        summary.most_compiled = counts
+            .into_iter()
+            .take(top)
+            .map(|((name, signature), count)| (format!("{name}{signature}"), count))
+            .collect();
+        summary
+    }
+
+    /// Writes the events as JSON Lines.
+    pub fn write_json_lines(&self, mut out: impl Write) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        for event in &state.events {
+            event.write_json(&mut out)?;
+        }
+        out.flush()
+    }
+}
+
+/// Merges inline records into a tree of the methods inlined into the
+/// compiled method, naming methods with `method_name`.
+fn inline_tree(
+    records: &[InlinedFrames],
+    mut method_name: impl FnMut(jmethodID) -> (String, String),
+) -> Vec<InlinedMethod> {
+    // Each record lists the frames at some address, innermost first; the
+    // outermost is the compiled method itself.
+    let mut inlined: Vec<InlinedMethod> = Vec::new();
+    for record in records {
+        let mut level = &mut inlined;
+        let mut caller_bci = None;
+        for frame in record.frames.iter().rev() {
+            if let Some(bci) = caller_bci {
+                let bci = bci as jint;
+                let (name, signature) = method_name(frame.method);
+                let index = match level
+                    .iter()
+                    .position(|m| m.caller_bci == bci && m.name == name && m.signature == signature)
+                {
+                    Some(index) => index,
+                    None => {
+                        level.push(InlinedMethod {
+                            name,
+                            signature,
+                            caller_bci: bci,
+                            inlined: Vec::new(),
+                        });
+                        level.len() - 1
+                    }
+                };
+                level = &mut level[index].inlined;
+            }
+            caller_bci = Some(frame.location);
+        }
+    }
+    inlined
+}
+
+unsafe fn now(env: *mut jvmtiEnv) -> jlong {
+    let mut time = 0;
+    jvmti!(env, v1, GetTime, &mut time);
+    time
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack::Frame;
+
+    fn frames(frames: &[(usize, i64)]) -> InlinedFrames {
+        InlinedFrames {
+            address: 0,
+            frames: frames
+                .iter()
+                .map(|&(method, location)| Frame {
+                    method: method as jmethodID,
+                    location,
+                })
+                .collect(),
+        }
+    }
+
+    fn inlined(name: &str, caller_bci: jint, inlined: Vec<InlinedMethod>) -> InlinedMethod {
+        InlinedMethod {
+            name: name.to_owned(),
+            signature: "()V".to_owned(),
+            caller_bci,
+            inlined,
+        }
+    }
+
+    #[test]
+    fn inline_records_merge_into_a_tree() {
+        // Method 1 calls 2 at bci 4, which calls 3 at bci 8, and calls 2 again
+        // at bci 12.
+        let records = [
+            frames(&[(1, 0)]),
+            frames(&[(2, 1), (1, 4)]),
+            frames(&[(3, 0), (2, 8), (1, 4)]),
+            frames(&[(2, 3), (1, 4)]),
+            frames(&[(2, 0), (1, 12)]),
+        ];
+        let tree = inline_tree(&records, |method| {
+            (format!("m{}", method as usize), "()V".to_owned())
+        });
+        assert_eq!(
+            tree,
+            [
+                inlined("m2", 4, vec![inlined("m3", 8, Vec::new())]),
+                inlined("m2", 12, Vec::new()),
+            ]
+        );
+        assert_eq!(tree[0].count(), 2);
+        assert_eq!(tree[0].depth(), 2);
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        let event = CompilationEvent {
+            time: 5,
+            name: "a\"b\\c\n\t\u{1}\u{1f}.\u{e9}\u{4e2d}".to_owned(),
+            signature: "()V".to_owned(),
+            code_addr: 0x1000,
+            kind: CompilationEventKind::Load {
+                code_size: 16,
+                inlined: vec![inlined("caf\u{e9}\r", 2, Vec::new())],
+            },
+        };
+        let mut out = Vec::new();
+        event.write_json(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"time_ns":5,"event":"load","method":"a\"b\\c\n\t\u0001\u001f.é中","#,
+                r#""signature":"()V","code_addr":"0x1000","code_size":16,"#,
+                r#""inlined":[{"method":"café\r","signature":"()V","bci":2}]}"#,
+                "\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,698 @@
+//! A `javap -c`-style disassembler for the bytecodes of loaded methods.
+//!
+//! [`MethodDisassembly::new`] fetches a method's bytecodes with
+//! `GetBytecodes`, the constant pool they refer to with `GetConstantPool`
+//! and its line number table, and decodes every instruction into a
+//! [`DecodedInstruction`]. Rendering it yields javap-style text, with
+//! `// line N` markers where source lines start and an optional `>>`
+//! marker on the instruction a breakpoint or sample refers to:
+//!
+//! ```text
+//! java/lang/String.length()I
+//!     // line 1519
+//! >>     0: aload_0
+//!        1: getfield      #37                 // Field value:[B
+//!        4: arraylength
+//! ```
+//!
+//! [`DecodedInstruction::new`] decodes instructions of class files parsed
+//! with [`crate::classfile`] just as well.
+
+use core::fmt;
+use core::ptr::null_mut;
+use std::fmt::Write;
+
+use jni_sys::jmethodID;
+
+use crate::classfile::opcodes::{self, *};
+use crate::classfile::{
+    Constant, ConstantPool, LoadedConstantPool, Mutf8, ParseError, ReferenceKind,
+};
+use crate::line_table::{LineTable, LineTableError};
+use crate::util::{check, take_array, take_string};
+use crate::{jlocation, jvmtiEnv, jvmtiError};
+
+/// Errors returned by [`MethodDisassembly::new`].
+#[derive(Clone, Debug)]
+pub enum DisassemblyError {
+    /// A JVMTI function failed.
+    Jvmti(jvmtiError),
+    /// The constant pool returned by `GetConstantPool` is malformed.
+    ConstantPool(ParseError),
+}
+
+impl From<jvmtiError> for DisassemblyError {
+    fn from(err: jvmtiError) -> Self {
+        DisassemblyError::Jvmti(err)
+    }
+}
+
+impl From<ParseError> for DisassemblyError {
+    fn from(err: ParseError) -> Self {
+        DisassemblyError::ConstantPool(err)
+    }
+}
+
+impl fmt::Display for DisassemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisassemblyError::Jvmti(err) => write!(f, "JVMTI call failed: {err:?}"),
+            DisassemblyError::ConstantPool(err) => write!(f, "malformed constant pool: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DisassemblyError {}
+
+/// The targets of a `tableswitch` or `lookupswitch`, as absolute code
+/// offsets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SwitchTable {
+    pub default: usize,
+    /// The keys and their targets, in the order of the instruction.
+    pub cases: Vec<(i32, usize)>,
+}
+
+/// A decoded instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    pub pc: usize,
+    /// The opcode. For `wide` instructions, this is the widened opcode.
+    pub opcode: u8,
+    /// The mnemonic as `javap` prints it, e.g. `invokevirtual`, or
+    /// `iload_w` for a `wide iload`.
+    pub mnemonic: &'static str,
+    /// The operands as `javap` prints them, e.g. `#7` or `300, 5`. Branch
+    /// targets are absolute. Empty for switches, see [`switch`](Self::switch).
+    pub operands: String,
+    /// The resolved constant pool entry, e.g. `Method java/lang/Object."<init>":()V`.
+    /// `None` for instructions without a constant pool operand or without a
+    /// pool to resolve it against.
+    pub comment: Option<String>,
+    pub switch: Option<SwitchTable>,
+}
+
+impl DecodedInstruction {
+    /// Decodes `insn`. Constant pool operands are resolved against `pool`;
+    /// members of `this_class` are printed without their class name, like
+    /// `javap` does.
+    pub fn new(
+        insn: &opcodes::Instruction<'_>,
+        pool: Option<&ConstantPool<'_>>,
+        this_class: Option<&str>,
+    ) -> Self {
+        let mut decoded = DecodedInstruction {
+            pc: insn.pc,
+            opcode: insn.opcode(),
+            mnemonic: opcodes::name(insn.opcode()).unwrap_or("?"),
+            operands: String::new(),
+            comment: None,
+            switch: None,
+        };
+        let target = |offset: i32| insn.pc as i64 + offset as i64;
+        let mut constant = None;
+        match insn.opcode() {
+            BIPUSH => decoded.operands = (insn.u1(1) as i8).to_string(),
+            SIPUSH => decoded.operands = (insn.u2(1) as i16).to_string(),
+            LDC => constant = Some(insn.u1(1) as u16),
+            LDC_W
+            | LDC2_W
+            | GETSTATIC..=INVOKESTATIC
+            | NEW
+            | ANEWARRAY
+            | CHECKCAST
+            | INSTANCEOF => constant = Some(insn.u2(1)),
+            ILOAD..=ALOAD | ISTORE..=ASTORE | RET => decoded.operands = insn.u1(1).to_string(),
+            IINC => decoded.operands = format!("{}, {}", insn.u1(1), insn.u1(2) as i8),
+            IFEQ..=JSR | IFNULL | IFNONNULL => {
+                decoded.operands = target(insn.u2(1) as i16 as i32).to_string()
+            }
+            GOTO_W | JSR_W => decoded.operands = target(insn.s4(1)).to_string(),
+            INVOKEINTERFACE | MULTIANEWARRAY => {
+                constant = Some(insn.u2(1));
+                decoded.operands = format!(",  {}", insn.u1(3));
+            }
+            INVOKEDYNAMIC => {
+                constant = Some(insn.u2(1));
+                decoded.operands = ",  0".to_owned();
+            }
+            NEWARRAY => {
+                decoded.operands = match array_type(insn.u1(1)) {
+                    Some(name) => format!(" {name}"),
+                    None => format!(" {}", insn.u1(1)),
+                }
+            }
+            WIDE => {
+                decoded.opcode = insn.u1(1);
+                decoded.mnemonic = wide_name(decoded.opcode);
+                decoded.operands = match decoded.opcode {
+                    IINC => format!("{}, {}", insn.u2(2), insn.u2(4) as i16),
+                    _ => insn.u2(2).to_string(),
+                };
+            }
+            TABLESWITCH | LOOKUPSWITCH => {
+                let targets = insn.branch_targets();
+                let operands = 1 + switch_padding(insn.pc);
+                let keys: Vec<i32> = if insn.opcode() == TABLESWITCH {
+                    (insn.s4(operands + 4)..=insn.s4(operands + 8)).collect()
+                } else {
+                    (0..targets.len() - 1)
+                        .map(|i| insn.s4(operands + 8 + i * 8))
+                        .collect()
+                };
+                decoded.switch = Some(SwitchTable {
+                    default: targets[0],
+                    cases: keys.into_iter().zip(targets[1..].iter().copied()).collect(),
+                });
+            }
+            _ => {}
+        }
+        if let Some(index) = constant {
+            decoded.operands = format!("#{index}{}", decoded.operands);
+            decoded.comment = pool.and_then(|pool| describe_constant(pool, index, this_class));
+        }
+        decoded
+    }
+
+    fn render(&self, out: &mut String, marker: &str) {
+        let mut line = format!("{marker}{:>6}: {:<13} ", self.pc, self.mnemonic);
+        match &self.switch {
+            Some(switch) => {
+                match switch.cases.first().zip(switch.cases.last()) {
+                    Some((first, last)) if self.opcode == TABLESWITCH => {
+                        let _ = write!(line, "{{ // {} to {}", first.0, last.0);
+                    }
+                    _ => {
+                        let _ = write!(line, "{{ // {}", switch.cases.len());
+                    }
+                }
+                out.push_str(&line);
+                out.push('\n');
+                for (key, target) in &switch.cases {
+                    let _ = writeln!(out, "{key:>22}: {target}");
+                }
+                let _ = writeln!(out, "{:>22}: {}", "default", switch.default);
+                out.push_str("          }\n");
+            }
+            None => {
+                line.push_str(&self.operands);
+                if let Some(comment) = &self.comment {
+                    line = format!("{line:<43} // {comment}");
+                }
+                out.push_str(line.trim_end());
+                out.push('\n');
+            }
+        }
+    }
+}
+
+/// The decoded bytecodes of a method.
+#[derive(Clone, Debug)]
+pub struct MethodDisassembly {
+    /// The internal name of the declaring class, e.g. `java/lang/String`.
+    pub class: String,
+    pub name: String,
+    pub signature: String,
+    /// The length of the bytecodes.
+    pub code_length: usize,
+    pub instructions: Vec<DecodedInstruction>,
+    /// The offset of the first instruction that could not be decoded. The
+    /// instructions following it are missing.
+    pub malformed_at: Option<usize>,
+    /// The line number table, empty if the method has none.
+    pub line_table: LineTable,
+}
+
+impl MethodDisassembly {
+    /// Disassembles `method`.
+    ///
+    /// Requires the `can_get_bytecodes` capability. Constant pool operands
+    /// are only resolved with `can_get_constant_pool`, and lines only
+    /// annotated with `can_get_line_numbers`. Native and abstract methods
+    /// fail with `JVMTI_ERROR_NATIVE_METHOD` and
+    /// `JVMTI_ERROR_ABSENT_INFORMATION`.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `method` a valid method id.
+    pub unsafe fn new(env: *mut jvmtiEnv, method: jmethodID) -> Result<Self, DisassemblyError> {
+        let mut name = null_mut();
+        let mut signature = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetMethodName,
+            method,
+            &mut name,
+            &mut signature,
+            null_mut()
+        ))?;
+        let name = take_string(env, name).unwrap_or_default();
+        let signature = take_string(env, signature).unwrap_or_default();
+
+        let mut klass = null_mut();
+        check(jvmti!(env, v1, GetMethodDeclaringClass, method, &mut klass))?;
+        let mut class_signature = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetClassSignature,
+            klass,
+            &mut class_signature,
+            null_mut()
+        ))?;
+        let class_signature = take_string(env, class_signature).unwrap_or_default();
+        let class = class_signature
+            .strip_prefix('L')
+            .and_then(|s| s.strip_suffix(';'))
+            .unwrap_or(&class_signature)
+            .to_owned();
+
+        let mut count = 0;
+        let mut bytes = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetBytecodes,
+            method,
+            &mut count,
+            &mut bytes
+        ))?;
+        let code = take_array(env, bytes, count);
+
+        let loaded = match LoadedConstantPool::new(env, klass) {
+            Ok(pool) => Some(pool),
+            Err(jvmtiError::JVMTI_ERROR_MUST_POSSESS_CAPABILITY) => None,
+            Err(err) => return Err(err.into()),
+        };
+        let pool = loaded.as_ref().map(LoadedConstantPool::parse).transpose()?;
+
+        let line_table = match LineTable::new(env, method) {
+            Ok(table) => table,
+            Err(LineTableError::Jvmti(err)) => return Err(err.into()),
+            Err(LineTableError::UnsupportedFormat(_)) => LineTable::default(),
+        };
+
+        Ok(Self::from_parts(
+            class,
+            name,
+            signature,
+            &code,
+            pool.as_ref(),
+            line_table,
+        ))
+    }
+
+    /// Disassembles `code`, resolving constant pool operands against `pool`
+    /// if given.
+    pub fn from_parts(
+        class: String,
+        name: String,
+        signature: String,
+        code: &[u8],
+        pool: Option<&ConstantPool<'_>>,
+        line_table: LineTable,
+    ) -> Self {
+        let mut instructions = Vec::new();
+        let mut malformed_at = None;
+        for insn in opcodes::instructions(code) {
+            match insn {
+                Ok(insn) => instructions.push(DecodedInstruction::new(&insn, pool, Some(&class))),
+                Err(pc) => malformed_at = Some(pc),
+            }
+        }
+        MethodDisassembly {
+            class,
+            name,
+            signature,
+            code_length: code.len(),
+            instructions,
+            malformed_at,
+            line_table,
+        }
+    }
+
+    /// Returns the instruction `location` points into, or `None` if it is
+    /// past the decoded instructions.
+    pub fn instruction_at(&self, location: jlocation) -> Option<&DecodedInstruction> {
+        let end = self.malformed_at.unwrap_or(self.code_length);
+        if location >= end as jlocation {
+            return None;
+        }
+        let index = self
+            .instructions
+            .partition_point(|insn| insn.pc as jlocation <= location);
+        self.instructions[..index].last()
+    }
+
+    /// Renders the method like `javap -c`, marking the instruction
+    /// `highlight` points into with `>>`.
+    pub fn render(&self, highlight: Option<jlocation>) -> String {
+        let highlighted = highlight
+            .and_then(|location| self.instruction_at(location))
+            .map(|insn| insn.pc);
+        let mut out = format!("{}.{}{}\n", self.class, self.name, self.signature);
+        let mut current_line = None;
+        for insn in &self.instructions {
+            let line = self.line_table.line_for(insn.pc as jlocation);
+            if line.is_some() && line != current_line {
+                let _ = writeln!(out, "    // line {}", line.unwrap_or_default());
+            }
+            current_line = line;
+            let marker = if highlighted == Some(insn.pc) {
+                ">>"
+            } else {
+                "  "
+            };
+            insn.render(&mut out, marker);
+        }
+        if let Some(pc) = self.malformed_at {
+            let _ = writeln!(out, "  {pc:>6}: <malformed instruction>");
+        }
+        out
+    }
+}
+
+impl fmt::Display for MethodDisassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(None))
+    }
+}
+
+fn wide_name(opcode: u8) -> &'static str {
+    match opcode {
+        ILOAD => "iload_w",
+        LLOAD => "lload_w",
+        FLOAD => "fload_w",
+        DLOAD => "dload_w",
+        ALOAD => "aload_w",
+        ISTORE => "istore_w",
+        LSTORE => "lstore_w",
+        FSTORE => "fstore_w",
+        DSTORE => "dstore_w",
+        ASTORE => "astore_w",
+        IINC => "iinc_w",
+        _ => "ret_w",
+    }
+}
+
+fn array_type(atype: u8) -> Option<&'static str> {
+    Some(match atype {
+        4 => "boolean",
+        5 => "char",
+        6 => "float",
+        7 => "double",
+        8 => "byte",
+        9 => "short",
+        10 => "int",
+        11 => "long",
+        _ => return None,
+    })
+}
+
+fn describe_constant(
+    pool: &ConstantPool<'_>,
+    index: u16,
+    this_class: Option<&str>,
+) -> Option<String> {
+    let member = |kind: &str| {
+        let member = pool.member_ref(index)?;
+        let name = quote(&member.name.to_str());
+        Some(if this_class.is_some_and(|class| member.class == class) {
+            format!("{kind} {name}:{}", member.descriptor)
+        } else {
+            format!(
+                "{kind} {}.{name}:{}",
+                quote(&member.class.to_str()),
+                member.descriptor
+            )
+        })
+    };
+    Some(match pool.get(index)? {
+        Constant::Integer(value) => format!("int {value}"),
+        Constant::Float(value) => format!("float {}f", java_float(*value as f64, true)),
+        Constant::Long(value) => format!("long {value}l"),
+        Constant::Double(value) => format!("double {}d", java_float(*value, false)),
+        Constant::Class { .. } => format!("class {}", quote(&pool.class_name(index)?.to_str())),
+        Constant::String { .. } => format!("String {}", escape(pool.string(index)?)),
+        Constant::Fieldref { .. } => member("Field")?,
+        Constant::Methodref { .. } => member("Method")?,
+        Constant::InterfaceMethodref { .. } => member("InterfaceMethod")?,
+        Constant::MethodType { .. } => format!("MethodType {}", pool.method_type(index)?),
+        Constant::MethodHandle { .. } => {
+            let (kind, member) = pool.method_handle(index)?;
+            format!(
+                "MethodHandle {} {}.{}:{}",
+                reference_kind_name(kind),
+                quote(&member.class.to_str()),
+                quote(&member.name.to_str()),
+                member.descriptor
+            )
+        }
+        constant @ (Constant::Dynamic { .. } | Constant::InvokeDynamic { .. }) => {
+            let kind = match constant {
+                Constant::Dynamic { .. } => "Dynamic",
+                _ => "InvokeDynamic",
+            };
+            let dynamic = pool.dynamic(index)?;
+            format!(
+                "{kind} #{}:{}:{}",
+                dynamic.bootstrap_method,
+                quote(&dynamic.name.to_str()),
+                dynamic.descriptor
+            )
+        }
+        _ => return None,
+    })
+}
+
+fn reference_kind_name(kind: ReferenceKind) -> &'static str {
+    match kind {
+        ReferenceKind::GetField => "REF_getField",
+        ReferenceKind::GetStatic => "REF_getStatic",
+        ReferenceKind::PutField => "REF_putField",
+        ReferenceKind::PutStatic => "REF_putStatic",
+        ReferenceKind::InvokeVirtual => "REF_invokeVirtual",
+        ReferenceKind::InvokeStatic => "REF_invokeStatic",
+        ReferenceKind::InvokeSpecial => "REF_invokeSpecial",
+        ReferenceKind::NewInvokeSpecial => "REF_newInvokeSpecial",
+        ReferenceKind::InvokeInterface => "REF_invokeInterface",
+    }
+}
+
+/// Quotes names that are not sequences of identifiers separated by `/`,
+/// such as `<init>` or `[I`.
+fn quote(name: &str) -> String {
+    let mut segment_start = true;
+    let plain = !name.is_empty()
+        && name.chars().all(|c| {
+            let valid = if segment_start {
+                c.is_alphabetic() || c == '_' || c == '$'
+            } else {
+                c == '/' || c.is_alphanumeric() || c == '_' || c == '$'
+            };
+            segment_start = c == '/';
+            valid
+        });
+    if plain {
+        name.to_owned()
+    } else {
+        format!("\"{}\"", escape_str(name))
+    }
+}
+
+fn escape(s: Mutf8<'_>) -> String {
+    escape_str(&s.to_str())
+}
+
+fn escape_str(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\u{c}' => escaped.push_str("\\f"),
+            '"' => escaped.push_str("\\\""),
+            '\'' => escaped.push_str("\\'"),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Formats a floating-point constant in the notation of `Float.toString`
+/// and `Double.toString`, with the shortest digits that round-trip.
+fn java_float(value: f64, single: bool) -> String {
+    if value.is_nan() {
+        return "NaN".to_owned();
+    }
+    if value.is_infinite() {
+        return if value > 0.0 { "Infinity" } else { "-Infinity" }.to_owned();
+    }
+    let magnitude = value.abs();
+    let text = if magnitude == 0.0 || (1e-3..1e7).contains(&magnitude) {
+        if single {
+            format!("{:?}", value as f32)
+        } else {
+            format!("{value:?}")
+        }
+    } else if single {
+        format!("{:E}", value as f32)
+    } else {
+        format!("{value:E}")
+    };
+    match text.split_once('E') {
+        Some((mantissa, exponent)) if !mantissa.contains('.') => {
+            format!("{mantissa}.0E{exponent}")
+        }
+        _ => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classfile::ClassFile;
+
+    const FIXTURE: &[u8] = include_bytes!("classfile/testdata/Fixture.class");
+
+    fn disassemble(name: &str) -> MethodDisassembly {
+        let class = ClassFile::parse(FIXTURE).unwrap();
+        let method = class
+            .methods
+            .iter()
+            .find(|method| method.name == name)
+            .unwrap();
+        MethodDisassembly::from_parts(
+            "Fixture".to_owned(),
+            name.to_owned(),
+            method.descriptor.to_string(),
+            method.code().unwrap().code,
+            Some(&class.constant_pool),
+            LineTable::default(),
+        )
+    }
+
+    #[test]
+    fn decodes_wide_instructions() {
+        let method = disassemble("widen");
+        let iinc = &method.instructions[0];
+        assert_eq!((iinc.opcode, iinc.mnemonic), (IINC, "iinc_w"));
+        assert_eq!(iinc.operands, "0, 1000");
+        assert_eq!(method.instructions[1].pc, 6);
+        assert_eq!(method.instruction_at(5).map(|insn| insn.pc), Some(0));
+    }
+
+    #[test]
+    fn decodes_tableswitch() {
+        let method = disassemble("dense");
+        let switch = &method.instructions[1];
+        assert_eq!(switch.mnemonic, "tableswitch");
+        assert_eq!(
+            switch.switch,
+            Some(SwitchTable {
+                default: 43,
+                cases: vec![(0, 32), (1, 34), (2, 37), (3, 40)],
+            })
+        );
+        assert_eq!(method.instructions[2].pc, 32);
+    }
+
+    #[test]
+    fn decodes_padded_lookupswitch() {
+        let method = disassemble("classify");
+        let switch = &method.instructions[1];
+        // Two bytes of padding align the operands of the switch at pc 1.
+        assert_eq!(switch.pc, 1);
+        assert_eq!(method.instructions[2].pc, 36);
+        assert!(method.malformed_at.is_none());
+        assert_eq!(
+            method.render(Some(1)),
+            concat!(
+                "Fixture.classify(I)I\n",
+                "       0: iload_0\n",
+                ">>     1: lookupswitch  { // 3\n",
+                "                     1: 36\n",
+                "                     2: 39\n",
+                "                   100: 42\n",
+                "               default: 46\n",
+                "          }\n",
+                "      36: bipush        10\n",
+                "      38: ireturn\n",
+                "      39: bipush        20\n",
+                "      41: ireturn\n",
+                "      42: sipush        1000\n",
+                "      45: ireturn\n",
+                "      46: iload_0\n",
+                "      47: ifge          54\n",
+                "      50: iconst_m1\n",
+                "      51: goto          55\n",
+                "      54: iconst_0\n",
+                "      55: ireturn\n",
+            )
+        );
+    }
+
+    #[test]
+    fn resolves_constant_pool_operands() {
+        let method = disassemble("<init>");
+        let comments: Vec<_> = method
+            .instructions
+            .iter()
+            .filter_map(|insn| Some((insn.mnemonic, insn.comment.as_deref()?)))
+            .collect();
+        assert_eq!(
+            comments,
+            [
+                ("invokespecial", "Method java/lang/Object.\"<init>\":()V"),
+                ("new", "class java/util/ArrayList"),
+                ("invokespecial", "Method java/util/ArrayList.\"<init>\":()V"),
+                ("putfield", "Field names:Ljava/util/List;"),
+                ("getfield", "Field names:Ljava/util/List;"),
+                (
+                    "invokeinterface",
+                    "InterfaceMethod java/util/List.add:(Ljava/lang/Object;)Z"
+                ),
+            ]
+        );
+        let invokeinterface = method
+            .instructions
+            .iter()
+            .find(|insn| insn.opcode == INVOKEINTERFACE)
+            .unwrap();
+        assert_eq!(invokeinterface.operands, "#16,  2");
+
+        let sum = disassemble("sum");
+        let ldc = sum.instructions.iter().find(|insn| insn.opcode == LDC2_W);
+        assert_eq!(
+            ldc.and_then(|insn| insn.comment.as_deref()),
+            Some("long 1099511627776l")
+        );
+
+        let unresolved = MethodDisassembly::from_parts(
+            "Fixture".to_owned(),
+            "<init>".to_owned(),
+            String::new(),
+            &[ALOAD_0, INVOKESPECIAL, 0, 1, RETURN],
+            None,
+            LineTable::default(),
+        );
+        assert_eq!(unresolved.instructions[1].operands, "#1");
+        assert_eq!(unresolved.instructions[1].comment, None);
+    }
+
+    #[test]
+    fn escapes_string_constants() {
+        let method = disassemble("widen");
+        let ldc = method.instructions.iter().find(|insn| insn.opcode == LDC);
+        assert_eq!(
+            ldc.and_then(|insn| insn.comment.as_deref()),
+            Some(r#"String tab\tline\n\u0001\"quoted\""#)
+        );
+        assert_eq!(escape_str("\r\u{8}\u{c}'\\\u{7f}é"), r"\r\b\f\'\\\u007fé");
+    }
+}
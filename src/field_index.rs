@@ -0,0 +1,245 @@
+//! Resolution of JVMTI field indices.
+//!
+//! `FIELD` and `STATIC_FIELD` heap references, and the primitive field
+//! callback, identify fields by an index whose numbering spans the whole
+//! class hierarchy: the fields of every interface the class implements,
+//! directly or indirectly, come first, followed by the fields of
+//! `java.lang.Object` down to the class itself, each in `GetClassFields`
+//! order and including static fields. For an interface, only the fields of
+//! its superinterfaces precede its own fields. [`ClassFields`] computes that
+//! list so that indices can be mapped back to fields.
+
+use core::ptr::null_mut;
+
+use jni_sys::{jclass, jfieldID, jint, JNIEnv};
+
+use crate::util::{check, delete_local_refs, take_array, take_string};
+use crate::{jvmtiEnv, jvmtiError};
+
+const ACC_STATIC: jint = 0x0008;
+
+/// A field at some index of a [`ClassFields`] list.
+#[derive(Clone, Debug)]
+pub struct FieldEntry {
+    pub field: jfieldID,
+    pub name: String,
+    /// The type descriptor, e.g. `I` or `Ljava/lang/String;`.
+    pub signature: String,
+    /// The access flags as returned by `GetFieldModifiers`.
+    pub modifiers: jint,
+    /// The signature of the declaring class, e.g. `Ljava/lang/Object;`.
+    pub declaring_class: String,
+}
+
+impl FieldEntry {
+    pub fn is_static(&self) -> bool {
+        self.modifiers & ACC_STATIC != 0
+    }
+}
+
+/// The fields of a class in JVMTI field index order.
+#[derive(Clone, Debug, Default)]
+pub struct ClassFields {
+    fields: Vec<FieldEntry>,
+    interface_fields: usize,
+    /// Start of the fields of each class of the hierarchy, superclasses
+    /// first.
+    groups: Vec<usize>,
+}
+
+impl ClassFields {
+    /// Computes the field list of `klass`.
+    ///
+    /// The fields of interfaces only count towards the indices of the
+    /// other fields; their relative order is unspecified.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment, `jni` the JNI environment of
+    /// the current thread and `klass` a valid class reference.
+    pub unsafe fn new(
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        klass: jclass,
+    ) -> Result<Self, jvmtiError> {
+        let mut is_interface = false;
+        check(jvmti!(env, v1, IsInterface, klass, &mut is_interface))?;
+
+        // The class followed by its superclasses; all but `klass` are owned.
+        let mut chain = vec![klass];
+        if !is_interface {
+            loop {
+                let superclass = ((**jni).v1_1.GetSuperclass)(jni, *chain.last().unwrap());
+                if superclass.is_null() {
+                    break;
+                }
+                chain.push(superclass);
+            }
+        }
+        let mut interfaces = Vec::new();
+        let result = Self::collect(env, jni, &chain, &mut interfaces);
+        delete_local_refs(jni, &chain[1..]);
+        delete_local_refs(jni, &interfaces);
+        result
+    }
+
+    unsafe fn collect(
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        chain: &[jclass],
+        interfaces: &mut Vec<jclass>,
+    ) -> Result<Self, jvmtiError> {
+        for &klass in chain {
+            let mut pending = implemented_interfaces(env, klass)?;
+            while let Some(interface) = pending.pop() {
+                let seen = interfaces
+                    .iter()
+                    .any(|&other| ((**jni).v1_1.IsSameObject)(jni, other, interface));
+                if seen {
+                    delete_local_refs(jni, &[interface]);
+                    continue;
+                }
+                interfaces.push(interface);
+                match implemented_interfaces(env, interface) {
+                    Ok(more) => pending.extend(more),
+                    Err(err) => {
+                        delete_local_refs(jni, &pending);
+                        return Err(err);
+                    }
+                }
+            }
+        }
+
+        let mut fields = Vec::new();
+        for &interface in interfaces.iter() {
+            push_declared_fields(env, interface, &mut fields)?;
+        }
+        let interface_fields = fields.len();
+        let mut groups = Vec::with_capacity(chain.len());
+        for &klass in chain.iter().rev() {
+            groups.push(fields.len());
+            push_declared_fields(env, klass, &mut fields)?;
+        }
+        Ok(ClassFields {
+            fields,
+            interface_fields,
+            groups,
+        })
+    }
+
+    /// Returns the field at `index`.
+    pub fn get(&self, index: jint) -> Option<&FieldEntry> {
+        self.fields.get(usize::try_from(index).ok()?)
+    }
+
+    /// Returns all fields, indexed by field index.
+    pub fn fields(&self) -> &[FieldEntry] {
+        &self.fields
+    }
+
+    /// Returns the number of interface fields at the start of the list.
+    pub fn interface_field_count(&self) -> usize {
+        self.interface_fields
+    }
+
+    /// Returns the fields declared by the class itself.
+    pub fn declared(&self) -> &[FieldEntry] {
+        self.hierarchy().next().unwrap_or_default()
+    }
+
+    /// Returns the fields declared by the class and each of its
+    /// superclasses, starting with the class itself.
+    pub fn hierarchy(&self) -> impl DoubleEndedIterator<Item = &[FieldEntry]> + '_ {
+        (0..self.groups.len()).rev().map(move |i| {
+            let end = self.groups.get(i + 1).copied();
+            &self.fields[self.groups[i]..end.unwrap_or(self.fields.len())]
+        })
+    }
+
+    /// Returns the instance fields of the class and its superclasses, in
+    /// index order.
+    pub fn instance_fields(&self) -> impl Iterator<Item = &FieldEntry> + '_ {
+        self.fields[self.interface_fields..]
+            .iter()
+            .filter(|field| !field.is_static())
+    }
+}
+
+unsafe fn push_declared_fields(
+    env: *mut jvmtiEnv,
+    klass: jclass,
+    fields: &mut Vec<FieldEntry>,
+) -> Result<(), jvmtiError> {
+    let mut count = 0;
+    let mut ids = null_mut();
+    let ids = match jvmti!(env, v1, GetClassFields, klass, &mut count, &mut ids) {
+        jvmtiError::JVMTI_ERROR_NONE => take_array(env, ids, count),
+        jvmtiError::JVMTI_ERROR_CLASS_NOT_PREPARED => return Ok(()),
+        err => return Err(err),
+    };
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let mut signature = null_mut();
+    check(jvmti!(
+        env,
+        v1,
+        GetClassSignature,
+        klass,
+        &mut signature,
+        null_mut()
+    ))?;
+    let declaring_class = take_string(env, signature).unwrap_or_default();
+    for field in ids {
+        let mut name = null_mut();
+        let mut signature = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetFieldName,
+            klass,
+            field,
+            &mut name,
+            &mut signature,
+            null_mut()
+        ))?;
+        let mut modifiers = 0;
+        check(jvmti!(
+            env,
+            v1,
+            GetFieldModifiers,
+            klass,
+            field,
+            &mut modifiers
+        ))?;
+        fields.push(FieldEntry {
+            field,
+            name: take_string(env, name).unwrap_or_default(),
+            signature: take_string(env, signature).unwrap_or_default(),
+            modifiers,
+            declaring_class: declaring_class.clone(),
+        });
+    }
+    Ok(())
+}
+
+unsafe fn implemented_interfaces(
+    env: *mut jvmtiEnv,
+    klass: jclass,
+) -> Result<Vec<jclass>, jvmtiError> {
+    let mut count = 0;
+    let mut interfaces = null_mut();
+    match jvmti!(
+        env,
+        v1,
+        GetImplementedInterfaces,
+        klass,
+        &mut count,
+        &mut interfaces
+    ) {
+        jvmtiError::JVMTI_ERROR_NONE => Ok(take_array(env, interfaces, count)),
+        jvmtiError::JVMTI_ERROR_CLASS_NOT_PREPARED => Ok(Vec::new()),
+        err => Err(err),
+    }
+}
@@ -0,0 +1,671 @@
+//! Closure based wrappers around `FollowReferences` and `IterateThroughHeap`.
+//!
+//! A [`HeapVisitor`] collects one closure per callback kind. Only the
+//! callbacks that were provided are registered with the VM, so walking the
+//! heap does not pay for primitive value reporting unless asked to. A panic
+//! inside a closure aborts the walk and is resumed once the VM returns.
+//!
+//! All functions require the `can_tag_objects` capability.
+
+use core::cell::Cell;
+use core::ffi::c_void;
+use core::fmt;
+use core::ptr::null_mut;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+use bitflags::bitflags;
+use jni_sys::{jchar, jclass, jint, jlong, jobject, jvalue};
+
+use crate::locals::JavaValue;
+use crate::util::check;
+use crate::{
+    jvmtiArrayPrimitiveValueCallback, jvmtiEnv, jvmtiError, jvmtiHeapCallbacks,
+    jvmtiHeapIterationCallback, jvmtiHeapReferenceCallback, jvmtiHeapReferenceInfo,
+    jvmtiHeapReferenceInfoJniLocal, jvmtiHeapReferenceInfoStackLocal, jvmtiHeapReferenceKind,
+    jvmtiPrimitiveFieldCallback, jvmtiPrimitiveType, jvmtiReservedCallback,
+    jvmtiStringPrimitiveValueCallback, HeapReferenceInfoRef, JVMTI_HEAP_FILTER_CLASS_TAGGED,
+    JVMTI_HEAP_FILTER_CLASS_UNTAGGED, JVMTI_HEAP_FILTER_TAGGED, JVMTI_HEAP_FILTER_UNTAGGED,
+    JVMTI_VISIT_ABORT, JVMTI_VISIT_OBJECTS,
+};
+
+/// Layout compatible twin of [`jvmtiHeapCallbacks`] whose slots may be null.
+///
+/// The VM skips a callback kind whose slot is null, but the public binding
+/// declares every slot as a non-nullable function pointer.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct NullableHeapCallbacks {
+    heap_iteration_callback: Option<jvmtiHeapIterationCallback>,
+    heap_reference_callback: Option<jvmtiHeapReferenceCallback>,
+    primitive_field_callback: Option<jvmtiPrimitiveFieldCallback>,
+    array_primitive_value_callback: Option<jvmtiArrayPrimitiveValueCallback>,
+    string_primitive_value_callback: Option<jvmtiStringPrimitiveValueCallback>,
+    reserved: [Option<jvmtiReservedCallback>; 11],
+}
+
+const _: () = assert!(
+    core::mem::size_of::<NullableHeapCallbacks>() == core::mem::size_of::<jvmtiHeapCallbacks>()
+);
+
+impl NullableHeapCallbacks {
+    fn as_raw(&self) -> *const jvmtiHeapCallbacks {
+        (self as *const Self).cast()
+    }
+}
+
+bitflags! {
+    /// Objects to exclude from a heap walk.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct HeapFilter: jint {
+        /// Skip tagged objects.
+        const TAGGED = JVMTI_HEAP_FILTER_TAGGED as jint;
+        /// Skip untagged objects.
+        const UNTAGGED = JVMTI_HEAP_FILTER_UNTAGGED as jint;
+        /// Skip objects whose class is tagged.
+        const CLASS_TAGGED = JVMTI_HEAP_FILTER_CLASS_TAGGED as jint;
+        /// Skip objects whose class is untagged.
+        const CLASS_UNTAGGED = JVMTI_HEAP_FILTER_CLASS_UNTAGGED as jint;
+    }
+}
+
+/// What the VM should do after a callback returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VisitControl {
+    /// Keep going, following the references of the reported object.
+    Continue,
+    /// Keep going, but do not follow the references of the reported object.
+    /// Only meaningful for reference callbacks; elsewhere it is the same as
+    /// [`Continue`](Self::Continue).
+    Skip,
+    /// Stop the walk.
+    Abort,
+}
+
+impl VisitControl {
+    fn into_raw(self) -> jint {
+        match self {
+            VisitControl::Continue => JVMTI_VISIT_OBJECTS as jint,
+            VisitControl::Skip => 0,
+            VisitControl::Abort => JVMTI_VISIT_ABORT as jint,
+        }
+    }
+}
+
+/// A reference reported by `FollowReferences`, combining the
+/// `jvmtiHeapReferenceKind` with the matching `jvmtiHeapReferenceInfo`.
+#[derive(Clone, Copy, Debug)]
+pub enum HeapReference {
+    /// From an object to its class.
+    Class,
+    /// From an object to the value of one of its instance fields.
+    Field { index: jint },
+    /// From an array to one of its elements.
+    ArrayElement { index: jint },
+    /// From a class to its class loader.
+    ClassLoader,
+    /// From a class to its signers array.
+    Signers,
+    /// From a class to its protection domain.
+    ProtectionDomain,
+    /// From a class to one of its interfaces.
+    Interface,
+    /// From a class to the value of one of its static fields.
+    StaticField { index: jint },
+    /// From a class to a resolved entry in its constant pool.
+    ConstantPool { index: jint },
+    /// From a class to its superclass.
+    Superclass,
+    /// Heap root: a JNI global reference.
+    JniGlobal,
+    /// Heap root: a system class.
+    SystemClass,
+    /// Heap root: a monitor.
+    Monitor,
+    /// Heap root: a local variable on the stack.
+    StackLocal(jvmtiHeapReferenceInfoStackLocal),
+    /// Heap root: a JNI local reference.
+    JniLocal(jvmtiHeapReferenceInfoJniLocal),
+    /// Heap root: a thread.
+    Thread,
+    /// Heap root: anything else.
+    Other,
+}
+
+impl HeapReference {
+    /// Decodes a reference reported to a heap reference or primitive field
+    /// callback.
+    ///
+    /// # Safety
+    ///
+    /// `info` must point to the reference info the VM passed along with `kind`,
+    /// or be null for kinds that carry no info.
+    pub unsafe fn from_raw(
+        kind: jvmtiHeapReferenceKind,
+        info: *const jvmtiHeapReferenceInfo,
+    ) -> Self {
+        use jvmtiHeapReferenceKind::*;
+        let info = match info.as_ref() {
+            Some(info) => info.get(kind),
+            None => HeapReferenceInfoRef::None,
+        };
+        match (info, kind) {
+            (HeapReferenceInfoRef::Field(field), JVMTI_HEAP_REFERENCE_STATIC_FIELD) => {
+                HeapReference::StaticField { index: field.index }
+            }
+            (HeapReferenceInfoRef::Field(field), _) => HeapReference::Field { index: field.index },
+            (HeapReferenceInfoRef::Array(array), _) => {
+                HeapReference::ArrayElement { index: array.index }
+            }
+            (HeapReferenceInfoRef::ConstantPool(constant_pool), _) => HeapReference::ConstantPool {
+                index: constant_pool.index,
+            },
+            (HeapReferenceInfoRef::StackLocal(stack_local), _) => {
+                HeapReference::StackLocal(*stack_local)
+            }
+            (HeapReferenceInfoRef::JniLocal(jni_local), _) => HeapReference::JniLocal(*jni_local),
+            (HeapReferenceInfoRef::None, JVMTI_HEAP_REFERENCE_CLASS) => HeapReference::Class,
+            (HeapReferenceInfoRef::None, JVMTI_HEAP_REFERENCE_CLASS_LOADER) => {
+                HeapReference::ClassLoader
+            }
+            (HeapReferenceInfoRef::None, JVMTI_HEAP_REFERENCE_SIGNERS) => HeapReference::Signers,
+            (HeapReferenceInfoRef::None, JVMTI_HEAP_REFERENCE_PROTECTION_DOMAIN) => {
+                HeapReference::ProtectionDomain
+            }
+            (HeapReferenceInfoRef::None, JVMTI_HEAP_REFERENCE_INTERFACE) => {
+                HeapReference::Interface
+            }
+            (HeapReferenceInfoRef::None, JVMTI_HEAP_REFERENCE_SUPERCLASS) => {
+                HeapReference::Superclass
+            }
+            (HeapReferenceInfoRef::None, JVMTI_HEAP_REFERENCE_JNI_GLOBAL) => {
+                HeapReference::JniGlobal
+            }
+            (HeapReferenceInfoRef::None, JVMTI_HEAP_REFERENCE_SYSTEM_CLASS) => {
+                HeapReference::SystemClass
+            }
+            (HeapReferenceInfoRef::None, JVMTI_HEAP_REFERENCE_MONITOR) => HeapReference::Monitor,
+            (HeapReferenceInfoRef::None, JVMTI_HEAP_REFERENCE_THREAD) => HeapReference::Thread,
+            (HeapReferenceInfoRef::None, _) => HeapReference::Other,
+        }
+    }
+
+    /// Returns whether the reference comes from a heap root rather than from
+    /// another object.
+    pub fn is_root(&self) -> bool {
+        matches!(
+            self,
+            HeapReference::JniGlobal
+                | HeapReference::SystemClass
+                | HeapReference::Monitor
+                | HeapReference::StackLocal(_)
+                | HeapReference::JniLocal(_)
+                | HeapReference::Thread
+                | HeapReference::Other
+        )
+    }
+}
+
+/// An object reported by `IterateThroughHeap`.
+#[derive(Debug)]
+pub struct HeapObject<'a> {
+    /// The tag of the object's class, or zero.
+    pub class_tag: jlong,
+    /// The object's size in bytes.
+    pub size: jlong,
+    /// The object's tag. Setting it tags the object.
+    pub tag: &'a Cell<jlong>,
+    /// The length of the object if it is an array.
+    pub length: Option<jint>,
+}
+
+/// A reference reported by `FollowReferences`.
+#[derive(Debug)]
+pub struct HeapReferenceEvent<'a> {
+    pub reference: HeapReference,
+    /// The tag of the referee's class, or zero.
+    pub class_tag: jlong,
+    /// The tag of the referrer's class, or zero. Zero for roots.
+    pub referrer_class_tag: jlong,
+    /// The referee's size in bytes.
+    pub size: jlong,
+    /// The referee's tag.
+    pub tag: &'a Cell<jlong>,
+    /// The referrer's tag, `None` for roots. May be the same cell as `tag` for
+    /// objects referring to themselves.
+    pub referrer_tag: Option<&'a Cell<jlong>>,
+    /// The length of the referee if it is an array.
+    pub length: Option<jint>,
+}
+
+/// A primitive field value reported by `FollowReferences` or
+/// `IterateThroughHeap`.
+#[derive(Debug)]
+pub struct PrimitiveFieldEvent<'a> {
+    /// [`HeapReference::Field`] or [`HeapReference::StaticField`].
+    pub reference: HeapReference,
+    /// The tag of the object's class, or zero.
+    pub object_class_tag: jlong,
+    /// The tag of the object (or class, for static fields).
+    pub object_tag: &'a Cell<jlong>,
+    pub value: JavaValue,
+}
+
+/// The elements of a primitive array.
+#[derive(Clone, Copy, Debug)]
+pub enum PrimitiveArray<'a> {
+    Boolean(&'a [u8]),
+    Byte(&'a [i8]),
+    Char(&'a [u16]),
+    Short(&'a [i16]),
+    Int(&'a [i32]),
+    Long(&'a [i64]),
+    Float(&'a [f32]),
+    Double(&'a [f64]),
+}
+
+/// A primitive array reported by `FollowReferences` or `IterateThroughHeap`.
+#[derive(Debug)]
+pub struct ArrayPrimitiveValueEvent<'a> {
+    /// The tag of the array's class, or zero.
+    pub class_tag: jlong,
+    /// The array's size in bytes.
+    pub size: jlong,
+    /// The array's tag.
+    pub tag: &'a Cell<jlong>,
+    pub elements: PrimitiveArray<'a>,
+}
+
+/// A `java.lang.String` value reported by `FollowReferences` or
+/// `IterateThroughHeap`.
+#[derive(Debug)]
+pub struct StringPrimitiveValueEvent<'a> {
+    /// The tag of the string's class, or zero.
+    pub class_tag: jlong,
+    /// The string's size in bytes.
+    pub size: jlong,
+    /// The string's tag.
+    pub tag: &'a Cell<jlong>,
+    /// The UTF-16 contents of the string.
+    pub value: &'a [jchar],
+}
+
+type HeapIterationFn<'a> = dyn FnMut(HeapObject<'_>) -> VisitControl + 'a;
+type HeapReferenceFn<'a> = dyn FnMut(HeapReferenceEvent<'_>) -> VisitControl + 'a;
+type PrimitiveFieldFn<'a> = dyn FnMut(PrimitiveFieldEvent<'_>) -> VisitControl + 'a;
+type ArrayPrimitiveValueFn<'a> = dyn FnMut(ArrayPrimitiveValueEvent<'_>) -> VisitControl + 'a;
+type StringPrimitiveValueFn<'a> = dyn FnMut(StringPrimitiveValueEvent<'_>) -> VisitControl + 'a;
+
+/// A set of closures to walk the heap with.
+#[derive(Default)]
+pub struct HeapVisitor<'a> {
+    heap_iteration: Option<Box<HeapIterationFn<'a>>>,
+    heap_reference: Option<Box<HeapReferenceFn<'a>>>,
+    primitive_field: Option<Box<PrimitiveFieldFn<'a>>>,
+    array_primitive_value: Option<Box<ArrayPrimitiveValueFn<'a>>>,
+    string_primitive_value: Option<Box<StringPrimitiveValueFn<'a>>>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl fmt::Debug for HeapVisitor<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeapVisitor")
+            .field("heap_iteration", &self.heap_iteration.is_some())
+            .field("heap_reference", &self.heap_reference.is_some())
+            .field("primitive_field", &self.primitive_field.is_some())
+            .field(
+                "array_primitive_value",
+                &self.array_primitive_value.is_some(),
+            )
+            .field(
+                "string_primitive_value",
+                &self.string_primitive_value.is_some(),
+            )
+            .finish()
+    }
+}
+
+impl<'a> HeapVisitor<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called for every object by [`iterate_through_heap`](Self::iterate_through_heap).
+    pub fn heap_iteration(mut self, f: impl FnMut(HeapObject<'_>) -> VisitControl + 'a) -> Self {
+        self.heap_iteration = Some(Box::new(f));
+        self
+    }
+
+    /// Called for every reference by [`follow_references`](Self::follow_references).
+    pub fn heap_reference(
+        mut self,
+        f: impl FnMut(HeapReferenceEvent<'_>) -> VisitControl + 'a,
+    ) -> Self {
+        self.heap_reference = Some(Box::new(f));
+        self
+    }
+
+    /// Called for every primitive field of the visited objects.
+    pub fn primitive_field(
+        mut self,
+        f: impl FnMut(PrimitiveFieldEvent<'_>) -> VisitControl + 'a,
+    ) -> Self {
+        self.primitive_field = Some(Box::new(f));
+        self
+    }
+
+    /// Called for every visited primitive array.
+    pub fn array_primitive_value(
+        mut self,
+        f: impl FnMut(ArrayPrimitiveValueEvent<'_>) -> VisitControl + 'a,
+    ) -> Self {
+        self.array_primitive_value = Some(Box::new(f));
+        self
+    }
+
+    /// Called for every visited `java.lang.String`.
+    pub fn string_primitive_value(
+        mut self,
+        f: impl FnMut(StringPrimitiveValueEvent<'_>) -> VisitControl + 'a,
+    ) -> Self {
+        self.string_primitive_value = Some(Box::new(f));
+        self
+    }
+
+    /// Walks the objects reachable from `initial_object`, or from the heap
+    /// roots if it is null. A non-null `klass` restricts reporting to
+    /// instances of that class.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment, `klass` and `initial_object`
+    /// valid references or null.
+    pub unsafe fn follow_references(
+        &mut self,
+        env: *mut jvmtiEnv,
+        filter: HeapFilter,
+        klass: jclass,
+        initial_object: jobject,
+    ) -> Result<(), jvmtiError> {
+        let callbacks = self.raw_callbacks();
+        let user_data = self as *mut Self as *const c_void;
+        let err = jvmti!(
+            env,
+            v1_1,
+            FollowReferences,
+            filter.bits(),
+            klass,
+            initial_object,
+            callbacks.as_raw(),
+            user_data
+        );
+        self.resume_panic();
+        check(err)
+    }
+
+    /// Visits every object in the heap, reachable or not. A non-null `klass`
+    /// restricts the iteration to instances of that class.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `klass` a valid reference
+    /// or null.
+    pub unsafe fn iterate_through_heap(
+        &mut self,
+        env: *mut jvmtiEnv,
+        filter: HeapFilter,
+        klass: jclass,
+    ) -> Result<(), jvmtiError> {
+        let callbacks = self.raw_callbacks();
+        let user_data = self as *mut Self as *const c_void;
+        let err = jvmti!(
+            env,
+            v1_1,
+            IterateThroughHeap,
+            filter.bits(),
+            klass,
+            callbacks.as_raw(),
+            user_data
+        );
+        self.resume_panic();
+        check(err)
+    }
+
+    fn raw_callbacks(&self) -> NullableHeapCallbacks {
+        NullableHeapCallbacks {
+            heap_iteration_callback: self
+                .heap_iteration
+                .is_some()
+                .then_some(heap_iteration_callback),
+            heap_reference_callback: self
+                .heap_reference
+                .is_some()
+                .then_some(heap_reference_callback),
+            primitive_field_callback: self
+                .primitive_field
+                .is_some()
+                .then_some(primitive_field_callback),
+            array_primitive_value_callback: self
+                .array_primitive_value
+                .is_some()
+                .then_some(array_primitive_value_callback),
+            string_primitive_value_callback: self
+                .string_primitive_value
+                .is_some()
+                .then_some(string_primitive_value_callback),
+            reserved: [None; 11],
+        }
+    }
+
+    fn guard(&mut self, f: impl FnOnce(&mut Self) -> VisitControl) -> jint {
+        if self.panic.is_some() {
+            return VisitControl::Abort.into_raw();
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(control) => control.into_raw(),
+            Err(payload) => {
+                self.panic = Some(payload);
+                VisitControl::Abort.into_raw()
+            }
+        }
+    }
+
+    fn resume_panic(&mut self) {
+        if let Some(payload) = self.panic.take() {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+/// Clears the tag of every object tagged in `env`.
+///
+/// # Safety
+///
+/// `env` must be a valid JVMTI environment.
+pub unsafe fn clear_tags(env: *mut jvmtiEnv) -> Result<(), jvmtiError> {
+    HeapVisitor::new()
+        .heap_iteration(|object| {
+            object.tag.set(0);
+            VisitControl::Continue
+        })
+        .iterate_through_heap(env, HeapFilter::UNTAGGED, null_mut())
+}
+
+unsafe fn visitor<'a>(user_data: *mut c_void) -> &'a mut HeapVisitor<'a> {
+    &mut *(user_data as *mut HeapVisitor<'a>)
+}
+
+/// Tags are exposed as cells because the VM passes the same pointer as tag
+/// and referrer tag for self references.
+unsafe fn tag_cell<'a>(ptr: *mut jlong) -> &'a Cell<jlong> {
+    &*(ptr as *const Cell<jlong>)
+}
+
+fn length(length: jint) -> Option<jint> {
+    (length >= 0).then_some(length)
+}
+
+unsafe fn primitive_value(value: jvalue, value_type: jvmtiPrimitiveType) -> JavaValue {
+    match value_type {
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_BOOLEAN => JavaValue::Boolean(value.b != 0),
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_BYTE => JavaValue::Byte(value.b),
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_CHAR => JavaValue::Char(value.c),
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_SHORT => JavaValue::Short(value.s),
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_INT => JavaValue::Int(value.i),
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_LONG => JavaValue::Long(value.j),
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_FLOAT => JavaValue::Float(value.f),
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_DOUBLE => JavaValue::Double(value.d),
+    }
+}
+
+unsafe fn primitive_array<'a>(
+    element_type: jvmtiPrimitiveType,
+    elements: *mut c_void,
+    count: jint,
+) -> PrimitiveArray<'a> {
+    use core::slice::from_raw_parts;
+    let count = count.max(0) as usize;
+    match element_type {
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_BOOLEAN => {
+            PrimitiveArray::Boolean(from_raw_parts(elements as *const u8, count))
+        }
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_BYTE => {
+            PrimitiveArray::Byte(from_raw_parts(elements as *const i8, count))
+        }
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_CHAR => {
+            PrimitiveArray::Char(from_raw_parts(elements as *const u16, count))
+        }
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_SHORT => {
+            PrimitiveArray::Short(from_raw_parts(elements as *const i16, count))
+        }
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_INT => {
+            PrimitiveArray::Int(from_raw_parts(elements as *const i32, count))
+        }
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_LONG => {
+            PrimitiveArray::Long(from_raw_parts(elements as *const i64, count))
+        }
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_FLOAT => {
+            PrimitiveArray::Float(from_raw_parts(elements as *const f32, count))
+        }
+        jvmtiPrimitiveType::JVMTI_PRIMITIVE_TYPE_DOUBLE => {
+            PrimitiveArray::Double(from_raw_parts(elements as *const f64, count))
+        }
+    }
+}
+
+unsafe extern "system" fn heap_iteration_callback(
+    class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    length_: jint,
+    user_data: *mut c_void,
+) -> jint {
+    let object = HeapObject {
+        class_tag,
+        size,
+        tag: tag_cell(tag_ptr),
+        length: length(length_),
+    };
+    visitor(user_data).guard(|v| {
+        v.heap_iteration
+            .as_mut()
+            .map_or(VisitControl::Continue, |f| f(object))
+    })
+}
+
+unsafe extern "system" fn heap_reference_callback(
+    reference_kind: jvmtiHeapReferenceKind,
+    reference_info: *const jvmtiHeapReferenceInfo,
+    class_tag: jlong,
+    referrer_class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    referrer_tag_ptr: *mut jlong,
+    length_: jint,
+    user_data: *mut c_void,
+) -> jint {
+    let event = HeapReferenceEvent {
+        reference: HeapReference::from_raw(reference_kind, reference_info),
+        class_tag,
+        referrer_class_tag,
+        size,
+        tag: tag_cell(tag_ptr),
+        referrer_tag: (!referrer_tag_ptr.is_null()).then(|| tag_cell(referrer_tag_ptr)),
+        length: length(length_),
+    };
+    visitor(user_data).guard(|v| {
+        v.heap_reference
+            .as_mut()
+            .map_or(VisitControl::Continue, |f| f(event))
+    })
+}
+
+unsafe extern "system" fn primitive_field_callback(
+    kind: jvmtiHeapReferenceKind,
+    info: *const jvmtiHeapReferenceInfo,
+    object_class_tag: jlong,
+    object_tag_ptr: *mut jlong,
+    value: jvalue,
+    value_type: jvmtiPrimitiveType,
+    user_data: *mut c_void,
+) -> jint {
+    let event = PrimitiveFieldEvent {
+        reference: HeapReference::from_raw(kind, info),
+        object_class_tag,
+        object_tag: tag_cell(object_tag_ptr),
+        value: primitive_value(value, value_type),
+    };
+    visitor(user_data).guard(|v| {
+        v.primitive_field
+            .as_mut()
+            .map_or(VisitControl::Continue, |f| f(event))
+    })
+}
+
+unsafe extern "system" fn array_primitive_value_callback(
+    class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    element_count: jint,
+    element_type: jvmtiPrimitiveType,
+    elements: *mut c_void,
+    user_data: *mut c_void,
+) -> jint {
+    let event = ArrayPrimitiveValueEvent {
+        class_tag,
+        size,
+        tag: tag_cell(tag_ptr),
+        elements: primitive_array(element_type, elements, element_count),
+    };
+    visitor(user_data).guard(|v| {
+        v.array_primitive_value
+            .as_mut()
+            .map_or(VisitControl::Continue, |f| f(event))
+    })
+}
+
+unsafe extern "system" fn string_primitive_value_callback(
+    class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    value: *mut jchar,
+    value_length: jint,
+    user_data: *mut c_void,
+) -> jint {
+    let value = if value.is_null() {
+        &[]
+    } else {
+        core::slice::from_raw_parts(value as *const jchar, value_length.max(0) as usize)
+    };
+    let event = StringPrimitiveValueEvent {
+        class_tag,
+        size,
+        tag: tag_cell(tag_ptr),
+        value,
+    };
+    visitor(user_data).guard(|v| {
+        v.string_primitive_value
+            .as_mut()
+            .map_or(VisitControl::Continue, |f| f(event))
+    })
+}
@@ -0,0 +1,210 @@
+//! Per-class instance counts and shallow sizes, like `jmap -histo`.
+//!
+//! [`ClassHistogram::collect`] temporarily tags every loaded class, walks the
+//! heap with `IterateThroughHeap` and attributes each object to its class
+//! through the class tag. The original class tags are restored afterwards.
+//! Requires the `can_tag_objects` capability.
+
+use core::fmt;
+use core::ptr::null_mut;
+
+use jni_sys::{jclass, jlong, jobject, JNIEnv};
+
+use crate::heap::{HeapFilter, HeapVisitor, VisitControl};
+use crate::util::{check, class_name, delete_local_refs, take_array, take_string};
+use crate::{jvmtiEnv, jvmtiError};
+
+/// Restricts a histogram to classes defined by a class loader.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum LoaderFilter {
+    /// Count instances of every class.
+    #[default]
+    All,
+    /// Only count instances of classes defined by the bootstrap loader.
+    Bootstrap,
+    /// Only count instances of classes defined by this loader.
+    Loader(jobject),
+}
+
+/// Options for [`ClassHistogram::collect`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HistogramOptions {
+    /// Force a garbage collection first so that only live objects are
+    /// counted, like `jmap -histo:live`.
+    pub live: bool,
+    pub class_loader: LoaderFilter,
+}
+
+/// One line of a [`ClassHistogram`].
+#[derive(Clone, Debug)]
+pub struct HistogramEntry {
+    /// The class name as returned by `Class.getName()`.
+    pub class_name: String,
+    pub instances: u64,
+    /// The sum of the shallow sizes of all instances.
+    pub bytes: u64,
+}
+
+/// Instance counts and shallow sizes per class, sorted by size in
+/// descending order.
+///
+/// The [`Display`](fmt::Display) implementation renders the `jmap -histo`
+/// text format.
+#[derive(Clone, Debug, Default)]
+pub struct ClassHistogram {
+    entries: Vec<HistogramEntry>,
+}
+
+impl ClassHistogram {
+    /// Builds a histogram of the current heap.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `jni` the JNI environment
+    /// of the current thread. Loader references in `options` must be valid.
+    pub unsafe fn collect(
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        options: HistogramOptions,
+    ) -> Result<Self, jvmtiError> {
+        if options.live {
+            check(jvmti!(env, v1, ForceGarbageCollection))?;
+        }
+
+        let mut count = 0;
+        let mut classes = null_mut();
+        check(jvmti!(env, v1, GetLoadedClasses, &mut count, &mut classes))?;
+        let classes = take_array(env, classes, count);
+
+        let result = Self::collect_classes(env, jni, &classes, options.class_loader);
+        delete_local_refs(jni, &classes);
+        result
+    }
+
+    unsafe fn collect_classes(
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        classes: &[jclass],
+        filter: LoaderFilter,
+    ) -> Result<Self, jvmtiError> {
+        let mut original_tags = Vec::with_capacity(classes.len());
+        for &klass in classes {
+            let mut tag = 0;
+            check(jvmti!(env, v1, GetTag, klass, &mut tag))?;
+            original_tags.push(tag);
+        }
+
+        let result = Self::tag_and_count(env, jni, classes, filter);
+
+        for (&klass, &tag) in classes.iter().zip(&original_tags) {
+            jvmti!(env, v1, SetTag, klass, tag);
+        }
+        result
+    }
+
+    unsafe fn tag_and_count(
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        classes: &[jclass],
+        filter: LoaderFilter,
+    ) -> Result<Self, jvmtiError> {
+        let mut included = Vec::with_capacity(classes.len());
+        for (i, &klass) in classes.iter().enumerate() {
+            check(jvmti!(env, v1, SetTag, klass, i as jlong + 1))?;
+            included.push(loader_matches(env, jni, klass, filter)?);
+        }
+
+        let mut counts = vec![(0u64, 0u64); classes.len()];
+        HeapVisitor::new()
+            .heap_iteration(|object| {
+                if let Some(count) = counts.get_mut((object.class_tag as usize).wrapping_sub(1)) {
+                    count.0 += 1;
+                    count.1 += object.size as u64;
+                }
+                VisitControl::Continue
+            })
+            .iterate_through_heap(env, HeapFilter::CLASS_UNTAGGED, null_mut())?;
+
+        let mut entries = Vec::new();
+        for (i, &(instances, bytes)) in counts.iter().enumerate() {
+            if instances == 0 || !included[i] {
+                continue;
+            }
+            let mut signature = null_mut();
+            check(jvmti!(
+                env,
+                v1,
+                GetClassSignature,
+                classes[i],
+                &mut signature,
+                null_mut()
+            ))?;
+            entries.push(HistogramEntry {
+                class_name: class_name(&take_string(env, signature).unwrap_or_default()),
+                instances,
+                bytes,
+            });
+        }
+        entries.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| b.instances.cmp(&a.instances))
+                .then_with(|| a.class_name.cmp(&b.class_name))
+        });
+        Ok(ClassHistogram { entries })
+    }
+
+    /// Returns the entries sorted by size in descending order.
+    pub fn entries(&self) -> &[HistogramEntry] {
+        &self.entries
+    }
+
+    pub fn total_instances(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.instances).sum()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.bytes).sum()
+    }
+}
+
+unsafe fn loader_matches(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    klass: jclass,
+    filter: LoaderFilter,
+) -> Result<bool, jvmtiError> {
+    let expected = match filter {
+        LoaderFilter::All => return Ok(true),
+        LoaderFilter::Bootstrap => null_mut(),
+        LoaderFilter::Loader(loader) => loader,
+    };
+    let mut loader = null_mut();
+    check(jvmti!(env, v1, GetClassLoader, klass, &mut loader))?;
+    let matches = ((**jni).v1_1.IsSameObject)(jni, loader, expected);
+    delete_local_refs(jni, &[loader]);
+    Ok(matches)
+}
+
+impl fmt::Display for ClassHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, " num     #instances         #bytes  class name")?;
+        writeln!(f, "----------------------------------------------")?;
+        for (i, entry) in self.entries.iter().enumerate() {
+            writeln!(
+                f,
+                "{:4}: {:13} {:13}  {}",
+                i + 1,
+                entry.instances,
+                entry.bytes,
+                entry.class_name
+            )?;
+        }
+        writeln!(
+            f,
+            "Total {:13} {:13}",
+            self.total_instances(),
+            self.total_bytes()
+        )
+    }
+}
@@ -0,0 +1,1255 @@
+//! HPROF binary heap dumps.
+//!
+//! [`dump_heap`] writes a `JAVA PROFILE 1.0.2` file that Eclipse MAT and
+//! VisualVM can open, without going through `jcmd GC.heap_dump`. Objects are
+//! discovered with a single `FollowReferences` walk: reference callbacks
+//! provide roots, instance fields, array elements, static fields and constant
+//! pool entries, while the primitive field and primitive array callbacks
+//! provide everything else. String contents are part of the dump through
+//! their backing arrays; the string value callback only fills in a backing
+//! array the VM did not report.
+//!
+//! Each thread's stack, as `GetAllStackTraces` reports it just before the
+//! walk, is written as a `STACK TRACE`, which the frame numbers of the Java
+//! frame and JNI local roots of the thread refer to.
+//!
+//! Every object is tagged with its HPROF id while the dump is written. Objects
+//! that were already tagged in `env` get their original tag back afterwards
+//! and all other tags are cleared, so `env` must not tag objects from another
+//! thread while a dump is taken. Requires the `can_tag_objects` capability.
+//!
+//! The dump is streamed: heap records are written in `HEAP DUMP SEGMENT`
+//! records as soon as the VM moves on to the next object. This relies on the
+//! VM reporting the references, primitive fields and values of an object
+//! together, which HotSpot does but the JVMTI specification does not promise.
+//! On a VM that interleaves objects, an object would be written once per run
+//! of callbacks, each record missing the values reported in the other runs.
+
+use core::cell::RefCell;
+use core::fmt;
+use core::ptr::null_mut;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jni_sys::{jclass, jint, jlong, jmethodID, JNIEnv};
+
+use crate::field_index::ClassFields;
+use crate::heap::{HeapFilter, HeapReference, HeapVisitor, PrimitiveArray, VisitControl};
+use crate::locals::JavaValue;
+use crate::method_cache::MethodInfo;
+use crate::util::{check, deallocate, delete_local_refs, take_array, take_string};
+use crate::{jvmtiEnv, jvmtiError, jvmtiFrameInfo, jvmtiStackInfo};
+
+const TAG_UTF8: u8 = 0x01;
+const TAG_LOAD_CLASS: u8 = 0x02;
+const TAG_STACK_FRAME: u8 = 0x04;
+const TAG_STACK_TRACE: u8 = 0x05;
+const TAG_HEAP_DUMP_SEGMENT: u8 = 0x1c;
+const TAG_HEAP_DUMP_END: u8 = 0x2c;
+
+const ROOT_UNKNOWN: u8 = 0xff;
+const ROOT_JNI_GLOBAL: u8 = 0x01;
+const ROOT_JNI_LOCAL: u8 = 0x02;
+const ROOT_JAVA_FRAME: u8 = 0x03;
+const ROOT_STICKY_CLASS: u8 = 0x05;
+const ROOT_MONITOR_USED: u8 = 0x07;
+const ROOT_THREAD_OBJECT: u8 = 0x08;
+const CLASS_DUMP: u8 = 0x20;
+const INSTANCE_DUMP: u8 = 0x21;
+const OBJ_ARRAY_DUMP: u8 = 0x22;
+const PRIM_ARRAY_DUMP: u8 = 0x23;
+
+/// The serial of the empty stack trace all objects refer to. Threads get
+/// the serials that follow.
+const STACK_TRACE_SERIAL: u32 = 1;
+/// The number of frames of each thread's stack trace. Roots in deeper
+/// frames get frame number -1, as do roots of threads without frames.
+const MAX_FRAMES: jint = 1024;
+/// The largest body of a record, whose length is a `u4`.
+const MAX_RECORD_LENGTH: usize = u32::MAX as usize;
+
+/// Errors returned by [`dump_heap`].
+#[derive(Debug)]
+pub enum HprofError {
+    /// A JVMTI function failed.
+    Jvmti(jvmtiError),
+    /// Writing the dump failed.
+    Io(io::Error),
+}
+
+impl From<jvmtiError> for HprofError {
+    fn from(err: jvmtiError) -> Self {
+        HprofError::Jvmti(err)
+    }
+}
+
+impl From<io::Error> for HprofError {
+    fn from(err: io::Error) -> Self {
+        HprofError::Io(err)
+    }
+}
+
+impl fmt::Display for HprofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HprofError::Jvmti(err) => write!(f, "JVMTI call failed: {err:?}"),
+            HprofError::Io(err) => write!(f, "failed to write heap dump: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for HprofError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HprofError::Jvmti(_) => None,
+            HprofError::Io(err) => Some(err),
+        }
+    }
+}
+
+/// Options for [`dump_heap`].
+#[derive(Clone, Copy, Debug)]
+pub struct HprofOptions {
+    /// Force a garbage collection first so that only live objects are dumped.
+    pub live: bool,
+    /// The size in bytes after which a `HEAP DUMP SEGMENT` record is closed
+    /// and a new one started.
+    pub segment_size: usize,
+}
+
+impl Default for HprofOptions {
+    fn default() -> Self {
+        HprofOptions {
+            live: false,
+            segment_size: 1 << 20,
+        }
+    }
+}
+
+/// Writes an HPROF heap dump to the file at `path`.
+///
+/// # Safety
+///
+/// See [`dump_heap`].
+pub unsafe fn dump_heap_to_file(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    path: impl AsRef<Path>,
+    options: HprofOptions,
+) -> Result<(), HprofError> {
+    let file = BufWriter::new(File::create(path)?);
+    dump_heap(env, jni, file, options)?.flush()?;
+    Ok(())
+}
+
+/// Writes an HPROF heap dump to `out` and returns it.
+///
+/// # Safety
+///
+/// `env` must be a valid JVMTI environment that no other thread tags objects
+/// in during the dump, and `jni` the JNI environment of the current thread.
+pub unsafe fn dump_heap<W: Write>(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    out: W,
+    options: HprofOptions,
+) -> Result<W, HprofError> {
+    if options.live {
+        check(jvmti!(env, v1, ForceGarbageCollection))?;
+    }
+    let mut ids = Ids::default();
+    let original_tags = retag_tagged_objects(env, &mut ids)?;
+    let result = write_dump(env, jni, out, options, ids, &original_tags);
+    restore_tags(env, &original_tags)?;
+    result
+}
+
+/// Gives every object that is already tagged an HPROF id as its tag and
+/// returns the original tags by id.
+unsafe fn retag_tagged_objects(
+    env: *mut jvmtiEnv,
+    ids: &mut Ids,
+) -> Result<HashMap<u64, jlong>, jvmtiError> {
+    let mut original_tags = HashMap::new();
+    HeapVisitor::new()
+        .heap_iteration(|object| {
+            let id = ids.next();
+            original_tags.insert(id, object.tag.get());
+            object.tag.set(id as jlong);
+            VisitControl::Continue
+        })
+        .iterate_through_heap(env, HeapFilter::UNTAGGED, null_mut())?;
+    Ok(original_tags)
+}
+
+/// Puts back the tags replaced by [`retag_tagged_objects`] and clears the ids
+/// the dump assigned to all other objects.
+unsafe fn restore_tags(
+    env: *mut jvmtiEnv,
+    original_tags: &HashMap<u64, jlong>,
+) -> Result<(), jvmtiError> {
+    HeapVisitor::new()
+        .heap_iteration(|object| {
+            let id = object.tag.get() as u64;
+            object.tag.set(original_tags.get(&id).copied().unwrap_or(0));
+            VisitControl::Continue
+        })
+        .iterate_through_heap(env, HeapFilter::UNTAGGED, null_mut())
+}
+
+unsafe fn write_dump<W: Write>(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    out: W,
+    options: HprofOptions,
+    mut ids: Ids,
+    original_tags: &HashMap<u64, jlong>,
+) -> Result<W, HprofError> {
+    let mut writer = Writer::new(out, options.segment_size);
+    writer.header()?;
+
+    let classes = load_classes(env, jni, &mut ids)?;
+    let (stacks, frames) = thread_stacks(env, jni, &mut ids)?;
+    let strings = StringLayout::new(&classes, &ids);
+    for (string, &id) in &ids.strings {
+        writer.utf8(id, string.as_bytes())?;
+    }
+    writer.stack_trace(STACK_TRACE_SERIAL, 0, &[])?;
+    let mut class_serials = HashMap::with_capacity(classes.len());
+    for (serial, class) in classes.values().enumerate() {
+        writer.load_class(serial as u32 + 1, class.id, class.name_id)?;
+        class_serials.insert(class.id, serial as u32 + 1);
+    }
+    for frame in &frames {
+        let class_serial = class_serials.get(&frame.class_id).copied().unwrap_or(0);
+        writer.stack_frame(frame, class_serial)?;
+    }
+    let mut threads = HashMap::with_capacity(stacks.len());
+    for (index, stack) in stacks.iter().enumerate() {
+        let serial = index as u32 + 1;
+        let trace_serial = STACK_TRACE_SERIAL + serial;
+        writer.stack_trace(trace_serial, serial, &stack.frames)?;
+        threads.insert(stack.id, (serial, trace_serial, stack.frames.len()));
+    }
+
+    let dump = RefCell::new(Dump {
+        writer,
+        classes: &classes,
+        strings,
+        next_id: ids.next,
+        retagged: original_tags
+            .keys()
+            .filter(|id| !classes.contains_key(id))
+            .chain(stacks.iter().map(|stack| &stack.id))
+            .copied()
+            .collect(),
+        pending: None,
+        undumped: HashMap::new(),
+        threads,
+        dumped_classes: HashSet::new(),
+        error: None,
+    });
+
+    HeapVisitor::new()
+        .heap_reference(|event| dump.borrow_mut().reference(event))
+        .primitive_field(|event| {
+            let mut dump = dump.borrow_mut();
+            let id = event.object_tag.get() as u64;
+            let index = match event.reference {
+                HeapReference::Field { index } | HeapReference::StaticField { index } => index,
+                _ => return VisitControl::Continue,
+            };
+            dump.pending(id, event.object_class_tag as u64)
+                .fields
+                .push((index, Value::Primitive(event.value)));
+            dump.control()
+        })
+        .array_primitive_value(|event| {
+            let mut dump = dump.borrow_mut();
+            let id = event.tag.get() as u64;
+            dump.pending(id, event.class_tag as u64).primitive_array = Some(event.elements.into());
+            dump.control()
+        })
+        .string_primitive_value(|event| {
+            let mut dump = dump.borrow_mut();
+            let id = event.tag.get() as u64;
+            if dump.strings.is_some() {
+                dump.pending(id, event.class_tag as u64).string = Some(event.value.to_vec());
+            }
+            dump.control()
+        })
+        .follow_references(env, HeapFilter::empty(), null_mut(), null_mut())?;
+
+    let mut dump = dump.into_inner();
+    if let Some(err) = dump.error.take() {
+        return Err(err.into());
+    }
+    dump.flush()?;
+    // Objects without outgoing references or primitive values, such as empty
+    // arrays, are never reported as referrers.
+    let undumped: Vec<_> = dump.undumped.keys().copied().collect();
+    for id in undumped {
+        let (class_id, _) = dump.undumped[&id];
+        dump.pending = Some(Pending::new(id, class_id));
+        dump.flush()?;
+    }
+    for class in classes.values() {
+        if !dump.dumped_classes.contains(&class.id) {
+            dump.class_dump(class.id, &Pending::new(class.id, 0))?;
+        }
+    }
+    Ok(dump.writer.finish()?)
+}
+
+/// HPROF basic types.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BasicType {
+    Object = 2,
+    Boolean = 4,
+    Char = 5,
+    Float = 6,
+    Double = 7,
+    Byte = 8,
+    Short = 9,
+    Int = 10,
+    Long = 11,
+}
+
+impl BasicType {
+    fn from_descriptor(descriptor: &str) -> Self {
+        match descriptor.as_bytes().first() {
+            Some(b'Z') => BasicType::Boolean,
+            Some(b'C') => BasicType::Char,
+            Some(b'F') => BasicType::Float,
+            Some(b'D') => BasicType::Double,
+            Some(b'B') => BasicType::Byte,
+            Some(b'S') => BasicType::Short,
+            Some(b'I') => BasicType::Int,
+            Some(b'J') => BasicType::Long,
+            _ => BasicType::Object,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            BasicType::Object => 8,
+            BasicType::Boolean | BasicType::Byte => 1,
+            BasicType::Char | BasicType::Short => 2,
+            BasicType::Float | BasicType::Int => 4,
+            BasicType::Double | BasicType::Long => 8,
+        }
+    }
+}
+
+/// Big-endian serialization into record bodies.
+trait Put {
+    fn u1(&mut self, value: u8);
+    fn u2(&mut self, value: u16);
+    fn u4(&mut self, value: u32);
+    fn u8(&mut self, value: u64);
+}
+
+impl Put for Vec<u8> {
+    fn u1(&mut self, value: u8) {
+        self.push(value);
+    }
+
+    fn u2(&mut self, value: u16) {
+        self.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u4(&mut self, value: u32) {
+        self.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u8(&mut self, value: u64) {
+        self.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+struct Writer<W> {
+    out: W,
+    segment: Vec<u8>,
+    /// The length of `segment` before the sub-record being written.
+    sub_record_start: usize,
+    segment_size: usize,
+}
+
+impl<W: Write> Writer<W> {
+    fn new(out: W, segment_size: usize) -> Self {
+        Writer {
+            out,
+            segment: Vec::new(),
+            sub_record_start: 0,
+            segment_size,
+        }
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        let mut header = b"JAVA PROFILE 1.0.2\0".to_vec();
+        header.u4(BasicType::Object.size() as u32);
+        header.u8(millis);
+        self.out.write_all(&header)
+    }
+
+    fn record(&mut self, tag: u8, body: &[u8]) -> io::Result<()> {
+        let mut header = Vec::with_capacity(9);
+        header.u1(tag);
+        header.u4(0);
+        header.u4(body.len() as u32);
+        self.out.write_all(&header)?;
+        self.out.write_all(body)
+    }
+
+    fn utf8(&mut self, id: u64, value: &[u8]) -> io::Result<()> {
+        let mut body = Vec::with_capacity(8 + value.len());
+        body.u8(id);
+        body.extend_from_slice(value);
+        self.record(TAG_UTF8, &body)
+    }
+
+    fn stack_trace(&mut self, serial: u32, thread_serial: u32, frames: &[u64]) -> io::Result<()> {
+        let mut body = Vec::with_capacity(12 + 8 * frames.len());
+        body.u4(serial);
+        body.u4(thread_serial);
+        body.u4(frames.len() as u32);
+        for &frame in frames {
+            body.u8(frame);
+        }
+        self.record(TAG_STACK_TRACE, &body)
+    }
+
+    fn stack_frame(&mut self, frame: &Frame, class_serial: u32) -> io::Result<()> {
+        let mut body = Vec::with_capacity(40);
+        body.u8(frame.id);
+        body.u8(frame.name_id);
+        body.u8(frame.signature_id);
+        body.u8(frame.source_file_id);
+        body.u4(class_serial);
+        body.u4(frame.line as u32);
+        self.record(TAG_STACK_FRAME, &body)
+    }
+
+    fn load_class(&mut self, serial: u32, id: u64, name_id: u64) -> io::Result<()> {
+        let mut body = Vec::new();
+        body.u4(serial);
+        body.u8(id);
+        body.u4(STACK_TRACE_SERIAL);
+        body.u8(name_id);
+        self.record(TAG_LOAD_CLASS, &body)
+    }
+
+    /// Returns the buffer of the current heap dump segment.
+    fn heap(&mut self) -> &mut Vec<u8> {
+        &mut self.segment
+    }
+
+    /// Closes the current segment if it has grown past the segment size.
+    /// A sub-record that would take the segment past the longest record is
+    /// moved to the next segment.
+    fn end_sub_record(&mut self) -> io::Result<()> {
+        if self.segment.len() > MAX_RECORD_LENGTH {
+            let sub_record = self.segment.split_off(self.sub_record_start);
+            if sub_record.len() > MAX_RECORD_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "heap dump sub-record longer than a record can be",
+                ));
+            }
+            self.flush_segment()?;
+            self.segment = sub_record;
+        }
+        if self.segment.len() >= self.segment_size {
+            self.flush_segment()?;
+        }
+        self.sub_record_start = self.segment.len();
+        Ok(())
+    }
+
+    fn flush_segment(&mut self) -> io::Result<()> {
+        if self.segment.is_empty() {
+            return Ok(());
+        }
+        let segment = core::mem::take(&mut self.segment);
+        self.record(TAG_HEAP_DUMP_SEGMENT, &segment)?;
+        self.segment = segment;
+        self.segment.clear();
+        self.sub_record_start = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.flush_segment()?;
+        self.record(TAG_HEAP_DUMP_END, &[])?;
+        Ok(self.out)
+    }
+}
+
+/// Hands out HPROF ids for objects and strings. Object ids double as tags.
+#[derive(Default)]
+struct Ids {
+    next: u64,
+    strings: HashMap<String, u64>,
+}
+
+impl Ids {
+    fn next(&mut self) -> u64 {
+        self.next += 1;
+        self.next
+    }
+
+    fn string(&mut self, value: &str) -> u64 {
+        if let Some(&id) = self.strings.get(value) {
+            return id;
+        }
+        let id = self.next();
+        self.strings.insert(value.to_owned(), id);
+        id
+    }
+}
+
+struct Field {
+    name_id: u64,
+    ty: BasicType,
+    is_static: bool,
+}
+
+struct ClassInfo {
+    id: u64,
+    name: String,
+    name_id: u64,
+    super_id: u64,
+    /// Declared fields in `GetClassFields` order.
+    fields: Vec<Field>,
+    layout: Layout,
+}
+
+/// Tags every loaded class with its HPROF id and collects what is needed to
+/// lay out class and instance dumps.
+unsafe fn load_classes(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    ids: &mut Ids,
+) -> Result<HashMap<u64, ClassInfo>, jvmtiError> {
+    let mut count = 0;
+    let mut classes = null_mut();
+    check(jvmti!(env, v1, GetLoadedClasses, &mut count, &mut classes))?;
+    let classes = take_array(env, classes, count);
+    let infos = class_infos(env, jni, &classes, ids);
+    delete_local_refs(jni, &classes);
+    infos
+}
+
+unsafe fn class_infos(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    classes: &[jclass],
+    ids: &mut Ids,
+) -> Result<HashMap<u64, ClassInfo>, jvmtiError> {
+    for &klass in classes {
+        // Classes that were tagged before the dump already carry an id.
+        if tag_of(env, klass)? == 0 {
+            check(jvmti!(env, v1, SetTag, klass, ids.next() as jlong))?;
+        }
+    }
+
+    let mut infos = HashMap::with_capacity(classes.len());
+    for &klass in classes {
+        let info = class_info(env, jni, klass, ids)?;
+        infos.insert(info.id, info);
+    }
+    Ok(infos)
+}
+
+unsafe fn class_info(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    klass: jclass,
+    ids: &mut Ids,
+) -> Result<ClassInfo, jvmtiError> {
+    let id = tag_of(env, klass)?;
+
+    let mut signature = null_mut();
+    check(jvmti!(
+        env,
+        v1,
+        GetClassSignature,
+        klass,
+        &mut signature,
+        null_mut()
+    ))?;
+    let signature = take_string(env, signature).unwrap_or_default();
+    let name = match signature
+        .strip_prefix('L')
+        .and_then(|s| s.strip_suffix(';'))
+    {
+        Some(name) => name.to_owned(),
+        None => signature,
+    };
+
+    let superclass = ((**jni).v1_1.GetSuperclass)(jni, klass);
+    let super_id = tag_of(env, superclass);
+    delete_local_refs(jni, &[superclass]);
+    let super_id = super_id?;
+
+    let class_fields = ClassFields::new(env, jni, klass)?;
+    let fields = class_fields
+        .declared()
+        .iter()
+        .map(|field| Field {
+            name_id: ids.string(&field.name),
+            ty: BasicType::from_descriptor(&field.signature),
+            is_static: field.is_static(),
+        })
+        .collect();
+
+    Ok(ClassInfo {
+        id,
+        name_id: ids.string(&name),
+        name,
+        super_id,
+        fields,
+        layout: Layout::new(&class_fields),
+    })
+}
+
+/// A `STACK FRAME` record.
+struct Frame {
+    id: u64,
+    name_id: u64,
+    signature_id: u64,
+    /// 0 if unknown.
+    source_file_id: u64,
+    class_id: u64,
+    /// The line number, -1 if unknown and -3 for native methods.
+    line: jint,
+}
+
+/// The stack of a thread, as frame ids from the top.
+struct ThreadStack {
+    id: u64,
+    frames: Vec<u64>,
+}
+
+/// Tags every thread with its HPROF id, unless it already has one, and
+/// collects the stacks of the threads and the frames on them.
+unsafe fn thread_stacks(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    ids: &mut Ids,
+) -> Result<(Vec<ThreadStack>, Vec<Frame>), jvmtiError> {
+    let mut infos = null_mut();
+    let mut count = 0;
+    check(jvmti!(
+        env,
+        v1,
+        GetAllStackTraces,
+        MAX_FRAMES,
+        &mut infos,
+        &mut count
+    ))?;
+    let infos: &[jvmtiStackInfo] = match infos.is_null() {
+        true => &[],
+        false => core::slice::from_raw_parts(infos, count.max(0) as usize),
+    };
+    let result = collect_stacks(env, jni, infos, ids);
+    for info in infos {
+        delete_local_refs(jni, &[info.thread]);
+    }
+    deallocate(env, infos.as_ptr() as *mut jvmtiStackInfo);
+    result
+}
+
+unsafe fn collect_stacks(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    infos: &[jvmtiStackInfo],
+    ids: &mut Ids,
+) -> Result<(Vec<ThreadStack>, Vec<Frame>), jvmtiError> {
+    let mut stacks = Vec::with_capacity(infos.len());
+    let mut frames = Vec::new();
+    let mut frame_ids = HashMap::new();
+    let mut methods = HashMap::new();
+    for info in infos {
+        let mut id = tag_of(env, info.thread)?;
+        if id == 0 {
+            id = ids.next();
+            check(jvmti!(env, v1, SetTag, info.thread, id as jlong))?;
+        }
+        let buffer: &[jvmtiFrameInfo] = match info.frame_buffer.is_null() {
+            true => &[],
+            false => {
+                core::slice::from_raw_parts(info.frame_buffer, info.frame_count.max(0) as usize)
+            }
+        };
+        let mut stack = ThreadStack {
+            id,
+            frames: Vec::with_capacity(buffer.len()),
+        };
+        for frame in buffer {
+            let key = (frame.method as usize, frame.location);
+            if let Some(&frame_id) = frame_ids.get(&key) {
+                stack.frames.push(frame_id);
+                continue;
+            }
+            let (method, class_id) = match methods.entry(key.0) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(frame_method(env, jni, frame.method)?),
+            };
+            let line = match frame.location {
+                -1 => -3,
+                location => method.line_table.line_for(location).unwrap_or(-1),
+            };
+            let id = ids.next();
+            frames.push(Frame {
+                id,
+                name_id: ids.string(&method.name),
+                signature_id: ids.string(&method.signature),
+                source_file_id: method
+                    .source_file
+                    .as_deref()
+                    .map_or(0, |file| ids.string(file)),
+                class_id: *class_id,
+                line,
+            });
+            frame_ids.insert(key, id);
+            stack.frames.push(id);
+        }
+        stacks.push(stack);
+    }
+    Ok((stacks, frames))
+}
+
+/// Returns the metadata of `method` and the id of its declaring class.
+unsafe fn frame_method(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    method: jmethodID,
+) -> Result<(MethodInfo, u64), jvmtiError> {
+    let info = MethodInfo::resolve(env, method)?;
+    let mut klass = null_mut();
+    check(jvmti!(env, v1, GetMethodDeclaringClass, method, &mut klass))?;
+    let class_id = tag_of(env, klass);
+    delete_local_refs(jni, &[klass]);
+    Ok((info, class_id?))
+}
+
+unsafe fn tag_of(env: *mut jvmtiEnv, object: jclass) -> Result<u64, jvmtiError> {
+    if object.is_null() {
+        return Ok(0);
+    }
+    let mut tag = 0;
+    check(jvmti!(env, v1, GetTag, object, &mut tag))?;
+    Ok(tag as u64)
+}
+
+/// Where a JVMTI field index points to, relative to the class that owns the
+/// index space.
+#[derive(Clone, Copy)]
+enum Slot {
+    /// Position of an instance field in the instance dump.
+    Instance(usize),
+    /// Position of a static field among the class' own fields.
+    Static(usize),
+    /// A static field of a superclass.
+    Inherited,
+}
+
+/// Maps the JVMTI field indices of a class to HPROF positions.
+///
+/// HPROF lists instance fields from the class up to `java.lang.Object`,
+/// the reverse of the JVMTI order.
+struct Layout {
+    base: usize,
+    slots: Vec<Slot>,
+    instance_types: Vec<BasicType>,
+}
+
+impl Layout {
+    fn new(fields: &ClassFields) -> Self {
+        let base = fields.interface_field_count();
+        let mut slots = vec![Slot::Inherited; fields.fields().len() - base];
+        let mut instance_types = Vec::new();
+        let mut end = slots.len();
+        for (depth, declared) in fields.hierarchy().enumerate() {
+            end -= declared.len();
+            for (i, field) in declared.iter().enumerate() {
+                slots[end + i] = if !field.is_static() {
+                    instance_types.push(BasicType::from_descriptor(&field.signature));
+                    Slot::Instance(instance_types.len() - 1)
+                } else if depth == 0 {
+                    Slot::Static(i)
+                } else {
+                    Slot::Inherited
+                };
+            }
+        }
+        Layout {
+            base,
+            slots,
+            instance_types,
+        }
+    }
+
+    fn slot(&self, index: jint) -> Option<Slot> {
+        let index = (index as usize).checked_sub(self.base)?;
+        self.slots.get(index).copied()
+    }
+
+    fn instance_size(&self) -> usize {
+        self.instance_types.iter().map(|ty| ty.size()).sum()
+    }
+}
+
+enum Value {
+    Object(u64),
+    Primitive(JavaValue),
+}
+
+struct PrimitiveElements {
+    ty: BasicType,
+    count: u32,
+    bytes: Vec<u8>,
+}
+
+impl From<PrimitiveArray<'_>> for PrimitiveElements {
+    fn from(elements: PrimitiveArray<'_>) -> Self {
+        fn convert<T, const N: usize>(
+            ty: BasicType,
+            values: &[T],
+            to_bytes: impl Fn(&T) -> [u8; N],
+        ) -> PrimitiveElements {
+            PrimitiveElements {
+                ty,
+                count: values.len() as u32,
+                bytes: values.iter().flat_map(to_bytes).collect(),
+            }
+        }
+        match elements {
+            PrimitiveArray::Boolean(values) => convert(BasicType::Boolean, values, |v| [*v]),
+            PrimitiveArray::Byte(values) => convert(BasicType::Byte, values, |v| v.to_be_bytes()),
+            PrimitiveArray::Char(values) => convert(BasicType::Char, values, |v| v.to_be_bytes()),
+            PrimitiveArray::Short(values) => convert(BasicType::Short, values, |v| v.to_be_bytes()),
+            PrimitiveArray::Int(values) => convert(BasicType::Int, values, |v| v.to_be_bytes()),
+            PrimitiveArray::Long(values) => convert(BasicType::Long, values, |v| v.to_be_bytes()),
+            PrimitiveArray::Float(values) => convert(BasicType::Float, values, |v| v.to_be_bytes()),
+            PrimitiveArray::Double(values) => {
+                convert(BasicType::Double, values, |v| v.to_be_bytes())
+            }
+        }
+    }
+}
+
+/// Everything reported about the object the VM is currently visiting.
+struct Pending {
+    id: u64,
+    class_id: u64,
+    /// Instance fields, or static fields for class objects.
+    fields: Vec<(jint, Value)>,
+    elements: Vec<(jint, u64)>,
+    primitive_array: Option<PrimitiveElements>,
+    /// The contents of a `java.lang.String`.
+    string: Option<Vec<u16>>,
+    loader: u64,
+    signers: u64,
+    protection_domain: u64,
+    constant_pool: Vec<(u16, u64)>,
+}
+
+impl Pending {
+    fn new(id: u64, class_id: u64) -> Self {
+        Pending {
+            id,
+            class_id,
+            fields: Vec::new(),
+            elements: Vec::new(),
+            primitive_array: None,
+            string: None,
+            loader: 0,
+            signers: 0,
+            protection_domain: 0,
+            constant_pool: Vec::new(),
+        }
+    }
+}
+
+struct Dump<'c, W> {
+    writer: Writer<W>,
+    classes: &'c HashMap<u64, ClassInfo>,
+    strings: Option<StringLayout>,
+    next_id: u64,
+    /// Objects other than classes that were tagged before the walk and have
+    /// not been discovered by it yet.
+    retagged: HashSet<u64>,
+    pending: Option<Pending>,
+    /// Class ids and array lengths of objects that have been discovered but
+    /// not dumped yet.
+    undumped: HashMap<u64, (u64, Option<jint>)>,
+    /// Thread object ids to thread and stack trace serials and the number of
+    /// frames of the stack trace.
+    threads: HashMap<u64, (u32, u32, usize)>,
+    dumped_classes: HashSet<u64>,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Dump<'_, W> {
+    fn control(&self) -> VisitControl {
+        match self.error {
+            Some(_) => VisitControl::Abort,
+            None => VisitControl::Continue,
+        }
+    }
+
+    fn reference(&mut self, event: crate::heap::HeapReferenceEvent<'_>) -> VisitControl {
+        let mut id = event.tag.get() as u64;
+        let discovered = if id == 0 {
+            self.next_id += 1;
+            id = self.next_id;
+            event.tag.set(id as jlong);
+            true
+        } else {
+            self.retagged.remove(&id)
+        };
+        if discovered {
+            self.undumped
+                .insert(id, (event.class_tag as u64, event.length));
+        }
+
+        let Some(referrer_tag) = event.referrer_tag else {
+            if let Err(err) = self.root(event.reference, id) {
+                self.error = Some(err);
+            }
+            return self.control();
+        };
+        let pending = self.pending(referrer_tag.get() as u64, event.referrer_class_tag as u64);
+        match event.reference {
+            HeapReference::Field { index } | HeapReference::StaticField { index } => {
+                pending.fields.push((index, Value::Object(id)))
+            }
+            HeapReference::ArrayElement { index } => pending.elements.push((index, id)),
+            HeapReference::ClassLoader => pending.loader = id,
+            HeapReference::Signers => pending.signers = id,
+            HeapReference::ProtectionDomain => pending.protection_domain = id,
+            HeapReference::ConstantPool { index } => pending.constant_pool.push((index as u16, id)),
+            _ => {}
+        }
+        self.control()
+    }
+
+    /// Returns the pending object `id`, dumping the previous one if the VM
+    /// moved on to another object.
+    fn pending(&mut self, id: u64, class_id: u64) -> &mut Pending {
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.id != id)
+        {
+            if let Err(err) = self.flush() {
+                self.error = Some(err);
+            }
+        }
+        self.pending
+            .get_or_insert_with(|| Pending::new(id, class_id))
+    }
+
+    /// Returns the serial of the thread tagged `thread_tag` and the frame
+    /// number of the frame at `depth` in its stack trace, -1 if it has none.
+    fn thread_frame(&self, thread_tag: jlong, depth: jint) -> (u32, u32) {
+        match self.threads.get(&(thread_tag as u64)) {
+            Some(&(serial, _, frames)) if (0..frames as jint).contains(&depth) => {
+                (serial, depth as u32)
+            }
+            Some(&(serial, _, _)) => (serial, u32::MAX),
+            None => (0, u32::MAX),
+        }
+    }
+
+    fn root(&mut self, reference: HeapReference, id: u64) -> io::Result<()> {
+        let heap = self.writer.heap();
+        match reference {
+            HeapReference::JniGlobal => {
+                heap.u1(ROOT_JNI_GLOBAL);
+                heap.u8(id);
+                heap.u8(0);
+            }
+            HeapReference::SystemClass => {
+                heap.u1(ROOT_STICKY_CLASS);
+                heap.u8(id);
+            }
+            HeapReference::Monitor => {
+                heap.u1(ROOT_MONITOR_USED);
+                heap.u8(id);
+            }
+            HeapReference::StackLocal(info) => {
+                let (serial, frame) = self.thread_frame(info.thread_tag, info.depth);
+                let heap = self.writer.heap();
+                heap.u1(ROOT_JAVA_FRAME);
+                heap.u8(id);
+                heap.u4(serial);
+                heap.u4(frame);
+            }
+            HeapReference::JniLocal(info) => {
+                let (serial, frame) = self.thread_frame(info.thread_tag, info.depth);
+                let heap = self.writer.heap();
+                heap.u1(ROOT_JNI_LOCAL);
+                heap.u8(id);
+                heap.u4(serial);
+                heap.u4(frame);
+            }
+            HeapReference::Thread => {
+                // Threads started after the stacks were taken have none.
+                let serial = self.threads.len() as u32 + 1;
+                let (serial, trace_serial, _) =
+                    *self
+                        .threads
+                        .entry(id)
+                        .or_insert((serial, STACK_TRACE_SERIAL, 0));
+                let heap = self.writer.heap();
+                heap.u1(ROOT_THREAD_OBJECT);
+                heap.u8(id);
+                heap.u4(serial);
+                heap.u4(trace_serial);
+            }
+            _ => {
+                heap.u1(ROOT_UNKNOWN);
+                heap.u8(id);
+            }
+        }
+        self.writer.end_sub_record()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+        let length = self
+            .undumped
+            .remove(&pending.id)
+            .and_then(|(_, length)| length);
+        if self.classes.contains_key(&pending.id) {
+            return self.class_dump(pending.id, &pending);
+        }
+        let class = self.classes.get(&pending.class_id);
+        let name = class.map_or("", |class| class.name.as_str());
+        if let Some(elements) = &pending.primitive_array {
+            self.primitive_array_dump(pending.id, elements)
+        } else if name.len() == 2 && name.starts_with('[') {
+            let elements = PrimitiveElements {
+                ty: BasicType::from_descriptor(&name[1..]),
+                count: 0,
+                bytes: Vec::new(),
+            };
+            self.primitive_array_dump(pending.id, &elements)
+        } else if name.starts_with('[') {
+            self.object_array_dump(&pending, length.unwrap_or(0))
+        } else {
+            self.instance_dump(&pending)
+        }
+    }
+
+    fn class_dump(&mut self, id: u64, pending: &Pending) -> io::Result<()> {
+        self.dumped_classes.insert(id);
+        let class = &self.classes[&id];
+        let layout = &class.layout;
+
+        let statics: Vec<&Field> = class.fields.iter().filter(|f| f.is_static).collect();
+        let mut static_values = vec![None; class.fields.len()];
+        for (index, value) in &pending.fields {
+            if let Some(Slot::Static(i)) = layout.slot(*index) {
+                static_values[i] = Some(value);
+            }
+        }
+
+        let heap = self.writer.heap();
+        heap.u1(CLASS_DUMP);
+        heap.u8(id);
+        heap.u4(STACK_TRACE_SERIAL);
+        heap.u8(class.super_id);
+        heap.u8(pending.loader);
+        heap.u8(pending.signers);
+        heap.u8(pending.protection_domain);
+        heap.u8(0);
+        heap.u8(0);
+        heap.u4(layout.instance_size() as u32);
+
+        heap.u2(pending.constant_pool.len() as u16);
+        for &(index, value) in &pending.constant_pool {
+            heap.u2(index);
+            heap.u1(BasicType::Object as u8);
+            heap.u8(value);
+        }
+
+        heap.u2(statics.len() as u16);
+        for (i, field) in class.fields.iter().enumerate() {
+            if !field.is_static {
+                continue;
+            }
+            heap.u8(field.name_id);
+            heap.u1(field.ty as u8);
+            put_value(heap, field.ty, static_values[i]);
+        }
+
+        heap.u2((class.fields.len() - statics.len()) as u16);
+        for field in class.fields.iter().filter(|f| !f.is_static) {
+            heap.u8(field.name_id);
+            heap.u1(field.ty as u8);
+        }
+        self.writer.end_sub_record()
+    }
+
+    fn instance_dump(&mut self, pending: &Pending) -> io::Result<()> {
+        let layout = self
+            .classes
+            .get(&pending.class_id)
+            .map(|class| &class.layout);
+        let types = layout.map_or(&[][..], |layout| &layout.instance_types);
+        let mut values = vec![None; types.len()];
+        if let Some(layout) = layout {
+            for (index, value) in &pending.fields {
+                if let Some(Slot::Instance(i)) = layout.slot(*index) {
+                    values[i] = Some(value);
+                }
+            }
+        }
+
+        let backing_array;
+        if let (Some(strings), Some(contents)) = (&self.strings, &pending.string) {
+            if strings.class_id == pending.class_id && values[strings.value].is_none() {
+                let coder = strings.coder.and_then(|coder| match values[coder] {
+                    Some(Value::Primitive(JavaValue::Byte(coder))) => Some(*coder),
+                    _ => None,
+                });
+                self.next_id += 1;
+                backing_array = Value::Object(self.next_id);
+                values[strings.value] = Some(&backing_array);
+                self.primitive_array_dump(self.next_id, &string_elements(contents, coder))?;
+            }
+        }
+
+        let heap = self.writer.heap();
+        heap.u1(INSTANCE_DUMP);
+        heap.u8(pending.id);
+        heap.u4(STACK_TRACE_SERIAL);
+        heap.u8(pending.class_id);
+        heap.u4(types.iter().map(|ty| ty.size()).sum::<usize>() as u32);
+        for (&ty, value) in types.iter().zip(values) {
+            put_value(heap, ty, value);
+        }
+        self.writer.end_sub_record()
+    }
+
+    fn object_array_dump(&mut self, pending: &Pending, length: jint) -> io::Result<()> {
+        let mut elements = vec![0; length.max(0) as usize];
+        for &(index, id) in &pending.elements {
+            if let Some(element) = elements.get_mut(index as usize) {
+                *element = id;
+            }
+        }
+
+        let heap = self.writer.heap();
+        heap.u1(OBJ_ARRAY_DUMP);
+        heap.u8(pending.id);
+        heap.u4(STACK_TRACE_SERIAL);
+        heap.u4(elements.len() as u32);
+        heap.u8(pending.class_id);
+        for element in elements {
+            heap.u8(element);
+        }
+        self.writer.end_sub_record()
+    }
+
+    fn primitive_array_dump(&mut self, id: u64, elements: &PrimitiveElements) -> io::Result<()> {
+        let heap = self.writer.heap();
+        heap.u1(PRIM_ARRAY_DUMP);
+        heap.u8(id);
+        heap.u4(STACK_TRACE_SERIAL);
+        heap.u4(elements.count);
+        heap.u1(elements.ty as u8);
+        heap.extend_from_slice(&elements.bytes);
+        self.writer.end_sub_record()
+    }
+}
+
+/// Where the contents of `java.lang.String` instances go.
+struct StringLayout {
+    class_id: u64,
+    /// Position of the `value` field in instance dumps.
+    value: usize,
+    /// Position of the `coder` field of compact strings.
+    coder: Option<usize>,
+}
+
+impl StringLayout {
+    fn new(classes: &HashMap<u64, ClassInfo>, ids: &Ids) -> Option<Self> {
+        let class = classes
+            .values()
+            .find(|class| class.name == "java/lang/String")?;
+        // `java.lang.Object` has no instance fields, so the instance fields of
+        // a string are its declared ones.
+        let position = |name: &str| {
+            let name_id = ids.strings.get(name)?;
+            class
+                .fields
+                .iter()
+                .filter(|field| !field.is_static)
+                .position(|field| field.name_id == *name_id)
+        };
+        Some(StringLayout {
+            class_id: class.id,
+            value: position("value")?,
+            coder: position("coder"),
+        })
+    }
+}
+
+/// Builds the backing array of a string the way the VM stores it: `char[]`
+/// without a coder, otherwise `byte[]` in Latin-1 or native UTF-16.
+fn string_elements(contents: &[u16], coder: Option<i8>) -> PrimitiveElements {
+    let (ty, count, bytes) = match coder {
+        None => (
+            BasicType::Char,
+            contents.len(),
+            contents.iter().flat_map(|c| c.to_be_bytes()).collect(),
+        ),
+        Some(0) => (
+            BasicType::Byte,
+            contents.len(),
+            contents.iter().map(|&c| c as u8).collect(),
+        ),
+        Some(_) => (
+            BasicType::Byte,
+            contents.len() * 2,
+            contents.iter().flat_map(|c| c.to_ne_bytes()).collect(),
+        ),
+    };
+    PrimitiveElements {
+        ty,
+        count: count as u32,
+        bytes,
+    }
+}
+
+fn put_value(heap: &mut Vec<u8>, ty: BasicType, value: Option<&Value>) {
+    match value {
+        Some(Value::Object(id)) => heap.u8(*id),
+        Some(Value::Primitive(value)) => match *value {
+            JavaValue::Boolean(v) => heap.u1(v as u8),
+            JavaValue::Byte(v) => heap.u1(v as u8),
+            JavaValue::Char(v) => heap.u2(v),
+            JavaValue::Short(v) => heap.u2(v as u16),
+            JavaValue::Int(v) => heap.u4(v as u32),
+            JavaValue::Long(v) => heap.u8(v as u64),
+            JavaValue::Float(v) => heap.u4(v.to_bits()),
+            JavaValue::Double(v) => heap.u8(v.to_bits()),
+            JavaValue::Object(_) => heap.u8(0),
+        },
+        None => heap.extend(core::iter::repeat_n(0, ty.size())),
+    }
+}
@@ -0,0 +1,403 @@
+//! jitdump files for `perf inject --jit`.
+//!
+//! The [jitdump format] records the code of every compiled method together
+//! with its source lines, which lets `perf inject --jit` turn JIT code into
+//! ELF images that `perf report` and `perf annotate` can symbolize and
+//! disassemble. [`JitDump`] writes `jit-<pid>.dump` from the
+//! `CompiledMethodLoad` and `DynamicCodeGenerated` events, and maps the file
+//! executable once so that `perf record -k mono` notices it.
+//!
+//! Line information comes from the `jvmtiAddrLocationMap` of the event when
+//! the VM provides one, and from the inline records otherwise, as HotSpot
+//! does. The format has no unload record: perf tells apart code loaded at
+//! the same address by the timestamps of the load records.
+//!
+//! Requires the `can_generate_compiled_method_load_events` capability, and
+//! `can_get_line_numbers` and `can_get_source_file_name` for line
+//! information.
+//!
+//! [jitdump format]: https://git.kernel.org/pub/scm/linux/kernel/git/torvalds/linux.git/tree/tools/perf/Documentation/jitdump-specification.txt
+
+use core::ffi::{c_char, c_void};
+use core::ops::Range;
+use core::ptr::null_mut;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use jni_sys::{jint, jmethodID};
+
+use crate::jvmticmlr::{inline_ranges, inline_records};
+use crate::method_cache::MethodCache;
+use crate::util::check;
+use crate::{jlocation, jvmtiAddrLocationMap, jvmtiEnv, jvmtiError, jvmtiEvent, jvmtiEventMode};
+
+const MAGIC: u32 = 0x4A69_5444;
+const VERSION: u32 = 1;
+const HEADER_SIZE: u32 = 40;
+
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+const JIT_CODE_CLOSE: u32 = 3;
+
+#[cfg(target_arch = "x86_64")]
+const ELF_MACHINE: u32 = 62;
+#[cfg(target_arch = "x86")]
+const ELF_MACHINE: u32 = 3;
+#[cfg(target_arch = "aarch64")]
+const ELF_MACHINE: u32 = 183;
+#[cfg(target_arch = "arm")]
+const ELF_MACHINE: u32 = 40;
+#[cfg(target_arch = "riscv64")]
+const ELF_MACHINE: u32 = 243;
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "x86",
+    target_arch = "aarch64",
+    target_arch = "arm",
+    target_arch = "riscv64"
+)))]
+const ELF_MACHINE: u32 = 0;
+
+/// Options for [`JitDump`].
+#[derive(Clone, Debug)]
+pub struct JitDumpOptions {
+    /// The directory of the `jit-<pid>.dump` file, the temporary directory
+    /// by default.
+    pub directory: Option<PathBuf>,
+    /// Write `JIT_CODE_DEBUG_INFO` records with source lines.
+    pub debug_info: bool,
+}
+
+impl Default for JitDumpOptions {
+    fn default() -> Self {
+        JitDumpOptions {
+            directory: None,
+            debug_info: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    out: BufWriter<File>,
+    code_index: u64,
+    closed: bool,
+}
+
+/// Writes a jitdump file for the code compiled by the VM.
+#[derive(Debug)]
+pub struct JitDump {
+    path: PathBuf,
+    options: JitDumpOptions,
+    state: Mutex<State>,
+    marker: *mut c_void,
+    marker_size: usize,
+    cache: MethodCache,
+}
+
+// The marker mapping is only unmapped on drop.
+unsafe impl Send for JitDump {}
+unsafe impl Sync for JitDump {}
+
+impl JitDump {
+    /// Creates the dump file, writes its header and maps it.
+    pub fn create(options: JitDumpOptions) -> io::Result<Self> {
+        let directory = options.directory.clone().unwrap_or_else(std::env::temp_dir);
+        let path = directory.join(format!("jit-{}.dump", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+
+        // perf finds the dump through the mmap event of an executable
+        // mapping of the file.
+        let marker_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(4096) as usize;
+        let marker = unsafe {
+            libc::mmap(
+                null_mut(),
+                marker_size,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if marker == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut out = BufWriter::new(file);
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(&MAGIC.to_ne_bytes());
+        header.extend_from_slice(&VERSION.to_ne_bytes());
+        header.extend_from_slice(&HEADER_SIZE.to_ne_bytes());
+        header.extend_from_slice(&ELF_MACHINE.to_ne_bytes());
+        header.extend_from_slice(&0u32.to_ne_bytes());
+        header.extend_from_slice(&std::process::id().to_ne_bytes());
+        header.extend_from_slice(&timestamp().to_ne_bytes());
+        header.extend_from_slice(&0u64.to_ne_bytes());
+        let result = out.write_all(&header).and_then(|()| out.flush());
+        let dump = JitDump {
+            path,
+            options,
+            state: Mutex::new(State {
+                out,
+                code_index: 0,
+                closed: false,
+            }),
+            marker,
+            marker_size,
+            cache: MethodCache::new(),
+        };
+        result.map(|()| dump)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Enables the `CompiledMethodLoad` and `DynamicCodeGenerated` events and
+    /// has the VM send them for the code that already exists.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment whose callbacks forward the
+    /// events to this dump.
+    pub unsafe fn start(&self, env: *mut jvmtiEnv) -> Result<(), jvmtiError> {
+        for event in [
+            jvmtiEvent::JVMTI_EVENT_COMPILED_METHOD_LOAD,
+            jvmtiEvent::JVMTI_EVENT_DYNAMIC_CODE_GENERATED,
+        ] {
+            check(jvmti!(
+                env,
+                v1,
+                SetEventNotificationMode,
+                jvmtiEventMode::JVMTI_ENABLE,
+                event,
+                null_mut::<jni_sys::_jobject>()
+            ))?;
+        }
+        check(jvmti!(
+            env,
+            v1,
+            GenerateEvents,
+            jvmtiEvent::JVMTI_EVENT_DYNAMIC_CODE_GENERATED
+        ))?;
+        check(jvmti!(
+            env,
+            v1,
+            GenerateEvents,
+            jvmtiEvent::JVMTI_EVENT_COMPILED_METHOD_LOAD
+        ))
+    }
+
+    /// Records a compiled method with its code and, if enabled, its source
+    /// lines. Forward `CompiledMethodLoad` events here.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with the arguments of a `CompiledMethodLoad` event,
+    /// from the event callback.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn on_compiled_method_load(
+        &self,
+        env: *mut jvmtiEnv,
+        method: jmethodID,
+        code_size: jint,
+        code_addr: *const c_void,
+        map_length: jint,
+        map: *const jvmtiAddrLocationMap,
+        compile_info: *const c_void,
+    ) -> io::Result<()> {
+        let name = match self.cache.get(env, method) {
+            Ok(info) => format!("{}{}", info.qualified_name(), info.signature),
+            Err(_) => "<unknown method>".to_owned(),
+        };
+        let code = code_bytes(code_addr, code_size);
+        let lines = match self.options.debug_info {
+            true => {
+                let code = code_addr as usize..code_addr as usize + code.len();
+                self.lines(env, method, code, map_length, map, compile_info)
+            }
+            false => Vec::new(),
+        };
+        self.write_code(&name, code_addr as u64, code, &lines)
+    }
+
+    /// Records generated code such as the interpreter or stubs. Forward
+    /// `DynamicCodeGenerated` events here.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with the arguments of a `DynamicCodeGenerated` event,
+    /// from the event callback.
+    pub unsafe fn on_dynamic_code_generated(
+        &self,
+        name: *const c_char,
+        address: *const c_void,
+        length: jint,
+    ) -> io::Result<()> {
+        let name = CStr::from_ptr(name).to_string_lossy();
+        self.write_code(&name, address as u64, code_bytes(address, length), &[])
+    }
+
+    /// Writes the `JIT_CODE_CLOSE` record. Later events are ignored.
+    pub fn close(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Ok(());
+        }
+        state.closed = true;
+        let mut record = Vec::new();
+        record_header(&mut record, JIT_CODE_CLOSE);
+        finish_record(&mut record);
+        state.out.write_all(&record)?;
+        state.out.flush()
+    }
+
+    /// Returns the source lines of a compiled method as `(address, file,
+    /// line)`, sorted by address.
+    unsafe fn lines(
+        &self,
+        env: *mut jvmtiEnv,
+        method: jmethodID,
+        code: Range<usize>,
+        map_length: jint,
+        map: *const jvmtiAddrLocationMap,
+        compile_info: *const c_void,
+    ) -> Vec<(u64, String, u32)> {
+        let mut locations: Vec<(u64, jmethodID, jlocation)> = Vec::new();
+        if !map.is_null() && map_length > 0 {
+            let map = core::slice::from_raw_parts(map, map_length as usize);
+            locations.extend(
+                map.iter()
+                    .map(|entry| (entry.start_address as u64, method, entry.location)),
+            );
+        } else {
+            // Debug entries start a line, while inline records end one, so
+            // each record's line starts after the previous record. The
+            // innermost frame tells the line being executed.
+            let records = inline_records(compile_info);
+            for (range, record) in inline_ranges(&records, code) {
+                if let Some(frame) = record.and_then(|record| record.frames.first()) {
+                    locations.push((range.start as u64, frame.method, frame.location));
+                }
+            }
+        }
+        locations.sort_by_key(|&(address, _, _)| address);
+
+        let mut lines: Vec<(u64, String, u32)> = Vec::with_capacity(locations.len());
+        for (address, method, location) in locations {
+            let Ok(info) = self.cache.get(env, method) else {
+                continue;
+            };
+            let (Some(file), Some(line)) = (info.source_path(), info.line_table.line_for(location))
+            else {
+                continue;
+            };
+            let same = lines
+                .last()
+                .is_some_and(|last| last.1 == file && last.2 == line as u32);
+            if !same {
+                lines.push((address, file, line as u32));
+            }
+        }
+        lines
+    }
+
+    fn write_code(
+        &self,
+        name: &str,
+        address: u64,
+        code: &[u8],
+        lines: &[(u64, String, u32)],
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Ok(());
+        }
+        if !lines.is_empty() {
+            let mut record = Vec::new();
+            record_header(&mut record, JIT_CODE_DEBUG_INFO);
+            record.extend_from_slice(&address.to_ne_bytes());
+            record.extend_from_slice(&(lines.len() as u64).to_ne_bytes());
+            for (address, file, line) in lines {
+                record.extend_from_slice(&address.to_ne_bytes());
+                record.extend_from_slice(&line.to_ne_bytes());
+                record.extend_from_slice(&0u32.to_ne_bytes());
+                push_str(&mut record, file);
+            }
+            record.resize(record.len().next_multiple_of(8), 0);
+            finish_record(&mut record);
+            state.out.write_all(&record)?;
+        }
+
+        let mut record = Vec::with_capacity(64 + name.len() + code.len());
+        record_header(&mut record, JIT_CODE_LOAD);
+        record.extend_from_slice(&std::process::id().to_ne_bytes());
+        record.extend_from_slice(&(current_tid() as u32).to_ne_bytes());
+        record.extend_from_slice(&address.to_ne_bytes());
+        record.extend_from_slice(&address.to_ne_bytes());
+        record.extend_from_slice(&(code.len() as u64).to_ne_bytes());
+        record.extend_from_slice(&state.code_index.to_ne_bytes());
+        push_str(&mut record, name);
+        record.extend_from_slice(code);
+        finish_record(&mut record);
+        state.code_index += 1;
+        state.out.write_all(&record)?;
+        state.out.flush()
+    }
+}
+
+impl Drop for JitDump {
+    fn drop(&mut self) {
+        let _ = self.close();
+        unsafe { libc::munmap(self.marker, self.marker_size) };
+    }
+}
+
+unsafe fn code_bytes<'a>(address: *const c_void, size: jint) -> &'a [u8] {
+    if address.is_null() || size <= 0 {
+        return &[];
+    }
+    core::slice::from_raw_parts(address as *const u8, size as usize)
+}
+
+/// Starts a record with its `id`, a size to be filled in by
+/// `finish_record` and the current timestamp.
+fn record_header(record: &mut Vec<u8>, id: u32) {
+    record.extend_from_slice(&id.to_ne_bytes());
+    record.extend_from_slice(&0u32.to_ne_bytes());
+    record.extend_from_slice(&timestamp().to_ne_bytes());
+}
+
+fn finish_record(record: &mut [u8]) {
+    let size = record.len() as u32;
+    record[4..8].copy_from_slice(&size.to_ne_bytes());
+}
+
+fn push_str(record: &mut Vec<u8>, value: &str) {
+    record.extend_from_slice(value.as_bytes());
+    record.push(0);
+}
+
+/// Returns `CLOCK_MONOTONIC` in nanoseconds, the clock of `perf record -k
+/// mono`.
+fn timestamp() -> u64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+fn current_tid() -> libc::c_long {
+    unsafe { libc::syscall(libc::SYS_gettid) }
+}
@@ -1,6 +1,9 @@
 use core::ffi::{c_char, c_void};
+use core::ops::Range;
 use jni_sys::{jint, jmethodID};
 
+use crate::stack::Frame;
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub enum jvmtiCMLRKind {
@@ -16,10 +19,10 @@ pub type jvmtiCompiledMethodLoadRecordHeader = _jvmtiCompiledMethodLoadRecordHea
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct _jvmtiCompiledMethodLoadRecordHeader {
-    kind: jvmtiCMLRKind,    /* id for the kind of info passed in the record */
-    majorinfoversion: jint, /* major and minor info version values. Init'ed */
-    minorinfoversion: jint, /* to current version value in jvmtiExport.cpp. */
-    next: *mut _jvmtiCompiledMethodLoadRecordHeader,
+    pub kind: jvmtiCMLRKind,    /* id for the kind of info passed in the record */
+    pub majorinfoversion: jint, /* major and minor info version values. Init'ed */
+    pub minorinfoversion: jint, /* to current version value in jvmtiExport.cpp. */
+    pub next: *mut _jvmtiCompiledMethodLoadRecordHeader,
 }
 
 pub type PCStackInfo = _PCStackInfo;
@@ -31,10 +34,10 @@ pub type PCStackInfo = _PCStackInfo;
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct _PCStackInfo {
-    pc: *mut c_void,         /* the pc address for this compiled method */
-    numstackframes: jint,    /* number of methods on the stack */
-    methods: *mut jmethodID, /* array of numstackframes method ids */
-    bcis: *mut jint,         /* array of numstackframes bytecode indices */
+    pub pc: *mut c_void,         /* the pc address for this compiled method */
+    pub numstackframes: jint,    /* number of methods on the stack */
+    pub methods: *mut jmethodID, /* array of numstackframes method ids */
+    pub bcis: *mut jint,         /* array of numstackframes bytecode indices */
 }
 
 pub type jvmtiCompiledMethodLoadInlineRecord = _jvmtiCompiledMethodLoadInlineRecord;
@@ -64,3 +67,144 @@ pub struct _jvmtiCompiledMethodLoadDummyRecord {
     pub header: jvmtiCompiledMethodLoadRecordHeader, /* common header for casting */
     pub message: [c_char; 50],
 }
+
+/// The frames at a code address of a compiled method.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InlinedFrames {
+    pub address: usize,
+    /// The methods on the compile-time stack, innermost first, with the
+    /// bytecode index as location.
+    pub frames: Vec<Frame>,
+}
+
+/// Reads the inline records from the `compile_info` of a
+/// `CompiledMethodLoad` event, sorted by address. Returns an empty list if
+/// the VM passed none.
+///
+/// # Safety
+///
+/// `compile_info` must be null or the pointer passed to the event callback,
+/// and only be read during the callback.
+pub unsafe fn inline_records(compile_info: *const c_void) -> Vec<InlinedFrames> {
+    let mut records = Vec::new();
+    let mut header = compile_info as *const jvmtiCompiledMethodLoadRecordHeader;
+    while !header.is_null() {
+        if let jvmtiCMLRKind::JVMTI_CMLR_INLINE_INFO = (*header).kind {
+            let record = &*(header as *const jvmtiCompiledMethodLoadInlineRecord);
+            if !record.pcinfo.is_null() {
+                let infos =
+                    core::slice::from_raw_parts(record.pcinfo, record.numpcs.max(0) as usize);
+                for info in infos {
+                    let count = info.numstackframes.max(0) as usize;
+                    let frames = if count == 0 || info.methods.is_null() || info.bcis.is_null() {
+                        Vec::new()
+                    } else {
+                        let methods = core::slice::from_raw_parts(info.methods, count);
+                        let bcis = core::slice::from_raw_parts(info.bcis, count);
+                        methods
+                            .iter()
+                            .zip(bcis)
+                            .map(|(&method, &bci)| Frame {
+                                method,
+                                location: bci.into(),
+                            })
+                            .collect()
+                    };
+                    records.push(InlinedFrames {
+                        address: info.pc as usize,
+                        frames,
+                    });
+                }
+            }
+        }
+        header = (*header).next;
+    }
+    records.sort_by_key(|record| record.address);
+    records
+}
+
+/// Returns the record describing the code at `address` among `records`
+/// sorted by address.
+///
+/// Like HotSpot's `PcDesc`s, a record describes the code ending at its
+/// address, so the first record at or after `address` applies. Code past
+/// the last record has none.
+pub fn record_at(records: &[InlinedFrames], address: usize) -> Option<&InlinedFrames> {
+    records.get(records.partition_point(|record| record.address < address))
+}
+
+/// Splits `code` into the address ranges that [`record_at`] maps to the same
+/// record, in address order. The range past the last record comes with
+/// `None`.
+pub fn inline_ranges(
+    records: &[InlinedFrames],
+    code: Range<usize>,
+) -> Vec<(Range<usize>, Option<&InlinedFrames>)> {
+    let mut ranges = Vec::new();
+    let mut from = code.start;
+    for record in records {
+        let to = record.address.saturating_add(1).min(code.end);
+        if from < to {
+            ranges.push((from..to, Some(record)));
+            from = to;
+        }
+    }
+    if from < code.end {
+        ranges.push((from..code.end, None));
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(address: usize, location: i64) -> InlinedFrames {
+        InlinedFrames {
+            address,
+            frames: vec![Frame {
+                method: core::ptr::null_mut(),
+                location,
+            }],
+        }
+    }
+
+    #[test]
+    fn records_describe_the_code_ending_at_their_address() {
+        let records = [record(0x10, 1), record(0x18, 2), record(0x30, 3)];
+        assert_eq!(record_at(&records, 0x00), Some(&records[0]));
+        assert_eq!(record_at(&records, 0x10), Some(&records[0]));
+        assert_eq!(record_at(&records, 0x11), Some(&records[1]));
+        assert_eq!(record_at(&records, 0x30), Some(&records[2]));
+        assert_eq!(record_at(&records, 0x31), None);
+
+        let ranges = inline_ranges(&records, 0x08..0x40);
+        assert_eq!(
+            ranges,
+            [
+                (0x08..0x11, Some(&records[0])),
+                (0x11..0x19, Some(&records[1])),
+                (0x19..0x31, Some(&records[2])),
+                (0x31..0x40, None),
+            ]
+        );
+        for (range, record) in ranges {
+            for address in range {
+                assert_eq!(record_at(&records, address), record);
+            }
+        }
+    }
+
+    #[test]
+    fn ranges_are_clipped_to_the_code() {
+        let records = [record(0x04, 1), record(0x20, 2), record(0x50, 3)];
+        assert_eq!(
+            inline_ranges(&records, 0x08..0x30),
+            [
+                (0x08..0x21, Some(&records[1])),
+                (0x21..0x30, Some(&records[2]))
+            ]
+        );
+        assert!(inline_ranges(&records, 0x08..0x08).is_empty());
+    }
+}
@@ -10,7 +10,35 @@ use jni_sys::{
 };
 use jni_sys_macros::jni_to_union;
 
+#[macro_use]
+mod util;
+
+pub mod alloc_profiler;
+pub mod classfile;
+pub mod code_map;
+pub mod collapsed;
+pub mod compile_log;
+pub mod disassembler;
+pub mod field_index;
+pub mod heap;
+pub mod heap_graph;
+pub mod histogram;
+pub mod hprof;
+#[cfg(target_os = "linux")]
+pub mod jitdump;
 pub mod jvmticmlr;
+pub mod line_table;
+pub mod locals;
+pub mod method_cache;
+pub mod perf_map;
+pub mod pprof;
+pub mod redefine;
+pub mod retained;
+pub mod root_path;
+pub mod sampler;
+pub mod stack;
+pub mod tags;
+pub mod transform;
 
 pub const JVMTI_VERSION_1: jint = 0x30010000;
 pub const JVMTI_VERSION_1_0: jint = 0x30010000;
@@ -582,16 +610,55 @@ pub union _jvmtiHeapReferenceInfo {
     pub other: jvmtiHeapReferenceInfoReserved,
 }
 
+/// The variant of a [`jvmtiHeapReferenceInfo`] that is active for a given
+/// [`jvmtiHeapReferenceKind`].
+#[derive(Clone, Copy, Debug)]
+pub enum HeapReferenceInfoRef<'a> {
+    /// `JVMTI_HEAP_REFERENCE_FIELD` and `JVMTI_HEAP_REFERENCE_STATIC_FIELD`.
+    Field(&'a jvmtiHeapReferenceInfoField),
+    /// `JVMTI_HEAP_REFERENCE_ARRAY_ELEMENT`.
+    Array(&'a jvmtiHeapReferenceInfoArray),
+    /// `JVMTI_HEAP_REFERENCE_CONSTANT_POOL`.
+    ConstantPool(&'a jvmtiHeapReferenceInfoConstantPool),
+    /// `JVMTI_HEAP_REFERENCE_STACK_LOCAL`.
+    StackLocal(&'a jvmtiHeapReferenceInfoStackLocal),
+    /// `JVMTI_HEAP_REFERENCE_JNI_LOCAL`.
+    JniLocal(&'a jvmtiHeapReferenceInfoJniLocal),
+    /// Every other kind carries no information.
+    None,
+}
+
+impl _jvmtiHeapReferenceInfo {
+    /// Returns the variant that is active for `kind`.
+    ///
+    /// # Safety
+    ///
+    /// `kind` must be the reference kind the VM reported along with this
+    /// info, so that the returned variant has been initialized.
+    pub unsafe fn get(&self, kind: jvmtiHeapReferenceKind) -> HeapReferenceInfoRef<'_> {
+        use jvmtiHeapReferenceKind::*;
+        match kind {
+            JVMTI_HEAP_REFERENCE_FIELD | JVMTI_HEAP_REFERENCE_STATIC_FIELD => {
+                HeapReferenceInfoRef::Field(&self.field)
+            }
+            JVMTI_HEAP_REFERENCE_ARRAY_ELEMENT => HeapReferenceInfoRef::Array(&self.array),
+            JVMTI_HEAP_REFERENCE_CONSTANT_POOL => {
+                HeapReferenceInfoRef::ConstantPool(&self.constant_pool)
+            }
+            JVMTI_HEAP_REFERENCE_STACK_LOCAL => HeapReferenceInfoRef::StackLocal(&self.stack_local),
+            JVMTI_HEAP_REFERENCE_JNI_LOCAL => HeapReferenceInfoRef::JniLocal(&self.jni_local),
+            _ => HeapReferenceInfoRef::None,
+        }
+    }
+}
+
+/// Without the reference kind there is no way to tell which variant is
+/// initialized, so the contents are not printed. Use
+/// [`_jvmtiHeapReferenceInfo::get`] to debug the active variant.
 impl core::fmt::Debug for _jvmtiHeapReferenceInfo {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("_jvmtiHeapReferenceInfo")
-            .field("field", unsafe { &self.field })
-            .field("array", unsafe { &self.array })
-            .field("constant_pool", unsafe { &self.constant_pool })
-            .field("stack_local", unsafe { &self.stack_local })
-            .field("jni_local", unsafe { &self.jni_local })
-            .field("other", unsafe { &self.other })
-            .finish()
+            .finish_non_exhaustive()
     }
 }
 
@@ -0,0 +1,161 @@
+//! Mapping between `jlocation`s and source lines.
+//!
+//! `GetLineNumberTable` returns entries in no particular order and may contain
+//! several entries for the same `start_location`. [`LineTable`] normalizes
+//! them once so that lookups in either direction are cheap.
+
+use core::fmt;
+use core::ops::Range;
+use core::ptr::null_mut;
+
+use jni_sys::{jint, jmethodID};
+
+use crate::util::{check, take_array};
+use crate::{jlocation, jvmtiEnv, jvmtiError, jvmtiJlocationFormat, jvmtiLineNumberEntry};
+
+/// Errors returned by [`LineTable::new`].
+#[derive(Clone, Copy, Debug)]
+pub enum LineTableError {
+    /// A JVMTI function failed.
+    Jvmti(jvmtiError),
+    /// The VM does not report locations as bytecode indices, so line number
+    /// entries cannot be interpreted.
+    UnsupportedFormat(jvmtiJlocationFormat),
+}
+
+impl From<jvmtiError> for LineTableError {
+    fn from(err: jvmtiError) -> Self {
+        LineTableError::Jvmti(err)
+    }
+}
+
+impl fmt::Display for LineTableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineTableError::Jvmti(err) => write!(f, "JVMTI call failed: {err:?}"),
+            LineTableError::UnsupportedFormat(format) => {
+                write!(f, "unsupported jlocation format {format:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LineTableError {}
+
+/// The line number table of a method, sorted by location.
+#[derive(Clone, Debug, Default)]
+pub struct LineTable {
+    entries: Vec<jvmtiLineNumberEntry>,
+    end: jlocation,
+}
+
+impl LineTable {
+    /// Builds the line table of `method` from `GetLineNumberTable` and
+    /// `GetMethodLocation`.
+    ///
+    /// Native methods and methods without line number information yield an
+    /// empty table. Fails with [`LineTableError::UnsupportedFormat`] unless
+    /// `GetJLocationFormat` reports `JVMTI_JLOCATION_JVMBCI`.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `method` a valid method id.
+    pub unsafe fn new(env: *mut jvmtiEnv, method: jmethodID) -> Result<Self, LineTableError> {
+        let mut format = jvmtiJlocationFormat::JVMTI_JLOCATION_OTHER;
+        check(jvmti!(env, v1, GetJLocationFormat, &mut format))?;
+        if !matches!(format, jvmtiJlocationFormat::JVMTI_JLOCATION_JVMBCI) {
+            return Err(LineTableError::UnsupportedFormat(format));
+        }
+
+        let mut start = 0;
+        let mut end = 0;
+        match jvmti!(env, v1, GetMethodLocation, method, &mut start, &mut end) {
+            jvmtiError::JVMTI_ERROR_NONE => {}
+            jvmtiError::JVMTI_ERROR_NATIVE_METHOD => return Ok(LineTable::default()),
+            err => return Err(err.into()),
+        }
+
+        let mut count = 0;
+        let mut table = null_mut();
+        match jvmti!(env, v1, GetLineNumberTable, method, &mut count, &mut table) {
+            jvmtiError::JVMTI_ERROR_NONE => {}
+            jvmtiError::JVMTI_ERROR_ABSENT_INFORMATION
+            | jvmtiError::JVMTI_ERROR_NATIVE_METHOD
+            | jvmtiError::JVMTI_ERROR_MUST_POSSESS_CAPABILITY => return Ok(LineTable::default()),
+            err => return Err(err.into()),
+        }
+        Ok(Self::from_entries(take_array(env, table, count), end))
+    }
+
+    /// Builds a table from raw entries of a method whose last bytecode is at
+    /// `end`. Entries are sorted by location; for duplicated locations the
+    /// last entry wins.
+    pub fn from_entries(mut entries: Vec<jvmtiLineNumberEntry>, end: jlocation) -> Self {
+        entries.sort_by_key(|entry| entry.start_location);
+        let mut deduped: Vec<jvmtiLineNumberEntry> = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match deduped.last_mut() {
+                Some(last) if last.start_location == entry.start_location => *last = entry,
+                _ => deduped.push(entry),
+            }
+        }
+        LineTable {
+            entries: deduped,
+            end,
+        }
+    }
+
+    /// Returns the sorted, deduplicated entries.
+    pub fn entries(&self) -> &[jvmtiLineNumberEntry] {
+        &self.entries
+    }
+
+    /// Returns the location of the last instruction of the method.
+    pub fn end(&self) -> jlocation {
+        self.end
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the line containing `location`, if known.
+    pub fn line_for(&self, location: jlocation) -> Option<jint> {
+        if location < 0 || location > self.end {
+            return None;
+        }
+        let index = self
+            .entries
+            .partition_point(|entry| entry.start_location <= location);
+        index.checked_sub(1).map(|i| self.entries[i].line_number)
+    }
+
+    /// Returns the bytecode ranges belonging to `line`, merging adjacent ones.
+    /// The first location of each range is where a breakpoint for the line
+    /// should be set.
+    pub fn locations_for_line(&self, line: jint) -> Vec<Range<jlocation>> {
+        let mut ranges: Vec<Range<jlocation>> = Vec::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.line_number != line {
+                continue;
+            }
+            let next = self
+                .entries
+                .get(i + 1)
+                .map_or(self.end + 1, |next| next.start_location);
+            match ranges.last_mut() {
+                Some(last) if last.end == entry.start_location => last.end = next,
+                _ => ranges.push(entry.start_location..next),
+            }
+        }
+        ranges
+    }
+
+    /// Returns the distinct line numbers of the method in ascending order.
+    pub fn lines(&self) -> Vec<jint> {
+        let mut lines: Vec<_> = self.entries.iter().map(|entry| entry.line_number).collect();
+        lines.sort_unstable();
+        lines.dedup();
+        lines
+    }
+}
@@ -0,0 +1,393 @@
+//! Typed access to the local variables of a stack frame.
+//!
+//! [`FrameInspector`] combines `GetFrameLocation` and `GetLocalVariableTable`
+//! to find the variables in scope at the current location of a frame, then
+//! picks the matching `GetLocal*`/`SetLocal*` function from each variable's
+//! descriptor. All functions require the `can_access_local_variables`
+//! capability.
+
+use core::fmt;
+use core::ptr::null_mut;
+use std::collections::BTreeMap;
+
+use jni_sys::{jint, jmethodID, jobject};
+
+use crate::util::{check, deallocate, take_string};
+use crate::{jlocation, jthread, jvmtiEnv, jvmtiError};
+
+/// A Java value read from or written to a local variable.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JavaValue {
+    Boolean(bool),
+    Byte(i8),
+    Char(u16),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    /// A JNI local reference, or null.
+    Object(jobject),
+}
+
+impl JavaValue {
+    /// Returns the descriptor character of the value's type, `L` for objects.
+    pub fn type_char(&self) -> char {
+        match self {
+            JavaValue::Boolean(_) => 'Z',
+            JavaValue::Byte(_) => 'B',
+            JavaValue::Char(_) => 'C',
+            JavaValue::Short(_) => 'S',
+            JavaValue::Int(_) => 'I',
+            JavaValue::Long(_) => 'J',
+            JavaValue::Float(_) => 'F',
+            JavaValue::Double(_) => 'D',
+            JavaValue::Object(_) => 'L',
+        }
+    }
+}
+
+/// Errors returned by [`FrameInspector`].
+#[derive(Clone, Debug)]
+pub enum LocalsError {
+    /// A JVMTI function failed.
+    Jvmti(jvmtiError),
+    /// No variable of that name is in scope at the frame's location.
+    NoSuchVariable(String),
+    /// The value does not match the declared type of the variable.
+    TypeMismatch {
+        name: String,
+        signature: String,
+        value: JavaValue,
+    },
+}
+
+impl From<jvmtiError> for LocalsError {
+    fn from(err: jvmtiError) -> Self {
+        LocalsError::Jvmti(err)
+    }
+}
+
+impl fmt::Display for LocalsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalsError::Jvmti(err) => write!(f, "JVMTI call failed: {err:?}"),
+            LocalsError::NoSuchVariable(name) => write!(f, "no variable `{name}` in scope"),
+            LocalsError::TypeMismatch {
+                name,
+                signature,
+                value,
+            } => write!(
+                f,
+                "cannot store a `{}` value in `{name}` of type `{signature}`",
+                value.type_char()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LocalsError {}
+
+/// An entry of a method's local variable table.
+#[derive(Clone, Debug)]
+pub struct LocalVariable {
+    pub name: String,
+    /// The type descriptor, e.g. `I` or `Ljava/lang/String;`.
+    pub signature: String,
+    pub generic_signature: Option<String>,
+    pub slot: jint,
+    /// First location where the variable is live.
+    pub start_location: jlocation,
+    /// Number of locations the variable is live for.
+    pub length: jint,
+}
+
+impl LocalVariable {
+    /// Returns whether the variable is live at `location`.
+    pub fn in_scope(&self, location: jlocation) -> bool {
+        location >= self.start_location && location < self.start_location + self.length as jlocation
+    }
+}
+
+/// Returns the local variable table of `method`.
+///
+/// # Safety
+///
+/// `env` must be a valid JVMTI environment and `method` a valid method id.
+pub unsafe fn local_variable_table(
+    env: *mut jvmtiEnv,
+    method: jmethodID,
+) -> Result<Vec<LocalVariable>, jvmtiError> {
+    let mut count = 0;
+    let mut table = null_mut();
+    check(jvmti!(
+        env,
+        v1,
+        GetLocalVariableTable,
+        method,
+        &mut count,
+        &mut table
+    ))?;
+    if table.is_null() {
+        return Ok(Vec::new());
+    }
+    let entries = core::slice::from_raw_parts(table, count.max(0) as usize);
+    let variables = entries
+        .iter()
+        .map(|entry| LocalVariable {
+            name: take_string(env, entry.name).unwrap_or_default(),
+            signature: take_string(env, entry.signature).unwrap_or_default(),
+            generic_signature: take_string(env, entry.generic_signature),
+            slot: entry.slot,
+            start_location: entry.start_location,
+            length: entry.length,
+        })
+        .collect();
+    deallocate(env, table);
+    Ok(variables)
+}
+
+/// Reads and writes the local variables of one frame of a thread.
+///
+/// The thread must be suspended, or be the current thread, for the lifetime
+/// of the inspector.
+#[derive(Debug)]
+pub struct FrameInspector {
+    env: *mut jvmtiEnv,
+    thread: jthread,
+    depth: jint,
+    method: jmethodID,
+    location: jlocation,
+    variables: Vec<LocalVariable>,
+}
+
+impl FrameInspector {
+    /// Inspects the frame at `depth` of `thread`, `0` being the current frame.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `thread` a valid thread
+    /// reference (or null for the current thread). Both must outlive the
+    /// inspector.
+    pub unsafe fn new(
+        env: *mut jvmtiEnv,
+        thread: jthread,
+        depth: jint,
+    ) -> Result<Self, LocalsError> {
+        let mut method = null_mut();
+        let mut location = 0;
+        check(jvmti!(
+            env,
+            v1,
+            GetFrameLocation,
+            thread,
+            depth,
+            &mut method,
+            &mut location
+        ))?;
+        let variables = match local_variable_table(env, method) {
+            Ok(table) => table
+                .into_iter()
+                .filter(|variable| variable.in_scope(location))
+                .collect(),
+            // Native frames have no local variables.
+            Err(
+                jvmtiError::JVMTI_ERROR_ABSENT_INFORMATION | jvmtiError::JVMTI_ERROR_NATIVE_METHOD,
+            ) => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(FrameInspector {
+            env,
+            thread,
+            depth,
+            method,
+            location,
+            variables,
+        })
+    }
+
+    pub fn method(&self) -> jmethodID {
+        self.method
+    }
+
+    pub fn location(&self) -> jlocation {
+        self.location
+    }
+
+    /// Returns the variables in scope at the frame's location.
+    pub fn variables(&self) -> &[LocalVariable] {
+        &self.variables
+    }
+
+    /// Returns the in-scope variable called `name`.
+    pub fn variable(&self, name: &str) -> Option<&LocalVariable> {
+        self.variables.iter().find(|variable| variable.name == name)
+    }
+
+    /// Returns the receiver of the frame, or `None` for static and native
+    /// methods.
+    ///
+    /// # Safety
+    ///
+    /// The thread must still be at the inspected frame.
+    pub unsafe fn this(&self) -> Result<Option<jobject>, LocalsError> {
+        let mut value = null_mut();
+        match jvmti!(
+            self.env,
+            v1_2,
+            GetLocalInstance,
+            self.thread,
+            self.depth,
+            &mut value
+        ) {
+            jvmtiError::JVMTI_ERROR_NONE => Ok(Some(value)),
+            // Static methods have no receiver in slot 0, and native frames
+            // have no slots at all.
+            jvmtiError::JVMTI_ERROR_INVALID_SLOT | jvmtiError::JVMTI_ERROR_OPAQUE_FRAME => Ok(None),
+            err => Err(err.into()),
+        }
+    }
+
+    /// Reads the variable called `name`.
+    ///
+    /// # Safety
+    ///
+    /// The thread must still be at the inspected frame.
+    pub unsafe fn get(&self, name: &str) -> Result<JavaValue, LocalsError> {
+        let variable = self
+            .variable(name)
+            .ok_or_else(|| LocalsError::NoSuchVariable(name.to_owned()))?;
+        self.read(variable)
+    }
+
+    /// Reads every in-scope variable.
+    ///
+    /// # Safety
+    ///
+    /// The thread must still be at the inspected frame.
+    pub unsafe fn values(&self) -> Result<BTreeMap<String, JavaValue>, LocalsError> {
+        self.variables
+            .iter()
+            .map(|variable| Ok((variable.name.clone(), self.read(variable)?)))
+            .collect()
+    }
+
+    /// Writes `value` to the variable called `name`. The value's type must
+    /// match the variable's descriptor exactly; object values are accepted
+    /// for any reference type.
+    ///
+    /// # Safety
+    ///
+    /// The thread must still be at the inspected frame, and object values
+    /// must be valid references.
+    pub unsafe fn set(&self, name: &str, value: JavaValue) -> Result<(), LocalsError> {
+        let variable = self
+            .variable(name)
+            .ok_or_else(|| LocalsError::NoSuchVariable(name.to_owned()))?;
+        let type_char = match variable.signature.as_bytes().first() {
+            Some(b'[') => 'L',
+            Some(&c) => c as char,
+            None => '?',
+        };
+        if type_char != value.type_char() {
+            return Err(LocalsError::TypeMismatch {
+                name: variable.name.clone(),
+                signature: variable.signature.clone(),
+                value,
+            });
+        }
+
+        let (env, thread, depth, slot) = (self.env, self.thread, self.depth, variable.slot);
+        let err = match value {
+            JavaValue::Boolean(v) => jvmti!(env, v1, SetLocalInt, thread, depth, slot, v as jint),
+            JavaValue::Byte(v) => jvmti!(env, v1, SetLocalInt, thread, depth, slot, v as jint),
+            JavaValue::Char(v) => jvmti!(env, v1, SetLocalInt, thread, depth, slot, v as jint),
+            JavaValue::Short(v) => jvmti!(env, v1, SetLocalInt, thread, depth, slot, v as jint),
+            JavaValue::Int(v) => jvmti!(env, v1, SetLocalInt, thread, depth, slot, v),
+            JavaValue::Long(v) => jvmti!(env, v1, SetLocalLong, thread, depth, slot, v),
+            JavaValue::Float(v) => jvmti!(env, v1, SetLocalFloat, thread, depth, slot, v),
+            JavaValue::Double(v) => jvmti!(env, v1, SetLocalDouble, thread, depth, slot, v),
+            JavaValue::Object(v) => jvmti!(env, v1, SetLocalObject, thread, depth, slot, v),
+        };
+        Ok(check(err)?)
+    }
+
+    unsafe fn read(&self, variable: &LocalVariable) -> Result<JavaValue, LocalsError> {
+        let (env, thread, depth, slot) = (self.env, self.thread, self.depth, variable.slot);
+        let value = match variable.signature.as_bytes().first() {
+            Some(b'J') => {
+                let mut value = 0;
+                check(jvmti!(
+                    env,
+                    v1,
+                    GetLocalLong,
+                    thread,
+                    depth,
+                    slot,
+                    &mut value
+                ))?;
+                JavaValue::Long(value)
+            }
+            Some(b'F') => {
+                let mut value = 0.0;
+                check(jvmti!(
+                    env,
+                    v1,
+                    GetLocalFloat,
+                    thread,
+                    depth,
+                    slot,
+                    &mut value
+                ))?;
+                JavaValue::Float(value)
+            }
+            Some(b'D') => {
+                let mut value = 0.0;
+                check(jvmti!(
+                    env,
+                    v1,
+                    GetLocalDouble,
+                    thread,
+                    depth,
+                    slot,
+                    &mut value
+                ))?;
+                JavaValue::Double(value)
+            }
+            Some(b'L' | b'[') => {
+                let mut value = null_mut();
+                check(jvmti!(
+                    env,
+                    v1,
+                    GetLocalObject,
+                    thread,
+                    depth,
+                    slot,
+                    &mut value
+                ))?;
+                JavaValue::Object(value)
+            }
+            Some(&c) => {
+                let mut value = 0;
+                check(jvmti!(
+                    env,
+                    v1,
+                    GetLocalInt,
+                    thread,
+                    depth,
+                    slot,
+                    &mut value
+                ))?;
+                match c {
+                    b'Z' => JavaValue::Boolean(value != 0),
+                    b'B' => JavaValue::Byte(value as i8),
+                    b'C' => JavaValue::Char(value as u16),
+                    b'S' => JavaValue::Short(value as i16),
+                    _ => JavaValue::Int(value),
+                }
+            }
+            None => return Err(jvmtiError::JVMTI_ERROR_TYPE_MISMATCH.into()),
+        };
+        Ok(value)
+    }
+}
@@ -0,0 +1,372 @@
+//! A concurrent cache of method metadata keyed by `jmethodID`.
+//!
+//! Resolving a method through `GetMethodName`, `GetClassSignature` and
+//! `GetLineNumberTable` costs several VM transitions and allocations, which is
+//! far too slow for profilers that see the same methods over and over. A
+//! [`MethodCache`] resolves each method once and hands out shared
+//! [`MethodInfo`] values afterwards.
+//!
+//! Entries are populated lazily by [`MethodCache::get`], or eagerly from the
+//! `ClassPrepare` event through [`MethodCache::on_class_prepare`]. Classes seen
+//! by the latter are tagged, so that forwarding `ObjectFree` events to
+//! [`MethodCache::on_object_free`] drops their methods when the class unloads.
+//! Redefined classes are handled by [`MethodCache::invalidate_class`] and
+//! [`MethodCache::sweep`].
+
+use core::ptr::null_mut;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use jni_sys::{jclass, jint, jlong, jmethodID, JNIEnv};
+
+use crate::line_table::{LineTable, LineTableError};
+use crate::tags::TagAllocator;
+use crate::util::{check, class_name, delete_local_refs, take_array, take_string};
+use crate::{jlocation, jvmtiEnv, jvmtiError};
+
+/// Metadata of a single method.
+#[derive(Clone, Debug)]
+pub struct MethodInfo {
+    /// The method name, e.g. `toString`.
+    pub name: String,
+    /// The method descriptor, e.g. `()Ljava/lang/String;`.
+    pub signature: String,
+    /// The signature of the declaring class, e.g. `Ljava/lang/Object;`.
+    pub class_signature: String,
+    /// The source file name of the declaring class, e.g. `Object.java`.
+    /// `None` without the `can_get_source_file_name` capability or if the
+    /// class was compiled without it.
+    pub source_file: Option<String>,
+    /// The access flags as returned by `GetMethodModifiers`.
+    pub modifiers: jint,
+    /// The line number table. Empty for native methods and classes compiled
+    /// without debug information.
+    pub line_table: LineTable,
+    /// Whether the method was obsolete (replaced by a redefinition) when it
+    /// was resolved.
+    pub obsolete: bool,
+}
+
+impl MethodInfo {
+    /// Resolves the metadata of `method`.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `method` a valid method id.
+    pub unsafe fn resolve(env: *mut jvmtiEnv, method: jmethodID) -> Result<Self, jvmtiError> {
+        let mut name = null_mut();
+        let mut signature = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetMethodName,
+            method,
+            &mut name,
+            &mut signature,
+            null_mut()
+        ))?;
+        let name = take_string(env, name).unwrap_or_default();
+        let signature = take_string(env, signature).unwrap_or_default();
+
+        let mut klass = null_mut();
+        check(jvmti!(env, v1, GetMethodDeclaringClass, method, &mut klass))?;
+        let mut class_signature = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetClassSignature,
+            klass,
+            &mut class_signature,
+            null_mut()
+        ))?;
+        let class_signature = take_string(env, class_signature).unwrap_or_default();
+
+        let mut source_file = null_mut();
+        let source_file = match jvmti!(env, v1, GetSourceFileName, klass, &mut source_file) {
+            jvmtiError::JVMTI_ERROR_NONE => take_string(env, source_file),
+            _ => None,
+        };
+
+        let mut modifiers = 0;
+        check(jvmti!(env, v1, GetMethodModifiers, method, &mut modifiers))?;
+
+        let line_table = match LineTable::new(env, method) {
+            Ok(table) => table,
+            Err(LineTableError::Jvmti(err)) => return Err(err),
+            Err(LineTableError::UnsupportedFormat(_)) => LineTable::default(),
+        };
+
+        let mut obsolete = false;
+        check(jvmti!(env, v1, IsMethodObsolete, method, &mut obsolete))?;
+
+        Ok(MethodInfo {
+            name,
+            signature,
+            class_signature,
+            source_file,
+            modifiers,
+            line_table,
+            obsolete,
+        })
+    }
+
+    /// Returns the method name qualified with the declaring class, e.g.
+    /// `java.lang.String.valueOf`.
+    pub fn qualified_name(&self) -> String {
+        format!("{}.{}", class_name(&self.class_signature), self.name)
+    }
+
+    /// Returns the path of the source file relative to the source root,
+    /// e.g. `java/lang/String.java`.
+    pub fn source_path(&self) -> Option<String> {
+        let file = self.source_file.as_ref()?;
+        let class = self.class_signature.strip_prefix('L')?;
+        Some(match class.rfind('/') {
+            Some(end) => format!("{}/{file}", &class[..end]),
+            None => file.clone(),
+        })
+    }
+
+    /// Renders a frame at `location` in the method, e.g.
+    /// `java.lang.String.valueOf (line 4375)`. The line is omitted if
+    /// unknown.
+    pub fn describe(&self, location: jlocation) -> String {
+        match self.line_table.line_for(location) {
+            Some(line) => format!("{} (line {line})", self.qualified_name()),
+            None => self.qualified_name(),
+        }
+    }
+}
+
+/// A thread safe `jmethodID` to [`MethodInfo`] cache.
+#[derive(Debug)]
+pub struct MethodCache {
+    methods: RwLock<HashMap<usize, Arc<MethodInfo>>>,
+    classes: Mutex<HashMap<jlong, Vec<usize>>>,
+    class_tags: TagAllocator,
+}
+
+impl Default for MethodCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MethodCache {
+    /// The first tag the cache assigns to untagged classes in
+    /// [`on_class_prepare`](Self::on_class_prepare).
+    pub const FIRST_CLASS_TAG: jlong = 1 << 48;
+
+    pub fn new() -> Self {
+        MethodCache {
+            methods: RwLock::new(HashMap::new()),
+            classes: Mutex::new(HashMap::new()),
+            class_tags: TagAllocator::starting_at(Self::FIRST_CLASS_TAG),
+        }
+    }
+
+    /// Returns the cached metadata of `method`, if any, without calling into
+    /// the VM.
+    pub fn get_cached(&self, method: jmethodID) -> Option<Arc<MethodInfo>> {
+        self.methods
+            .read()
+            .unwrap()
+            .get(&(method as usize))
+            .cloned()
+    }
+
+    /// Returns the metadata of `method`, resolving and caching it on a miss.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `method` a valid method id.
+    pub unsafe fn get(
+        &self,
+        env: *mut jvmtiEnv,
+        method: jmethodID,
+    ) -> Result<Arc<MethodInfo>, jvmtiError> {
+        if let Some(info) = self.get_cached(method) {
+            return Ok(info);
+        }
+        let info = Arc::new(MethodInfo::resolve(env, method)?);
+        let mut methods = self.methods.write().unwrap();
+        Ok(methods.entry(method as usize).or_insert(info).clone())
+    }
+
+    /// Eagerly caches all methods of `klass`. Meant to be called from the
+    /// `ClassPrepare` event.
+    ///
+    /// Untagged classes are tagged so their methods can be dropped from
+    /// [`on_object_free`](Self::on_object_free) once the class unloads. This
+    /// requires the `can_tag_objects` capability; without it, or if another
+    /// user of the environment has tagged the class, the methods are still
+    /// cached but never invalidated by unloading.
+    ///
+    /// Methods that fail to resolve are skipped; [`get`](Self::get) retries
+    /// them on demand.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `klass` a valid class
+    /// reference.
+    pub unsafe fn on_class_prepare(
+        &self,
+        env: *mut jvmtiEnv,
+        klass: jclass,
+    ) -> Result<(), jvmtiError> {
+        let mut count = 0;
+        let mut methods = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetClassMethods,
+            klass,
+            &mut count,
+            &mut methods
+        ))?;
+        let methods = take_array(env, methods, count);
+        let tag = self.class_tag(env, klass)?;
+
+        let mut resolved = Vec::with_capacity(methods.len());
+        for &method in &methods {
+            if let Ok(info) = MethodInfo::resolve(env, method) {
+                resolved.push((method as usize, Arc::new(info)));
+            }
+        }
+        let keys = resolved.iter().map(|(key, _)| *key).collect();
+        self.methods.write().unwrap().extend(resolved);
+
+        if let Some(tag) = tag {
+            self.classes.lock().unwrap().insert(tag, keys);
+        }
+        Ok(())
+    }
+
+    /// Eagerly caches the methods of every prepared class currently loaded.
+    /// Useful when attaching to a running VM, where `ClassPrepare` has already
+    /// fired for most classes.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `jni` the JNI environment
+    /// of the current thread.
+    pub unsafe fn cache_loaded_classes(
+        &self,
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+    ) -> Result<(), jvmtiError> {
+        let mut count = 0;
+        let mut classes = null_mut();
+        check(jvmti!(env, v1, GetLoadedClasses, &mut count, &mut classes))?;
+        let classes = take_array(env, classes, count);
+        let mut result = Ok(());
+        for &klass in &classes {
+            match self.on_class_prepare(env, klass) {
+                Ok(()) | Err(jvmtiError::JVMTI_ERROR_CLASS_NOT_PREPARED) => {}
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        delete_local_refs(jni, &classes);
+        result
+    }
+
+    /// Drops the methods of the class tagged `tag`. Forward `ObjectFree`
+    /// events here; returns whether the tag belonged to a cached class.
+    pub fn on_object_free(&self, tag: jlong) -> bool {
+        let Some(keys) = self.classes.lock().unwrap().remove(&tag) else {
+            return false;
+        };
+        let mut methods = self.methods.write().unwrap();
+        for key in keys {
+            methods.remove(&key);
+        }
+        true
+    }
+
+    /// Drops the cached methods of `klass`, e.g. after it has been redefined.
+    /// They are resolved again on the next lookup.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `klass` a valid class
+    /// reference.
+    pub unsafe fn invalidate_class(
+        &self,
+        env: *mut jvmtiEnv,
+        klass: jclass,
+    ) -> Result<(), jvmtiError> {
+        let mut count = 0;
+        let mut methods = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetClassMethods,
+            klass,
+            &mut count,
+            &mut methods
+        ))?;
+        let mut cached = self.methods.write().unwrap();
+        for method in take_array(env, methods, count) {
+            cached.remove(&(method as usize));
+        }
+        Ok(())
+    }
+
+    /// Drops every entry whose method id is no longer valid (its class was
+    /// unloaded) or has become obsolete since it was cached. Returns the
+    /// number of dropped entries.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn sweep(&self, env: *mut jvmtiEnv) -> usize {
+        let mut methods = self.methods.write().unwrap();
+        let before = methods.len();
+        methods.retain(|&key, info| {
+            let mut obsolete = false;
+            let err = jvmti!(env, v1, IsMethodObsolete, key as jmethodID, &mut obsolete);
+            err == jvmtiError::JVMTI_ERROR_NONE && obsolete == info.obsolete
+        });
+        before - methods.len()
+    }
+
+    /// Removes every entry.
+    pub fn clear(&self) {
+        self.methods.write().unwrap().clear();
+        self.classes.lock().unwrap().clear();
+    }
+
+    /// Returns the number of cached methods.
+    pub fn len(&self) -> usize {
+        self.methods.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the tag of `klass`, tagging it first if it is untagged, or
+    /// `None` if it carries a tag the cache did not hand out or tagging is
+    /// not possible.
+    unsafe fn class_tag(
+        &self,
+        env: *mut jvmtiEnv,
+        klass: jclass,
+    ) -> Result<Option<jlong>, jvmtiError> {
+        let mut tag = 0;
+        match check(jvmti!(env, v1, GetTag, klass, &mut tag)) {
+            Ok(()) => {}
+            Err(jvmtiError::JVMTI_ERROR_MUST_POSSESS_CAPABILITY) => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        if tag != 0 {
+            return Ok(Some(tag).filter(|tag| self.class_tags.owns(*tag)));
+        }
+        let tag = self.class_tags.allocate();
+        check(jvmti!(env, v1, SetTag, klass, tag))?;
+        Ok(Some(tag))
+    }
+}
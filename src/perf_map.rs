@@ -0,0 +1,266 @@
+//! perf map files for JIT compiled code.
+//!
+//! Linux `perf` symbolizes samples in anonymous executable memory through
+//! `/tmp/perf-<pid>.map`, a text file with one `START SIZE name` line per
+//! code region. [`PerfMap`] writes such a file from the
+//! `CompiledMethodLoad` and `DynamicCodeGenerated` events, and catches up
+//! on code compiled before it was started with `GenerateEvents`.
+//!
+//! Forward the events to the `on_*` methods of the map. Entries are only
+//! ever appended, so unloaded methods stay in the file until
+//! [`PerfMap::compact`] rewrites it from the code that is still loaded.
+//! perf only reads the file when it reports, so the file must still be
+//! there at that point.
+//!
+//! Requires the `can_generate_compiled_method_load_events` capability.
+
+use core::ffi::{c_char, c_void};
+use core::ptr::null_mut;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use jni_sys::{jint, jmethodID};
+
+use crate::jvmticmlr::{inline_ranges, inline_records};
+use crate::method_cache::MethodCache;
+use crate::stack::Frame;
+use crate::util::check;
+use crate::{jvmtiAddrLocationMap, jvmtiEnv, jvmtiError, jvmtiEvent, jvmtiEventMode};
+
+/// Options for [`PerfMap`].
+#[derive(Clone, Debug, Default)]
+pub struct PerfMapOptions {
+    /// The file to write, `/tmp/perf-<pid>.map` by default.
+    pub path: Option<PathBuf>,
+    /// Split compiled methods into one entry per inlined method, named
+    /// like `Outer.caller->Inner.callee`, using the inline records of the
+    /// `CompiledMethodLoad` event.
+    pub unfold_inlined: bool,
+    /// Append the method descriptor to method names.
+    pub signatures: bool,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    start: usize,
+    size: usize,
+    name: String,
+}
+
+#[derive(Debug)]
+struct State {
+    out: BufWriter<File>,
+    /// Entries of the compiled methods by code address.
+    methods: BTreeMap<usize, Vec<Entry>>,
+    /// Entries of dynamically generated code by address.
+    stubs: BTreeMap<usize, Entry>,
+}
+
+/// Writes a perf map file for the code compiled by the VM.
+#[derive(Debug)]
+pub struct PerfMap {
+    path: PathBuf,
+    options: PerfMapOptions,
+    state: Mutex<State>,
+    cache: MethodCache,
+}
+
+impl PerfMap {
+    /// Creates the map file, truncating it if it exists.
+    pub fn create(options: PerfMapOptions) -> io::Result<Self> {
+        let path = options
+            .path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("/tmp/perf-{}.map", std::process::id())));
+        let out = BufWriter::new(File::create(&path)?);
+        Ok(PerfMap {
+            path,
+            options,
+            state: Mutex::new(State {
+                out,
+                methods: BTreeMap::new(),
+                stubs: BTreeMap::new(),
+            }),
+            cache: MethodCache::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Enables the `CompiledMethodLoad`, `CompiledMethodUnload` and
+    /// `DynamicCodeGenerated` events and has the VM send them for the code
+    /// that already exists.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment whose callbacks forward the
+    /// events to this map.
+    pub unsafe fn start(&self, env: *mut jvmtiEnv) -> Result<(), jvmtiError> {
+        for event in [
+            jvmtiEvent::JVMTI_EVENT_COMPILED_METHOD_LOAD,
+            jvmtiEvent::JVMTI_EVENT_COMPILED_METHOD_UNLOAD,
+            jvmtiEvent::JVMTI_EVENT_DYNAMIC_CODE_GENERATED,
+        ] {
+            check(jvmti!(
+                env,
+                v1,
+                SetEventNotificationMode,
+                jvmtiEventMode::JVMTI_ENABLE,
+                event,
+                null_mut::<jni_sys::_jobject>()
+            ))?;
+        }
+        check(jvmti!(
+            env,
+            v1,
+            GenerateEvents,
+            jvmtiEvent::JVMTI_EVENT_DYNAMIC_CODE_GENERATED
+        ))?;
+        check(jvmti!(
+            env,
+            v1,
+            GenerateEvents,
+            jvmtiEvent::JVMTI_EVENT_COMPILED_METHOD_LOAD
+        ))
+    }
+
+    /// Records a compiled method. Forward `CompiledMethodLoad` events here.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with the arguments of a `CompiledMethodLoad` event,
+    /// from the event callback.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn on_compiled_method_load(
+        &self,
+        env: *mut jvmtiEnv,
+        method: jmethodID,
+        code_size: jint,
+        code_addr: *const c_void,
+        _map_length: jint,
+        _map: *const jvmtiAddrLocationMap,
+        compile_info: *const c_void,
+    ) -> io::Result<()> {
+        let start = code_addr as usize;
+        let end = start + code_size.max(0) as usize;
+        let root = self.method_name(env, method);
+        let mut entries: Vec<Entry> = Vec::new();
+        if self.options.unfold_inlined {
+            let records = inline_records(compile_info);
+            for (range, record) in inline_ranges(&records, start..end) {
+                let name = match record {
+                    Some(record) => self.chain_name(env, &record.frames, &root),
+                    None => root.clone(),
+                };
+                match entries.last_mut() {
+                    Some(last) if last.name == name => last.size += range.len(),
+                    _ => entries.push(Entry {
+                        start: range.start,
+                        size: range.len(),
+                        name,
+                    }),
+                }
+            }
+        } else {
+            entries.push(Entry {
+                start,
+                size: end - start,
+                name: root,
+            });
+        }
+
+        let mut state = self.state.lock().unwrap();
+        for entry in &entries {
+            write_entry(&mut state.out, entry)?;
+        }
+        state.out.flush()?;
+        state.methods.insert(start, entries);
+        Ok(())
+    }
+
+    /// Forgets a compiled method. Forward `CompiledMethodUnload` events
+    /// here. The file keeps its entries until [`compact`](Self::compact).
+    pub fn on_compiled_method_unload(&self, _method: jmethodID, code_addr: *const c_void) {
+        let mut state = self.state.lock().unwrap();
+        state.methods.remove(&(code_addr as usize));
+    }
+
+    /// Records generated code such as the interpreter or stubs. Forward
+    /// `DynamicCodeGenerated` events here.
+    ///
+    /// # Safety
+    ///
+    /// `name` must be a valid C string.
+    pub unsafe fn on_dynamic_code_generated(
+        &self,
+        name: *const c_char,
+        address: *const c_void,
+        length: jint,
+    ) -> io::Result<()> {
+        let entry = Entry {
+            start: address as usize,
+            size: length.max(0) as usize,
+            name: CStr::from_ptr(name).to_string_lossy().into_owned(),
+        };
+        let mut state = self.state.lock().unwrap();
+        write_entry(&mut state.out, &entry)?;
+        state.out.flush()?;
+        state.stubs.insert(entry.start, entry);
+        Ok(())
+    }
+
+    /// Rewrites the file with only the code that is still loaded.
+    pub fn compact(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.out.flush()?;
+        let file = state.out.get_mut();
+        file.set_len(0)?;
+        file.rewind()?;
+        for entry in state.stubs.values() {
+            write_entry(&mut state.out, entry)?;
+        }
+        for entry in state.methods.values().flatten() {
+            write_entry(&mut state.out, entry)?;
+        }
+        state.out.flush()
+    }
+
+    /// Returns the number of compiled methods currently loaded.
+    pub fn method_count(&self) -> usize {
+        self.state.lock().unwrap().methods.len()
+    }
+
+    unsafe fn method_name(&self, env: *mut jvmtiEnv, method: jmethodID) -> String {
+        match self.cache.get(env, method) {
+            Ok(info) if self.options.signatures => {
+                format!("{}{}", info.qualified_name(), info.signature)
+            }
+            Ok(info) => info.qualified_name(),
+            Err(_) => "<unknown method>".to_owned(),
+        }
+    }
+
+    /// Names an inline chain, outermost method first.
+    unsafe fn chain_name(&self, env: *mut jvmtiEnv, frames: &[Frame], root: &str) -> String {
+        if frames.len() <= 1 {
+            return root.to_owned();
+        }
+        let names: Vec<String> = frames
+            .iter()
+            .rev()
+            .map(|frame| self.method_name(env, frame.method))
+            .collect();
+        names.join("->")
+    }
+}
+
+fn write_entry(out: &mut impl Write, entry: &Entry) -> io::Result<()> {
+    writeln!(out, "{:x} {:x} {}", entry.start, entry.size, entry.name)
+}
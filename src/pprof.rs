@@ -0,0 +1,703 @@
+//! pprof `profile.proto` export.
+//!
+//! [`ProfileBuilder`] collects samples, each a stack of [`Frame`]s with one
+//! value per sample type, and encodes them as a gzip compressed
+//! [`profile.proto`](https://github.com/google/pprof/blob/main/proto/profile.proto)
+//! message as read by `go tool pprof` and compatible tools.
+//!
+//! Methods become `Function`s named like `java.lang.String.valueOf`, with the
+//! source file derived from the package and `GetSourceFileName`, and frames
+//! become `Location`s with the line from `GetLineNumberTable`. Both are
+//! deduplicated across samples. [`ProfileBuilder::cpu`],
+//! [`wall`](ProfileBuilder::wall), [`allocation`](ProfileBuilder::allocation)
+//! and [`lock`](ProfileBuilder::lock) set up the sample types pprof expects
+//! for the usual profiles.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use jni_sys::jmethodID;
+
+use crate::alloc_profiler::AllocationProfiler;
+use crate::jvmtiEnv;
+use crate::method_cache::{MethodCache, MethodInfo};
+use crate::sampler::{CallTree, SampleCounts};
+use crate::stack::Frame;
+use crate::util::class_name;
+
+/// A label attached to a sample.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Label {
+    Str {
+        key: String,
+        value: String,
+    },
+    Num {
+        key: String,
+        value: i64,
+        unit: String,
+    },
+}
+
+#[derive(Debug)]
+struct Sample {
+    locations: Vec<u64>,
+    values: Vec<i64>,
+    /// Encoded `Label` messages.
+    labels: Vec<Message>,
+}
+
+#[derive(Debug)]
+struct Function {
+    name: i64,
+    system_name: i64,
+    filename: i64,
+    start_line: i64,
+}
+
+#[derive(Debug)]
+struct Location {
+    function: u64,
+    line: i64,
+}
+
+/// Accumulates samples and encodes them as a pprof profile.
+#[derive(Debug)]
+pub struct ProfileBuilder {
+    sample_types: Vec<(i64, i64)>,
+    default_sample_type: i64,
+    period_type: Option<(i64, i64)>,
+    period: i64,
+    time: SystemTime,
+    duration: Option<Duration>,
+    comments: Vec<i64>,
+    strings: Vec<String>,
+    string_ids: HashMap<String, i64>,
+    functions: Vec<Function>,
+    function_ids: HashMap<jmethodID, u64>,
+    locations: Vec<Location>,
+    location_ids: HashMap<Frame, u64>,
+    samples: Vec<Sample>,
+}
+
+// The builder only holds method IDs, which are not bound to a thread.
+unsafe impl Send for ProfileBuilder {}
+unsafe impl Sync for ProfileBuilder {}
+
+impl ProfileBuilder {
+    /// Creates a profile whose samples carry one value for each
+    /// `(type, unit)` pair of `sample_types`, e.g. `("cpu", "nanoseconds")`.
+    pub fn new(sample_types: &[(&str, &str)]) -> Self {
+        let mut builder = ProfileBuilder {
+            sample_types: Vec::new(),
+            default_sample_type: 0,
+            period_type: None,
+            period: 0,
+            time: SystemTime::now(),
+            duration: None,
+            comments: Vec::new(),
+            strings: vec![String::new()],
+            string_ids: HashMap::from([(String::new(), 0)]),
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+            locations: Vec::new(),
+            location_ids: HashMap::new(),
+            samples: Vec::new(),
+        };
+        builder.sample_types = sample_types
+            .iter()
+            .map(|&(kind, unit)| (builder.string(kind), builder.string(unit)))
+            .collect();
+        builder
+    }
+
+    /// A CPU profile with `samples`/`count` and `cpu`/`nanoseconds` values,
+    /// sampled every `interval`.
+    pub fn cpu(interval: Duration) -> Self {
+        Self::new(&[("samples", "count"), ("cpu", "nanoseconds")]).with_period(
+            "cpu",
+            "nanoseconds",
+            interval.as_nanos() as i64,
+        )
+    }
+
+    /// A wall-clock profile with `samples`/`count` and `wall`/`nanoseconds`
+    /// values, sampled every `interval`.
+    pub fn wall(interval: Duration) -> Self {
+        Self::new(&[("samples", "count"), ("wall", "nanoseconds")]).with_period(
+            "wall",
+            "nanoseconds",
+            interval.as_nanos() as i64,
+        )
+    }
+
+    /// An allocation profile with `alloc_objects`/`count`,
+    /// `alloc_space`/`bytes`, `inuse_objects`/`count` and `inuse_space`/`bytes`
+    /// values, sampled every `interval` bytes.
+    pub fn allocation(interval: i64) -> Self {
+        Self::new(&[
+            ("alloc_objects", "count"),
+            ("alloc_space", "bytes"),
+            ("inuse_objects", "count"),
+            ("inuse_space", "bytes"),
+        ])
+        .with_period("space", "bytes", interval)
+    }
+
+    /// A lock contention profile with `contentions`/`count` and
+    /// `delay`/`nanoseconds` values.
+    pub fn lock() -> Self {
+        Self::new(&[("contentions", "count"), ("delay", "nanoseconds")]).with_period(
+            "contentions",
+            "count",
+            1,
+        )
+    }
+
+    /// Sets the sampling period, e.g. `("cpu", "nanoseconds", 10_000_000)`.
+    pub fn with_period(mut self, kind: &str, unit: &str, period: i64) -> Self {
+        self.period_type = Some((self.string(kind), self.string(unit)));
+        self.period = period;
+        self
+    }
+
+    /// Sets the sample type pprof shows by default.
+    pub fn with_default_sample_type(mut self, kind: &str) -> Self {
+        self.default_sample_type = self.string(kind);
+        self
+    }
+
+    /// Sets the start time and duration of the profile. By default the
+    /// profile starts when the builder is created and lasts until it is
+    /// encoded.
+    pub fn with_time(mut self, start: SystemTime, duration: Duration) -> Self {
+        self.time = start;
+        self.duration = Some(duration);
+        self
+    }
+
+    pub fn add_comment(&mut self, comment: &str) {
+        let comment = self.string(comment);
+        self.comments.push(comment);
+    }
+
+    /// Adds a sample with the given stack, innermost frame first, and one
+    /// value per sample type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of values differs from the number of sample
+    /// types.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn add_sample(
+        &mut self,
+        env: *mut jvmtiEnv,
+        cache: &MethodCache,
+        stack: &[Frame],
+        values: &[i64],
+    ) {
+        self.add_labeled_sample(env, cache, stack, values, Vec::new())
+    }
+
+    /// Like [`add_sample`](Self::add_sample), with labels such as the thread
+    /// name.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn add_labeled_sample(
+        &mut self,
+        env: *mut jvmtiEnv,
+        cache: &MethodCache,
+        stack: &[Frame],
+        values: &[i64],
+        labels: Vec<Label>,
+    ) {
+        assert_eq!(
+            values.len(),
+            self.sample_types.len(),
+            "one value per sample type"
+        );
+        let locations = stack
+            .iter()
+            .map(|frame| self.location(env, cache, frame))
+            .collect();
+        let labels = labels
+            .into_iter()
+            .map(|label| {
+                let mut message = Message::default();
+                match label {
+                    Label::Str { key, value } => {
+                        message.int(1, self.string(&key));
+                        message.int(2, self.string(&value));
+                    }
+                    Label::Num { key, value, unit } => {
+                        message.int(1, self.string(&key));
+                        message.int(3, value);
+                        message.int(4, self.string(&unit));
+                    }
+                }
+                message
+            })
+            .collect();
+        self.samples.push(Sample {
+            locations,
+            values: values.to_vec(),
+            labels,
+        });
+    }
+
+    /// Adds the samples of a [`CallTree`], with the values `values` returns
+    /// for the samples ending at each node. For a [`cpu`](Self::cpu)
+    /// profile, pass `|c| vec![c.samples() as i64, c.cpu_nanos as i64]`.
+    /// Lines are unknown as the tree does not record locations.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn add_call_tree(
+        &mut self,
+        env: *mut jvmtiEnv,
+        cache: &MethodCache,
+        tree: &CallTree,
+        values: impl Fn(&SampleCounts) -> Vec<i64>,
+    ) {
+        for (node, data) in tree.nodes().iter().enumerate().skip(1) {
+            if data.exclusive.samples() == 0 {
+                continue;
+            }
+            let stack: Vec<Frame> = tree
+                .path(node)
+                .into_iter()
+                .map(|method| Frame {
+                    method,
+                    location: -1,
+                })
+                .collect();
+            self.add_sample(env, cache, &stack, &values(&data.exclusive));
+        }
+    }
+
+    /// Adds the sites of an [`AllocationProfiler`] to an
+    /// [`allocation`](Self::allocation) profile, labeled with the allocated
+    /// class. In-use values are zero unless live objects are tracked.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn add_allocations(
+        &mut self,
+        env: *mut jvmtiEnv,
+        cache: &MethodCache,
+        profiler: &AllocationProfiler,
+    ) {
+        for (site, stats) in profiler.snapshot() {
+            let values = [
+                stats.allocations.round() as i64,
+                stats.bytes.round() as i64,
+                stats.live_allocations.round() as i64,
+                stats.live_bytes.round() as i64,
+            ];
+            let label = Label::Str {
+                key: "object".to_owned(),
+                value: class_name(&site.class_signature),
+            };
+            self.add_labeled_sample(env, cache, &site.stack, &values, vec![label]);
+        }
+    }
+
+    /// Returns the number of samples added.
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn string(&mut self, value: &str) -> i64 {
+        if let Some(&id) = self.string_ids.get(value) {
+            return id;
+        }
+        let id = self.strings.len() as i64;
+        self.strings.push(value.to_owned());
+        self.string_ids.insert(value.to_owned(), id);
+        id
+    }
+
+    unsafe fn location(&mut self, env: *mut jvmtiEnv, cache: &MethodCache, frame: &Frame) -> u64 {
+        if let Some(&id) = self.location_ids.get(frame) {
+            return id;
+        }
+        let info = cache.get(env, frame.method).ok();
+        let function = self.function(frame.method, info.as_deref());
+        let line = info
+            .and_then(|info| info.line_table.line_for(frame.location))
+            .unwrap_or(0);
+        self.locations.push(Location {
+            function,
+            line: line as i64,
+        });
+        let id = self.locations.len() as u64;
+        self.location_ids.insert(*frame, id);
+        id
+    }
+
+    fn function(&mut self, method: jmethodID, info: Option<&MethodInfo>) -> u64 {
+        if let Some(&id) = self.function_ids.get(&method) {
+            return id;
+        }
+        let function = match info {
+            Some(info) => {
+                let name = info.qualified_name();
+                let system_name = format!("{name}{}", info.signature);
+                let filename = info.source_path().unwrap_or_default();
+                let start_line = info.line_table.lines().into_iter().min().unwrap_or(0);
+                Function {
+                    name: self.string(&name),
+                    system_name: self.string(&system_name),
+                    filename: self.string(&filename),
+                    start_line: start_line as i64,
+                }
+            }
+            None => {
+                let name = self.string("<unknown method>");
+                Function {
+                    name,
+                    system_name: name,
+                    filename: 0,
+                    start_line: 0,
+                }
+            }
+        };
+        self.functions.push(function);
+        let id = self.functions.len() as u64;
+        self.function_ids.insert(method, id);
+        id
+    }
+
+    /// Encodes the profile as an uncompressed `profile.proto` message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut profile = Message::default();
+        for &(kind, unit) in &self.sample_types {
+            profile.message(1, &value_type(kind, unit));
+        }
+        for sample in &self.samples {
+            let mut message = Message::default();
+            message.packed(1, sample.locations.iter().copied());
+            message.packed(2, sample.values.iter().map(|&value| value as u64));
+            for label in &sample.labels {
+                message.message(3, label);
+            }
+            profile.message(2, &message);
+        }
+        for (index, location) in self.locations.iter().enumerate() {
+            let mut line = Message::default();
+            line.uint(1, location.function);
+            line.int(2, location.line);
+            let mut message = Message::default();
+            message.uint(1, index as u64 + 1);
+            message.message(4, &line);
+            profile.message(4, &message);
+        }
+        for (index, function) in self.functions.iter().enumerate() {
+            let mut message = Message::default();
+            message.uint(1, index as u64 + 1);
+            message.int(2, function.name);
+            message.int(3, function.system_name);
+            message.int(4, function.filename);
+            message.int(5, function.start_line);
+            profile.message(5, &message);
+        }
+        for string in &self.strings {
+            profile.bytes(6, string.as_bytes());
+        }
+        let start = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let duration = self
+            .duration
+            .unwrap_or_else(|| self.time.elapsed().unwrap_or_default());
+        profile.int(9, start.as_nanos() as i64);
+        profile.int(10, duration.as_nanos() as i64);
+        if let Some((kind, unit)) = self.period_type {
+            profile.message(11, &value_type(kind, unit));
+        }
+        profile.int(12, self.period);
+        profile.packed(13, self.comments.iter().map(|&comment| comment as u64));
+        profile.int(14, self.default_sample_type);
+        profile.0
+    }
+
+    /// Writes the gzip compressed profile to `out`.
+    pub fn write(&self, out: impl Write) -> io::Result<()> {
+        let mut encoder = GzEncoder::new(out, Compression::default());
+        encoder.write_all(&self.encode())?;
+        encoder.finish()?.flush()
+    }
+
+    /// Writes the gzip compressed profile to the file at `path`.
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+}
+
+fn value_type(kind: i64, unit: i64) -> Message {
+    let mut message = Message::default();
+    message.int(1, kind);
+    message.int(2, unit);
+    message
+}
+
+/// A protobuf message being encoded. Fields with default values are left
+/// out, as in proto3.
+#[derive(Debug, Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, 0);
+            self.varint(value);
+        }
+    }
+
+    fn int(&mut self, field: u32, value: i64) {
+        self.uint(field, value as u64);
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn message(&mut self, field: u32, message: &Message) {
+        self.bytes(field, &message.0);
+    }
+
+    fn packed(&mut self, field: u32, values: impl Iterator<Item = u64>) {
+        let mut packed = Message::default();
+        for value in values {
+            packed.varint(value);
+        }
+        if !packed.0.is_empty() {
+            self.bytes(field, &packed.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::ptr::null_mut;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    /// A decoded protobuf field: a varint or a length delimited value.
+    #[derive(Debug, PartialEq)]
+    enum Field {
+        Varint(u64),
+        Bytes(Vec<u8>),
+    }
+
+    fn varint(bytes: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = bytes.split_first().expect("truncated varint");
+            *bytes = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return value;
+            }
+        }
+        panic!("varint too long");
+    }
+
+    fn decode(mut bytes: &[u8]) -> Vec<(u64, Field)> {
+        let mut fields = Vec::new();
+        while !bytes.is_empty() {
+            let key = varint(&mut bytes);
+            let field = match key & 7 {
+                0 => Field::Varint(varint(&mut bytes)),
+                2 => {
+                    let len = varint(&mut bytes) as usize;
+                    let (value, rest) = bytes.split_at(len);
+                    bytes = rest;
+                    Field::Bytes(value.to_vec())
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push((key >> 3, field));
+        }
+        fields
+    }
+
+    fn varints(fields: &[(u64, Field)], number: u64) -> Vec<u64> {
+        fields
+            .iter()
+            .filter(|(n, _)| *n == number)
+            .map(|(_, field)| match field {
+                Field::Varint(value) => *value,
+                Field::Bytes(_) => panic!("field {number} is not a varint"),
+            })
+            .collect()
+    }
+
+    fn messages(fields: &[(u64, Field)], number: u64) -> Vec<Vec<(u64, Field)>> {
+        fields
+            .iter()
+            .filter(|(n, _)| *n == number)
+            .map(|(_, field)| match field {
+                Field::Bytes(bytes) => decode(bytes),
+                Field::Varint(_) => panic!("field {number} is not a message"),
+            })
+            .collect()
+    }
+
+    fn packed(fields: &[(u64, Field)], number: u64) -> Vec<u64> {
+        let mut values = Vec::new();
+        for (_, field) in fields.iter().filter(|(n, _)| *n == number) {
+            let Field::Bytes(bytes) = field else {
+                panic!("packed field {number} is not length delimited");
+            };
+            let mut bytes = &bytes[..];
+            while !bytes.is_empty() {
+                values.push(varint(&mut bytes));
+            }
+        }
+        values
+    }
+
+    #[test]
+    fn message_wire_format() {
+        let mut message = Message::default();
+        message.uint(1, 300);
+        message.int(2, 0);
+        message.int(3, -1);
+        message.bytes(6, b"ab");
+        message.packed(13, [1, 128].into_iter());
+        message.packed(14, core::iter::empty());
+        let mut expected = vec![0x08, 0xac, 0x02, 0x18];
+        expected.extend([0xff; 9]);
+        expected.extend([0x01, 0x32, 0x02, b'a', b'b', 0x6a, 0x03, 0x01, 0x80, 0x01]);
+        assert_eq!(message.0, expected);
+    }
+
+    #[test]
+    fn profile_round_trip() {
+        let start = UNIX_EPOCH + Duration::from_secs(5);
+        let mut builder = ProfileBuilder::cpu(Duration::from_millis(10))
+            .with_default_sample_type("cpu")
+            .with_time(start, Duration::from_secs(2));
+        builder.add_comment("test");
+
+        // Resolve the frame up front so that adding the sample needs no VM.
+        let frame = Frame {
+            method: 0x10 as jmethodID,
+            location: 3,
+        };
+        let function = builder.function(frame.method, None);
+        builder.locations.push(Location { function, line: 42 });
+        builder.location_ids.insert(frame, 1);
+        let label = Label::Num {
+            key: "thread".to_owned(),
+            value: 7,
+            unit: "id".to_owned(),
+        };
+        unsafe {
+            builder.add_labeled_sample(
+                null_mut(),
+                &MethodCache::new(),
+                &[frame],
+                &[1, 10_000_000],
+                vec![label],
+            );
+        }
+
+        let mut gzipped = Vec::new();
+        builder.write(&mut gzipped).unwrap();
+        let mut encoded = Vec::new();
+        GzDecoder::new(&gzipped[..])
+            .read_to_end(&mut encoded)
+            .unwrap();
+        assert_eq!(encoded, builder.encode());
+
+        let profile = decode(&encoded);
+        let strings: Vec<String> = profile
+            .iter()
+            .filter(|(n, _)| *n == 6)
+            .map(|(_, field)| match field {
+                Field::Bytes(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+                Field::Varint(_) => panic!("string is not length delimited"),
+            })
+            .collect();
+        let string = |id: u64| strings[id as usize].as_str();
+        assert_eq!(string(0), "");
+
+        let sample_types: Vec<_> = messages(&profile, 1)
+            .iter()
+            .map(|value_type| {
+                (
+                    string(varints(value_type, 1)[0]),
+                    string(varints(value_type, 2)[0]),
+                )
+            })
+            .collect();
+        assert_eq!(sample_types, [("samples", "count"), ("cpu", "nanoseconds")]);
+
+        let samples = messages(&profile, 2);
+        assert_eq!(samples.len(), 1);
+        assert_eq!(packed(&samples[0], 1), [1]);
+        assert_eq!(packed(&samples[0], 2), [1, 10_000_000]);
+        let labels = messages(&samples[0], 3);
+        assert_eq!(labels.len(), 1);
+        assert_eq!(string(varints(&labels[0], 1)[0]), "thread");
+        assert_eq!(varints(&labels[0], 3), [7]);
+        assert_eq!(string(varints(&labels[0], 4)[0]), "id");
+
+        let locations = messages(&profile, 4);
+        assert_eq!(locations.len(), 1);
+        assert_eq!(varints(&locations[0], 1), [1]);
+        let lines = messages(&locations[0], 4);
+        assert_eq!(varints(&lines[0], 1), [1]);
+        assert_eq!(varints(&lines[0], 2), [42]);
+
+        let functions = messages(&profile, 5);
+        assert_eq!(functions.len(), 1);
+        assert_eq!(varints(&functions[0], 1), [1]);
+        assert_eq!(string(varints(&functions[0], 2)[0]), "<unknown method>");
+        assert_eq!(varints(&functions[0], 3), varints(&functions[0], 2));
+
+        assert_eq!(varints(&profile, 9), [5_000_000_000]);
+        assert_eq!(varints(&profile, 10), [2_000_000_000]);
+        let period_type = &messages(&profile, 11)[0];
+        assert_eq!(string(varints(period_type, 1)[0]), "cpu");
+        assert_eq!(string(varints(period_type, 2)[0]), "nanoseconds");
+        assert_eq!(varints(&profile, 12), [10_000_000]);
+        assert_eq!(
+            packed(&profile, 13)
+                .into_iter()
+                .map(string)
+                .collect::<Vec<_>>(),
+            ["test"]
+        );
+        assert_eq!(string(varints(&profile, 14)[0]), "cpu");
+    }
+}
@@ -0,0 +1,979 @@
+//! Class redefinition with pre-flight checks.
+//!
+//! `RedefineClasses` replaces the definitions of loaded classes, but only if
+//! the new class files keep their shape: the same superclass, interfaces,
+//! fields and methods with the same modifiers. Otherwise it fails the whole
+//! batch with one of the `JVMTI_ERROR_UNSUPPORTED_REDEFINITION_*` codes and
+//! no hint as to which class or member caused it.
+//!
+//! A [`Redefinition`] collects class definitions and, before calling the VM,
+//! compares the [`ClassShape`] of each loaded class with that of its new
+//! class file. Every change the VM would reject is reported as a
+//! [`Violation`] naming the field or method involved, and every class gets
+//! its own [`ClassReport`]. Checks that need the loaded class file, such as
+//! changes to `NestHost`, `NestMembers`, `Record` or `PermittedSubclasses`,
+//! and verification are left to the VM.
+//!
+//! The VM compares the `access_flags` of the class files, which JVMTI does
+//! not report for nested classes: `GetClassModifiers` returns the flags of
+//! their `InnerClasses` entry instead. Unless the class file the class was
+//! loaded from is given with [`Redefinition::add_with_loaded`], changes of
+//! the modifiers of a nested class are only reported as warnings.
+//!
+//! Redefinition requires the `can_redefine_classes` capability.
+
+use core::ffi::c_uchar;
+use core::fmt;
+use core::ptr::null_mut;
+
+use jni_sys::{jboolean, jclass, jint, JNIEnv};
+
+use crate::classfile::{AccessFlags, ClassFile, Member, ParseError};
+use crate::util::{check, delete_local_refs, take_array, take_string};
+use crate::{jvmtiClassDefinition, jvmtiEnv, jvmtiError};
+
+/// The class modifiers the VM recognizes.
+const CLASS_MODIFIERS: AccessFlags = AccessFlags::from_bits_retain(0x7631);
+/// The field modifiers the VM recognizes.
+const FIELD_MODIFIERS: AccessFlags = AccessFlags::from_bits_retain(0x50df);
+/// The method modifiers the VM recognizes.
+const METHOD_MODIFIERS: AccessFlags = AccessFlags::from_bits_retain(0x1dff);
+
+/// A field or method of a [`ClassShape`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MemberShape {
+    pub name: String,
+    pub descriptor: String,
+    pub modifiers: AccessFlags,
+}
+
+/// The parts of a class `RedefineClasses` does not allow to change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClassShape {
+    /// The internal name, e.g. `java/lang/String`.
+    pub name: String,
+    /// The internal name of the superclass, `None` for `java.lang.Object`.
+    pub super_class: Option<String>,
+    /// The internal names of the direct superinterfaces, in declaration
+    /// order.
+    pub interfaces: Vec<String>,
+    /// The modifiers as `GetClassModifiers` reports them: those of the
+    /// `InnerClasses` entry for nested classes.
+    pub modifiers: AccessFlags,
+    /// The `access_flags` of the class file, restricted to those the VM
+    /// recognizes, which `RedefineClasses` compares. `None` if unknown, as
+    /// for the shapes of loaded classes.
+    pub access_flags: Option<AccessFlags>,
+    /// The declared fields, in class file order.
+    pub fields: Vec<MemberShape>,
+    pub methods: Vec<MemberShape>,
+}
+
+impl ClassShape {
+    /// Describes the loaded class `klass`.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment, `jni` the JNI environment of
+    /// the current thread and `klass` a valid reference.
+    pub unsafe fn new(
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        klass: jclass,
+    ) -> Result<Self, jvmtiError> {
+        let name = internal_name(env, klass)?;
+
+        let mut modifiers = 0;
+        check(jvmti!(env, v1, GetClassModifiers, klass, &mut modifiers))?;
+        let modifiers = AccessFlags::from_bits_retain(modifiers as u16);
+
+        let super_class = ((**jni).v1_1.GetSuperclass)(jni, klass);
+        let super_class = if !super_class.is_null() {
+            let name = internal_name(env, super_class);
+            delete_local_refs(jni, &[super_class]);
+            Some(name?)
+        } else if modifiers.contains(AccessFlags::INTERFACE) {
+            // Interfaces extend `java.lang.Object` in their class files,
+            // but `GetSuperclass` returns null for them.
+            Some("java/lang/Object".to_owned())
+        } else {
+            None
+        };
+
+        let mut count = 0;
+        let mut interfaces = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetImplementedInterfaces,
+            klass,
+            &mut count,
+            &mut interfaces
+        ))?;
+        let interfaces = take_array(env, interfaces, count);
+        let names = interfaces
+            .iter()
+            .map(|&interface| internal_name(env, interface))
+            .collect::<Result<_, _>>();
+        delete_local_refs(jni, &interfaces);
+        let interfaces = names?;
+
+        let mut count = 0;
+        let mut fields = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetClassFields,
+            klass,
+            &mut count,
+            &mut fields
+        ))?;
+        let mut field_shapes = Vec::with_capacity(count as usize);
+        for field in take_array(env, fields, count) {
+            let mut name = null_mut();
+            let mut signature = null_mut();
+            check(jvmti!(
+                env,
+                v1,
+                GetFieldName,
+                klass,
+                field,
+                &mut name,
+                &mut signature,
+                null_mut()
+            ))?;
+            // Taken before the next call can fail and return early.
+            let name = take_string(env, name).unwrap_or_default();
+            let descriptor = take_string(env, signature).unwrap_or_default();
+            let mut modifiers = 0;
+            check(jvmti!(
+                env,
+                v1,
+                GetFieldModifiers,
+                klass,
+                field,
+                &mut modifiers
+            ))?;
+            field_shapes.push(MemberShape {
+                name,
+                descriptor,
+                modifiers: AccessFlags::from_bits_retain(modifiers as u16) & FIELD_MODIFIERS,
+            });
+        }
+
+        let mut count = 0;
+        let mut methods = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetClassMethods,
+            klass,
+            &mut count,
+            &mut methods
+        ))?;
+        let mut method_shapes = Vec::with_capacity(count as usize);
+        for method in take_array(env, methods, count) {
+            let mut name = null_mut();
+            let mut signature = null_mut();
+            check(jvmti!(
+                env,
+                v1,
+                GetMethodName,
+                method,
+                &mut name,
+                &mut signature,
+                null_mut()
+            ))?;
+            let name = take_string(env, name).unwrap_or_default();
+            let descriptor = take_string(env, signature).unwrap_or_default();
+            let mut modifiers = 0;
+            check(jvmti!(env, v1, GetMethodModifiers, method, &mut modifiers))?;
+            method_shapes.push(MemberShape {
+                name,
+                descriptor,
+                modifiers: AccessFlags::from_bits_retain(modifiers as u16) & METHOD_MODIFIERS,
+            });
+        }
+
+        Ok(ClassShape {
+            name,
+            super_class,
+            interfaces,
+            modifiers,
+            access_flags: None,
+            fields: field_shapes,
+            methods: method_shapes,
+        })
+    }
+
+    /// Describes the class `class` defines, with the modifiers the VM would
+    /// report once it is loaded.
+    pub fn from_class_file(class: &ClassFile<'_>) -> Self {
+        let name = class.name().to_str().into_owned();
+        let flags = member_class_flags(class, &name).unwrap_or(class.access_flags);
+        let mut modifiers = flags - AccessFlags::SUPER - AccessFlags::MODULE;
+        if class.access_flags.contains(AccessFlags::SUPER) {
+            modifiers |= AccessFlags::SUPER;
+        }
+        let members = |members: &[Member<'_>], mask| {
+            members
+                .iter()
+                .map(|member| MemberShape {
+                    name: member.name.to_str().into_owned(),
+                    descriptor: member.descriptor.to_str().into_owned(),
+                    modifiers: member.access_flags & mask,
+                })
+                .collect()
+        };
+        ClassShape {
+            super_class: class.super_name().map(|name| name.to_str().into_owned()),
+            interfaces: class
+                .interface_names()
+                .map(|name| name.to_str().into_owned())
+                .collect(),
+            modifiers,
+            access_flags: Some(class.access_flags & CLASS_MODIFIERS),
+            fields: members(&class.fields, FIELD_MODIFIERS),
+            methods: members(&class.methods, METHOD_MODIFIERS),
+            name,
+        }
+    }
+
+    /// Returns the changes from `self` to `new` that `RedefineClasses`
+    /// would reject.
+    pub fn diff(&self, new: &ClassShape) -> Vec<Violation> {
+        if self.name != new.name {
+            // The rest would only compare unrelated classes.
+            return vec![Violation::NameMismatch {
+                expected: self.name.clone(),
+                found: new.name.clone(),
+            }];
+        }
+        let mut violations = Vec::new();
+        if self.super_class != new.super_class {
+            violations.push(Violation::SuperclassChanged {
+                old: self.super_class.clone(),
+                new: new.super_class.clone(),
+            });
+        }
+        if self.interfaces != new.interfaces {
+            violations.push(Violation::InterfacesChanged {
+                old: self.interfaces.clone(),
+                new: new.interfaces.clone(),
+            });
+        }
+        if let (Some(old), Some(new)) = (self.access_flags, new.access_flags) {
+            if old != new {
+                violations.push(Violation::ClassModifiersChanged { old, new });
+            }
+        }
+
+        let same =
+            |a: &MemberShape, b: &MemberShape| a.name == b.name && a.descriptor == b.descriptor;
+        let mut fields_changed = false;
+        for old in &self.fields {
+            match new.fields.iter().find(|field| same(field, old)) {
+                None => {
+                    fields_changed = true;
+                    violations.push(Violation::FieldRemoved(old.clone()));
+                }
+                Some(field) if field.modifiers != old.modifiers => {
+                    violations.push(Violation::FieldModifiersChanged {
+                        old: old.clone(),
+                        new: field.clone(),
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+        for field in &new.fields {
+            if !self.fields.iter().any(|old| same(old, field)) {
+                fields_changed = true;
+                violations.push(Violation::FieldAdded(field.clone()));
+            }
+        }
+        if !fields_changed
+            && !self
+                .fields
+                .iter()
+                .zip(&new.fields)
+                .all(|(old, field)| same(old, field))
+        {
+            violations.push(Violation::FieldsReordered);
+        }
+
+        // Methods may become native and vice versa, for native method
+        // prefixes.
+        for old in &self.methods {
+            match new.methods.iter().find(|method| same(method, old)) {
+                None => violations.push(Violation::MethodDeleted(old.clone())),
+                Some(method)
+                    if method.modifiers - AccessFlags::NATIVE
+                        != old.modifiers - AccessFlags::NATIVE =>
+                {
+                    violations.push(Violation::MethodModifiersChanged {
+                        old: old.clone(),
+                        new: method.clone(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        for method in &new.methods {
+            if !self.methods.iter().any(|old| same(old, method)) {
+                violations.push(Violation::MethodAdded(method.clone()));
+            }
+        }
+        violations
+    }
+
+    /// Returns the changes from `self` to `new` that `RedefineClasses` may
+    /// reject but [`diff`](Self::diff) cannot tell: changes of the
+    /// modifiers when the `access_flags` of either class file are unknown.
+    pub fn warnings(&self, new: &ClassShape) -> Vec<Violation> {
+        let unknown = self.access_flags.is_none() || new.access_flags.is_none();
+        if self.name == new.name && unknown && self.modifiers != new.modifiers {
+            vec![Violation::ClassModifiersChanged {
+                old: self.modifiers,
+                new: new.modifiers,
+            }]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Returns the flags of the `InnerClasses` entry of `class` for itself, if
+/// it is a nested class.
+fn member_class_flags(class: &ClassFile<'_>, name: &str) -> Option<AccessFlags> {
+    let data = class.attribute("InnerClasses")?.data;
+    let u2 = |at: usize| Some(u16::from_be_bytes([*data.get(at)?, *data.get(at + 1)?]));
+    (0..u2(0)? as usize).find_map(|i| {
+        let entry = 2 + i * 8;
+        let inner = class.constant_pool.class_name(u2(entry)?)?;
+        if inner == name {
+            u2(entry + 6).map(AccessFlags::from_bits_retain)
+        } else {
+            None
+        }
+    })
+}
+
+unsafe fn internal_name(env: *mut jvmtiEnv, klass: jclass) -> Result<String, jvmtiError> {
+    let mut signature = null_mut();
+    check(jvmti!(
+        env,
+        v1,
+        GetClassSignature,
+        klass,
+        &mut signature,
+        null_mut()
+    ))?;
+    let signature = take_string(env, signature).unwrap_or_default();
+    Ok(
+        match signature
+            .strip_prefix('L')
+            .and_then(|name| name.strip_suffix(';'))
+        {
+            Some(name) => name.to_owned(),
+            None => signature,
+        },
+    )
+}
+
+/// A change `RedefineClasses` would reject.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// `IsModifiableClass` reports that the class cannot be redefined, as
+    /// for arrays, primitive classes and some classes of the VM.
+    Unmodifiable,
+    InvalidClassFormat(ParseError),
+    /// The new class file defines another class.
+    NameMismatch {
+        expected: String,
+        found: String,
+    },
+    SuperclassChanged {
+        old: Option<String>,
+        new: Option<String>,
+    },
+    /// The direct superinterfaces or their order changed.
+    InterfacesChanged {
+        old: Vec<String>,
+        new: Vec<String>,
+    },
+    /// The `access_flags` of the class file changed or, in
+    /// [`ClassReport::warnings`], the modifiers `GetClassModifiers` reports.
+    ClassModifiersChanged {
+        old: AccessFlags,
+        new: AccessFlags,
+    },
+    FieldAdded(MemberShape),
+    FieldRemoved(MemberShape),
+    /// The same fields are declared in another order.
+    FieldsReordered,
+    FieldModifiersChanged {
+        old: MemberShape,
+        new: MemberShape,
+    },
+    /// A method was added. VMs before JDK 13 allowed adding private static
+    /// and private final methods, current ones only with
+    /// `-XX:+AllowRedefinitionToAddDeleteMethods`.
+    MethodAdded(MemberShape),
+    /// A method was deleted, with the same exceptions as for
+    /// [`MethodAdded`](Self::MethodAdded).
+    MethodDeleted(MemberShape),
+    MethodModifiersChanged {
+        old: MemberShape,
+        new: MemberShape,
+    },
+}
+
+impl Violation {
+    /// Returns the error `RedefineClasses` fails with for this change.
+    pub fn error(&self) -> jvmtiError {
+        match self {
+            Violation::Unmodifiable => jvmtiError::JVMTI_ERROR_UNMODIFIABLE_CLASS,
+            Violation::InvalidClassFormat(_) => jvmtiError::JVMTI_ERROR_INVALID_CLASS_FORMAT,
+            Violation::NameMismatch { .. } => jvmtiError::JVMTI_ERROR_NAMES_DONT_MATCH,
+            Violation::SuperclassChanged { .. } | Violation::InterfacesChanged { .. } => {
+                jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_HIERARCHY_CHANGED
+            }
+            Violation::ClassModifiersChanged { .. } => {
+                jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_CLASS_MODIFIERS_CHANGED
+            }
+            Violation::FieldAdded(_)
+            | Violation::FieldRemoved(_)
+            | Violation::FieldsReordered
+            | Violation::FieldModifiersChanged { .. } => {
+                jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_SCHEMA_CHANGED
+            }
+            Violation::MethodAdded(_) => {
+                jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_METHOD_ADDED
+            }
+            Violation::MethodDeleted(_) => {
+                jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_METHOD_DELETED
+            }
+            Violation::MethodModifiersChanged { .. } => {
+                jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_METHOD_MODIFIERS_CHANGED
+            }
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_none = |name: &Option<String>| name.clone().unwrap_or_else(|| "none".to_owned());
+        match self {
+            Violation::Unmodifiable => write!(f, "the class is not modifiable"),
+            Violation::InvalidClassFormat(err) => write!(f, "{err}"),
+            Violation::NameMismatch { expected, found } => {
+                write!(f, "the class file defines {found} instead of {expected}")
+            }
+            Violation::SuperclassChanged { old, new } => write!(
+                f,
+                "superclass changed from {} to {}",
+                or_none(old),
+                or_none(new)
+            ),
+            Violation::InterfacesChanged { old, new } => write!(
+                f,
+                "superinterfaces changed from [{}] to [{}]",
+                old.join(", "),
+                new.join(", ")
+            ),
+            Violation::ClassModifiersChanged { old, new } => write!(
+                f,
+                "class modifiers changed from {:#06x} to {:#06x}",
+                old.bits(),
+                new.bits()
+            ),
+            Violation::FieldAdded(field) => {
+                write!(f, "field {}:{} added", field.name, field.descriptor)
+            }
+            Violation::FieldRemoved(field) => {
+                write!(f, "field {}:{} removed", field.name, field.descriptor)
+            }
+            Violation::FieldsReordered => write!(f, "fields reordered"),
+            Violation::FieldModifiersChanged { old, new } => write!(
+                f,
+                "modifiers of field {}:{} changed from {:#06x} to {:#06x}",
+                new.name,
+                new.descriptor,
+                old.modifiers.bits(),
+                new.modifiers.bits()
+            ),
+            Violation::MethodAdded(method) => {
+                write!(f, "method {}{} added", method.name, method.descriptor)
+            }
+            Violation::MethodDeleted(method) => {
+                write!(f, "method {}{} deleted", method.name, method.descriptor)
+            }
+            Violation::MethodModifiersChanged { old, new } => write!(
+                f,
+                "modifiers of method {}{} changed from {:#06x} to {:#06x}",
+                new.name,
+                new.descriptor,
+                old.modifiers.bits(),
+                new.modifiers.bits()
+            ),
+        }
+    }
+}
+
+/// What happened to a class of a [`Redefinition`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RedefinitionOutcome {
+    /// The class passed the pre-flight checks. Only reported by
+    /// [`Redefinition::check`].
+    Passed,
+    Redefined,
+    /// The pre-flight checks found changes the VM would reject, so the
+    /// class was not submitted.
+    Rejected(Vec<Violation>),
+    /// The class passed the pre-flight checks but was not submitted
+    /// because other classes of the batch were rejected.
+    Skipped,
+    /// Querying the loaded class or `RedefineClasses` failed. The VM
+    /// redefines all classes or none, so all classes submitted together
+    /// report the same error.
+    Failed(jvmtiError),
+}
+
+/// The outcome of a class of a [`Redefinition`].
+#[derive(Clone, Debug)]
+pub struct ClassReport {
+    pub klass: jclass,
+    /// The internal name of the loaded class, empty if it could not be
+    /// determined.
+    pub name: String,
+    pub outcome: RedefinitionOutcome,
+    /// Changes the VM may reject that could not be checked, see
+    /// [`ClassShape::warnings`].
+    pub warnings: Vec<Violation>,
+}
+
+impl fmt::Display for ClassReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            RedefinitionOutcome::Passed => write!(f, "{}: passed", self.name),
+            RedefinitionOutcome::Redefined => write!(f, "{}: redefined", self.name),
+            RedefinitionOutcome::Rejected(violations) => {
+                write!(f, "{}: rejected", self.name)?;
+                for violation in violations {
+                    write!(f, "\n  {violation} ({:?})", violation.error())?;
+                }
+                Ok(())
+            }
+            RedefinitionOutcome::Skipped => {
+                write!(f, "{}: skipped, other classes were rejected", self.name)
+            }
+            RedefinitionOutcome::Failed(err) => write!(f, "{}: failed with {err:?}", self.name),
+        }?;
+        for warning in &self.warnings {
+            write!(f, "\n  warning: {warning} ({:?})", warning.error())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Definition {
+    klass: jclass,
+    class_data: Vec<u8>,
+    loaded_class_data: Option<Vec<u8>>,
+}
+
+/// A batch of class definitions to check and hand to `RedefineClasses`.
+#[derive(Clone, Debug, Default)]
+pub struct Redefinition {
+    definitions: Vec<Definition>,
+}
+
+impl Redefinition {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the new class file of `klass`.
+    pub fn add(&mut self, klass: jclass, class_data: Vec<u8>) -> &mut Self {
+        self.definitions.push(Definition {
+            klass,
+            class_data,
+            loaded_class_data: None,
+        });
+        self
+    }
+
+    /// Adds the new class file of `klass` along with the one it is loaded
+    /// from, e.g. as a `ClassFileLoadHook` received it, to check changes of
+    /// the modifiers of nested classes against.
+    pub fn add_with_loaded(
+        &mut self,
+        klass: jclass,
+        class_data: Vec<u8>,
+        loaded_class_data: Vec<u8>,
+    ) -> &mut Self {
+        self.definitions.push(Definition {
+            klass,
+            class_data,
+            loaded_class_data: Some(loaded_class_data),
+        });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.definitions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.definitions.is_empty()
+    }
+
+    /// Runs the pre-flight checks without redefining anything. Classes
+    /// report [`Passed`](RedefinitionOutcome::Passed),
+    /// [`Rejected`](RedefinitionOutcome::Rejected) or
+    /// [`Failed`](RedefinitionOutcome::Failed).
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment, `jni` the JNI environment of
+    /// the current thread and the classes valid references.
+    pub unsafe fn check(&self, env: *mut jvmtiEnv, jni: *mut JNIEnv) -> Vec<ClassReport> {
+        self.definitions
+            .iter()
+            .map(|definition| check_class(env, jni, definition))
+            .collect()
+    }
+
+    /// Checks the classes and redefines them if all of them pass. Otherwise
+    /// nothing is redefined and the classes that passed report
+    /// [`Skipped`](RedefinitionOutcome::Skipped).
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment with the
+    /// `can_redefine_classes` capability, `jni` the JNI environment of the
+    /// current thread and the classes valid references.
+    pub unsafe fn redefine(&self, env: *mut jvmtiEnv, jni: *mut JNIEnv) -> Vec<ClassReport> {
+        let mut reports = self.check(env, jni);
+        if reports
+            .iter()
+            .all(|report| report.outcome == RedefinitionOutcome::Passed)
+        {
+            self.submit(env, &mut reports);
+        } else {
+            for report in &mut reports {
+                if report.outcome == RedefinitionOutcome::Passed {
+                    report.outcome = RedefinitionOutcome::Skipped;
+                }
+            }
+        }
+        reports
+    }
+
+    /// Checks the classes and redefines those that pass.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment with the
+    /// `can_redefine_classes` capability, `jni` the JNI environment of the
+    /// current thread and the classes valid references.
+    pub unsafe fn redefine_passing(
+        &self,
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+    ) -> Vec<ClassReport> {
+        let mut reports = self.check(env, jni);
+        self.submit(env, &mut reports);
+        reports
+    }
+
+    /// Hands the classes that passed the checks to `RedefineClasses`.
+    unsafe fn submit(&self, env: *mut jvmtiEnv, reports: &mut [ClassReport]) {
+        let definitions: Vec<jvmtiClassDefinition> = self
+            .definitions
+            .iter()
+            .zip(reports.iter())
+            .filter(|(_, report)| report.outcome == RedefinitionOutcome::Passed)
+            .map(|(definition, _)| jvmtiClassDefinition {
+                klass: definition.klass,
+                class_byte_count: definition.class_data.len() as jint,
+                class_bytes: definition.class_data.as_ptr() as *const c_uchar,
+            })
+            .collect();
+        if definitions.is_empty() {
+            return;
+        }
+        let outcome = match check(jvmti!(
+            env,
+            v1,
+            RedefineClasses,
+            definitions.len() as jint,
+            definitions.as_ptr()
+        )) {
+            Ok(()) => RedefinitionOutcome::Redefined,
+            Err(err) => RedefinitionOutcome::Failed(err),
+        };
+        for report in reports {
+            if report.outcome == RedefinitionOutcome::Passed {
+                report.outcome = outcome.clone();
+            }
+        }
+    }
+}
+
+unsafe fn check_class(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    definition: &Definition,
+) -> ClassReport {
+    let klass = definition.klass;
+    let mut report = ClassReport {
+        klass,
+        name: String::new(),
+        outcome: RedefinitionOutcome::Passed,
+        warnings: Vec::new(),
+    };
+    match internal_name(env, klass) {
+        Ok(name) => report.name = name,
+        Err(err) => {
+            report.outcome = RedefinitionOutcome::Failed(err);
+            return report;
+        }
+    }
+
+    let mut modifiable: jboolean = false;
+    if let Err(err) = check(jvmti!(env, v1_1, IsModifiableClass, klass, &mut modifiable)) {
+        report.outcome = RedefinitionOutcome::Failed(err);
+        return report;
+    }
+    if !modifiable {
+        report.outcome = RedefinitionOutcome::Rejected(vec![Violation::Unmodifiable]);
+        return report;
+    }
+
+    let (new, nested) = match ClassFile::parse(&definition.class_data) {
+        Ok(class) => {
+            let new = ClassShape::from_class_file(&class);
+            let nested = member_class_flags(&class, &new.name).is_some();
+            (new, nested)
+        }
+        Err(err) => {
+            report.outcome =
+                RedefinitionOutcome::Rejected(vec![Violation::InvalidClassFormat(err)]);
+            return report;
+        }
+    };
+    let mut old = match ClassShape::new(env, jni, klass) {
+        Ok(old) => old,
+        Err(err) => {
+            report.outcome = RedefinitionOutcome::Failed(err);
+            return report;
+        }
+    };
+    let loaded = definition
+        .loaded_class_data
+        .as_deref()
+        .map(ClassFile::parse);
+    old.access_flags = match loaded {
+        Some(Ok(loaded)) => Some(loaded.access_flags & CLASS_MODIFIERS),
+        // `GetClassModifiers` reports the `access_flags` of classes that
+        // are not nested, taking the loaded class to be nested if the new
+        // class file is.
+        _ if !nested => Some(old.modifiers & CLASS_MODIFIERS),
+        _ => None,
+    };
+    report.warnings = old.warnings(&new);
+    let violations = old.diff(&new);
+    if !violations.is_empty() {
+        report.outcome = RedefinitionOutcome::Rejected(violations);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &[u8] = include_bytes!("classfile/testdata/Fixture.class");
+    /// The nested class `Fixture.Inner`.
+    const INNER: &[u8] = include_bytes!("classfile/testdata/Fixture$Inner.class");
+
+    fn shape(data: &[u8]) -> ClassShape {
+        ClassShape::from_class_file(&ClassFile::parse(data).unwrap())
+    }
+
+    fn member(name: &str, descriptor: &str, modifiers: AccessFlags) -> MemberShape {
+        MemberShape {
+            name: name.to_owned(),
+            descriptor: descriptor.to_owned(),
+            modifiers,
+        }
+    }
+
+    #[test]
+    fn describes_fixture() {
+        let fixture = shape(FIXTURE);
+        assert_eq!(fixture.name, "Fixture");
+        assert_eq!(fixture.super_class.as_deref(), Some("java/lang/Object"));
+        assert!(fixture.interfaces.is_empty());
+        assert_eq!(fixture.modifiers, AccessFlags::PUBLIC | AccessFlags::SUPER);
+        assert_eq!(fixture.access_flags, Some(fixture.modifiers));
+        assert_eq!(
+            fixture.fields[..2],
+            [
+                member("calls", "I", AccessFlags::STATIC),
+                member("BIG", "J", AccessFlags::STATIC | AccessFlags::FINAL),
+            ]
+        );
+        assert!(fixture.methods.contains(&member(
+            "rename",
+            "(ILjava/lang/String;)V",
+            AccessFlags::SYNCHRONIZED
+        )));
+    }
+
+    #[test]
+    fn nested_classes_have_the_modifiers_of_their_inner_classes_entry() {
+        let fixture = ClassFile::parse(FIXTURE).unwrap();
+        assert_eq!(member_class_flags(&fixture, "Fixture"), None);
+        assert_eq!(
+            member_class_flags(&fixture, "Fixture$Inner"),
+            Some(AccessFlags::empty())
+        );
+        assert_eq!(
+            member_class_flags(&fixture, "java/lang/invoke/MethodHandles$Lookup"),
+            Some(AccessFlags::PUBLIC | AccessFlags::STATIC | AccessFlags::FINAL)
+        );
+
+        let inner = shape(INNER);
+        assert_eq!(inner.name, "Fixture$Inner");
+        // `ACC_SUPER` only comes from the class file.
+        assert_eq!(inner.modifiers, AccessFlags::SUPER);
+        assert_eq!(inner.access_flags, Some(AccessFlags::SUPER));
+    }
+
+    #[test]
+    fn unchanged_class_has_no_violations() {
+        let old = shape(FIXTURE);
+        let new = shape(FIXTURE);
+        assert!(old.diff(&new).is_empty());
+        assert!(old.warnings(&new).is_empty());
+
+        let loaded = ClassShape {
+            access_flags: None,
+            ..old.clone()
+        };
+        assert!(loaded.diff(&new).is_empty());
+        assert!(loaded.warnings(&new).is_empty());
+    }
+
+    #[test]
+    fn reports_added_removed_and_reordered_fields() {
+        let old = shape(FIXTURE);
+
+        let mut new = old.clone();
+        let removed = new.fields.remove(0);
+        let added = member("extra", "I", AccessFlags::PRIVATE);
+        new.fields.push(added.clone());
+        // Reordering is not reported on top of added or removed fields.
+        assert_eq!(
+            old.diff(&new),
+            [
+                Violation::FieldRemoved(removed),
+                Violation::FieldAdded(added)
+            ]
+        );
+
+        let mut new = old.clone();
+        new.fields.swap(0, 1);
+        assert_eq!(old.diff(&new), [Violation::FieldsReordered]);
+
+        let mut new = old.clone();
+        new.fields[0].modifiers |= AccessFlags::VOLATILE;
+        assert_eq!(
+            old.diff(&new),
+            [Violation::FieldModifiersChanged {
+                old: old.fields[0].clone(),
+                new: new.fields[0].clone(),
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_added_and_deleted_methods() {
+        let old = shape(FIXTURE);
+        let mut new = old.clone();
+        let deleted = new.methods.remove(1);
+        let added = member("added", "()V", AccessFlags::PRIVATE | AccessFlags::STATIC);
+        new.methods.push(added.clone());
+        let violations = old.diff(&new);
+        assert_eq!(
+            violations,
+            [
+                Violation::MethodDeleted(deleted),
+                Violation::MethodAdded(added)
+            ]
+        );
+        assert_eq!(
+            violations[0].error(),
+            jvmtiError::JVMTI_ERROR_UNSUPPORTED_REDEFINITION_METHOD_DELETED
+        );
+    }
+
+    #[test]
+    fn methods_may_only_change_native() {
+        let old = shape(FIXTURE);
+        let mut new = old.clone();
+        new.methods[1].modifiers |= AccessFlags::NATIVE;
+        assert!(old.diff(&new).is_empty());
+
+        new.methods[1].modifiers |= AccessFlags::FINAL;
+        assert_eq!(
+            old.diff(&new),
+            [Violation::MethodModifiersChanged {
+                old: old.methods[1].clone(),
+                new: new.methods[1].clone(),
+            }]
+        );
+    }
+
+    #[test]
+    fn class_modifier_changes_of_nested_classes_are_warnings() {
+        let old = shape(FIXTURE);
+        let mut new = old.clone();
+        new.modifiers |= AccessFlags::FINAL;
+        new.access_flags = new.access_flags.map(|flags| flags | AccessFlags::FINAL);
+        let changed = Violation::ClassModifiersChanged {
+            old: AccessFlags::PUBLIC | AccessFlags::SUPER,
+            new: AccessFlags::PUBLIC | AccessFlags::SUPER | AccessFlags::FINAL,
+        };
+        assert_eq!(old.diff(&new), core::slice::from_ref(&changed));
+        assert!(old.warnings(&new).is_empty());
+        // The loaded top-level class reports the same modifiers as its class
+        // file, so they are compared as `access_flags`.
+        let loaded = ClassShape {
+            access_flags: Some(old.modifiers),
+            ..old.clone()
+        };
+        assert_eq!(loaded.diff(&new), [changed]);
+
+        // Without the class file the nested class was loaded from, only its
+        // `InnerClasses` flags are known.
+        let inner = ClassShape {
+            access_flags: None,
+            ..shape(INNER)
+        };
+        let mut new = shape(INNER);
+        new.modifiers |= AccessFlags::PRIVATE;
+        assert!(inner.diff(&new).is_empty());
+        assert_eq!(
+            inner.warnings(&new),
+            [Violation::ClassModifiersChanged {
+                old: AccessFlags::SUPER,
+                new: AccessFlags::SUPER | AccessFlags::PRIVATE,
+            }]
+        );
+    }
+}
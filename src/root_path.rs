@@ -0,0 +1,455 @@
+//! Shortest reference chains from GC roots.
+//!
+//! [`path_to_root`] answers "why is this object alive?": it records the heap
+//! as a [`HeapGraph`] along with the kind of every reference, searches breadth
+//! first from the heap roots and returns the shortest chain ending at the
+//! object. Each link is described with resolved names, e.g. the field name of
+//! a `FIELD` reference, or the method, line and local variable of a
+//! `STACK_LOCAL` root.
+//!
+//! The walk clears all tags of `env` before and after, so it should be done
+//! from a dedicated JVMTI environment. Requires the `can_tag_objects`
+//! capability.
+
+use core::fmt;
+use core::ptr::null_mut;
+use std::collections::{HashMap, VecDeque};
+
+use jni_sys::{jclass, jint, jlong, jmethodID, jobject, JNIEnv};
+
+use crate::field_index::ClassFields;
+use crate::heap::{clear_tags, HeapReference};
+use crate::heap_graph::HeapGraph;
+use crate::locals::local_variable_table;
+use crate::method_cache::MethodInfo;
+use crate::util::{check, delete_local_refs, take_array, take_string};
+use crate::{jthread, jvmtiEnv, jvmtiError, jvmtiThreadInfo};
+
+/// One object on a [`RootPath`].
+#[derive(Clone, Debug)]
+pub struct PathStep {
+    /// The class name of the object as returned by `Class.getName()`.
+    pub class_name: String,
+    /// For `java.lang.Class` instances, the name of the class.
+    pub class_object: Option<String>,
+    /// How the object is referenced: the kind of root for the first step, the
+    /// reference from the previous step otherwise.
+    pub reference: HeapReference,
+    /// `reference` with resolved names, e.g. `field elementData`, `[3]` or
+    /// `local variable list in Main.main (line 7) of thread "main"`.
+    pub description: String,
+}
+
+/// A chain of references from a heap root to an object.
+///
+/// The [`Display`](fmt::Display) implementation renders one step per line,
+/// starting at the root.
+#[derive(Clone, Debug)]
+pub struct RootPath {
+    steps: Vec<PathStep>,
+}
+
+impl RootPath {
+    /// Returns the steps from the root object to the target object.
+    pub fn steps(&self) -> &[PathStep] {
+        &self.steps
+    }
+
+    /// Returns the object directly referenced by the heap root.
+    pub fn root(&self) -> &PathStep {
+        &self.steps[0]
+    }
+
+    /// Returns the number of references between the root and the target.
+    pub fn len(&self) -> usize {
+        self.steps.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Display for PathStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.class_object {
+            Some(name) => write!(f, "class {name}"),
+            None => f.write_str(&self.class_name),
+        }
+    }
+}
+
+impl fmt::Display for RootPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let root = self.root();
+        writeln!(f, "{root} [{}]", root.description)?;
+        for step in &self.steps[1..] {
+            writeln!(f, "  {} -> {step}", step.description)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the shortest reference chain from a heap root to `object`, or
+/// `None` if it is unreachable.
+///
+/// # Safety
+///
+/// `env` must be a valid JVMTI environment whose tags may be overwritten,
+/// `jni` the JNI environment of the current thread and `object` a valid
+/// reference.
+pub unsafe fn path_to_root(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    object: jobject,
+) -> Result<Option<RootPath>, jvmtiError> {
+    let mut count = 0;
+    let mut classes = null_mut();
+    check(jvmti!(env, v1, GetLoadedClasses, &mut count, &mut classes))?;
+    let classes = take_array(env, classes, count);
+    let mut threads = null_mut();
+    let threads = match check(jvmti!(env, v1, GetAllThreads, &mut count, &mut threads)) {
+        Ok(()) => take_array(env, threads, count),
+        Err(err) => {
+            delete_local_refs(jni, &classes);
+            return Err(err);
+        }
+    };
+
+    let result = Walk::run(env, jni, &classes, &threads, object);
+    delete_local_refs(jni, &classes);
+    delete_local_refs(jni, &threads);
+    let cleared = clear_tags(env);
+    let path = result?;
+    cleared?;
+    Ok(path)
+}
+
+/// Like [`path_to_root`], for the object tagged `tag` in `tag_env`.
+///
+/// `tag_env` is only used to look up the object and must not be `env`, whose
+/// tags are cleared.
+///
+/// # Safety
+///
+/// See [`path_to_root`]; `tag_env` must be a valid JVMTI environment.
+pub unsafe fn path_to_root_by_tag(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    tag_env: *mut jvmtiEnv,
+    tag: jlong,
+) -> Result<Option<RootPath>, jvmtiError> {
+    let mut count = 0;
+    let mut objects = null_mut();
+    check(jvmti!(
+        tag_env,
+        v1,
+        GetObjectsWithTags,
+        1,
+        &tag,
+        &mut count,
+        &mut objects,
+        null_mut()
+    ))?;
+    let objects = take_array(env, objects, count);
+    let Some(&object) = objects.first() else {
+        return Ok(None);
+    };
+    let path = path_to_root(env, jni, object);
+    delete_local_refs(jni, &objects);
+    path
+}
+
+/// Marks an edge whose reference is stored in `Walk::roots`.
+const ROOT: u8 = u8::MAX;
+
+/// A reference between two nodes, packed to keep large heaps affordable.
+#[derive(Clone, Copy)]
+struct Edge {
+    from: u32,
+    to: u32,
+    index: jint,
+    kind: u8,
+}
+
+impl Edge {
+    fn reference(&self, roots: &[HeapReference]) -> HeapReference {
+        let index = self.index;
+        match self.kind {
+            0 => HeapReference::Class,
+            1 => HeapReference::Field { index },
+            2 => HeapReference::ArrayElement { index },
+            3 => HeapReference::ClassLoader,
+            4 => HeapReference::Signers,
+            5 => HeapReference::ProtectionDomain,
+            6 => HeapReference::Interface,
+            7 => HeapReference::StaticField { index },
+            8 => HeapReference::ConstantPool { index },
+            9 => HeapReference::Superclass,
+            _ => roots[index as usize],
+        }
+    }
+}
+
+/// The recorded heap: a [`HeapGraph`] whose extra nodes are the live threads
+/// and the target, plus the kind of every reference.
+struct Walk<'a> {
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    classes: &'a [jclass],
+    graph: HeapGraph,
+    edges: Vec<Edge>,
+    roots: Vec<HeapReference>,
+    /// Names of the live threads by node.
+    thread_names: HashMap<jlong, String>,
+}
+
+impl<'a> Walk<'a> {
+    unsafe fn run(
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        classes: &'a [jclass],
+        threads: &[jthread],
+        object: jobject,
+    ) -> Result<Option<RootPath>, jvmtiError> {
+        let mut objects = threads.to_vec();
+        objects.push(object);
+        let mut edges = Vec::new();
+        let mut roots = Vec::new();
+        let (graph, nodes) =
+            HeapGraph::build_from(env, classes, &objects, |from, to, reference| {
+                let (kind, index) = match reference {
+                    HeapReference::Class => (0, 0),
+                    HeapReference::Field { index } => (1, index),
+                    HeapReference::ArrayElement { index } => (2, index),
+                    HeapReference::ClassLoader => (3, 0),
+                    HeapReference::Signers => (4, 0),
+                    HeapReference::ProtectionDomain => (5, 0),
+                    HeapReference::Interface => (6, 0),
+                    HeapReference::StaticField { index } => (7, index),
+                    HeapReference::ConstantPool { index } => (8, index),
+                    HeapReference::Superclass => (9, 0),
+                    root => {
+                        roots.push(root);
+                        (ROOT, roots.len() as jint - 1)
+                    }
+                };
+                edges.push(Edge {
+                    from,
+                    to,
+                    index,
+                    kind,
+                });
+            })?;
+
+        let mut walk = Walk {
+            env,
+            jni,
+            classes,
+            graph,
+            edges,
+            roots,
+            thread_names: HashMap::new(),
+        };
+        for (&thread, &node) in threads.iter().zip(&nodes) {
+            let name = thread_name(env, jni, thread)?;
+            walk.thread_names.insert(node as jlong, name);
+        }
+        walk.shortest_path(nodes[threads.len()])
+    }
+
+    unsafe fn shortest_path(&self, target: u32) -> Result<Option<RootPath>, jvmtiError> {
+        // The node each node was first reached from.
+        let mut reached_from = vec![u32::MAX; self.graph.node_count()];
+        let mut queue = VecDeque::from([HeapGraph::ROOT]);
+        'search: while let Some(node) = queue.pop_front() {
+            for &to in self.graph.successors(node) {
+                if to == HeapGraph::ROOT || reached_from[to as usize] != u32::MAX {
+                    continue;
+                }
+                reached_from[to as usize] = node;
+                if to == target {
+                    break 'search;
+                }
+                queue.push_back(to);
+            }
+        }
+        if reached_from[target as usize] == u32::MAX {
+            return Ok(None);
+        }
+
+        let mut links = Vec::new();
+        let mut node = target;
+        while node != HeapGraph::ROOT {
+            links.push((reached_from[node as usize], node));
+            node = reached_from[node as usize];
+        }
+        links.reverse();
+        // The first recorded reference of each link.
+        let mut references = HashMap::new();
+        for edge in &self.edges {
+            if links.contains(&(edge.from, edge.to)) {
+                references.entry((edge.from, edge.to)).or_insert(*edge);
+            }
+        }
+        let steps = links
+            .iter()
+            .map(|link| self.step(references[link]))
+            .collect::<Result<_, _>>()?;
+        Ok(Some(RootPath { steps }))
+    }
+
+    unsafe fn step(&self, edge: Edge) -> Result<PathStep, jvmtiError> {
+        let reference = edge.reference(&self.roots);
+        let description = match reference {
+            HeapReference::Class => "class".to_owned(),
+            HeapReference::Field { index } => {
+                let name = match self.class(edge.from) {
+                    Some(klass) => field_name(self.env, self.jni, klass, index)?,
+                    None => None,
+                };
+                match name {
+                    Some(name) => format!("field {name}"),
+                    None => format!("field #{index}"),
+                }
+            }
+            HeapReference::ArrayElement { index } => format!("[{index}]"),
+            HeapReference::ClassLoader => "class loader".to_owned(),
+            HeapReference::Signers => "signers".to_owned(),
+            HeapReference::ProtectionDomain => "protection domain".to_owned(),
+            HeapReference::Interface => "interface".to_owned(),
+            HeapReference::StaticField { index } => {
+                let name = match self.class_object(edge.from) {
+                    Some(klass) => field_name(self.env, self.jni, klass, index)?,
+                    None => None,
+                };
+                match name {
+                    Some(name) => format!("static field {name}"),
+                    None => format!("static field #{index}"),
+                }
+            }
+            HeapReference::ConstantPool { index } => format!("constant pool #{index}"),
+            HeapReference::Superclass => "superclass".to_owned(),
+            HeapReference::JniGlobal => "JNI global".to_owned(),
+            HeapReference::SystemClass => "system class".to_owned(),
+            HeapReference::Monitor => "busy monitor".to_owned(),
+            HeapReference::StackLocal(info) => {
+                let mut description = String::from("local variable");
+                if let Some(name) = variable_name(self.env, info.method, info.location, info.slot) {
+                    description.push(' ');
+                    description.push_str(&name);
+                }
+                description.push_str(&format!(
+                    " in {}",
+                    method_description(self.env, info.method, Some(info.location))
+                ));
+                self.push_thread(&mut description, info.thread_tag);
+                description
+            }
+            HeapReference::JniLocal(info) => {
+                let mut description = String::from("JNI local");
+                if !info.method.is_null() {
+                    description.push_str(&format!(
+                        " in {}",
+                        method_description(self.env, info.method, None)
+                    ));
+                }
+                self.push_thread(&mut description, info.thread_tag);
+                description
+            }
+            HeapReference::Thread => {
+                let mut description = String::from("thread");
+                if let Some(name) = self.thread_names.get(&(edge.to as jlong)) {
+                    description.push_str(&format!(" {name:?}"));
+                }
+                description
+            }
+            HeapReference::Other => "other root".to_owned(),
+        };
+
+        let class_name = self
+            .graph
+            .class_name(edge.to)
+            .unwrap_or_default()
+            .to_owned();
+        let class_object = self
+            .graph
+            .class_object(edge.to)
+            .map(|class| self.graph.class_names()[class as usize].clone());
+        Ok(PathStep {
+            class_name,
+            class_object,
+            reference,
+            description,
+        })
+    }
+
+    fn push_thread(&self, description: &mut String, thread_tag: jlong) {
+        if let Some(name) = self.thread_names.get(&thread_tag) {
+            description.push_str(&format!(" of thread {name:?}"));
+        }
+    }
+
+    /// Returns the class of the object at `node`.
+    fn class(&self, node: u32) -> Option<jclass> {
+        let class = self.graph.class_of(node)?;
+        self.classes.get(class as usize).copied()
+    }
+
+    /// Returns the class whose class object is at `node`.
+    fn class_object(&self, node: u32) -> Option<jclass> {
+        let class = self.graph.class_object(node)?;
+        self.classes.get(class as usize).copied()
+    }
+}
+
+unsafe fn thread_name(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    thread: jthread,
+) -> Result<String, jvmtiError> {
+    let mut info: jvmtiThreadInfo = core::mem::zeroed();
+    check(jvmti!(env, v1, GetThreadInfo, thread, &mut info))?;
+    delete_local_refs(jni, &[info.thread_group, info.context_class_loader]);
+    Ok(take_string(env, info.name).unwrap_or_default())
+}
+
+/// Renders a method as `Class.method (line n)`, with the line if `location`
+/// is known.
+unsafe fn method_description(
+    env: *mut jvmtiEnv,
+    method: jmethodID,
+    location: Option<jlong>,
+) -> String {
+    match (MethodInfo::resolve(env, method), location) {
+        (Ok(info), Some(location)) => info.describe(location),
+        (Ok(info), None) => info.qualified_name(),
+        (Err(_), _) => "<unknown method>".to_owned(),
+    }
+}
+
+/// Returns the name of the local variable in `slot` at `location`.
+unsafe fn variable_name(
+    env: *mut jvmtiEnv,
+    method: jmethodID,
+    location: jlong,
+    slot: jint,
+) -> Option<String> {
+    local_variable_table(env, method)
+        .ok()?
+        .into_iter()
+        .find(|variable| variable.slot == slot && variable.in_scope(location))
+        .map(|variable| variable.name)
+}
+
+/// Returns the name of the field with the JVMTI field index `index` in
+/// `klass`.
+unsafe fn field_name(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    klass: jclass,
+    index: jint,
+) -> Result<Option<String>, jvmtiError> {
+    let fields = ClassFields::new(env, jni, klass)?;
+    Ok(fields.get(index).map(|field| field.name.clone()))
+}
@@ -0,0 +1,628 @@
+//! Wall-clock and CPU sampling profiler.
+//!
+//! [`Sampler`] runs a JVMTI agent thread that wakes up every
+//! [`SamplerOptions::interval`], takes the stack traces of the threads
+//! selected by [`ThreadFilter`] and adds them to a [`CallTree`]. Each sample
+//! counts as running or waiting depending on the state of the thread, and,
+//! with [`SamplerOptions::cpu_time`], carries the CPU time the thread
+//! consumed since it was last sampled.
+//!
+//! The sampler tags the threads it has seen from
+//! [`Sampler::FIRST_THREAD_TAG`] on to remember whether they are selected and
+//! their CPU time. Other code may tag objects in the same environment from a
+//! disjoint range; threads that already carry a tag are sampled without CPU
+//! time. Forward `ObjectFree` events to
+//! [`Sampler::on_object_free`] to forget threads once they are collected,
+//! which requires the `can_generate_object_free_events` capability and the
+//! `ObjectFree` event to be enabled.
+//!
+//! Measuring CPU time requires the `can_get_thread_cpu_time` capability.
+
+use core::ffi::c_void;
+use core::ptr::null_mut;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use jni_sys::{jint, jlong, jmethodID, JNIEnv};
+
+use crate::collapsed::CollapsedStacks;
+use crate::method_cache::MethodCache;
+use crate::stack::Frame;
+use crate::tags::{TagAllocator, TagError, TagMap};
+use crate::util::{check, deallocate, delete_local_refs, take_array, take_string};
+use crate::{
+    jthread, jvmtiEnv, jvmtiError, jvmtiFrameInfo, jvmtiStackInfo, jvmtiThreadInfo,
+    JVMTI_THREAD_NORM_PRIORITY, JVMTI_THREAD_STATE_RUNNABLE, JVMTI_THREAD_STATE_SUSPENDED,
+};
+
+/// The name of the agent thread started by [`Sampler::start`].
+pub const SAMPLER_THREAD_NAME: &str = "JVMTI Sampler";
+
+/// Options for [`Sampler`].
+#[derive(Clone, Debug)]
+pub struct SamplerOptions {
+    /// The time between two samples.
+    pub interval: Duration,
+    /// The maximum number of frames recorded per sample.
+    pub max_depth: usize,
+    /// The threads to sample.
+    pub threads: ThreadFilter,
+    /// Also record samples of threads that are not running, for a wall-clock
+    /// profile.
+    pub waiting: bool,
+    /// Measure the CPU time of the sampled threads.
+    pub cpu_time: bool,
+}
+
+impl Default for SamplerOptions {
+    fn default() -> Self {
+        SamplerOptions {
+            interval: Duration::from_millis(10),
+            max_depth: 64,
+            threads: ThreadFilter::default(),
+            waiting: true,
+            cpu_time: false,
+        }
+    }
+}
+
+/// Selects threads by name.
+///
+/// A thread is matched against its name when the sampler first sees it.
+#[derive(Clone, Debug)]
+pub struct ThreadFilter {
+    /// Only sample threads whose name starts with one of these prefixes, or
+    /// all threads if empty.
+    pub include: Vec<String>,
+    /// Never sample threads whose name starts with one of these prefixes.
+    pub exclude: Vec<String>,
+    /// Sample daemon threads.
+    pub daemons: bool,
+}
+
+impl Default for ThreadFilter {
+    fn default() -> Self {
+        ThreadFilter {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            daemons: true,
+        }
+    }
+}
+
+impl ThreadFilter {
+    /// Returns whether the filter selects every thread.
+    pub fn is_all(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty() && self.daemons
+    }
+
+    pub fn matches(&self, name: &str, is_daemon: bool) -> bool {
+        (self.daemons || !is_daemon)
+            && (self.include.is_empty() || self.include.iter().any(|p| name.starts_with(p)))
+            && !self.exclude.iter().any(|p| name.starts_with(p))
+    }
+}
+
+/// Sample counts of a [`CallTree`] node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SampleCounts {
+    /// Samples of runnable threads. Threads in native methods are runnable
+    /// even while they block.
+    pub running: u64,
+    /// Samples of blocked, waiting, sleeping or suspended threads.
+    pub waiting: u64,
+    /// The CPU time attributed to the samples.
+    pub cpu_nanos: u64,
+}
+
+impl SampleCounts {
+    pub fn samples(&self) -> u64 {
+        self.running + self.waiting
+    }
+
+    fn add(&mut self, other: &SampleCounts) {
+        self.running += other.running;
+        self.waiting += other.waiting;
+        self.cpu_nanos += other.cpu_nanos;
+    }
+}
+
+/// A method in a [`CallTree`], reached through the methods of its ancestors.
+#[derive(Clone, Debug)]
+pub struct CallTreeNode {
+    /// The method, or null for the root.
+    pub method: jmethodID,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    /// The samples of this node and its descendants.
+    pub inclusive: SampleCounts,
+    /// The samples whose innermost frame is this node.
+    pub exclusive: SampleCounts,
+}
+
+/// Samples aggregated by call path, keyed by `jmethodID`.
+///
+/// Node [`CallTree::ROOT`] stands for all threads; its children are the
+/// outermost frames of the samples.
+#[derive(Clone, Debug)]
+pub struct CallTree {
+    nodes: Vec<CallTreeNode>,
+    index: HashMap<(usize, jmethodID), usize>,
+}
+
+// Like `Frame`, the tree only holds method IDs, which are not bound to a
+// thread.
+unsafe impl Send for CallTree {}
+unsafe impl Sync for CallTree {}
+
+impl Default for CallTree {
+    fn default() -> Self {
+        CallTree {
+            nodes: vec![CallTreeNode {
+                method: null_mut(),
+                parent: None,
+                children: Vec::new(),
+                inclusive: SampleCounts::default(),
+                exclusive: SampleCounts::default(),
+            }],
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl CallTree {
+    pub const ROOT: usize = 0;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a sample with the given stack, innermost frame first.
+    pub fn add(&mut self, stack: &[Frame], counts: SampleCounts) {
+        let mut node = Self::ROOT;
+        self.nodes[node].inclusive.add(&counts);
+        for frame in stack.iter().rev() {
+            node = match self.index.get(&(node, frame.method)) {
+                Some(&child) => child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(CallTreeNode {
+                        method: frame.method,
+                        parent: Some(node),
+                        children: Vec::new(),
+                        inclusive: SampleCounts::default(),
+                        exclusive: SampleCounts::default(),
+                    });
+                    self.nodes[node].children.push(child);
+                    self.index.insert((node, frame.method), child);
+                    child
+                }
+            };
+            self.nodes[node].inclusive.add(&counts);
+        }
+        self.nodes[node].exclusive.add(&counts);
+    }
+
+    pub fn root(&self) -> &CallTreeNode {
+        &self.nodes[Self::ROOT]
+    }
+
+    pub fn node(&self, node: usize) -> &CallTreeNode {
+        &self.nodes[node]
+    }
+
+    pub fn nodes(&self) -> &[CallTreeNode] {
+        &self.nodes
+    }
+
+    /// Returns the number of nodes, including the root.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns whether no sample was added.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.len() == 1
+    }
+
+    /// Returns the stack leading to `node`, innermost frame first.
+    pub fn path(&self, mut node: usize) -> Vec<jmethodID> {
+        let mut path = Vec::new();
+        while let Some(parent) = self.nodes[node].parent {
+            path.push(self.nodes[node].method);
+            node = parent;
+        }
+        path
+    }
+
+    /// Returns the call paths of the tree, weighted by `weight` applied to
+    /// the samples ending at each node. The tree does not record locations,
+    /// so frames have location `-1`.
+    pub fn collapsed(&self, weight: impl Fn(&SampleCounts) -> u64) -> CollapsedStacks {
+        let mut stacks = CollapsedStacks::new();
+        for (node, data) in self.nodes.iter().enumerate().skip(1) {
+            let weight = weight(&data.exclusive);
+            if weight > 0 {
+                let stack: Vec<Frame> = self
+                    .path(node)
+                    .into_iter()
+                    .map(|method| Frame {
+                        method,
+                        location: -1,
+                    })
+                    .collect();
+                stacks.add(&stack, weight);
+            }
+        }
+        stacks
+    }
+
+    /// Writes the tree as indented text, children sorted by samples. Nodes
+    /// with less than `min_percent` of all samples are left out.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn write(
+        &self,
+        env: *mut jvmtiEnv,
+        cache: &MethodCache,
+        mut out: impl Write,
+        min_percent: f64,
+    ) -> io::Result<()> {
+        let total = self.root().inclusive;
+        writeln!(
+            out,
+            "{} samples ({} running, {} waiting), {:.3} ms CPU",
+            total.samples(),
+            total.running,
+            total.waiting,
+            total.cpu_nanos as f64 / 1e6
+        )?;
+        let percent =
+            |counts: &SampleCounts| 100.0 * counts.samples() as f64 / total.samples().max(1) as f64;
+        let mut pending = vec![(Self::ROOT, 0)];
+        while let Some((node, depth)) = pending.pop() {
+            if node != Self::ROOT {
+                let node = &self.nodes[node];
+                let name = match cache.get(env, node.method) {
+                    Ok(info) => info.qualified_name(),
+                    Err(_) => "<unknown method>".to_owned(),
+                };
+                write!(
+                    out,
+                    "{:width$}{:.1}% {} running, {} waiting",
+                    "",
+                    percent(&node.inclusive),
+                    node.inclusive.running,
+                    node.inclusive.waiting,
+                    width = 2 * (depth - 1),
+                )?;
+                if node.inclusive.cpu_nanos > 0 {
+                    write!(out, ", {:.3} ms CPU", node.inclusive.cpu_nanos as f64 / 1e6)?;
+                }
+                writeln!(out, "  {name}")?;
+            }
+            let mut children: Vec<_> = self.nodes[node]
+                .children
+                .iter()
+                .copied()
+                .filter(|&child| percent(&self.nodes[child].inclusive) >= min_percent)
+                .collect();
+            children.sort_by_key(|&child| self.nodes[child].inclusive.samples());
+            pending.extend(children.into_iter().map(|child| (child, depth + 1)));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct SampledThread {
+    selected: bool,
+    cpu_nanos: AtomicI64,
+}
+
+/// A sampling profiler running in a JVMTI agent thread.
+#[derive(Debug)]
+pub struct Sampler {
+    options: SamplerOptions,
+    tree: Mutex<CallTree>,
+    threads: TagMap<SampledThread>,
+    running: AtomicBool,
+    generation: AtomicU64,
+    samples: AtomicU64,
+}
+
+impl Sampler {
+    /// The first tag the sampler gives to the threads it has seen.
+    pub const FIRST_THREAD_TAG: jlong = 1 << 44;
+
+    pub fn new(options: SamplerOptions) -> Self {
+        Sampler {
+            options,
+            tree: Mutex::new(CallTree::new()),
+            threads: TagMap::with_allocator(TagAllocator::starting_at(Self::FIRST_THREAD_TAG)),
+            running: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            samples: AtomicU64::new(0),
+        }
+    }
+
+    pub fn options(&self) -> &SamplerOptions {
+        &self.options
+    }
+
+    /// Starts the agent thread, unless it is already running.
+    ///
+    /// Returns `JVMTI_ERROR_INTERNAL` if the `java.lang.Thread` object
+    /// cannot be created.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `jni` the JNI environment
+    /// of the current thread. The VM must be in the live phase.
+    pub unsafe fn start(
+        self: &Arc<Self>,
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+    ) -> Result<(), jvmtiError> {
+        if self.running.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        let result = match new_thread(jni, SAMPLER_THREAD_NAME) {
+            Some(thread) => {
+                let arg = Box::into_raw(Box::new((self.clone(), generation)));
+                let result = check(jvmti!(
+                    env,
+                    v1,
+                    RunAgentThread,
+                    thread,
+                    run,
+                    arg as *const c_void,
+                    JVMTI_THREAD_NORM_PRIORITY as jint
+                ));
+                if result.is_err() {
+                    drop(Box::from_raw(arg));
+                }
+                delete_local_refs(jni, &[thread]);
+                result
+            }
+            None => Err(jvmtiError::JVMTI_ERROR_INTERNAL),
+        };
+        if result.is_err() {
+            self.running.store(false, Ordering::Release);
+        }
+        result
+    }
+
+    /// Asks the agent thread to stop. It exits at its next wake-up.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Release);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+
+    /// Forgets the thread tagged `tag`. Forward `ObjectFree` events here;
+    /// returns whether the tag belonged to a sampled thread.
+    pub fn on_object_free(&self, tag: jlong) -> bool {
+        self.threads.on_object_free(tag).is_some()
+    }
+
+    fn is_current(&self, generation: u64) -> bool {
+        self.is_running() && self.generation.load(Ordering::Acquire) == generation
+    }
+
+    /// Samples the selected threads once. The agent thread calls this every
+    /// interval; it can also be called directly instead of starting it.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `jni` the JNI environment
+    /// of the current thread.
+    pub unsafe fn sample(&self, env: *mut jvmtiEnv, jni: *mut JNIEnv) -> Result<(), jvmtiError> {
+        if ((**jni).v1_2.PushLocalFrame)(jni, 16) != 0 {
+            return Err(jvmtiError::JVMTI_ERROR_OUT_OF_MEMORY);
+        }
+        let result = self.sample_threads(env, jni);
+        ((**jni).v1_2.PopLocalFrame)(jni, null_mut());
+        result
+    }
+
+    unsafe fn sample_threads(
+        &self,
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+    ) -> Result<(), jvmtiError> {
+        let max_depth = self.options.max_depth as jint;
+        let mut infos = null_mut();
+        let mut count = 0;
+        if self.options.threads.is_all() {
+            check(jvmti!(
+                env,
+                v1,
+                GetAllStackTraces,
+                max_depth,
+                &mut infos,
+                &mut count
+            ))?;
+        } else {
+            let mut threads = null_mut();
+            check(jvmti!(env, v1, GetAllThreads, &mut count, &mut threads))?;
+            let mut selected = Vec::new();
+            for thread in take_array(env, threads, count) {
+                if self.thread(env, jni, thread)?.0 {
+                    selected.push(thread);
+                }
+            }
+            count = selected.len() as jint;
+            if !selected.is_empty() {
+                check(jvmti!(
+                    env,
+                    v1,
+                    GetThreadListStackTraces,
+                    count,
+                    selected.as_ptr(),
+                    max_depth,
+                    &mut infos
+                ))?;
+            }
+        }
+        let result = self.record(env, jni, infos, count);
+        deallocate(env, infos);
+        result
+    }
+
+    unsafe fn record(
+        &self,
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        infos: *mut jvmtiStackInfo,
+        count: jint,
+    ) -> Result<(), jvmtiError> {
+        if infos.is_null() {
+            return Ok(());
+        }
+        let infos = core::slice::from_raw_parts(infos, count.max(0) as usize);
+        let mut samples = Vec::with_capacity(infos.len());
+        for info in infos {
+            // Agent threads, including this one, have no Java frames.
+            if info.frame_count <= 0 {
+                continue;
+            }
+            let (selected, tag) = self.thread(env, jni, info.thread)?;
+            let state = info.state as u32;
+            let running = state & JVMTI_THREAD_STATE_RUNNABLE != 0
+                && state & JVMTI_THREAD_STATE_SUSPENDED == 0;
+            if !selected || !(running || self.options.waiting) {
+                continue;
+            }
+            let cpu_nanos = match self.options.cpu_time {
+                true => self.cpu_delta(env, info.thread, tag),
+                false => 0,
+            };
+            let frames: &[jvmtiFrameInfo] =
+                core::slice::from_raw_parts(info.frame_buffer, info.frame_count as usize);
+            let stack: Vec<Frame> = frames.iter().map(|&frame| frame.into()).collect();
+            let counts = SampleCounts {
+                running: running as u64,
+                waiting: !running as u64,
+                cpu_nanos,
+            };
+            samples.push((stack, counts));
+        }
+        let mut tree = self.tree.lock().unwrap();
+        for (stack, counts) in &samples {
+            tree.add(stack, *counts);
+        }
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Returns whether `thread` is selected, and its tag.
+    unsafe fn thread(
+        &self,
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        thread: jthread,
+    ) -> Result<(bool, i64), jvmtiError> {
+        if let Some(tag) = self.threads.tag(env, thread)? {
+            let selected = self.threads.with_tag(tag, |t| t.selected);
+            return Ok((selected.unwrap_or(false), tag));
+        }
+        let mut info: jvmtiThreadInfo = core::mem::zeroed();
+        check(jvmti!(env, v1, GetThreadInfo, thread, &mut info))?;
+        delete_local_refs(jni, &[info.thread_group, info.context_class_loader]);
+        let name = take_string(env, info.name).unwrap_or_default();
+        let selected = self.options.threads.matches(&name, info.is_daemon);
+        let mut cpu_nanos = 0;
+        if self.options.cpu_time {
+            jvmti!(env, v1, GetThreadCpuTime, thread, &mut cpu_nanos);
+        }
+        let state = SampledThread {
+            selected,
+            cpu_nanos: AtomicI64::new(cpu_nanos),
+        };
+        // A thread tagged by someone else is looked up again on every sample
+        // and reports no CPU time.
+        match self.threads.insert(env, thread, state) {
+            Ok((tag, _)) => Ok((selected, tag)),
+            Err(TagError::ForeignTag(_)) => Ok((selected, 0)),
+            Err(TagError::Jvmti(err)) => Err(err),
+        }
+    }
+
+    /// Returns the CPU time `thread` consumed since the previous call.
+    unsafe fn cpu_delta(&self, env: *mut jvmtiEnv, thread: jthread, tag: i64) -> u64 {
+        let mut now = 0;
+        if jvmti!(env, v1, GetThreadCpuTime, thread, &mut now) != jvmtiError::JVMTI_ERROR_NONE {
+            return 0;
+        }
+        self.threads
+            .with_tag(tag, |t| now - t.cpu_nanos.swap(now, Ordering::Relaxed))
+            .map_or(0, |delta| delta.max(0) as u64)
+    }
+
+    /// Returns the number of times the threads were sampled.
+    pub fn sample_count(&self) -> u64 {
+        self.samples.load(Ordering::Relaxed)
+    }
+
+    /// Returns a copy of the call tree collected so far.
+    pub fn call_tree(&self) -> CallTree {
+        self.tree.lock().unwrap().clone()
+    }
+
+    /// Discards the samples collected so far.
+    pub fn reset(&self) {
+        *self.tree.lock().unwrap() = CallTree::new();
+        self.samples.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Creates an unstarted `java.lang.Thread` named `name`.
+unsafe fn new_thread(jni: *mut JNIEnv, name: &str) -> Option<jthread> {
+    let class = ((**jni).v1_1.FindClass)(jni, c"java/lang/Thread".as_ptr());
+    let mut thread = null_mut();
+    if !class.is_null() {
+        let init = ((**jni).v1_1.GetMethodID)(
+            jni,
+            class,
+            c"<init>".as_ptr(),
+            c"(Ljava/lang/String;)V".as_ptr(),
+        );
+        let name = std::ffi::CString::new(name).unwrap();
+        let name = ((**jni).v1_1.NewStringUTF)(jni, name.as_ptr());
+        if !init.is_null() && !name.is_null() {
+            thread =
+                ((**jni).v1_1.NewObjectA)(jni, class, init, [jni_sys::jvalue { l: name }].as_ptr());
+        }
+        delete_local_refs(jni, &[class, name]);
+    }
+    if thread.is_null() {
+        ((**jni).v1_1.ExceptionClear)(jni);
+        return None;
+    }
+    Some(thread)
+}
+
+unsafe extern "system" fn run(env: *mut jvmtiEnv, jni: *mut JNIEnv, arg: *mut c_void) {
+    let (sampler, generation) = *Box::from_raw(arg as *mut (Arc<Sampler>, u64));
+    let mut next = Instant::now();
+    loop {
+        next += sampler.options.interval;
+        std::thread::sleep(next.saturating_duration_since(Instant::now()));
+        if !sampler.is_current(generation) {
+            break;
+        }
+        if sampler.sample(env, jni).is_err() {
+            sampler.stop();
+            break;
+        }
+    }
+}
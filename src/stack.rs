@@ -0,0 +1,69 @@
+//! Stack traces as plain values.
+//!
+//! [`Frame`] is a `jvmtiFrameInfo` that can be hashed, compared and shared
+//! between threads, so that profilers can use stack traces as aggregation
+//! keys.
+
+use core::ptr::null_mut;
+
+use jni_sys::jmethodID;
+
+use crate::util::check;
+use crate::{jlocation, jthread, jvmtiEnv, jvmtiError, jvmtiFrameInfo};
+
+/// A frame of a stack trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Frame {
+    pub method: jmethodID,
+    /// The location of the executing instruction, or `-1` for native methods.
+    pub location: jlocation,
+}
+
+// A `jmethodID` identifies a method for the lifetime of its class; unlike
+// object references it is not bound to a thread.
+unsafe impl Send for Frame {}
+unsafe impl Sync for Frame {}
+
+impl From<jvmtiFrameInfo> for Frame {
+    fn from(frame: jvmtiFrameInfo) -> Self {
+        Frame {
+            method: frame.method,
+            location: frame.location,
+        }
+    }
+}
+
+/// Returns up to `max_depth` frames of `thread`, innermost first.
+///
+/// # Safety
+///
+/// `env` must be a valid JVMTI environment and `thread` a valid thread
+/// reference, or null for the current thread.
+pub unsafe fn stack_trace(
+    env: *mut jvmtiEnv,
+    thread: jthread,
+    max_depth: usize,
+) -> Result<Vec<Frame>, jvmtiError> {
+    let mut frames = vec![
+        jvmtiFrameInfo {
+            method: null_mut(),
+            location: 0,
+        };
+        max_depth
+    ];
+    let mut count = 0;
+    check(jvmti!(
+        env,
+        v1,
+        GetStackTrace,
+        thread,
+        0,
+        max_depth as _,
+        frames.as_mut_ptr(),
+        &mut count
+    ))?;
+    Ok(frames[..count.max(0) as usize]
+        .iter()
+        .map(|&frame| frame.into())
+        .collect())
+}
@@ -0,0 +1,348 @@
+//! Attaching Rust values to Java objects through object tags.
+//!
+//! `SetTag`, `GetTag` and `GetObjectsWithTags` only deal in `jlong`s. A
+//! [`TagAllocator`] hands out unique tags, and a [`TagMap`] uses them to keep
+//! a side table from tags to Rust values. Forwarding `ObjectFree` events to
+//! [`TagMap::on_object_free`] drops the values of collected objects, and
+//! [`TagMap::object`] looks up the object behind a tag.
+//!
+//! All functions require the `can_tag_objects` capability; cleanup on
+//! collection additionally requires `can_generate_object_free_events` and the
+//! `ObjectFree` event to be enabled.
+//!
+//! Tags are local to a JVMTI environment, so everything that tags objects in
+//! one environment has to share the `jlong` tag space. Each user claims a
+//! range by starting its [`TagAllocator`] at a different tag, and a
+//! [`TagMap`] refuses to retag objects carrying a tag outside its own range
+//! (see [`TagError::ForeignTag`]). Within this crate, [`TagMap::new`] starts
+//! at `1`, [`AllocationProfiler`](crate::alloc_profiler::AllocationProfiler)
+//! tags samples from [`AllocationProfiler::FIRST_TAG`](crate::alloc_profiler::AllocationProfiler::FIRST_TAG),
+//! [`Sampler`](crate::sampler::Sampler) tags threads from
+//! [`Sampler::FIRST_THREAD_TAG`](crate::sampler::Sampler::FIRST_THREAD_TAG)
+//! and [`MethodCache`](crate::method_cache::MethodCache) tags classes from
+//! [`MethodCache::FIRST_CLASS_TAG`](crate::method_cache::MethodCache::FIRST_CLASS_TAG).
+//! The heap histogram and HPROF writer restore the tags they overwrite, while
+//! the heap graph and root path searches clear every tag of their environment
+//! and need one of their own.
+
+use core::fmt;
+use core::ptr::null_mut;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::RwLock;
+
+use jni_sys::{jint, jlong, jobject};
+
+use crate::util::{check, take_array};
+use crate::{jvmtiEnv, jvmtiError};
+
+/// Errors returned by [`TagMap::insert`].
+#[derive(Clone, Copy, Debug)]
+pub enum TagError {
+    /// A JVMTI function failed.
+    Jvmti(jvmtiError),
+    /// The object carries this tag, which was not handed out by the map's
+    /// allocator and so belongs to someone else sharing the environment.
+    ForeignTag(jlong),
+}
+
+impl From<jvmtiError> for TagError {
+    fn from(err: jvmtiError) -> Self {
+        TagError::Jvmti(err)
+    }
+}
+
+impl fmt::Display for TagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagError::Jvmti(err) => write!(f, "JVMTI call failed: {err:?}"),
+            TagError::ForeignTag(tag) => write!(f, "object already carries foreign tag {tag}"),
+        }
+    }
+}
+
+impl std::error::Error for TagError {}
+
+/// Hands out unique, non-zero object tags.
+#[derive(Debug)]
+pub struct TagAllocator {
+    first: jlong,
+    next: AtomicI64,
+}
+
+impl Default for TagAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TagAllocator {
+    /// Creates an allocator whose first tag is `1`.
+    pub const fn new() -> Self {
+        Self::starting_at(1)
+    }
+
+    /// Creates an allocator whose first tag is `first`, so that several
+    /// allocators can share an environment by using disjoint ranges.
+    pub const fn starting_at(first: jlong) -> Self {
+        TagAllocator {
+            first,
+            next: AtomicI64::new(first),
+        }
+    }
+
+    /// Returns a tag that has not been handed out before.
+    pub fn allocate(&self) -> jlong {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns whether `tag` has been handed out by this allocator.
+    pub fn owns(&self, tag: jlong) -> bool {
+        (self.first..self.next.load(Ordering::Relaxed)).contains(&tag)
+    }
+
+    /// Returns the tag of `object`, tagging it with a new tag first if it is
+    /// untagged.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `object` a valid reference.
+    pub unsafe fn tag_object(
+        &self,
+        env: *mut jvmtiEnv,
+        object: jobject,
+    ) -> Result<jlong, jvmtiError> {
+        let mut tag = 0;
+        check(jvmti!(env, v1, GetTag, object, &mut tag))?;
+        if tag == 0 {
+            tag = self.allocate();
+            check(jvmti!(env, v1, SetTag, object, tag))?;
+        }
+        Ok(tag)
+    }
+}
+
+/// A thread safe map from Java objects to Rust values, keyed by object tag.
+#[derive(Debug)]
+pub struct TagMap<T> {
+    tags: TagAllocator,
+    entries: RwLock<HashMap<jlong, T>>,
+}
+
+impl<T> Default for TagMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> TagMap<T> {
+    pub fn new() -> Self {
+        Self::with_allocator(TagAllocator::new())
+    }
+
+    /// Creates a map that tags objects with tags from `tags`.
+    pub fn with_allocator(tags: TagAllocator) -> Self {
+        TagMap {
+            tags,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Associates `value` with `object` and returns the tag of the object and
+    /// the value it was previously associated with, if any.
+    ///
+    /// Objects carrying a stale tag of the map, such as one dropped by
+    /// [`clear`](Self::clear), are retagged. Objects carrying a tag the map
+    /// did not hand out are left alone and fail with
+    /// [`TagError::ForeignTag`].
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `object` a valid reference.
+    pub unsafe fn insert(
+        &self,
+        env: *mut jvmtiEnv,
+        object: jobject,
+        value: T,
+    ) -> Result<(jlong, Option<T>), TagError> {
+        let mut tag = 0;
+        check(jvmti!(env, v1, GetTag, object, &mut tag))?;
+        let mut entries = self.entries.write().unwrap();
+        if tag != 0 && !self.tags.owns(tag) {
+            return Err(TagError::ForeignTag(tag));
+        }
+        if tag == 0 || !entries.contains_key(&tag) {
+            tag = self.tags.allocate();
+            check(jvmti!(env, v1, SetTag, object, tag))?;
+        }
+        let previous = entries.insert(tag, value);
+        Ok((tag, previous))
+    }
+
+    /// Returns the tag of `object` if it has a value in the map.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `object` a valid reference.
+    pub unsafe fn tag(
+        &self,
+        env: *mut jvmtiEnv,
+        object: jobject,
+    ) -> Result<Option<jlong>, jvmtiError> {
+        let mut tag = 0;
+        check(jvmti!(env, v1, GetTag, object, &mut tag))?;
+        Ok(Some(tag).filter(|tag| self.contains_tag(*tag)))
+    }
+
+    /// Calls `f` with the value associated with `object`.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `object` a valid reference.
+    pub unsafe fn with<R>(
+        &self,
+        env: *mut jvmtiEnv,
+        object: jobject,
+        f: impl FnOnce(&T) -> R,
+    ) -> Result<Option<R>, jvmtiError> {
+        let mut tag = 0;
+        check(jvmti!(env, v1, GetTag, object, &mut tag))?;
+        Ok(self.with_tag(tag, f))
+    }
+
+    /// Returns a clone of the value associated with `object`.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `object` a valid reference.
+    pub unsafe fn get(&self, env: *mut jvmtiEnv, object: jobject) -> Result<Option<T>, jvmtiError>
+    where
+        T: Clone,
+    {
+        self.with(env, object, T::clone)
+    }
+
+    /// Calls `f` with the value associated with `tag`.
+    pub fn with_tag<R>(&self, tag: jlong, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.entries.read().unwrap().get(&tag).map(f)
+    }
+
+    /// Returns a clone of the value associated with `tag`.
+    pub fn get_by_tag(&self, tag: jlong) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.with_tag(tag, T::clone)
+    }
+
+    pub fn contains_tag(&self, tag: jlong) -> bool {
+        self.entries.read().unwrap().contains_key(&tag)
+    }
+
+    /// Removes the value associated with `object` and clears its tag.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment and `object` a valid reference.
+    pub unsafe fn remove(
+        &self,
+        env: *mut jvmtiEnv,
+        object: jobject,
+    ) -> Result<Option<T>, jvmtiError> {
+        let mut tag = 0;
+        check(jvmti!(env, v1, GetTag, object, &mut tag))?;
+        let value = self.entries.write().unwrap().remove(&tag);
+        if value.is_some() {
+            check(jvmti!(env, v1, SetTag, object, 0))?;
+        }
+        Ok(value)
+    }
+
+    /// Removes and returns the value of the object tagged `tag`. Forward
+    /// `ObjectFree` events here.
+    ///
+    /// Does not call into the VM, so it is safe to use from the `ObjectFree`
+    /// callback.
+    pub fn on_object_free(&self, tag: jlong) -> Option<T> {
+        self.entries.write().unwrap().remove(&tag)
+    }
+
+    /// Returns a JNI local reference to the object tagged `tag`, or `None` if
+    /// it is not in the map or has been collected.
+    ///
+    /// This walks the heap, so it is expensive; use [`objects`](Self::objects)
+    /// to look up many objects at once.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment attached to the current thread.
+    pub unsafe fn object(
+        &self,
+        env: *mut jvmtiEnv,
+        tag: jlong,
+    ) -> Result<Option<jobject>, jvmtiError> {
+        if !self.contains_tag(tag) {
+            return Ok(None);
+        }
+        Ok(objects_with_tags(env, &[tag])?
+            .into_iter()
+            .next()
+            .map(|(object, _)| object))
+    }
+
+    /// Returns JNI local references to all live objects in the map, together
+    /// with their tags.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment attached to the current thread.
+    pub unsafe fn objects(&self, env: *mut jvmtiEnv) -> Result<Vec<(jobject, jlong)>, jvmtiError> {
+        let tags: Vec<jlong> = self.entries.read().unwrap().keys().copied().collect();
+        objects_with_tags(env, &tags)
+    }
+
+    /// Returns the tags that have values in the map.
+    pub fn tags(&self) -> Vec<jlong> {
+        self.entries.read().unwrap().keys().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    /// Removes all values. The objects keep their tags, which the map no
+    /// longer recognizes.
+    pub fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Calls `GetObjectsWithTags` and pairs up the returned objects and tags.
+unsafe fn objects_with_tags(
+    env: *mut jvmtiEnv,
+    tags: &[jlong],
+) -> Result<Vec<(jobject, jlong)>, jvmtiError> {
+    if tags.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut count = 0;
+    let mut objects = null_mut();
+    let mut found_tags = null_mut();
+    check(jvmti!(
+        env,
+        v1,
+        GetObjectsWithTags,
+        tags.len() as jint,
+        tags.as_ptr(),
+        &mut count,
+        &mut objects,
+        &mut found_tags
+    ))?;
+    let objects = take_array(env, objects, count);
+    let found_tags = take_array(env, found_tags, count);
+    Ok(objects.into_iter().zip(found_tags).collect())
+}
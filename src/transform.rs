@@ -0,0 +1,733 @@
+//! A registry of class file transformers sharing one `ClassFileLoadHook`.
+//!
+//! A JVMTI environment has a single `ClassFileLoadHook` callback. A
+//! [`TransformerRegistry`] lets independent [`ClassTransformer`]s share it:
+//! each declares a [`ClassFilter`] and whether it can retransform classes,
+//! and they are applied in priority order, each receiving the output of the
+//! previous one. Forward `ClassFileLoadHook` events to
+//! [`TransformerRegistry::on_class_file_load_hook`].
+//!
+//! A transformer that fails, panics or returns bytes that do not parse as
+//! a class file is skipped for that class; the failure is counted in its
+//! [`TransformerStats`] and the other transformers still run.
+//!
+//! When a class is retransformed, which [`TransformerRegistry::retransform`]
+//! triggers for the loaded classes a transformer's filter matches, the VM
+//! only posts `ClassFileLoadHook` to retransform-capable environments and
+//! hands them the class file as the other environments left it at load
+//! time. [`TransformerRegistry::start`] therefore creates a second
+//! environment, without the `can_retransform_classes` capability, for the
+//! transformers that are not retransform-capable: their changes survive
+//! retransformation without them running again. As with
+//! `java.lang.instrument`, they run before the retransform-capable ones,
+//! and priorities order the transformers within each group.
+//! Retransformation requires the `can_retransform_classes` capability in
+//! the environment passed to `start`.
+
+use core::cell::Cell;
+use core::ffi::{c_char, c_uchar, c_uint, c_void};
+use core::fmt;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use std::error::Error;
+use std::ffi::CStr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use jni_sys::{jboolean, jclass, jint, jobject, JNIEnv, JavaVM, JNI_OK};
+
+use crate::classfile::{set_new_class_data, ClassFile};
+use crate::util::{check, delete_local_refs, take_array, take_string};
+use crate::{
+    jvmtiEnv, jvmtiError, jvmtiEvent, jvmtiEventCallbacks, jvmtiEventMode, JVMTI_VERSION_1_2,
+};
+
+/// The error type of [`ClassTransformer::transform`].
+pub type TransformError = Box<dyn Error + Send + Sync>;
+
+/// The class a `ClassFileLoadHook` event is for.
+#[derive(Clone, Copy, Debug)]
+pub struct ClassLoad<'a> {
+    pub jni: *mut JNIEnv,
+    /// The internal name, e.g. `java/lang/String`, if the class has one.
+    pub name: Option<&'a str>,
+    /// The defining loader, null for the bootstrap loader.
+    pub loader: jobject,
+    pub protection_domain: jobject,
+    /// The class being redefined or retransformed, null while loading.
+    pub class_being_redefined: jclass,
+    /// Whether the class is being retransformed through
+    /// [`TransformerRegistry::retransform`] or
+    /// [`TransformerRegistry::retransform_classes`].
+    pub retransforming: bool,
+}
+
+/// Rewrites class files.
+pub trait ClassTransformer: Send + Sync {
+    /// Returns the transformed class file, or `None` to leave it unchanged.
+    fn transform(
+        &self,
+        class: &ClassLoad<'_>,
+        class_data: &[u8],
+    ) -> Result<Option<Vec<u8>>, TransformError>;
+}
+
+impl<F> ClassTransformer for F
+where
+    F: Fn(&ClassLoad<'_>, &[u8]) -> Result<Option<Vec<u8>>, TransformError> + Send + Sync,
+{
+    fn transform(
+        &self,
+        class: &ClassLoad<'_>,
+        class_data: &[u8],
+    ) -> Result<Option<Vec<u8>>, TransformError> {
+        self(class, class_data)
+    }
+}
+
+/// The classes a transformer applies to, by internal name.
+///
+/// Patterns ending in `*` match names starting with the rest of the pattern,
+/// e.g. `com/example/*`; others match one class. Names may be given with
+/// dots, e.g. `com.example.*`. A filter without included patterns includes
+/// every class, and exclusions take precedence.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClassFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl ClassFilter {
+    /// Matches every class.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(pattern.replace('.', "/"));
+        self
+    }
+
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(pattern.replace('.', "/"));
+        self
+    }
+
+    /// Returns whether the class named `name` passes the filter. Classes
+    /// without a name only pass filters that include every class.
+    pub fn matches(&self, name: Option<&str>) -> bool {
+        let Some(name) = name else {
+            return self.include.is_empty() && self.exclude.is_empty();
+        };
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        };
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+}
+
+/// Options for [`TransformerRegistry::register`].
+#[derive(Clone, Debug, Default)]
+pub struct TransformerOptions {
+    /// Transformers with a higher priority run first. Those with the same
+    /// priority run in registration order.
+    pub priority: i32,
+    pub filter: ClassFilter,
+    /// Whether the transformer also runs when classes are retransformed.
+    /// Otherwise it runs in the environment [`TransformerRegistry::start`]
+    /// creates, before the retransform-capable transformers.
+    pub retransform: bool,
+}
+
+/// Identifies a registered transformer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TransformerId(u64);
+
+/// What a transformer did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransformerStats {
+    /// Classes it returned new bytes for.
+    pub transformed: u64,
+    /// Classes it left unchanged.
+    pub unchanged: u64,
+    /// Classes for which it failed, panicked or returned bytes that do not
+    /// parse.
+    pub failed: u64,
+    /// The class and error of the last failure.
+    pub last_error: Option<(String, String)>,
+}
+
+struct Registered {
+    id: TransformerId,
+    options: TransformerOptions,
+    transformer: Box<dyn ClassTransformer>,
+    stats: Mutex<TransformerStats>,
+}
+
+thread_local! {
+    /// Set while [`TransformerRegistry::retransform_classes`] runs.
+    /// `RetransformClasses` posts its events on the calling thread.
+    static RETRANSFORMING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Applies registered transformers to the classes a `ClassFileLoadHook`
+/// reports.
+#[derive(Default)]
+pub struct TransformerRegistry {
+    /// Sorted by descending priority, then by id.
+    transformers: RwLock<Vec<Registered>>,
+    next_id: Mutex<u64>,
+    /// The environment the transformers that are not retransform-capable
+    /// run in, null until [`start`](Self::start).
+    incapable_env: AtomicPtr<jvmtiEnv>,
+}
+
+impl fmt::Debug for TransformerRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let transformers = self.transformers.read().unwrap();
+        f.debug_struct("TransformerRegistry")
+            .field(
+                "transformers",
+                &transformers
+                    .iter()
+                    .map(|registered| (registered.id, &registered.options))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl TransformerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a transformer. It sees classes loaded from now on; call
+    /// [`retransform`](Self::retransform) to apply it to loaded classes.
+    pub fn register(
+        &self,
+        options: TransformerOptions,
+        transformer: impl ClassTransformer + 'static,
+    ) -> TransformerId {
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            TransformerId(*next_id)
+        };
+        let mut transformers = self.transformers.write().unwrap();
+        let at = transformers
+            .partition_point(|registered| registered.options.priority >= options.priority);
+        transformers.insert(
+            at,
+            Registered {
+                id,
+                options,
+                transformer: Box::new(transformer),
+                stats: Mutex::new(TransformerStats::default()),
+            },
+        );
+        id
+    }
+
+    /// Removes a transformer, returning its options if it was registered.
+    /// Classes it transformed keep their changes until retransformed, e.g.
+    /// with [`retransform_matching`](Self::retransform_matching) and the
+    /// returned filter.
+    pub fn unregister(&self, id: TransformerId) -> Option<TransformerOptions> {
+        let mut transformers = self.transformers.write().unwrap();
+        let index = transformers
+            .iter()
+            .position(|registered| registered.id == id)?;
+        Some(transformers.remove(index).options)
+    }
+
+    pub fn stats(&self, id: TransformerId) -> Option<TransformerStats> {
+        let transformers = self.transformers.read().unwrap();
+        let registered = transformers.iter().find(|registered| registered.id == id)?;
+        let stats = registered.stats.lock().unwrap().clone();
+        Some(stats)
+    }
+
+    /// Enables the `ClassFileLoadHook` event in `env`, which runs the
+    /// retransform-capable transformers, and in an environment this creates
+    /// for the others. The registry receives that environment's events
+    /// itself.
+    ///
+    /// # Safety
+    ///
+    /// `vm` must be the Java VM `env` belongs to, and `env` a valid JVMTI
+    /// environment.
+    pub unsafe fn start(
+        &'static self,
+        vm: *mut JavaVM,
+        env: *mut jvmtiEnv,
+    ) -> Result<(), jvmtiError> {
+        let mut incapable_env = self.incapable_env.load(Ordering::Acquire);
+        if incapable_env.is_null() {
+            let mut new_env: *mut c_void = null_mut();
+            if ((**vm).v1_2.GetEnv)(vm, &mut new_env, JVMTI_VERSION_1_2) != JNI_OK {
+                return Err(jvmtiError::JVMTI_ERROR_INTERNAL);
+            }
+            incapable_env = new_env.cast();
+            check(jvmti!(
+                incapable_env,
+                v1,
+                SetEnvironmentLocalStorage,
+                (self as *const Self).cast()
+            ))?;
+            let mut callbacks: jvmtiEventCallbacks = core::mem::zeroed();
+            callbacks.v1.ClassFileLoadHook = Some(incapable_class_file_load_hook);
+            check(jvmti!(
+                incapable_env,
+                v1,
+                SetEventCallbacks,
+                &callbacks,
+                size_of::<jvmtiEventCallbacks>() as jint
+            ))?;
+            self.incapable_env.store(incapable_env, Ordering::Release);
+        }
+        set_event(incapable_env, jvmtiEventMode::JVMTI_ENABLE)?;
+        set_event(env, jvmtiEventMode::JVMTI_ENABLE)
+    }
+
+    /// Disables the `ClassFileLoadHook` event in `env` and in the
+    /// environment [`start`](Self::start) created.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment.
+    pub unsafe fn stop(&self, env: *mut jvmtiEnv) -> Result<(), jvmtiError> {
+        let incapable_env = self.incapable_env.load(Ordering::Acquire);
+        if !incapable_env.is_null() {
+            set_event(incapable_env, jvmtiEventMode::JVMTI_DISABLE)?;
+        }
+        set_event(env, jvmtiEventMode::JVMTI_DISABLE)
+    }
+
+    /// Runs the transformers that apply to the class and hands the result
+    /// to the VM. Forward `ClassFileLoadHook` events here; returns whether
+    /// the class was changed. Once [`start`](Self::start) has created its
+    /// environment, only the retransform-capable transformers run for the
+    /// events of other environments.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with the arguments of a `ClassFileLoadHook` event,
+    /// from the event callback.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn on_class_file_load_hook(
+        &self,
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        class_being_redefined: jclass,
+        loader: jobject,
+        name: *const c_char,
+        protection_domain: jobject,
+        class_data_len: jint,
+        class_data: *const c_uchar,
+        new_class_data_len: *mut jint,
+        new_class_data: *mut *mut c_uint,
+    ) -> Result<bool, jvmtiError> {
+        let name = match name.is_null() {
+            true => None,
+            false => Some(CStr::from_ptr(name).to_string_lossy()),
+        };
+        let class = ClassLoad {
+            jni,
+            name: name.as_deref(),
+            loader,
+            protection_domain,
+            class_being_redefined,
+            retransforming: !class_being_redefined.is_null() && RETRANSFORMING.get(),
+        };
+        let original = core::slice::from_raw_parts(class_data, class_data_len.max(0) as usize);
+        let incapable_env = self.incapable_env.load(Ordering::Acquire);
+        let transformed = match incapable_env.is_null() {
+            true => self.transform(&class, original),
+            false => self.apply(&class, original, |options| {
+                options.retransform == (env != incapable_env)
+            }),
+        };
+        match transformed {
+            Some(bytes) => {
+                set_new_class_data(env, &bytes, new_class_data_len, new_class_data)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Runs the transformers that apply to `class` on `class_data`,
+    /// returning the new class file if any of them changed it. Only the
+    /// retransform-capable transformers run if `class` is being
+    /// retransformed.
+    pub fn transform(&self, class: &ClassLoad<'_>, class_data: &[u8]) -> Option<Vec<u8>> {
+        self.apply(class, class_data, |options| {
+            !class.retransforming || options.retransform
+        })
+    }
+
+    fn apply(
+        &self,
+        class: &ClassLoad<'_>,
+        class_data: &[u8],
+        runs: impl Fn(&TransformerOptions) -> bool,
+    ) -> Option<Vec<u8>> {
+        let transformers = self.transformers.read().unwrap();
+        let mut current: Option<Vec<u8>> = None;
+        for registered in transformers.iter() {
+            if !runs(&registered.options) || !registered.options.filter.matches(class.name) {
+                continue;
+            }
+            let input = current.as_deref().unwrap_or(class_data);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                registered.transformer.transform(class, input)
+            }));
+            let result = match result {
+                Ok(Ok(Some(bytes))) => match ClassFile::parse(&bytes) {
+                    Ok(_) => Ok(Some(bytes)),
+                    Err(err) => Err(format!("invalid class file: {err}")),
+                },
+                Ok(Ok(None)) => Ok(None),
+                Ok(Err(err)) => Err(err.to_string()),
+                Err(payload) => Err(match payload.downcast_ref::<&str>() {
+                    Some(message) => format!("panicked: {message}"),
+                    None => match payload.downcast_ref::<String>() {
+                        Some(message) => format!("panicked: {message}"),
+                        None => "panicked".to_owned(),
+                    },
+                }),
+            };
+            let mut stats = registered.stats.lock().unwrap();
+            match result {
+                Ok(Some(bytes)) => {
+                    stats.transformed += 1;
+                    current = Some(bytes);
+                }
+                Ok(None) => stats.unchanged += 1,
+                Err(err) => {
+                    stats.failed += 1;
+                    stats.last_error = Some((class.name.unwrap_or_default().to_owned(), err));
+                }
+            }
+        }
+        current
+    }
+
+    /// Retransforms the loaded classes the filter of the transformer `id`
+    /// matches, returning how many there were. Fails with
+    /// `JVMTI_ERROR_ILLEGAL_ARGUMENT` if the transformer is not registered
+    /// or not retransform-capable.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment with the
+    /// `can_retransform_classes` capability and `jni` the JNI environment of
+    /// the current thread.
+    pub unsafe fn retransform(
+        &self,
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        id: TransformerId,
+    ) -> Result<usize, jvmtiError> {
+        let filter = {
+            let transformers = self.transformers.read().unwrap();
+            match transformers.iter().find(|registered| registered.id == id) {
+                Some(registered) if registered.options.retransform => {
+                    registered.options.filter.clone()
+                }
+                _ => return Err(jvmtiError::JVMTI_ERROR_ILLEGAL_ARGUMENT),
+            }
+        };
+        self.retransform_matching(env, jni, &filter)
+    }
+
+    /// Retransforms the modifiable loaded classes `filter` matches,
+    /// returning how many there were.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment with the
+    /// `can_retransform_classes` capability and `jni` the JNI environment of
+    /// the current thread.
+    pub unsafe fn retransform_matching(
+        &self,
+        env: *mut jvmtiEnv,
+        jni: *mut JNIEnv,
+        filter: &ClassFilter,
+    ) -> Result<usize, jvmtiError> {
+        let mut count = 0;
+        let mut classes = null_mut();
+        check(jvmti!(env, v1, GetLoadedClasses, &mut count, &mut classes))?;
+        let classes = take_array(env, classes, count);
+        let result = matching_classes(env, &classes, filter).and_then(|matching| {
+            self.retransform_classes(env, &matching)?;
+            Ok(matching.len())
+        });
+        delete_local_refs(jni, &classes);
+        result
+    }
+
+    /// Retransforms `classes`, running the retransform-capable transformers
+    /// on their class files as the other transformers left them at load
+    /// time.
+    ///
+    /// # Safety
+    ///
+    /// `env` must be a valid JVMTI environment with the
+    /// `can_retransform_classes` capability, and `classes` valid references.
+    pub unsafe fn retransform_classes(
+        &self,
+        env: *mut jvmtiEnv,
+        classes: &[jclass],
+    ) -> Result<(), jvmtiError> {
+        if classes.is_empty() {
+            return Ok(());
+        }
+        let previous = RETRANSFORMING.replace(true);
+        let result = check(jvmti!(
+            env,
+            v1_1,
+            RetransformClasses,
+            classes.len() as jint,
+            classes.as_ptr()
+        ));
+        RETRANSFORMING.set(previous);
+        result
+    }
+}
+
+/// Returns the modifiable classes among `classes` that `filter` matches.
+unsafe fn matching_classes(
+    env: *mut jvmtiEnv,
+    classes: &[jclass],
+    filter: &ClassFilter,
+) -> Result<Vec<jclass>, jvmtiError> {
+    let mut matching = Vec::new();
+    for &klass in classes {
+        let mut modifiable: jboolean = false;
+        check(jvmti!(env, v1_1, IsModifiableClass, klass, &mut modifiable))?;
+        if !modifiable {
+            continue;
+        }
+        let mut signature = null_mut();
+        check(jvmti!(
+            env,
+            v1,
+            GetClassSignature,
+            klass,
+            &mut signature,
+            null_mut()
+        ))?;
+        let signature = take_string(env, signature).unwrap_or_default();
+        let name = signature
+            .strip_prefix('L')
+            .and_then(|name| name.strip_suffix(';'));
+        if name.is_some() && filter.matches(name) {
+            matching.push(klass);
+        }
+    }
+    Ok(matching)
+}
+
+unsafe fn set_event(env: *mut jvmtiEnv, mode: jvmtiEventMode) -> Result<(), jvmtiError> {
+    check(jvmti!(
+        env,
+        v1,
+        SetEventNotificationMode,
+        mode,
+        jvmtiEvent::JVMTI_EVENT_CLASS_FILE_LOAD_HOOK,
+        null_mut::<jni_sys::_jobject>()
+    ))
+}
+
+/// The `ClassFileLoadHook` of the environment [`TransformerRegistry::start`]
+/// creates, whose local storage points to the registry.
+#[allow(clippy::too_many_arguments)]
+unsafe extern "system" fn incapable_class_file_load_hook(
+    env: *mut jvmtiEnv,
+    jni: *mut JNIEnv,
+    class_being_redefined: jclass,
+    loader: jobject,
+    name: *const c_char,
+    protection_domain: jobject,
+    class_data_len: jint,
+    class_data: *const c_uchar,
+    new_class_data_len: *mut jint,
+    new_class_data: *mut *mut c_uint,
+) {
+    let mut registry = null_mut();
+    if check(jvmti!(env, v1, GetEnvironmentLocalStorage, &mut registry)).is_err()
+        || registry.is_null()
+    {
+        return;
+    }
+    let registry = &*(registry as *const TransformerRegistry);
+    let _ = registry.on_class_file_load_hook(
+        env,
+        jni,
+        class_being_redefined,
+        loader,
+        name,
+        protection_domain,
+        class_data_len,
+        class_data,
+        new_class_data_len,
+        new_class_data,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    const FIXTURE: &[u8] = include_bytes!("classfile/testdata/Fixture.class");
+
+    fn class_load(name: &str, retransforming: bool) -> ClassLoad<'_> {
+        ClassLoad {
+            jni: null_mut(),
+            name: Some(name),
+            loader: null_mut(),
+            protection_domain: null_mut(),
+            class_being_redefined: null_mut(),
+            retransforming,
+        }
+    }
+
+    fn options(priority: i32, filter: ClassFilter, retransform: bool) -> TransformerOptions {
+        TransformerOptions {
+            priority,
+            filter,
+            retransform,
+        }
+    }
+
+    /// Pins the closure signature so that it implements [`ClassTransformer`].
+    fn transformer(
+        f: impl Fn(&ClassLoad<'_>, &[u8]) -> Result<Option<Vec<u8>>, TransformError>
+            + Send
+            + Sync
+            + 'static,
+    ) -> impl ClassTransformer {
+        f
+    }
+
+    #[test]
+    fn filters_match_names_and_prefixes() {
+        let all = ClassFilter::all();
+        assert!(all.matches(Some("java/lang/String")));
+        assert!(all.matches(None));
+
+        let filter = ClassFilter::all()
+            .include("com.example.*")
+            .include("java/util/ArrayList")
+            .exclude("com/example/internal/*");
+        assert!(filter.matches(Some("com/example/Main")));
+        assert!(filter.matches(Some("com/example/sub/Util")));
+        assert!(filter.matches(Some("java/util/ArrayList")));
+        assert!(!filter.matches(Some("java/util/ArrayList$Itr")));
+        assert!(!filter.matches(Some("com/example/internal/Secret")));
+        assert!(!filter.matches(Some("org/example/Main")));
+        assert!(!filter.matches(None));
+
+        let exclude_only = ClassFilter::all().exclude("java/*");
+        assert!(exclude_only.matches(Some("com/example/Main")));
+        assert!(!exclude_only.matches(Some("java/lang/Object")));
+        assert!(!exclude_only.matches(None));
+    }
+
+    #[test]
+    fn transformers_run_by_priority_then_registration() {
+        let registry = TransformerRegistry::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for (name, priority, retransform) in [
+            ("low", 0, true),
+            ("high", 10, false),
+            ("low again", 0, false),
+            ("middle", 5, true),
+        ] {
+            let order = order.clone();
+            registry.register(
+                options(priority, ClassFilter::all(), retransform),
+                transformer(move |_, _| {
+                    order.lock().unwrap().push(name);
+                    Ok(None)
+                }),
+            );
+        }
+
+        assert_eq!(
+            registry.transform(&class_load("Fixture", false), FIXTURE),
+            None
+        );
+        assert_eq!(
+            *order.lock().unwrap(),
+            ["high", "middle", "low", "low again"]
+        );
+
+        order.lock().unwrap().clear();
+        registry.transform(&class_load("Fixture", true), FIXTURE);
+        assert_eq!(*order.lock().unwrap(), ["middle", "low"]);
+    }
+
+    #[test]
+    fn failing_transformers_are_isolated() {
+        let registry = TransformerRegistry::new();
+        let all = ClassFilter::all();
+        let panics = registry.register(
+            options(3, all.clone(), false),
+            transformer(|_, _| panic!("boom")),
+        );
+        let invalid = registry.register(
+            options(2, all.clone(), false),
+            transformer(|_, _| Ok(Some(vec![0xca, 0xfe]))),
+        );
+        let fails = registry.register(
+            options(1, all.clone(), false),
+            transformer(|_, _| Err("no thanks".into())),
+        );
+        // Sees the original class file and replaces it.
+        let valid = registry.register(
+            options(0, all.clone(), false),
+            transformer(|_, data| {
+                assert_eq!(data, FIXTURE);
+                Ok(Some(data.to_vec()))
+            }),
+        );
+        let skipped = registry.register(
+            options(0, ClassFilter::all().include("Other"), false),
+            transformer(|_, _| panic!("filtered out")),
+        );
+
+        let transformed = registry.transform(&class_load("Fixture", false), FIXTURE);
+        assert_eq!(transformed.as_deref(), Some(FIXTURE));
+
+        let failure = |message: &str| TransformerStats {
+            failed: 1,
+            last_error: Some(("Fixture".to_owned(), message.to_owned())),
+            ..TransformerStats::default()
+        };
+        assert_eq!(registry.stats(panics), Some(failure("panicked: boom")));
+        let invalid = registry.stats(invalid).unwrap();
+        assert_eq!(invalid.failed, 1);
+        assert!(invalid
+            .last_error
+            .unwrap()
+            .1
+            .starts_with("invalid class file: "));
+        assert_eq!(registry.stats(fails), Some(failure("no thanks")));
+        assert_eq!(
+            registry.stats(valid),
+            Some(TransformerStats {
+                transformed: 1,
+                ..TransformerStats::default()
+            })
+        );
+        assert_eq!(registry.stats(skipped), Some(TransformerStats::default()));
+    }
+}
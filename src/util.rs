@@ -0,0 +1,73 @@
+//! Internal helpers shared by the higher level modules of this crate.
+
+use core::ffi::{c_char, c_uchar};
+use std::ffi::CStr;
+
+use jni_sys::{jint, jobject, JNIEnv};
+
+use crate::{jvmtiEnv, jvmtiError};
+
+/// Calls `$name` from the `$version` section of the JVMTI function table,
+/// passing `$env` as the first argument.
+macro_rules! jvmti {
+    ($env:expr, $version:ident, $name:ident $(, $arg:expr)* $(,)?) => {
+        ((**$env).$version.$name)($env $(, $arg)*)
+    };
+}
+
+/// Turns a JVMTI error code into a `Result`.
+pub(crate) fn check(err: jvmtiError) -> Result<(), jvmtiError> {
+    match err {
+        jvmtiError::JVMTI_ERROR_NONE => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Releases memory handed out by the VM. Null pointers are ignored.
+pub(crate) unsafe fn deallocate<T>(env: *mut jvmtiEnv, ptr: *mut T) {
+    if !ptr.is_null() {
+        jvmti!(env, v1, Deallocate, ptr as *mut c_uchar);
+    }
+}
+
+/// Copies a VM-allocated modified UTF-8 string and deallocates it.
+pub(crate) unsafe fn take_string(env: *mut jvmtiEnv, ptr: *mut c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+    let value = CStr::from_ptr(ptr).to_string_lossy().into_owned();
+    deallocate(env, ptr);
+    Some(value)
+}
+
+/// Copies a VM-allocated array of `count` elements and deallocates it.
+pub(crate) unsafe fn take_array<T: Copy>(env: *mut jvmtiEnv, ptr: *mut T, count: jint) -> Vec<T> {
+    if ptr.is_null() {
+        return Vec::new();
+    }
+    let values = core::slice::from_raw_parts(ptr, count.max(0) as usize).to_vec();
+    deallocate(env, ptr);
+    values
+}
+
+/// Turns a class signature such as `Ljava/lang/String;` into the name
+/// `Class.getName()` would return, e.g. `java.lang.String` or
+/// `[Ljava.lang.String;`.
+pub(crate) fn class_name(signature: &str) -> String {
+    match signature
+        .strip_prefix('L')
+        .and_then(|s| s.strip_suffix(';'))
+    {
+        Some(name) => name.replace('/', "."),
+        None => signature.replace('/', "."),
+    }
+}
+
+/// Deletes JNI local references. Null references are ignored.
+pub(crate) unsafe fn delete_local_refs(jni: *mut JNIEnv, objects: &[jobject]) {
+    for &object in objects {
+        if !object.is_null() {
+            ((**jni).v1_1.DeleteLocalRef)(jni, object);
+        }
+    }
+}